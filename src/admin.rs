@@ -0,0 +1,698 @@
+use anyhow::{anyhow, bail, Result};
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use rand::Rng;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::assets;
+use crate::body::{self, Body};
+use crate::capture::warc_date;
+use crate::config::{AdminConfig, Config};
+use crate::context::RequestContext;
+use crate::diagnostics;
+use crate::history;
+use crate::logging;
+use crate::page_api;
+use crate::profiling::{self, ProfileFormat};
+use crate::proxy::{ClientConnection, ProxyState};
+use crate::script_manager::ScriptManager;
+
+/// Cookie holding a browser session, sent back only to `/admin/`.
+const SESSION_COOKIE: &str = "rusty_proxy_admin";
+
+/// Header carrying a session's CSRF token on requests that change something.
+const CSRF_HEADER: &str = "x-csrf-token";
+
+/// How long a client on the HTTPS listener gets to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Browser sessions opened with `POST /admin/login`, by session ID. A session
+/// authenticates through its cookie; requests other than `GET` and `HEAD` must also echo
+/// its CSRF token in `X-CSRF-Token`, which other sites' pages can't read.
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+struct Session {
+    csrf: String,
+    expires: Instant,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Sessions {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens a session, returning its ID and CSRF token.
+    fn open(&self, ttl: Duration) -> (String, String) {
+        let (id, csrf) = (random_token(), random_token());
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > Instant::now());
+        sessions.insert(
+            id.clone(),
+            Session {
+                csrf: csrf.clone(),
+                expires: Instant::now() + ttl,
+            },
+        );
+        (id, csrf)
+    }
+
+    /// The CSRF token and remaining lifetime of the request's live session.
+    fn find(&self, req: &Request<Body>) -> Option<(String, String, Duration)> {
+        let id = session_cookie(req)?;
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(&id)?;
+        let left = session.expires.checked_duration_since(Instant::now())?;
+        Some((id, session.csrf.clone(), left))
+    }
+
+    fn close(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+/// Admin requests are sent straight to the proxy (origin-form, no host in the request
+/// target) under `/admin/`, so they never collide with proxied traffic.
+pub fn is_admin_request(req: &Request<Body>, config: &Config) -> bool {
+    config.admin.enabled && req.uri().authority().is_none() && req.uri().path().starts_with("/admin/")
+}
+
+/// Answers an admin request; `secure` when it arrived on the HTTPS listener, so session
+/// cookies are marked `Secure`.
+pub async fn handle(req: Request<Body>, client_addr: SocketAddr, state: &ProxyState, secure: bool) -> Response<Body> {
+    if (req.method(), req.uri().path()) == (&Method::POST, "/admin/login") {
+        return login(req, client_addr, state, secure).await;
+    }
+    if !is_authorized(&req, client_addr, state) {
+        warn!("Rejected admin request from {}", client_addr);
+        return json_response(StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }));
+    }
+
+    info!("Admin {} {} from {}", req.method(), req.uri().path(), client_addr);
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/admin/session") => match state.admin_sessions.find(&req) {
+            Some((_, csrf, left)) => json_response(StatusCode::OK, json!({ "csrf_token": csrf, "expires_in": left.as_secs() })),
+            None => json_response(StatusCode::NOT_FOUND, json!({ "error": "no session" })),
+        },
+        (&Method::POST, "/admin/logout") => {
+            if let Some((id, _, _)) = state.admin_sessions.find(&req) {
+                state.admin_sessions.close(&id);
+            }
+            let mut response = json_response(StatusCode::OK, json!({ "logged_out": true }));
+            if let Ok(cookie) = cookie_header("", 0, secure).parse() {
+                response.headers_mut().insert("set-cookie", cookie);
+            }
+            response
+        }
+        (&Method::GET, "/admin/metrics") => Response::builder()
+            .status(200)
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(match &state.conformance {
+                Some(conformance) => state.metrics.render() + &conformance.render_metrics(),
+                None => state.metrics.render(),
+            }))
+            .unwrap(),
+        (&Method::GET, "/admin/pool") => json_response(
+            StatusCode::OK,
+            json!({
+                "idle_timeout_secs": state.config.proxy.pool_idle_timeout,
+                "max_idle_per_host": state.config.proxy.pool_max_idle_per_host,
+                "hit_ratio": state.metrics.pool_hit_ratio(),
+            }),
+        ),
+        (&Method::POST, "/admin/pool/flush") => {
+            state.upstream.flush();
+            json_response(StatusCode::OK, json!({ "flushed": true }))
+        }
+        (&Method::GET, "/admin/concurrency") => match &state.concurrency {
+            Some(concurrency) => json_response(StatusCode::OK, concurrency.snapshot()),
+            None => json_response(StatusCode::NOT_FOUND, json!({ "error": "adaptive concurrency is off (concurrency.enabled)" })),
+        },
+        (&Method::GET, "/admin/tunnels") => json_response(StatusCode::OK, state.tunnels.snapshot()),
+        (&Method::GET, "/admin/tunnels/ports") => json_response(StatusCode::OK, state.tunnels.port_snapshot()),
+        (&Method::GET, "/admin/injections") => json_response(StatusCode::OK, state.injector.recent_injections()),
+        (&Method::POST, "/admin/scripts/reload") => match state.injector.reload_scripts() {
+            Ok(count) => json_response(StatusCode::OK, json!({ "reloaded": true, "scripts": count })),
+            Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": e.to_string() })),
+        },
+        (&Method::GET, path) if path.starts_with("/admin/scripts/") && path.ends_with("/history") => {
+            let name = &path["/admin/scripts/".len()..path.len() - "/history".len()];
+            let scripts_dir = state.injector.script_manager().scripts_dir().to_path_buf();
+            match history::list(&scripts_dir, name) {
+                Ok(versions) => json_response(StatusCode::OK, json!({ "script": name, "versions": versions })),
+                Err(e) => json_response(StatusCode::NOT_FOUND, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::POST, path) if path.starts_with("/admin/scripts/") && path.ends_with("/rollback") => {
+            let name = &path["/admin/scripts/".len()..path.len() - "/rollback".len()];
+            let Some(to) = query_param(&req, "to").and_then(|to| to.parse::<u32>().ok()) else {
+                return json_response(StatusCode::BAD_REQUEST, json!({ "error": "to must be a version number from the script's history" }));
+            };
+            let scripts_dir = state.injector.script_manager().scripts_dir().to_path_buf();
+            let recorded = match history::rollback(&scripts_dir, name, to) {
+                Ok(recorded) => recorded,
+                Err(e) => return json_response(StatusCode::NOT_FOUND, json!({ "error": e.to_string() })),
+            };
+            info!("Script {} rolled back to version {} through the admin API", name, to);
+            match state.injector.reload_scripts() {
+                Ok(count) => json_response(StatusCode::OK, json!({ "script": name, "rolled_back_to": to, "version": recorded, "scripts": count })),
+                Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::POST, path) if path.starts_with("/admin/scripts/") && path.ends_with("/try") => {
+            let name = &path["/admin/scripts/".len()..path.len() - "/try".len()];
+            match state.injector.try_script(name) {
+                Ok(result) => json_response(StatusCode::OK, result),
+                Err(e) => json_response(StatusCode::NOT_FOUND, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::GET, "/admin/exchange") => match state.injector.last_exchange() {
+            Some(exchange) => json_response(StatusCode::OK, exchange),
+            None => json_response(StatusCode::NOT_FOUND, json!({ "error": "no response has reached the scripts since the REPL attached" })),
+        },
+        (&Method::POST, "/admin/exchange/watch") => {
+            state.injector.watch_exchanges();
+            json_response(StatusCode::OK, json!({ "watching": true }))
+        }
+        (&Method::GET, "/admin/store") => json_response(StatusCode::OK, json!(state.page_api.store_entries())),
+        (method, path) if path.starts_with("/admin/store/") => {
+            let key = path["/admin/store/".len()..].to_string();
+            if key.is_empty() {
+                return json_response(StatusCode::BAD_REQUEST, json!({ "error": "missing key" }));
+            }
+            match method.clone() {
+                Method::GET => match state.page_api.store_get(&key) {
+                    Some(value) => json_response(StatusCode::OK, json!({ "key": key, "value": value })),
+                    None => json_response(StatusCode::NOT_FOUND, json!({ "error": "no such key" })),
+                },
+                Method::PUT => {
+                    let value = match body::to_bytes(req.into_body()).await {
+                        Ok(body) => String::from_utf8_lossy(&body).to_string(),
+                        Err(e) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+                    };
+                    if !state.page_api.store_set(&key, value) {
+                        return json_response(StatusCode::PAYLOAD_TOO_LARGE, json!({ "error": "value too large" }));
+                    }
+                    json_response(StatusCode::OK, json!({ "key": key, "stored": true }))
+                }
+                Method::DELETE => json_response(StatusCode::OK, json!({ "key": key, "removed": state.page_api.store_remove(&key) })),
+                _ => json_response(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "method not allowed" })),
+            }
+        }
+        (&Method::POST, path) if path.starts_with("/admin/scripts/") && (path.ends_with("/enable") || path.ends_with("/disable")) => {
+            let (name, action) = path["/admin/scripts/".len()..].rsplit_once('/').unwrap_or_default();
+            let enabled = action == "enable";
+            if name.is_empty() || !state.injector.script_manager().list_scripts().iter().any(|n| n == name) {
+                return json_response(StatusCode::NOT_FOUND, json!({ "error": "no such script" }));
+            }
+            state.cluster.toggle(&state.injector, name, enabled);
+            info!("Script {} {} through the admin API", name, if enabled { "enabled" } else { "disabled" });
+            json_response(StatusCode::OK, json!({ "script": name, "enabled": enabled }))
+        }
+        (&Method::GET, "/admin/cluster") => json_response(StatusCode::OK, state.cluster.status(&state.injector)),
+        (&Method::POST, "/admin/cluster/state") => {
+            let remote = match body::to_bytes(req.into_body()).await.map_err(|e| e.to_string()).and_then(|body| serde_json::from_slice(&body).map_err(|e| e.to_string())) {
+                Ok(remote) => remote,
+                Err(e) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e })),
+            };
+            state.cluster.merge(&state.injector, remote);
+            json_response(StatusCode::OK, json!(state.cluster.snapshot(&state.injector)))
+        }
+        (&Method::GET, "/admin/assertions") | (&Method::DELETE, "/admin/assertions") => match &state.assertions {
+            Some(assertions) if req.method() == Method::DELETE => {
+                assertions.reset();
+                json_response(StatusCode::OK, json!(assertions.report()))
+            }
+            Some(assertions) => json_response(StatusCode::OK, json!(assertions.report())),
+            None => json_response(StatusCode::NOT_FOUND, json!({ "error": "no assertions file is configured" })),
+        },
+        (&Method::GET, "/admin/openapi") | (&Method::DELETE, "/admin/openapi") => match &state.conformance {
+            Some(conformance) if req.method() == Method::DELETE => {
+                conformance.reset();
+                json_response(StatusCode::OK, conformance.report())
+            }
+            Some(conformance) => json_response(StatusCode::OK, conformance.report()),
+            None => json_response(StatusCode::NOT_FOUND, json!({ "error": "no OpenAPI spec is configured" })),
+        },
+        (&Method::GET, "/admin/sso") | (&Method::DELETE, "/admin/sso") => match &state.sso {
+            Some(sso) if req.method() == Method::DELETE => {
+                sso.reset();
+                json_response(StatusCode::OK, sso.report())
+            }
+            Some(sso) => json_response(StatusCode::OK, sso.report()),
+            None => json_response(StatusCode::NOT_FOUND, json!({ "error": "SSO tracing is off (sso.trace)" })),
+        },
+        (&Method::GET, "/admin/explain") => {
+            let param = |name: &str| query_param(&req, name).and_then(|value| assets::percent_decode(&value));
+            let status = param("status").and_then(|status| status.parse().ok());
+            match explain_context(&param("url").unwrap_or_default(), param("method").as_deref(), param("headers").as_deref(), client_addr, state) {
+                Ok(ctx) => json_response(StatusCode::OK, state.injector.explain(&ctx, status)),
+                Err(e) => json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::GET, "/admin/trace") => json_response(StatusCode::OK, state.tracer.status()),
+        (&Method::PUT, "/admin/trace") => {
+            let url = query_param(&req, "url").and_then(|url| assets::percent_decode(&url)).unwrap_or_default();
+            let file = query_param(&req, "file").and_then(|file| assets::percent_decode(&file));
+            let seconds = query_param(&req, "seconds").and_then(|s| s.parse().ok());
+            match state.tracer.start(&url, file.as_deref(), seconds) {
+                Ok(status) => json_response(StatusCode::OK, status),
+                Err(e) => json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::DELETE, "/admin/trace") => json_response(StatusCode::OK, state.tracer.stop()),
+        (&Method::GET, "/admin/storage") => {
+            let Some(storage) = &state.storage else {
+                return json_response(StatusCode::NOT_FOUND, json!({ "error": "no storage backend is configured" }));
+            };
+            let prefix = query_param(&req, "prefix").and_then(|prefix| assets::percent_decode(&prefix)).unwrap_or_default();
+            match storage.list(&prefix).await {
+                Ok(keys) => json_response(
+                    StatusCode::OK,
+                    json!({
+                        "storage": storage.describe(),
+                        "keys": keys
+                            .iter()
+                            .map(|object| json!({ "key": object.key, "size": object.size, "modified": object.modified.map(warc_date) }))
+                            .collect::<Vec<_>>(),
+                    }),
+                ),
+                Err(e) => json_response(StatusCode::BAD_GATEWAY, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::GET, path) | (&Method::DELETE, path) if path.starts_with("/admin/storage/") => {
+            let Some(storage) = &state.storage else {
+                return json_response(StatusCode::NOT_FOUND, json!({ "error": "no storage backend is configured" }));
+            };
+            let key = assets::percent_decode(&path["/admin/storage/".len()..]).unwrap_or_default();
+            if req.method() == Method::DELETE {
+                return match storage.delete(&key).await {
+                    Ok(()) => json_response(StatusCode::OK, json!({ "deleted": key })),
+                    Err(e) => json_response(StatusCode::BAD_GATEWAY, json!({ "error": e.to_string() })),
+                };
+            }
+            match storage.get(&key).await {
+                Ok(Some(data)) => binary_response("application/octet-stream", data),
+                Ok(None) => json_response(StatusCode::NOT_FOUND, json!({ "error": "no such key" })),
+                Err(e) => json_response(StatusCode::BAD_GATEWAY, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::POST, "/admin/upgrade") => {
+            state.upgrade.notify_one();
+            json_response(StatusCode::ACCEPTED, json!({ "upgrading": true, "pid": std::process::id() }))
+        }
+        (&Method::GET, "/admin/debug/pprof/profile") | (&Method::GET, "/admin/debug/pprof/flamegraph") => {
+            if !state.config.admin.profiling {
+                return json_response(StatusCode::FORBIDDEN, json!({ "error": "profiling is disabled" }));
+            }
+            let (format, content_type) = if req.uri().path().ends_with("flamegraph") {
+                (ProfileFormat::Flamegraph, "image/svg+xml")
+            } else {
+                (ProfileFormat::Protobuf, "application/octet-stream")
+            };
+            let seconds = query_param(&req, "seconds").and_then(|s| s.parse().ok()).unwrap_or(10);
+            match profiling::cpu_profile(seconds, format).await {
+                Ok(body) => binary_response(content_type, body),
+                Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::GET, "/admin/debug/pprof/heap") => {
+            if !state.config.admin.profiling {
+                return json_response(StatusCode::FORBIDDEN, json!({ "error": "profiling is disabled" }));
+            }
+            match profiling::heap_profile().await {
+                Ok(body) => binary_response("application/octet-stream", body),
+                Err(e) => json_response(StatusCode::NOT_IMPLEMENTED, json!({ "error": e.to_string() })),
+            }
+        }
+        (&Method::GET, "/admin/diagnostics") => json_response(StatusCode::OK, diagnostics::snapshot(state)),
+        (&Method::GET, "/admin/log-level") => {
+            json_response(StatusCode::OK, json!({ "filter": logging::current_filter() }))
+        }
+        (&Method::PUT, "/admin/log-level") => {
+            let body = match body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(e) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+            };
+            let directives = String::from_utf8_lossy(&body).trim().to_string();
+            match logging::set_filter(&directives) {
+                Ok(()) => {
+                    info!("Log filter changed to {}", logging::current_filter());
+                    json_response(StatusCode::OK, json!({ "filter": logging::current_filter() }))
+                }
+                Err(e) => json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+            }
+        }
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "unknown admin endpoint" })),
+    }
+}
+
+/// With a token configured every caller must present it as a bearer token, or hold a
+/// session opened with it; without one the admin API only answers local programs on
+/// loopback (see `is_local_caller`).
+fn is_authorized(req: &Request<Body>, client_addr: SocketAddr, state: &ProxyState) -> bool {
+    let Some(token) = state.config.admin.token.as_deref().filter(|t| !t.is_empty()) else {
+        return client_addr.ip().is_loopback() && is_local_caller(req);
+    };
+    if bearer_token(req).is_some_and(|presented| secret_eq(presented, token)) {
+        return true;
+    }
+    match state.admin_sessions.find(req) {
+        Some(_) if matches!(*req.method(), Method::GET | Method::HEAD) => true,
+        Some((_, csrf, _)) => req.headers().get(CSRF_HEADER).and_then(|v| v.to_str().ok()).is_some_and(|sent| secret_eq(sent, &csrf)),
+        None => false,
+    }
+}
+
+/// Whether a token-less admin request comes from a program on this machine rather than a
+/// web page open in a browser here. The host asked for must be a loopback name, so a page
+/// can't reach the API through a rebound DNS name, and requests that change something
+/// must not come from another origin: browsers send `Origin` (and `Sec-Fetch-Site`) on
+/// those, while the CLI and scripts send neither.
+fn is_local_caller(req: &Request<Body>) -> bool {
+    let host = req.uri().authority().map(|authority| authority.as_str().to_string()).or_else(|| {
+        req.headers().get(hyper::header::HOST).and_then(|v| v.to_str().ok()).map(str::to_string)
+    });
+    let Some(authority) = host.and_then(|host| host.parse::<hyper::http::uri::Authority>().ok()) else {
+        return false;
+    };
+    let name = authority.host().trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+    let loopback = match name.parse::<std::net::IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => name == "localhost" || name.ends_with(".localhost"),
+    };
+    if !loopback {
+        return false;
+    }
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        return true;
+    }
+
+    let fetch_site = req.headers().get("sec-fetch-site").and_then(|v| v.to_str().ok());
+    if fetch_site.is_some_and(|site| site != "same-origin" && site != "none") {
+        return false;
+    }
+    match req.headers().get("origin") {
+        None => true,
+        Some(origin) => {
+            let Some(origin) = origin.to_str().ok().and_then(|origin| origin.parse::<Uri>().ok()) else {
+                return false;
+            };
+            let target = format!("{}://{}", origin.scheme_str().unwrap_or("http"), authority);
+            match (page_api::origin(&origin), target.parse().ok().as_ref().and_then(page_api::origin)) {
+                (Some(origin), Some(target)) => origin == target,
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Whether a presented secret matches, comparing every byte so the time taken doesn't
+/// tell how much of it was right.
+fn secret_eq(presented: &str, secret: &str) -> bool {
+    presented.len() == secret.len() && presented.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// `POST /admin/login`: trades the admin token, as a bearer token or `{"token": "..."}`,
+/// for a session cookie and the CSRF token that goes with it.
+async fn login(req: Request<Body>, client_addr: SocketAddr, state: &ProxyState, secure: bool) -> Response<Body> {
+    let Some(token) = state.config.admin.token.as_deref().filter(|t| !t.is_empty()) else {
+        return json_response(StatusCode::BAD_REQUEST, json!({ "error": "no admin token configured" }));
+    };
+    let presented = match bearer_token(&req).map(str::to_string) {
+        Some(presented) => Some(presented),
+        None => body::to_bytes(req.into_body())
+            .await
+            .ok()
+            .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok())
+            .and_then(|body| body["token"].as_str().map(str::to_string)),
+    };
+    if !presented.is_some_and(|presented| secret_eq(&presented, token)) {
+        warn!("Rejected admin login from {}", client_addr);
+        return json_response(StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }));
+    }
+
+    let ttl = state.config.admin.session_ttl;
+    let (id, csrf) = state.admin_sessions.open(Duration::from_secs(ttl));
+    info!("Admin session opened from {}", client_addr);
+    let mut response = json_response(StatusCode::OK, json!({ "csrf_token": csrf, "expires_in": ttl }));
+    if let Ok(cookie) = cookie_header(&id, ttl, secure).parse() {
+        response.headers_mut().insert("set-cookie", cookie);
+    }
+    response
+}
+
+fn bearer_token(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+fn session_cookie(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            (name == SESSION_COOKIE).then(|| value.to_string())
+        })
+}
+
+/// `Set-Cookie` for a session; a `max_age` of 0 clears it.
+fn cookie_header(id: &str, max_age: u64, secure: bool) -> String {
+    let secure = if secure { "; Secure" } else { "" };
+    format!("{}={}; Path=/admin/; Max-Age={}; HttpOnly; SameSite=Strict{}", SESSION_COOKIE, id, max_age, secure)
+}
+
+fn random_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Serves the admin API alone over HTTPS on `tls_listen`, for reaching it beyond
+/// localhost without sending the token or session cookie in the clear. Like the proxy
+/// port, non-loopback addresses need the `open-ports` feature.
+pub async fn serve_tls(state: Arc<ProxyState>) -> Result<()> {
+    let admin = &state.config.admin;
+    let listen = admin.tls_listen.as_deref().unwrap_or_default();
+    let addr: SocketAddr = listen.parse().map_err(|_| anyhow!("Invalid tls_listen {}", listen))?;
+    if !addr.ip().is_loopback() && !state.config.features.open_ports {
+        bail!("Not listening on {}: enable the open-ports feature to accept remote clients", addr);
+    }
+    let acceptor = tls_acceptor(admin)?;
+    let listener = TcpListener::bind(addr).await?;
+    info!("Admin API listening on https://{}", addr);
+
+    loop {
+        let (stream, client_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+        if !state.config.is_ip_allowed(&client_addr.ip().to_string()) {
+            warn!("Rejected admin connection from blocked IP: {}", client_addr.ip());
+            continue;
+        }
+        let (acceptor, state) = (acceptor.clone(), state.clone());
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => return debug!("TLS handshake with {} failed: {}", client_addr, e),
+                Err(_) => return debug!("TLS handshake with {} timed out", client_addr),
+            };
+            let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+                let state = state.clone();
+                async move {
+                    let req = req.map(Body::from);
+                    // HTTP/2 requests carry an authority, so only the path decides here
+                    let response = if req.uri().path().starts_with("/admin/") {
+                        handle(req, client_addr, &state, true).await
+                    } else {
+                        json_response(StatusCode::NOT_FOUND, json!({ "error": "unknown admin endpoint" }))
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            if let Err(e) = auto::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service).await {
+                debug!("Admin connection from {} closed with error: {}", client_addr, e);
+            }
+        });
+    }
+}
+
+pub fn tls_acceptor(admin: &AdminConfig) -> Result<TlsAcceptor> {
+    let default = match (
+        admin.tls_cert.as_deref().filter(|c| !c.is_empty()),
+        admin.tls_key.as_deref().filter(|k| !k.is_empty()),
+    ) {
+        (Some(cert), Some(key)) => Some(certified_key(cert, key)?),
+        (None, None) if !admin.tls_certs.is_empty() => None,
+        _ => bail!("tls_listen needs tls_cert and tls_key, or tls_certs"),
+    };
+    let named = admin
+        .tls_certs
+        .iter()
+        .map(|named| Ok((named.domains.clone(), certified_key(&named.cert, &named.key)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let mut server = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniCerts { named, default }));
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+fn certified_key(cert: &str, key: &str) -> Result<Arc<CertifiedKey>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("Cannot read certificate {}: {}", cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| anyhow!("Cannot read private key {}: {}", key, e))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key).map_err(|e| anyhow!("Unusable private key for {}: {}", cert, e))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// Picks the certificate for the name a client asks for: the first of `tls_certs` whose
+/// domains match it, else `tls_cert`.
+#[derive(Debug)]
+struct SniCerts {
+    named: Vec<(Vec<String>, Arc<CertifiedKey>)>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniCerts {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let named = hello.server_name().map(str::to_ascii_lowercase).and_then(|name| {
+            self.named
+                .iter()
+                .find(|(domains, _)| ScriptManager::domain_matches(&name, domains))
+                .map(|(_, key)| key.clone())
+        });
+        named.or_else(|| self.default.clone())
+    }
+}
+
+/// The context a request for `url` would get from the caller, for a dry run. `headers`
+/// is a JSON object or `Name: value` lines.
+fn explain_context(url: &str, method: Option<&str>, headers: Option<&str>, client_addr: SocketAddr, state: &ProxyState) -> Result<RequestContext> {
+    let uri: Uri = url.parse().map_err(|_| anyhow!("url must be a full URL, e.g. https://example.com/page"))?;
+    if uri.host().is_none() {
+        bail!("url must be a full URL, e.g. https://example.com/page");
+    }
+    let method = Method::from_bytes(method.unwrap_or("GET").to_ascii_uppercase().as_bytes())?;
+    let mut builder = Request::builder().method(method).uri(uri);
+    match headers.map(str::trim).filter(|h| !h.is_empty()) {
+        Some(json) if json.starts_with('{') => {
+            let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json).map_err(|e| anyhow!("headers is not a JSON object: {}", e))?;
+            for (name, value) in fields {
+                builder = builder.header(name, value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string()));
+            }
+        }
+        Some(lines) => {
+            for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+                let (name, value) = line.split_once(':').ok_or_else(|| anyhow!("header line {:?} has no colon", line))?;
+                builder = builder.header(name.trim(), value.trim());
+            }
+        }
+        None => {}
+    }
+    let req = builder.body(Body::empty())?;
+    let conn = ClientConnection {
+        id: 0,
+        addr: client_addr,
+        authenticated: AtomicBool::new(true),
+        requests: AtomicU64::new(1),
+        upgrades: AtomicU64::new(0),
+        opened: Instant::now(),
+    };
+    let scripts = state.injector.script_manager();
+    Ok(RequestContext::new(&req, &conn, 1, &scripts))
+}
+
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| value.to_string())
+    })
+}
+
+fn binary_response(content_type: &str, body: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .status(200)
+        .header("content-type", content_type)
+        .header("content-length", body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+pub fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    let body = value.to_string();
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin_request(method: Method, host: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri("/admin/scripts/reload").header("host", host);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn local_programs_may_change_things_without_a_token() {
+        assert!(is_local_caller(&admin_request(Method::POST, "127.0.0.1:8080", &[])));
+        assert!(is_local_caller(&admin_request(Method::POST, "localhost:8080", &[("origin", "http://localhost:8080")])));
+        assert!(is_local_caller(&admin_request(Method::DELETE, "[::1]:8080", &[("sec-fetch-site", "same-origin"), ("origin", "http://[::1]:8080")])));
+        assert!(is_local_caller(&admin_request(Method::GET, "localhost:8080", &[("origin", "https://evil.example")])));
+    }
+
+    #[test]
+    fn pages_may_not_change_things_without_a_token() {
+        assert!(!is_local_caller(&admin_request(Method::POST, "127.0.0.1:8080", &[("origin", "https://evil.example")])));
+        assert!(!is_local_caller(&admin_request(Method::POST, "127.0.0.1:8080", &[("origin", "null")])));
+        assert!(!is_local_caller(&admin_request(Method::POST, "127.0.0.1:8080", &[("origin", "http://127.0.0.1:9090")])));
+        assert!(!is_local_caller(&admin_request(Method::PUT, "127.0.0.1:8080", &[("sec-fetch-site", "cross-site")])));
+        // A rebound name resolves to loopback but isn't one
+        assert!(!is_local_caller(&admin_request(Method::GET, "rebind.evil.example:8080", &[])));
+    }
+
+    #[test]
+    fn secrets_compare_whole() {
+        assert!(secret_eq("sekret", "sekret"));
+        assert!(!secret_eq("sekreT", "sekret"));
+        assert!(!secret_eq("sekre", "sekret"));
+        assert!(!secret_eq("", "sekret"));
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{anyhow, bail, Result};
+use hyper::body::Bytes;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use crate::bundle;
+use crate::config::Config;
+use crate::diagnostics;
+use crate::proxy::ProxyState;
+
+/// How long one request to the management server may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Header naming this instance to the management server.
+const NODE_HEADER: &str = "x-rusty-proxy-node";
+
+/// Polls the management server at `[agent] url` every `interval`:
+///
+/// - `GET <url>/scripts`: a bundle from `script export` (or a single script), which
+///   becomes the whole contents of the managed directory
+/// - `GET <url>/config`: a complete config file, which replaces the one the proxy was
+///   started with; the proxy then upgrades in place to apply it
+/// - `POST <url>/stats`: this instance's diagnostics snapshot, as JSON
+///
+/// Either `GET` may answer `404` for a part the server doesn't manage, and `304` when
+/// it still matches the `ETag` from the last poll.
+pub async fn run(state: Arc<ProxyState>) {
+    let Some(url) = state.config.agent.url.as_deref().filter(|url| !url.is_empty()) else {
+        return;
+    };
+    let mut agent = Agent {
+        url: url.trim_end_matches('/').to_string(),
+        client: reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap_or_default(),
+        etags: HashMap::new(),
+        applied: HashMap::new(),
+        state,
+    };
+    info!("Agent: managed by {}", agent.url);
+    let mut ticks = tokio::time::interval(Duration::from_secs(agent.state.config.agent.interval.max(1)));
+    let mut failing = false;
+    loop {
+        ticks.tick().await;
+        match agent.poll().await {
+            // Logged once when the server goes away, not every poll
+            Err(e) if !failing => {
+                warn!("Agent: management server {} failed: {}", agent.url, e);
+                failing = true;
+            }
+            Err(e) => debug!("Agent: poll failed: {}", e),
+            Ok(()) if failing => {
+                info!("Agent: management server {} is back", agent.url);
+                failing = false;
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+struct Agent {
+    state: Arc<ProxyState>,
+    url: String,
+    client: reqwest::Client,
+    /// `ETag` of each resource last fetched
+    etags: HashMap<&'static str, String>,
+    /// Digest of each resource last applied, so an unchanged one isn't applied again
+    /// when the server sends no `ETag`
+    applied: HashMap<&'static str, Vec<u8>>,
+}
+
+impl Agent {
+    async fn poll(&mut self) -> Result<()> {
+        if let Some(bundle) = self.fetch("scripts").await? {
+            let count = self.install_scripts(&bundle)?;
+            info!("Agent: scripts updated, {} loaded", count);
+            self.applied.insert("scripts", Sha256::digest(&bundle).to_vec());
+        }
+        if let Some(config) = self.fetch("config").await? {
+            let changed = self.write_config(&config)?;
+            self.applied.insert("config", Sha256::digest(&config).to_vec());
+            if changed {
+                info!("Agent: config updated, upgrading to apply it");
+                self.state.upgrade.notify_one();
+            }
+        }
+        self.push_stats().await
+    }
+
+    /// The resource, unless it is unchanged since it was last applied.
+    async fn fetch(&mut self, resource: &'static str) -> Result<Option<Bytes>> {
+        let mut request = self.request(self.client.get(format!("{}/{}", self.url, resource)));
+        if let Some(etag) = self.etags.get(resource) {
+            request = request.header("if-none-match", etag);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_MODIFIED || status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            bail!("GET {} answered {}", resource, status);
+        }
+        let etag = response.headers().get("etag").and_then(|v| v.to_str().ok()).map(str::to_string);
+        let body = response.bytes().await?;
+        if let Some(etag) = etag {
+            self.etags.insert(resource, etag);
+        }
+        let unchanged = self.applied.get(resource).is_some_and(|digest| *digest == Sha256::digest(&body).to_vec());
+        Ok((!unchanged).then_some(body))
+    }
+
+    /// Replaces the managed directory with the bundle's scripts and reloads. The old
+    /// scripts are put back if the bundle can't be installed.
+    fn install_scripts(&self, bundle: &[u8]) -> Result<usize> {
+        let directory = &self.state.config.agent.directory;
+        let mut components = Path::new(directory).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            bail!("agent.directory must be a plain directory name, not {:?}", directory);
+        }
+        let assets_dir = PathBuf::from(&self.state.config.scripts.assets_dir);
+        self.state.injector.update_scripts(|scripts_dir| {
+            let managed = scripts_dir.join(directory);
+            let previous = scripts_dir.join(format!("{}.previous", directory));
+            let _ = fs::remove_dir_all(&previous);
+            if managed.exists() {
+                fs::rename(&managed, &previous)?;
+            }
+            match bundle::install_data(bundle, &self.url, &managed, &assets_dir, true) {
+                Ok(_) => {
+                    let _ = fs::remove_dir_all(&previous);
+                    Ok(())
+                }
+                Err(e) => {
+                    let _ = fs::remove_dir_all(&managed);
+                    if previous.exists() {
+                        fs::rename(&previous, &managed)?;
+                    }
+                    Err(e)
+                }
+            }
+        })
+    }
+
+    /// Writes a config that parses over the proxy's own; false when it is already the same.
+    fn write_config(&self, config: &[u8]) -> Result<bool> {
+        let path = self.state.config.path.as_ref().ok_or_else(|| anyhow!("no config file to update"))?;
+        let text = std::str::from_utf8(config).map_err(|_| anyhow!("config is not UTF-8"))?;
+        toml::from_str::<Config>(text).map_err(|e| anyhow!("config is invalid: {}", e))?;
+        if fs::read(path).is_ok_and(|current| current == config) {
+            return Ok(false);
+        }
+        fs::write(path, config)?;
+        Ok(true)
+    }
+
+    async fn push_stats(&self) -> Result<()> {
+        let body = json!({
+            "node": self.state.cluster.node(),
+            "diagnostics": diagnostics::snapshot(&self.state),
+        });
+        let request = self
+            .request(self.client.post(format!("{}/stats", self.url)))
+            .header("content-type", "application/json")
+            .body(body.to_string());
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            bail!("POST stats answered {}", response.status());
+        }
+        Ok(())
+    }
+
+    fn request(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = request.header(NODE_HEADER, self.state.cluster.node());
+        match self.state.config.agent.token.as_deref().filter(|t| !t.is_empty()) {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use hyper::body::Bytes;
+use hyper::Response;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::body::Body;
+use crate::context::RequestContext;
+use crate::pattern;
+use crate::script_manager::InjectionScript;
+use crate::streaming::{self, ChunkRewriter};
+
+/// An alert fires at most this often per script; the ones in between are counted and
+/// reported with the next.
+const ALERT_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// How much of a body is searched for an alert's `pattern`.
+const WATCH_LIMIT: usize = 1024 * 1024;
+
+/// Longest match quoted in an alert.
+const MATCH_QUOTE_LIMIT: usize = 200;
+
+/// Fires `Alert` scripts: webhooks and desktop notifications about the traffic they
+/// watch for.
+pub struct Alerts {
+    client: reqwest::Client,
+    /// Per script: when it last fired, and how many alerts were held back since
+    last_fired: Mutex<HashMap<String, (Instant, u64)>>,
+}
+
+impl Alerts {
+    pub fn new() -> Self {
+        Alerts {
+            client: reqwest::Client::new(),
+            last_fired: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fires the scripts without a `pattern` right away; the rest fire once the body has
+    /// passed through, if it matched.
+    pub fn watch(self: &Arc<Self>, scripts: Vec<InjectionScript>, ctx: &RequestContext, res: Response<Body>) -> Response<Body> {
+        let event = json!({
+            "method": ctx.method.as_str(),
+            "url": ctx.url.to_string(),
+            "status": res.status().as_u16(),
+            "client": ctx.client_ip.to_string(),
+        });
+        let mut patterns = Vec::new();
+        for script in scripts {
+            match script.pattern.as_deref().map(pattern::compile) {
+                None => self.fire(&script, &event, None),
+                Some(Ok(regex)) => patterns.push((script, regex)),
+                Some(Err(e)) => warn!("Invalid alert pattern in script {}: {}", script.name, e),
+            }
+        }
+        if patterns.is_empty() {
+            return res;
+        }
+        let (parts, body) = res.into_parts();
+        let watcher = Watcher {
+            alerts: self.clone(),
+            patterns,
+            event,
+            body: Some(Vec::new()),
+        };
+        Response::from_parts(parts, streaming::rewrite_body(body, watcher))
+    }
+
+    fn fire(&self, script: &InjectionScript, event: &Value, matched: Option<&str>) {
+        let suppressed = {
+            let mut last_fired = self.last_fired.lock().unwrap();
+            match last_fired.get_mut(&script.name) {
+                Some((at, held)) if at.elapsed() < ALERT_COOLDOWN => {
+                    *held += 1;
+                    debug!("Alert {} held back by its cooldown", script.name);
+                    return;
+                }
+                entry => {
+                    let held = entry.map(|(_, held)| *held).unwrap_or(0);
+                    last_fired.insert(script.name.clone(), (Instant::now(), 0));
+                    held
+                }
+            }
+        };
+
+        let mut event = event.clone();
+        event["alert"] = json!(script.name);
+        event["description"] = json!(script.description);
+        event["matched"] = json!(matched);
+        event["suppressed"] = json!(suppressed);
+        event["at"] = json!(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+        let summary = format!(
+            "{} {} returned {}",
+            event["method"].as_str().unwrap_or(""),
+            event["url"].as_str().unwrap_or(""),
+            event["status"]
+        );
+        info!("Alert {} fired: {}", script.name, summary);
+
+        // A body dropped after the runtime shut down can only log its alert
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        if let Some(webhook) = script.webhook.clone() {
+            let client = self.client.clone();
+            let payload = event.to_string();
+            let name = script.name.clone();
+            runtime.spawn(async move {
+                match client.post(&webhook).header("content-type", "application/json").body(payload).send().await {
+                    Ok(res) if !res.status().is_success() => warn!("Webhook for alert {} returned {}", name, res.status()),
+                    Ok(_) => {}
+                    Err(e) => warn!("Webhook for alert {} failed: {}", name, e),
+                }
+            });
+        }
+        if script.desktop {
+            let title = format!("rusty-proxy: {}", script.name);
+            runtime.spawn(async move {
+                if let Err(e) = tokio::process::Command::new("notify-send").arg(title).arg(summary).status().await {
+                    warn!("Desktop notification failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Keeps the start of the body aside for alerts with a `pattern`.
+struct Watcher {
+    alerts: Arc<Alerts>,
+    patterns: Vec<(InjectionScript, Regex)>,
+    event: Value,
+    body: Option<Vec<u8>>,
+}
+
+impl Watcher {
+    fn complete(&mut self) {
+        let Some(body) = self.body.take() else {
+            return;
+        };
+        let text = String::from_utf8_lossy(&body);
+        for (script, regex) in &self.patterns {
+            if let Some(found) = regex.find(&text) {
+                let mut cut = found.as_str().len().min(MATCH_QUOTE_LIMIT);
+                while !found.as_str().is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                self.alerts.fire(script, &self.event, Some(&found.as_str()[..cut]));
+            }
+        }
+    }
+}
+
+impl ChunkRewriter for Watcher {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        if let Some(body) = &mut self.body {
+            let room = WATCH_LIMIT.saturating_sub(body.len());
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+        Bytes::copy_from_slice(chunk)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.complete();
+        Bytes::new()
+    }
+}
+
+/// Hyper may drop a body without polling it to the end once `Content-Length` is sent.
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
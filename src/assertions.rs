@@ -0,0 +1,439 @@
+use anyhow::{anyhow, bail, Result};
+use hyper::body::Bytes;
+use hyper::header::HeaderMap;
+use hyper::{Method, Response};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::{info, warn};
+
+use crate::body::{self, Body};
+use crate::capture::{parse_response, read_warc};
+use crate::config::AssertionsConfig;
+use crate::context::RequestContext;
+use crate::pattern;
+use crate::streaming::{self, ChunkRewriter};
+
+/// How much of a body (before and after decoding) `body containing` searches.
+const BODY_LIMIT: usize = 4 * 1024 * 1024;
+
+/// Violations kept for the report; later ones are only counted.
+const VIOLATION_LIMIT: usize = 1000;
+
+/// Violations `rusty-proxy assert` prints per assertion.
+const PRINT_LIMIT: usize = 10;
+
+/// One line of an assertions file:
+///
+/// ```text
+/// expect GET https://api.example.com/v1/* to have header X-Debug=true
+/// expect * https://shop.example.com/* to not have header Server
+/// expect POST https://api.example.com/v1/orders to have status 201,202
+/// expect GET https://www.example.com/* to have status 2xx
+/// expect GET https://www.example.com/ to have body containing "Welcome"
+/// ```
+///
+/// `*` in the method matches any; in the URL and header values it matches any run of
+/// characters.
+#[derive(Debug, Clone)]
+pub struct Assertion {
+    pub line: usize,
+    pub text: String,
+    method: Option<String>,
+    url: Regex,
+    negated: bool,
+    check: Check,
+}
+
+#[derive(Debug, Clone)]
+enum Check {
+    Header { name: String, value: Option<Regex>, expected: Option<String> },
+    Status(Vec<String>),
+    BodyContains(String),
+}
+
+/// One response to check, as the client received it.
+pub struct Exchange<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub status: u16,
+    pub headers: &'a HeaderMap,
+    /// Still content-encoded; `None` when not recorded
+    pub body: Option<&'a [u8]>,
+}
+
+/// How the assertions fared, by assertion and by violating exchange.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Report {
+    pub assertions: Vec<Tally>,
+    /// The first violations, in the order they were seen
+    pub violations: Vec<Violation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tally {
+    pub line: usize,
+    pub text: String,
+    /// Exchanges the assertion applied to
+    pub matched: u64,
+    pub violated: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Violation {
+    pub line: usize,
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub reason: String,
+}
+
+impl Report {
+    fn new(assertions: &[Assertion]) -> Self {
+        Report {
+            assertions: assertions
+                .iter()
+                .map(|a| Tally {
+                    line: a.line,
+                    text: a.text.clone(),
+                    matched: 0,
+                    violated: 0,
+                })
+                .collect(),
+            violations: Vec::new(),
+        }
+    }
+
+    pub fn violated(&self) -> u64 {
+        self.assertions.iter().map(|t| t.violated).sum()
+    }
+
+    /// Checks one exchange against every assertion that applies to it.
+    fn observe(&mut self, assertions: &[Assertion], exchange: &Exchange) {
+        let mut decoded = None;
+        for (assertion, tally) in assertions.iter().zip(&mut self.assertions) {
+            if !assertion.applies(exchange.method, exchange.url) {
+                continue;
+            }
+            tally.matched += 1;
+            let body = if matches!(assertion.check, Check::BodyContains(_)) {
+                decoded.get_or_insert_with(|| exchange.body.and_then(|b| body::decode(exchange.headers, b, BODY_LIMIT))).as_ref().map(Vec::as_slice)
+            } else {
+                None
+            };
+            if let Err(reason) = assertion.evaluate(exchange, body) {
+                tally.violated += 1;
+                if self.violations.len() < VIOLATION_LIMIT {
+                    self.violations.push(Violation {
+                        line: assertion.line,
+                        method: exchange.method.to_string(),
+                        url: exchange.url.to_string(),
+                        status: exchange.status,
+                        reason,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Assertion {
+    pub fn applies(&self, method: &str, url: &str) -> bool {
+        self.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method)) && self.url.is_match(url)
+    }
+
+    fn needs_body(&self) -> bool {
+        matches!(self.check, Check::BodyContains(_))
+    }
+
+    /// `Err` with what was wrong when the exchange violates the assertion. `body` is the
+    /// decoded body, or the reason it couldn't be decoded.
+    fn evaluate(&self, exchange: &Exchange, body: Option<&[u8]>) -> Result<(), String> {
+        let (holds, expected, actual) = match &self.check {
+            Check::Header { name, value, expected } => {
+                let present: Vec<&str> = exchange.headers.get_all(name.as_str()).iter().filter_map(|v| v.to_str().ok()).collect();
+                let holds = match value {
+                    Some(value) => present.iter().any(|v| value.is_match(v)),
+                    None => !present.is_empty(),
+                };
+                let actual = match present.as_slice() {
+                    [] => format!("{} is missing", name),
+                    values => format!("{} is {:?}", name, values.join(", ")),
+                };
+                let expected = match expected {
+                    Some(value) => format!("header {}={}", name, value),
+                    None => format!("header {}", name),
+                };
+                (holds, expected, actual)
+            }
+            Check::Status(codes) => {
+                let status = exchange.status.to_string();
+                let holds = codes.iter().any(|code| code.len() == 3 && code.chars().zip(status.chars()).all(|(c, s)| c == 'x' || c == s));
+                (holds, format!("status {}", codes.join(",")), format!("status is {}", status))
+            }
+            Check::BodyContains(text) => {
+                let Some(body) = body else {
+                    return Err(format!("body {:?} can't be searched: not recorded or not decodable", text));
+                };
+                let holds = contains(body, text.as_bytes());
+                let actual = if holds { "it does" } else { "it doesn't" };
+                (holds, format!("body containing {:?}", text), actual.to_string())
+            }
+        };
+        match (holds, self.negated) {
+            (true, false) | (false, true) => Ok(()),
+            (false, false) => Err(format!("expected {}, but {}", expected, actual)),
+            (true, true) => Err(format!("expected no {}, but {}", expected, actual)),
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Reads an assertions file; blank lines and `#` comments are skipped.
+pub fn load(path: &Path) -> Result<Vec<Assertion>> {
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow!("Cannot read assertions {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+pub fn parse(text: &str) -> Result<Vec<Assertion>> {
+    static SYNTAX: OnceLock<Regex> = OnceLock::new();
+    let syntax = SYNTAX.get_or_init(|| Regex::new(r"(?i)^expect\s+(\S+)\s+(\S+)\s+to\s+(not\s+)?have\s+(header|status|body\s+containing)\s+(.+)$").unwrap());
+    let mut assertions = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let number = number + 1;
+        let c = syntax
+            .captures(line)
+            .ok_or_else(|| anyhow!("line {}: expected `expect <METHOD> <URL> to [not] have header|status|body containing ...`", number))?;
+        let argument = c[5].trim();
+        let check = match c[4].to_ascii_lowercase().as_str() {
+            "header" => {
+                let (name, value) = match argument.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim())),
+                    None => (argument, None),
+                };
+                if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    bail!("line {}: {:?} is not a header name", number, name);
+                }
+                Check::Header {
+                    name: name.to_string(),
+                    value: value.map(|value| glob(value, number)).transpose()?,
+                    expected: value.map(str::to_string),
+                }
+            }
+            "status" => {
+                let codes: Vec<String> = argument.split(',').map(|code| code.trim().to_ascii_lowercase()).collect();
+                let valid = |code: &String| code.len() == 3 && code.starts_with(|c: char| c.is_ascii_digit()) && code.chars().all(|c| c.is_ascii_digit() || c == 'x');
+                if let Some(code) = codes.iter().find(|code| !valid(code)) {
+                    bail!("line {}: {:?} is not a status code or class like 2xx", number, code);
+                }
+                Check::Status(codes)
+            }
+            _ => {
+                let text = argument.strip_prefix('"').and_then(|a| a.strip_suffix('"')).unwrap_or(argument);
+                Check::BodyContains(text.to_string())
+            }
+        };
+        assertions.push(Assertion {
+            line: number,
+            text: line.to_string(),
+            method: (&c[1] != "*").then(|| c[1].to_string()),
+            url: glob(&c[2], number)?,
+            negated: c.get(3).is_some(),
+            check,
+        });
+    }
+    Ok(assertions)
+}
+
+/// Anchored regex for a pattern where `*` matches anything, read from line `number`.
+fn glob(pattern: &str, number: usize) -> Result<Regex> {
+    let parts: Vec<String> = pattern.split('*').map(regex::escape).collect();
+    pattern::compile(&format!("^{}$", parts.join(".*"))).map_err(|e| anyhow!("line {}: {:?} is too complex a pattern: {}", number, pattern, e))
+}
+
+/// Checks the responses in WARC files, such as those written by `[capture]`.
+pub fn check_warc(assertions: &[Assertion], paths: &[String]) -> Result<Report> {
+    let mut report = Report::new(assertions);
+    for path in paths {
+        // A first pass finds each response's method in its request record
+        let mut methods: HashMap<String, String> = HashMap::new();
+        let mut by_target: HashMap<String, String> = HashMap::new();
+        read_warc(path, |record| {
+            if record.kind == "request" {
+                let method = String::from_utf8_lossy(&record.block).split(' ').next().unwrap_or("GET").to_string();
+                methods.insert(record.id.clone(), method.clone());
+                for id in &record.concurrent {
+                    methods.insert(id.clone(), method.clone());
+                }
+                by_target.insert(record.target.clone(), method);
+            }
+        })?;
+        read_warc(path, |record| {
+            if record.kind != "response" {
+                return;
+            }
+            let Some((status, headers, body)) = parse_response(&record.block) else {
+                return;
+            };
+            let method = std::iter::once(&record.id)
+                .chain(&record.concurrent)
+                .find_map(|id| methods.get(id))
+                .or_else(|| by_target.get(&record.target))
+                .map(String::as_str)
+                .unwrap_or("GET");
+            report.observe(
+                assertions,
+                &Exchange {
+                    method,
+                    url: &record.target,
+                    status,
+                    headers: &headers,
+                    body: Some(&body),
+                },
+            );
+        })?;
+    }
+    Ok(report)
+}
+
+/// Assertions checked against the live traffic of a running proxy, for the admin API.
+pub struct Assertions {
+    assertions: Vec<Assertion>,
+    report: Mutex<Report>,
+}
+
+impl Assertions {
+    /// `None` when no assertions file is configured or it can't be read.
+    pub fn open(config: &AssertionsConfig) -> Option<Arc<Assertions>> {
+        let path = config.file.as_deref().filter(|path| !path.is_empty())?;
+        let assertions = match load(Path::new(path)) {
+            Ok(assertions) => assertions,
+            Err(e) => {
+                warn!("Not checking assertions: {}", e);
+                return None;
+            }
+        };
+        info!("Checking traffic against {} assertions from {}", assertions.len(), path);
+        Some(Arc::new(Assertions {
+            report: Mutex::new(Report::new(&assertions)),
+            assertions,
+        }))
+    }
+
+    pub fn report(&self) -> Report {
+        self.report.lock().unwrap().clone()
+    }
+
+    /// Starts counting again from nothing.
+    pub fn reset(&self) {
+        *self.report.lock().unwrap() = Report::new(&self.assertions);
+    }
+
+    /// Checks the response as it is sent to the client; assertions on the body wait
+    /// until it has gone out.
+    pub fn observe(self: &Arc<Self>, ctx: &RequestContext, res: Response<Body>) -> Response<Body> {
+        let url = ctx.url.to_string();
+        let applicable: Vec<bool> = self.assertions.iter().map(|a| a.applies(ctx.method.as_str(), &url)).collect();
+        if !applicable.contains(&true) {
+            return res;
+        }
+        let needs_body = ctx.method != Method::HEAD && self.assertions.iter().zip(&applicable).any(|(a, applies)| *applies && a.needs_body());
+        let (parts, body) = res.into_parts();
+        let observer = Observer {
+            assertions: self.clone(),
+            method: ctx.method.to_string(),
+            url,
+            status: parts.status.as_u16(),
+            headers: parts.headers.clone(),
+            body: needs_body.then(Vec::new),
+            done: false,
+        };
+        if needs_body {
+            Response::from_parts(parts, streaming::rewrite_body(body, observer))
+        } else {
+            drop(observer);
+            Response::from_parts(parts, body)
+        }
+    }
+}
+
+struct Observer {
+    assertions: Arc<Assertions>,
+    method: String,
+    url: String,
+    status: u16,
+    headers: HeaderMap,
+    body: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl Observer {
+    fn complete(&mut self) {
+        if std::mem::replace(&mut self.done, true) {
+            return;
+        }
+        let exchange = Exchange {
+            method: &self.method,
+            url: &self.url,
+            status: self.status,
+            headers: &self.headers,
+            body: self.body.as_deref(),
+        };
+        self.assertions.report.lock().unwrap().observe(&self.assertions.assertions, &exchange);
+    }
+}
+
+impl ChunkRewriter for Observer {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        if let Some(body) = &mut self.body {
+            let room = BODY_LIMIT.saturating_sub(body.len());
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+        Bytes::copy_from_slice(chunk)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.complete();
+        Bytes::new()
+    }
+}
+
+/// Hyper may drop a body without polling it to the end once `Content-Length` is sent;
+/// responses checked without their body are checked here too.
+impl Drop for Observer {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
+
+/// Prints a report the way `rusty-proxy assert` shows it; returns whether it passed.
+pub fn print_report(report: &Report) -> bool {
+    for tally in &report.assertions {
+        match (tally.matched, tally.violated) {
+            (0, _) => println!("NONE  line {}: {} (no matching traffic)", tally.line, tally.text),
+            (matched, 0) => println!("PASS  line {}: {} ({} exchanges)", tally.line, tally.text, matched),
+            (matched, violated) => {
+                println!("FAIL  line {}: {} ({} of {} exchanges)", tally.line, tally.text, violated, matched);
+                let listed: Vec<&Violation> = report.violations.iter().filter(|v| v.line == tally.line).collect();
+                for violation in listed.iter().take(PRINT_LIMIT) {
+                    println!("        {} {} -> {}: {}", violation.method, violation.url, violation.status, violation.reason);
+                }
+                if violated > PRINT_LIMIT as u64 {
+                    println!("        ... and {} more", violated - PRINT_LIMIT as u64);
+                }
+            }
+        }
+    }
+    let violated = report.violated();
+    println!("{} violation(s)", violated);
+    violated == 0
+}
@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Result};
+use hyper::{Request, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+use crate::body::Body;
+use crate::proxy::ProxyState;
+use crate::storage::Storage;
+
+/// Paths under this prefix are answered by the proxy on any host instead of being forwarded.
+pub const RESERVED_PREFIX: &str = "/__rusty_proxy/";
+
+/// Files from `scripts.assets_dir`.
+const ASSETS_PREFIX: &str = "/__rusty_proxy/assets/";
+
+/// Remote script assets, cached and served under a path derived from their URL.
+const PINNED_PREFIX: &str = "/__rusty_proxy/assets/pinned/";
+
+/// Local assets may be edited while a demo runs, so clients revalidate them often.
+const LOCAL_CACHE_CONTROL: &str = "public, max-age=60";
+
+/// Pinned assets never change under the same path, so clients may keep them for a year.
+const PINNED_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+pub fn is_reserved(req: &Request<Body>) -> bool {
+    req.uri().path().starts_with(RESERVED_PREFIX)
+}
+
+/// Stable proxy path for a remote asset: a hash of the URL plus its file name, so the
+/// MIME type can be told from the extension.
+pub fn pinned_path(url: &str) -> String {
+    format!("{}{}/{}", PINNED_PREFIX, pin_key(url), file_name(url))
+}
+
+fn pin_key(url: &str) -> String {
+    Sha256::digest(url.as_bytes()).iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+fn file_name(url: &str) -> &str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    match path.rsplit('/').next() {
+        Some(name) if !name.is_empty() && !name.contains(':') => name,
+        _ => "asset",
+    }
+}
+
+/// Whether a remote asset is a stylesheet (injected with `<link>`) rather than a script.
+pub fn is_stylesheet(url: &str) -> bool {
+    file_name(url).to_lowercase().ends_with(".css")
+}
+
+pub fn mime_type(name: &str) -> &'static str {
+    match name.rsplit('.').next().map(|ext| ext.to_lowercase()).as_deref() {
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Files injected pages load from the proxy: local ones from `scripts.assets_dir`, and
+/// copies of the remote assets scripts declare, fetched once and kept in
+/// `scripts.asset_cache_dir` so demos don't depend on third-party CDNs staying up. With
+/// `[storage]` the copies are also kept there, as `assets/<key>`, so a fresh instance
+/// finds them without going to the CDN.
+pub struct AssetStore {
+    cache_dir: PathBuf,
+    assets_dir: PathBuf,
+    storage: Option<Arc<dyn Storage>>,
+    client: reqwest::Client,
+}
+
+impl AssetStore {
+    pub fn new(cache_dir: &str, assets_dir: &str, storage: Option<Arc<dyn Storage>>) -> Self {
+        AssetStore {
+            cache_dir: PathBuf::from(cache_dir),
+            assets_dir: PathBuf::from(assets_dir),
+            storage,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Maps a request path below `/__rusty_proxy/assets/` to a file inside `assets_dir`,
+    /// refusing anything that would leave the directory.
+    fn local_path(&self, relative: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(relative)?;
+        let mut path = self.assets_dir.clone();
+        for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+            if segment == "." || segment == ".." || segment.contains('\\') {
+                return None;
+            }
+            path.push(segment);
+        }
+        let root = self.assets_dir.canonicalize().ok()?;
+        let path = path.canonicalize().ok()?;
+        (path.starts_with(&root) && path.is_file()).then_some(path)
+    }
+
+    /// The asset's bytes, from the cache or storage, or fetched (and cached) on first use.
+    pub async fn pinned(&self, url: &str) -> Result<Vec<u8>> {
+        let path = self.cache_dir.join(pin_key(url));
+        if let Ok(data) = fs::read(&path) {
+            return Ok(data);
+        }
+        let key = format!("assets/{}", pin_key(url));
+        if let Some(storage) = &self.storage {
+            match storage.get(&key).await {
+                Ok(Some(data)) => {
+                    debug!("Pinned asset {} from storage", url);
+                    self.cache(url, &path, &data);
+                    return Ok(data);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to read asset {} from storage: {}", url, e),
+            }
+        }
+
+        debug!("Fetching pinned asset {}", url);
+        let response = self.client.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("{} answered {}", url, response.status()));
+        }
+        let data = response.bytes().await?.to_vec();
+        self.cache(url, &path, &data);
+        if let Some(storage) = &self.storage {
+            if let Err(e) = storage.put(&key, data.clone()).await {
+                warn!("Failed to store asset {}: {}", url, e);
+            }
+        }
+        Ok(data)
+    }
+
+    fn cache(&self, url: &str, path: &Path, data: &[u8]) {
+        // A read-only filesystem only costs the cache; the asset is still served
+        if let Err(e) = fs::create_dir_all(&self.cache_dir).and_then(|_| fs::write(path, data)) {
+            warn!("Failed to cache asset {} in {:?}: {}", url, self.cache_dir, e);
+        }
+    }
+
+    /// Fetches every asset not cached yet, so the first page load doesn't wait on the CDN.
+    pub async fn prefetch(&self, urls: Vec<String>) {
+        for url in urls {
+            match self.pinned(&url).await {
+                Ok(data) => info!("Pinned asset {} ({} bytes)", url, data.len()),
+                Err(e) => warn!("Failed to pin asset {}: {}", url, e),
+            }
+        }
+    }
+}
+
+pub async fn handle(req: Request<Body>, state: &ProxyState) -> Response<Body> {
+    let path = req.uri().path();
+    if let Some(rest) = path.strip_prefix(PINNED_PREFIX) {
+        let key = rest.split('/').next().unwrap_or("");
+        let url = match state
+            .injector
+            .script_manager()
+            .pinned_assets()
+            .into_iter()
+            .find(|url| pin_key(url) == key)
+        {
+            Some(url) => url,
+            None => return not_found(),
+        };
+        return match state.assets.pinned(&url).await {
+            Ok(data) => Response::builder()
+                .header("content-type", mime_type(file_name(&url)))
+                .header("content-length", data.len())
+                .header("cache-control", PINNED_CACHE_CONTROL)
+                .body(Body::from(data))
+                .unwrap(),
+            Err(e) => {
+                warn!("Failed to serve pinned asset {}: {}", url, e);
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from("asset unavailable"))
+                    .unwrap()
+            }
+        };
+    }
+
+    if let Some(relative) = path.strip_prefix(ASSETS_PREFIX) {
+        if let Some(file) = state.assets.local_path(relative) {
+            return serve_file(&req, &file).await;
+        }
+    }
+    not_found()
+}
+
+/// Serves a local asset with `Last-Modified`, answering `If-Modified-Since` with a 304.
+async fn serve_file(req: &Request<Body>, file: &Path) -> Response<Body> {
+    let modified = tokio::fs::metadata(file).await.ok().and_then(|m| m.modified().ok());
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    let unchanged = req
+        .headers()
+        .get("if-modified-since")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .zip(modified)
+        .map(|(since, modified)| modified.duration_since(since).map(|d| d.as_secs() == 0).unwrap_or(true))
+        .unwrap_or(false);
+
+    let mut builder = Response::builder()
+        .header("content-type", mime_type(&file.to_string_lossy()))
+        .header("cache-control", LOCAL_CACHE_CONTROL);
+    if let Some(last_modified) = &last_modified {
+        builder = builder.header("last-modified", last_modified);
+    }
+    if unchanged {
+        return builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+    }
+
+    match tokio::fs::read(file).await {
+        Ok(data) => {
+            let length = data.len();
+            let body = if req.method() == hyper::Method::HEAD { Body::empty() } else { Body::from(data) };
+            builder.header("content-length", length).body(body).unwrap()
+        }
+        Err(e) => {
+            warn!("Failed to read asset {:?}: {}", file, e);
+            not_found()
+        }
+    }
+}
+
+/// Decodes `%XX` escapes; `None` for malformed escapes or non-UTF-8 results.
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from("not found"))
+        .unwrap()
+}
@@ -0,0 +1,135 @@
+use futures_util::{Stream, TryStreamExt};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyDataStream, BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming, SizeHint};
+use hyper::header::HeaderMap;
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The body type of every request and response the proxy handles, whether it arrived from
+/// a client or upstream, was buffered, or is rewritten on the fly. Hyper 1 gives each
+/// source its own type; boxing them behind one keeps handlers and injectors uniform.
+pub struct Body(BoxBody<Bytes, BoxError>);
+
+impl Body {
+    pub fn empty() -> Self {
+        Body::new(Empty::new())
+    }
+
+    pub fn new<B>(body: B) -> Self
+    where
+        B: hyper::body::Body<Data = Bytes> + Send + Sync + 'static,
+        B::Error: Into<BoxError>,
+    {
+        Body(body.map_err(Into::into).boxed())
+    }
+
+    /// A body fed by a stream of chunks, for bodies produced while they are sent.
+    pub fn wrap_stream<S, O, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<O, E>> + Send + Sync + 'static,
+        O: Into<Bytes> + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        Body::new(StreamBody::new(stream.map_ok(|chunk| Frame::data(chunk.into())).map_err(Into::into)))
+    }
+
+    /// The data chunks of the body; trailers are dropped.
+    pub fn into_data_stream(self) -> BodyDataStream<Self> {
+        BodyExt::into_data_stream(self)
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::empty()
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Body")
+    }
+}
+
+impl From<Incoming> for Body {
+    fn from(body: Incoming) -> Self {
+        Body::new(body)
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(data: Bytes) -> Self {
+        Body::new(Full::new(data))
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(data: Vec<u8>) -> Self {
+        Body::from(Bytes::from(data))
+    }
+}
+
+impl From<String> for Body {
+    fn from(data: String) -> Self {
+        Body::from(Bytes::from(data))
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(data: &'static str) -> Self {
+        Body::from(Bytes::from_static(data.as_bytes()))
+    }
+}
+
+impl hyper::body::Body for Body {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        Pin::new(&mut self.0).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.0.size_hint()
+    }
+}
+
+/// Reads a whole body into memory.
+pub async fn to_bytes<B>(body: B) -> anyhow::Result<Bytes>
+where
+    B: hyper::body::Body,
+    B::Error: Into<BoxError>,
+{
+    match body.collect().await {
+        Ok(collected) => Ok(collected.to_bytes()),
+        Err(e) => {
+            let e: BoxError = e.into();
+            Err(anyhow::anyhow!(e))
+        }
+    }
+}
+
+/// Up to `limit` bytes of a body with its `Content-Encoding` (gzip, deflate or zstd)
+/// undone; `None` for encodings it can't undo, such as brotli. A body cut short still
+/// yields what decodes.
+pub fn decode(headers: &HeaderMap, body: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let encoding = headers.get("content-encoding").and_then(|v| v.to_str().ok()).unwrap_or("identity").trim().to_ascii_lowercase();
+    let reader: Box<dyn Read + '_> = match encoding.as_str() {
+        "identity" | "" => return Some(body[..body.len().min(limit)].to_vec()),
+        "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(body)),
+        "deflate" => Box::new(flate2::read::ZlibDecoder::new(body)),
+        "zstd" => Box::new(zstd::stream::read::Decoder::new(body).ok()?),
+        _ => return None,
+    };
+    let mut decoded = Vec::new();
+    let _ = reader.take(limit as u64).read_to_end(&mut decoded);
+    Some(decoded)
+}
@@ -0,0 +1,261 @@
+use anyhow::{anyhow, bail, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::history;
+use crate::script_manager::{InjectionScript, ScriptManager};
+
+/// Bundle layout version written to the manifest; newer bundles are refused.
+const FORMAT: u32 = 1;
+
+const MANIFEST: &str = "manifest.json";
+
+/// zstd level bundles are written with; they are small, so favour size.
+const COMPRESSION_LEVEL: i32 = 19;
+
+/// Largest file accepted from a bundle.
+const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// First bytes of a zstd frame, which tells a bundle from a plain script file.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// `manifest.json` at the root of a bundle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    /// Unix time the bundle was exported
+    pub created: u64,
+    pub proxy_version: String,
+    pub scripts: Vec<Entry>,
+    /// Files from `assets_dir` the scripts reference under `/__rusty_proxy/assets/`
+    #[serde(default)]
+    pub assets: Vec<Entry>,
+}
+
+/// One file in a bundle, checked against its digest on install.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Script name (with its directory, for nested scripts) or asset path
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Path inside the bundle
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// What `install` did with each script.
+#[derive(Debug, Default)]
+pub struct Installed {
+    /// Name and version of each script written
+    pub written: Vec<(String, String)>,
+    /// Scripts left alone because they already exist
+    pub kept: Vec<String>,
+    pub assets: usize,
+}
+
+/// Packs `names` (every script when empty) and the local assets they reference into a
+/// zstd-compressed tarball at `out`.
+pub fn export(manager: &ScriptManager, names: &[String], assets_dir: &Path, out: &Path) -> Result<Manifest> {
+    let mut names = if names.is_empty() { manager.list_scripts() } else { names.to_vec() };
+    names.sort();
+    names.dedup();
+
+    let mut files: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    let mut manifest = Manifest {
+        format: FORMAT,
+        created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        proxy_version: env!("CARGO_PKG_VERSION").to_string(),
+        scripts: Vec::new(),
+        assets: Vec::new(),
+    };
+    let mut referenced = Vec::new();
+    for name in &names {
+        let script = manager.get_script(name).ok_or_else(|| anyhow!("No script named {}", name))?;
+        // A payload kept in its own file travels inline
+        let script = manager.with_payload(script);
+        referenced.extend(asset_references(&script));
+        // Nested scripts keep their directory, and their file holds the name without it
+        let mut stored = script.clone().into_owned();
+        stored.content_file = None;
+        stored.name = name.rsplit('/').next().unwrap_or(name).to_string();
+        let data = serde_json::to_vec_pretty(&stored)?;
+        let path = format!("scripts/{}.json", name);
+        manifest.scripts.push(entry(name, Some(script.version.clone()), &path, &data));
+        files.insert(path, data);
+    }
+
+    referenced.sort();
+    referenced.dedup();
+    for asset in referenced {
+        let Some(file) = safe_join(assets_dir, &asset) else {
+            continue;
+        };
+        // References to files that don't exist (or are served by another host) stay as they are
+        let Ok(data) = fs::read(&file) else {
+            continue;
+        };
+        let path = format!("assets/{}", asset);
+        manifest.assets.push(entry(&asset, None, &path, &data));
+        files.insert(path, data);
+    }
+
+    let encoder = zstd::stream::write::Encoder::new(fs::File::create(out)?, COMPRESSION_LEVEL)?;
+    let mut tarball = tar::Builder::new(encoder);
+    let manifest_data = serde_json::to_vec_pretty(&manifest)?;
+    for (path, data) in std::iter::once((MANIFEST.to_string(), manifest_data)).chain(files) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created);
+        tarball.append_data(&mut header, &path, data.as_slice())?;
+    }
+    tarball.into_inner()?.finish()?;
+    Ok(manifest)
+}
+
+/// Installs a bundle, or a single script file, into `scripts_dir` (and its assets into
+/// `assets_dir`). Every file is checked against the manifest before anything is written.
+/// Existing scripts and assets are kept unless `force` is set.
+/// Scripts written keep their earlier versions in the script history.
+pub fn install(file: &Path, scripts_dir: &Path, assets_dir: &Path, force: bool) -> Result<Installed> {
+    unpack(&fs::read(file)?, &file.display().to_string(), scripts_dir, assets_dir, force, true)
+}
+
+/// `install` for a bundle or script already in memory; `source` names it in errors. No
+/// history is kept: this replaces directories the management server owns.
+pub fn install_data(data: &[u8], source: &str, scripts_dir: &Path, assets_dir: &Path, force: bool) -> Result<Installed> {
+    unpack(data, source, scripts_dir, assets_dir, force, false)
+}
+
+fn unpack(data: &[u8], source: &str, scripts_dir: &Path, assets_dir: &Path, force: bool, keep_history: bool) -> Result<Installed> {
+    if !data.starts_with(&ZSTD_MAGIC) {
+        return install_script(data, source, scripts_dir, force, keep_history);
+    }
+
+    let mut files = HashMap::new();
+    let mut archive = tar::Archive::new(zstd::stream::read::Decoder::new(data)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        if entry.size() > MAX_ENTRY_SIZE {
+            bail!("{} in the bundle is larger than {} bytes", entry.path()?.display(), MAX_ENTRY_SIZE);
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut content = Vec::new();
+        entry.take(MAX_ENTRY_SIZE).read_to_end(&mut content)?;
+        files.insert(path, content);
+    }
+
+    let manifest: Manifest = serde_json::from_slice(files.get(MANIFEST).ok_or_else(|| anyhow!("Bundle has no {}", MANIFEST))?)?;
+    if manifest.format > FORMAT {
+        bail!("Bundle format {} is newer than this proxy supports ({})", manifest.format, FORMAT);
+    }
+
+    // Resolve and verify everything first, so a damaged bundle installs nothing
+    let mut planned = Vec::new();
+    for (entries, root, prefix) in [(&manifest.scripts, scripts_dir, "scripts/"), (&manifest.assets, assets_dir, "assets/")] {
+        for entry in entries {
+            let content = files.get(&entry.path).ok_or_else(|| anyhow!("{} is listed in the manifest but missing", entry.path))?;
+            if hex_digest(content) != entry.sha256 {
+                bail!("Checksum mismatch for {}", entry.path);
+            }
+            let relative = entry.path.strip_prefix(prefix).ok_or_else(|| anyhow!("{} is outside {}", entry.path, prefix))?;
+            let target = safe_join(root, relative).ok_or_else(|| anyhow!("Refusing unsafe path {}", entry.path))?;
+            if prefix == "scripts/" {
+                serde_json::from_slice::<InjectionScript>(content).map_err(|e| anyhow!("{} is not a valid script: {}", entry.path, e))?;
+            }
+            planned.push((entry, prefix, target, content));
+        }
+    }
+
+    let mut installed = Installed::default();
+    for (entry, prefix, target, content) in planned {
+        if target.exists() && !force {
+            if prefix == "scripts/" {
+                installed.kept.push(entry.name.clone());
+            }
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if prefix == "scripts/" && keep_history {
+            history::write(scripts_dir, &target, content)?;
+        } else {
+            fs::write(&target, content)?;
+        }
+        if prefix == "scripts/" {
+            installed.written.push((entry.name.clone(), entry.version.clone().unwrap_or_default()));
+        } else {
+            installed.assets += 1;
+        }
+    }
+    Ok(installed)
+}
+
+fn install_script(data: &[u8], source: &str, scripts_dir: &Path, force: bool, keep_history: bool) -> Result<Installed> {
+    let script: InjectionScript = serde_json::from_slice(data).map_err(|e| anyhow!("{:?} is neither a bundle nor a valid script: {}", source, e))?;
+    let target = safe_join(scripts_dir, &format!("{}.json", script.name)).ok_or_else(|| anyhow!("Refusing unsafe script name {}", script.name))?;
+    let mut installed = Installed::default();
+    if target.exists() && !force {
+        installed.kept.push(script.name);
+    } else {
+        fs::create_dir_all(scripts_dir)?;
+        if keep_history {
+            history::write(scripts_dir, &target, data)?;
+        } else {
+            fs::write(&target, data)?;
+        }
+        installed.written.push((script.name, script.version));
+    }
+    Ok(installed)
+}
+
+/// Paths under `/__rusty_proxy/assets/` a script's payload or headers load; pinned
+/// remote assets are fetched by each proxy itself and left out.
+fn asset_references(script: &InjectionScript) -> Vec<String> {
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    let reference = REFERENCE.get_or_init(|| Regex::new(r#"/__rusty_proxy/assets/([^"'\s<>()?#]+)"#).unwrap());
+    std::iter::once(script.script_content.as_str())
+        .chain(script.headers.values().map(String::as_str))
+        .flat_map(|text| reference.captures_iter(text).map(|c| c[1].to_string()).collect::<Vec<_>>())
+        .filter(|path| !path.starts_with("pinned/"))
+        .collect()
+}
+
+/// `root` joined with a `/`-separated relative path, or `None` if the path could leave it.
+pub fn safe_join(root: &Path, relative: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in relative.split('/').filter(|s| !s.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains('\\') || segment.contains(':') {
+            return None;
+        }
+        path.push(segment);
+    }
+    (path != root).then_some(path)
+}
+
+fn entry(name: &str, version: Option<String>, path: &str, data: &[u8]) -> Entry {
+    Entry {
+        name: name.to_string(),
+        version,
+        path: path.to_string(),
+        sha256: hex_digest(data),
+        size: data.len() as u64,
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,578 @@
+use anyhow::{anyhow, bail, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::body::Bytes;
+use hyper::header::HeaderMap;
+use hyper::{Method, Response};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::body::Body;
+use crate::config::CaptureConfig;
+use crate::context::RequestContext;
+use crate::script_manager::ScriptManager;
+use crate::storage::Uploader;
+use crate::streaming::{self, ChunkRewriter};
+
+/// How often retention is enforced while no file is being rotated.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Largest WARC record read; longer ones are skipped.
+const RECORD_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Fields of our own on `response` records: the body size sent to the client, which a
+/// truncated record doesn't show, the upstream's time to answer in milliseconds, and the
+/// scripts that matched the request.
+pub const BODY_LENGTH_FIELD: &str = "X-Rusty-Proxy-Body-Length";
+pub const UPSTREAM_FIELD: &str = "X-Rusty-Proxy-Upstream-Ms";
+pub const SCRIPTS_FIELD: &str = "X-Rusty-Proxy-Scripts";
+
+/// Exchanges archived as WARC 1.1 `request` and `response` records, so sessions browsed
+/// through the proxy can be replayed with standard web-archive tools. Records are written
+/// by a background thread; a slow disk never holds up a response.
+pub struct Capture {
+    config: CaptureConfig,
+    /// The records of one exchange each, so they never land in different files
+    records: mpsc::Sender<Vec<Vec<u8>>>,
+}
+
+impl Capture {
+    /// `None` when capturing is off or the archive can't be opened.
+    /// Rotated files are also handed to `uploader`, as `captures`.
+    pub fn open(config: &CaptureConfig, uploader: Option<Uploader>) -> Option<Arc<Capture>> {
+        let path = config.warc.as_deref().filter(|path| !path.is_empty())?;
+        let mut writer = match WarcFile::open(PathBuf::from(path)) {
+            Ok(writer) => writer,
+            Err(e) => {
+                warn!("Not capturing: cannot open WARC file {}: {}", path, e);
+                return None;
+            }
+        };
+        info!("Capturing responses to {}", path);
+
+        let (records, queue) = mpsc::channel::<Vec<Vec<u8>>>();
+        let rotation = config.clone();
+        std::thread::spawn(move || {
+            enforce_retention(&writer.path, &rotation);
+            let mut housekept = Instant::now();
+            loop {
+                match queue.recv_timeout(HOUSEKEEPING_INTERVAL) {
+                    Ok(exchange) => writer.write(&exchange),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+                if writer.due(&rotation) {
+                    let rotated;
+                    (writer, rotated) = match writer.rotate() {
+                        Ok(rotation) => rotation,
+                        Err(e) => {
+                            warn!("Stopped capturing: cannot rotate WARC file: {}", e);
+                            break;
+                        }
+                    };
+                    if let Some(uploader) = &uploader {
+                        uploader.upload("captures", rotated);
+                    }
+                    enforce_retention(&writer.path, &rotation);
+                    housekept = Instant::now();
+                } else if housekept.elapsed() >= HOUSEKEEPING_INTERVAL {
+                    enforce_retention(&writer.path, &rotation);
+                    housekept = Instant::now();
+                }
+            }
+        });
+        Some(Arc::new(Capture {
+            config: config.clone(),
+            records,
+        }))
+    }
+
+    pub fn wants(&self, ctx: &RequestContext) -> bool {
+        self.wants_domain(&ctx.domain)
+    }
+
+    pub fn wants_domain(&self, domain: &str) -> bool {
+        self.config.domains.is_empty() || ScriptManager::domain_matches(domain, &self.config.domains)
+    }
+
+    /// Largest body archived per record.
+    pub fn max_body(&self) -> usize {
+        self.config.max_body
+    }
+
+    /// Archives what a page rendered, as posted by a `Snapshot` script: the DOM as a
+    /// `conversion` record of the page's URL (an alternative form of its response), with
+    /// canvas images linked to the DOM record by `WARC-Concurrent-To`.
+    pub fn record_snapshot(&self, page: &str, html: &[u8], images: &[(&str, Vec<u8>)]) {
+        let dom_id = record_id();
+        let mut records = vec![record(
+            "conversion",
+            &[("WARC-Record-ID", dom_id.clone()), ("WARC-Target-URI", page.to_string())],
+            "text/html; charset=utf-8",
+            html,
+        )];
+        for (content_type, image) in images {
+            records.push(record(
+                "conversion",
+                &[("WARC-Target-URI", page.to_string()), ("WARC-Concurrent-To", dom_id.clone())],
+                content_type,
+                image,
+            ));
+        }
+        let _ = self.records.send(records);
+    }
+
+    /// Archives the response as it is sent to the client, once its body has gone out.
+    /// `upstream` is how long the upstream took to answer; it goes into the record, with
+    /// the scripts that matched and the body size sent, for `rusty-proxy report`.
+    pub fn record(self: &Arc<Self>, ctx: &RequestContext, res: Response<Body>, upstream: Duration) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        let mut request = format!("{} {} HTTP/1.1\r\n", ctx.method, ctx.url.path_and_query().map(|p| p.as_str()).unwrap_or("/"));
+        if !ctx.headers.contains_key("host") {
+            if let Some(authority) = ctx.url.authority() {
+                request.push_str(&format!("host: {}\r\n", authority));
+            }
+        }
+        request.push_str(&header_lines(&ctx.headers));
+
+        let recorder = Recorder {
+            capture: self.clone(),
+            target: ctx.url.to_string(),
+            request: request.into_bytes(),
+            status: format!(
+                "HTTP/1.1 {} {}\r\n",
+                parts.status.as_u16(),
+                parts.status.canonical_reason().unwrap_or("")
+            ),
+            headers: parts.headers.clone(),
+            head: ctx.method == Method::HEAD,
+            body: Some(Vec::new()),
+            truncated: false,
+            sent: 0,
+            upstream,
+            scripts: ctx.matched_scripts.join(", "),
+        };
+        Response::from_parts(parts, streaming::rewrite_body(body, recorder))
+    }
+}
+
+/// The WARC file being written. Rotated files are renamed next to it with their rotation
+/// time, `session.warc.gz` becoming `session-20260131T120000Z.warc.gz`.
+struct WarcFile {
+    path: PathBuf,
+    file: File,
+    gzip: bool,
+    size: u64,
+    exchanges: u64,
+    opened: Instant,
+}
+
+impl WarcFile {
+    /// Opens the file for appending; a new file starts with a `warcinfo` record.
+    fn open(path: PathBuf) -> std::io::Result<WarcFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let mut writer = WarcFile {
+            gzip: path.to_string_lossy().ends_with(".gz"),
+            path,
+            file,
+            size,
+            exchanges: 0,
+            opened: Instant::now(),
+        };
+        if size == 0 {
+            let fields = format!(
+                "software: rusty-proxy/{}\r\nformat: WARC File Format 1.1\r\n",
+                env!("CARGO_PKG_VERSION")
+            );
+            writer.append(&record("warcinfo", &[], "application/warc-fields", fields.as_bytes()));
+        }
+        Ok(writer)
+    }
+
+    fn write(&mut self, exchange: &[Vec<u8>]) {
+        for record in exchange {
+            self.append(record);
+        }
+        self.exchanges += 1;
+    }
+
+    /// Appends one record; in a `.warc.gz` each record is its own gzip member, which is
+    /// what replay tools expect for random access.
+    fn append(&mut self, record: &[u8]) {
+        let result = if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(record).and_then(|_| encoder.finish()).and_then(|member| {
+                self.size += member.len() as u64;
+                self.file.write_all(&member)
+            })
+        } else {
+            self.size += record.len() as u64;
+            self.file.write_all(record)
+        };
+        if let Err(e) = result {
+            warn!("Failed to write WARC record: {}", e);
+        }
+    }
+
+    fn due(&self, config: &CaptureConfig) -> bool {
+        // A file holding only its warcinfo record is not worth keeping
+        self.exchanges > 0
+            && ((config.rotate_size > 0 && self.size >= config.rotate_size)
+                || (config.rotate_interval > 0 && self.opened.elapsed() >= Duration::from_secs(config.rotate_interval)))
+    }
+
+    /// The new file, and where the old one went.
+    fn rotate(self) -> std::io::Result<(WarcFile, PathBuf)> {
+        let (stem, extension) = split_name(&self.path);
+        let stamp = warc_date(SystemTime::now()).replace(['-', ':'], "");
+        let mut rotated = self.path.with_file_name(format!("{}-{}{}", stem, stamp, extension));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = self.path.with_file_name(format!("{}-{}-{}{}", stem, stamp, n, extension));
+            n += 1;
+        }
+        drop(self.file);
+        std::fs::rename(&self.path, &rotated)?;
+        info!("Rotated WARC file to {}", rotated.display());
+        Ok((WarcFile::open(self.path)?, rotated))
+    }
+}
+
+/// `session` and `.warc.gz` for `captures/session.warc.gz`.
+fn split_name(path: &Path) -> (String, String) {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    match name.find('.') {
+        Some(dot) if dot > 0 => (name[..dot].to_string(), name[dot..].to_string()),
+        _ => (name, String::new()),
+    }
+}
+
+/// The capture files of `config`, rotated ones oldest first, then the current one.
+pub fn files(config: &CaptureConfig) -> Vec<PathBuf> {
+    let Some(path) = config.warc.as_deref().filter(|path| !path.is_empty()).map(Path::new) else {
+        return vec![];
+    };
+    let mut files: Vec<PathBuf> = rotated_files(path).unwrap_or_default().into_iter().map(|(_, _, file)| file).collect();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    files
+}
+
+fn capture_dir(path: &Path) -> PathBuf {
+    match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from("."),
+    }
+}
+
+/// The files rotated away from `path`, oldest first, with their time and size.
+fn rotated_files(path: &Path) -> io::Result<Vec<(SystemTime, u64, PathBuf)>> {
+    let (stem, extension) = split_name(path);
+    let prefix = format!("{}-", stem);
+    let mut rotated: Vec<(SystemTime, u64, PathBuf)> = std::fs::read_dir(capture_dir(path))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.starts_with(&prefix) && name.ends_with(&extension)
+        })
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+    rotated.sort();
+    Ok(rotated)
+}
+
+/// Deletes rotated files past `keep_days`, then the oldest ones while the files together
+/// (the current one included) exceed `keep_bytes`.
+fn enforce_retention(path: &Path, config: &CaptureConfig) {
+    if config.keep_days == 0 && config.keep_bytes == 0 {
+        return;
+    }
+    let rotated = match rotated_files(path) {
+        Ok(rotated) => rotated,
+        Err(e) => {
+            warn!("Cannot read capture directory {}: {}", capture_dir(path).display(), e);
+            return;
+        }
+    };
+
+    let max_age = Duration::from_secs(config.keep_days * 86_400);
+    let mut total: u64 = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0) + rotated.iter().map(|(_, len, _)| len).sum::<u64>();
+    for (modified, len, file) in rotated {
+        let expired = config.keep_days > 0 && modified.elapsed().map(|age| age > max_age).unwrap_or(false);
+        let over = config.keep_bytes > 0 && total > config.keep_bytes;
+        if !expired && !over {
+            continue;
+        }
+        match std::fs::remove_file(&file) {
+            Ok(()) => {
+                info!("Deleted capture file {} ({})", file.display(), if expired { "expired" } else { "over keep_bytes" });
+                total -= len;
+            }
+            Err(e) => warn!("Cannot delete capture file {}: {}", file.display(), e),
+        }
+    }
+}
+
+/// Copies the body aside as it streams to the client.
+struct Recorder {
+    capture: Arc<Capture>,
+    target: String,
+    request: Vec<u8>,
+    status: String,
+    headers: HeaderMap,
+    head: bool,
+    body: Option<Vec<u8>>,
+    truncated: bool,
+    /// Body bytes sent, archived or not
+    sent: u64,
+    upstream: Duration,
+    scripts: String,
+}
+
+impl Recorder {
+    fn complete(&mut self) {
+        let Some(body) = self.body.take() else {
+            return;
+        };
+        // The body is stored de-chunked, so the recorded headers must frame it by length
+        let mut headers = std::mem::take(&mut self.headers);
+        let chunked = headers.remove("transfer-encoding").is_some();
+        if (chunked || !headers.contains_key("content-length")) && !self.head && !self.truncated {
+            headers.insert("content-length", body.len().into());
+        }
+        let mut block = self.status.clone().into_bytes();
+        block.extend_from_slice(header_lines(&headers).as_bytes());
+        block.extend_from_slice(b"\r\n");
+        block.extend_from_slice(&body);
+
+        let response_id = record_id();
+        let mut fields = vec![
+            ("WARC-Record-ID", response_id.clone()),
+            ("WARC-Target-URI", self.target.clone()),
+        ];
+        if self.truncated {
+            fields.push(("WARC-Truncated", "length".to_string()));
+        }
+        fields.push((BODY_LENGTH_FIELD, self.sent.to_string()));
+        fields.push((UPSTREAM_FIELD, self.upstream.as_millis().to_string()));
+        if !self.scripts.is_empty() {
+            fields.push((SCRIPTS_FIELD, self.scripts.clone()));
+        }
+        let response = record("response", &fields, "application/http;msgtype=response", &block);
+        let request = record(
+            "request",
+            &[
+                ("WARC-Record-ID", record_id()),
+                ("WARC-Target-URI", self.target.clone()),
+                ("WARC-Concurrent-To", response_id),
+            ],
+            "application/http;msgtype=request",
+            &[self.request.as_slice(), b"\r\n"].concat(),
+        );
+        let _ = self.capture.records.send(vec![response, request]);
+    }
+}
+
+impl ChunkRewriter for Recorder {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        self.sent += chunk.len() as u64;
+        if let Some(body) = &mut self.body {
+            let room = self.capture.config.max_body.saturating_sub(body.len());
+            if chunk.len() > room {
+                self.truncated = true;
+            }
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+        Bytes::copy_from_slice(chunk)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.complete();
+        Bytes::new()
+    }
+}
+
+/// Hyper may drop a body without polling it to the end once `Content-Length` is sent.
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
+
+fn header_lines(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect()
+}
+
+/// A complete WARC record. `fields` come after `WARC-Type` and `WARC-Date`; a record
+/// ID is added when they carry none.
+fn record(kind: &str, fields: &[(&str, String)], content_type: &str, block: &[u8]) -> Vec<u8> {
+    let mut head = format!("WARC/1.1\r\nWARC-Type: {}\r\nWARC-Date: {}\r\n", kind, warc_date(SystemTime::now()));
+    if !fields.iter().any(|(name, _)| *name == "WARC-Record-ID") {
+        head.push_str(&format!("WARC-Record-ID: {}\r\n", record_id()));
+    }
+    for (name, value) in fields {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\n\r\n", content_type, block.len()));
+    let mut record = head.into_bytes();
+    record.extend_from_slice(block);
+    record.extend_from_slice(b"\r\n\r\n");
+    record
+}
+
+fn record_id() -> String {
+    format!("<urn:uuid:{}>", uuid::Uuid::new_v4())
+}
+
+/// `2026-01-31T12:00:00Z`, the W3C-DTF form WARC dates use.
+pub fn warc_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rest) = ((secs / 86_400) as i64, secs % 86_400);
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rest / 3_600,
+        rest % 3_600 / 60,
+        rest % 60
+    )
+}
+
+/// A record read back from a WARC file.
+pub struct WarcRecord {
+    pub kind: String,
+    pub id: String,
+    pub target: String,
+    pub date: String,
+    pub concurrent: Vec<String>,
+    /// The other fields, by lowercase name
+    pub fields: HashMap<String, String>,
+    pub block: Vec<u8>,
+}
+
+/// Calls `each` with every record of a `.warc` or `.warc.gz` file.
+pub fn read_warc(path: &str, mut each: impl FnMut(WarcRecord)) -> Result<()> {
+    let mut file = File::open(path).map_err(|e| anyhow!("Cannot open {}: {}", path, e))?;
+    let mut magic = [0u8; 2];
+    let gzipped = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let file = File::open(path)?;
+    let mut reader: Box<dyn BufRead> = if gzipped {
+        Box::new(BufReader::new(MultiGzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+
+    loop {
+        let mut line = String::new();
+        // Records are separated by blank lines
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        if !line.starts_with("WARC/") {
+            bail!("{} is not a WARC file (found {:?})", path, line.trim_end());
+        }
+        let mut fields: HashMap<String, String> = HashMap::new();
+        let mut concurrent = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                let (name, value) = (name.trim().to_ascii_lowercase(), value.trim().to_string());
+                if name == "warc-concurrent-to" {
+                    concurrent.push(value);
+                } else {
+                    fields.insert(name, value);
+                }
+            }
+        }
+        let length: u64 = fields.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+        if length > RECORD_LIMIT {
+            warn!("Skipping WARC record of {} bytes in {}", length, path);
+            io::copy(&mut (&mut reader).take(length), &mut io::sink())?;
+            continue;
+        }
+        let mut block = Vec::with_capacity(length as usize);
+        (&mut reader).take(length).read_to_end(&mut block)?;
+        each(WarcRecord {
+            kind: fields.remove("warc-type").unwrap_or_default(),
+            id: fields.remove("warc-record-id").unwrap_or_default(),
+            target: fields.remove("warc-target-uri").unwrap_or_default(),
+            date: fields.remove("warc-date").unwrap_or_default(),
+            concurrent,
+            fields,
+            block,
+        });
+    }
+}
+
+/// Status, headers and de-chunked body of an HTTP response block.
+pub fn parse_response(block: &[u8]) -> Option<(u16, HeaderMap, Vec<u8>)> {
+    let head_end = block.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&block[..head_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let mut headers = HeaderMap::new();
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if let (Ok(name), Ok(value)) = (hyper::header::HeaderName::from_bytes(name.trim().as_bytes()), value.trim().parse()) {
+            headers.append(name, value);
+        }
+    }
+    let body = &block[head_end + 4..];
+    let chunked = headers.get("transfer-encoding").and_then(|v| v.to_str().ok()).is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+    let body = if chunked { dechunk(body) } else { body.to_vec() };
+    Some((status, headers, body))
+}
+
+fn dechunk(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    while let Some(line_end) = data.windows(2).position(|w| w == b"\r\n") {
+        let size = String::from_utf8_lossy(&data[..line_end]);
+        let Ok(size) = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16) else {
+            break;
+        };
+        data = &data[line_end + 2..];
+        if size == 0 || size > data.len() {
+            body.extend_from_slice(&data[..size.min(data.len())]);
+            break;
+        }
+        body.extend_from_slice(&data[..size]);
+        data = data.get(size + 2..).unwrap_or_default();
+    }
+    body
+}
@@ -0,0 +1,131 @@
+use hyper::body::Bytes;
+use hyper::Response;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::body::Body;
+use crate::context::RequestContext;
+use crate::streaming::{self, ChunkRewriter};
+
+/// Digests of one response's body: as upstream sent it, then as the client received it.
+/// Both are taken off the wire, so they differ whenever the proxy re-encoded the body,
+/// even without a script changing its content.
+pub struct Checksums {
+    method: String,
+    url: String,
+    matched_scripts: Vec<String>,
+    upstream: Mutex<Option<(String, u64)>>,
+}
+
+impl Checksums {
+    pub fn new(ctx: &RequestContext) -> Arc<Self> {
+        Arc::new(Checksums {
+            method: ctx.method.to_string(),
+            url: ctx.url.to_string(),
+            matched_scripts: ctx.matched_scripts.clone(),
+            upstream: Mutex::new(None),
+        })
+    }
+
+    /// Hashes the upstream body as the injector reads it.
+    pub fn upstream(self: &Arc<Self>, res: Response<Body>) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        Response::from_parts(parts, streaming::rewrite_body(body, Hasher::new(self.clone(), Side::Upstream)))
+    }
+
+    /// Hashes the body sent to the client, and logs both digests once it is complete.
+    pub fn delivered(self: &Arc<Self>, res: Response<Body>) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        Response::from_parts(parts, streaming::rewrite_body(body, Hasher::new(self.clone(), Side::Delivered)))
+    }
+
+    fn report(&self, delivered: &str, delivered_len: u64) {
+        // The injector answered without reading upstream (e.g. a 304 it served itself)
+        let Some((upstream, upstream_len)) = self.upstream.lock().unwrap().clone() else {
+            info!(
+                target: "rusty_proxy::checksum",
+                "{} {} upstream body not read, delivered sha256={} ({} bytes)",
+                self.method, self.url, delivered, delivered_len
+            );
+            return;
+        };
+        if upstream == delivered {
+            info!(
+                target: "rusty_proxy::checksum",
+                "{} {} unchanged sha256={} ({} bytes)",
+                self.method, self.url, upstream, upstream_len
+            );
+        } else if self.matched_scripts.is_empty() {
+            warn!(
+                target: "rusty_proxy::checksum",
+                "{} {} changed although no script targets it: upstream sha256={} ({} bytes), delivered sha256={} ({} bytes)",
+                self.method, self.url, upstream, upstream_len, delivered, delivered_len
+            );
+        } else {
+            info!(
+                target: "rusty_proxy::checksum",
+                "{} {} changed by [{}]: upstream sha256={} ({} bytes), delivered sha256={} ({} bytes)",
+                self.method, self.url, self.matched_scripts.join(", "), upstream, upstream_len, delivered, delivered_len
+            );
+        }
+    }
+}
+
+enum Side {
+    Upstream,
+    Delivered,
+}
+
+/// Passes chunks through unchanged while hashing them.
+struct Hasher {
+    checksums: Arc<Checksums>,
+    side: Side,
+    digest: Option<Sha256>,
+    len: u64,
+}
+
+impl Hasher {
+    fn new(checksums: Arc<Checksums>, side: Side) -> Self {
+        Hasher {
+            checksums,
+            side,
+            digest: Some(Sha256::new()),
+            len: 0,
+        }
+    }
+
+    fn complete(&mut self) {
+        let Some(digest) = self.digest.take() else {
+            return;
+        };
+        let hex: String = digest.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        match self.side {
+            Side::Upstream => *self.checksums.upstream.lock().unwrap() = Some((hex, self.len)),
+            Side::Delivered => self.checksums.report(&hex, self.len),
+        }
+    }
+}
+
+impl ChunkRewriter for Hasher {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        if let Some(digest) = &mut self.digest {
+            digest.update(chunk);
+        }
+        self.len += chunk.len() as u64;
+        Bytes::copy_from_slice(chunk)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.complete();
+        Bytes::new()
+    }
+}
+
+/// Hyper stops polling a body once its `Content-Length` is sent, so the end of the stream
+/// is not always seen; the digest is taken when the body is dropped instead.
+impl Drop for Hasher {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
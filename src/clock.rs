@@ -0,0 +1,69 @@
+use regex::Regex;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Parses a `Clock` script's `fake_time`: RFC 3339 (`2030-01-01T09:00:00Z`, with `Z` or a
+/// `+02:00` offset) or Unix seconds. Times before 1970 are refused.
+pub fn parse(text: &str) -> Option<SystemTime> {
+    let text = text.trim();
+    if let Ok(secs) = text.parse::<u64>() {
+        return Some(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+
+    static RFC3339: OnceLock<Regex> = OnceLock::new();
+
+    let rfc3339 = RFC3339.get_or_init(|| Regex::new(r"^(\d{4})-(\d{2})-(\d{2})[Tt ](\d{2}):(\d{2}):(\d{2})(\.\d+)?(?:([Zz])|([+-])(\d{2}):(\d{2}))$").unwrap());
+    let c = rfc3339.captures(text)?;
+    let field = |i: usize| c[i].parse::<i64>().ok();
+    let (year, month, day) = (field(1)?, field(2)?, field(3)?);
+    let (hour, minute, second) = (field(4)?, field(5)?, field(6)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let offset = match c.get(8) {
+        Some(_) => 0,
+        None => {
+            let minutes = field(10)? * 60 + field(11)?;
+            if &c[9] == "-" { -minutes } else { minutes }
+        }
+    };
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second - offset * 60;
+    let fraction = c.get(7).and_then(|f| format!("0{}", f.as_str()).parse::<f64>().ok()).unwrap_or(0.0);
+    let secs = u64::try_from(secs).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_secs_f64(fraction))
+}
+
+/// The fake time now, for a clock that showed `start` at `since` and runs at `rate`
+/// (0 freezes it).
+pub fn now(start: SystemTime, since: Instant, rate: f64) -> SystemTime {
+    start + Duration::from_secs_f64((since.elapsed().as_secs_f64() * rate).max(0.0))
+}
+
+/// Script that makes the page see `now`: `Date` (the constructor without arguments,
+/// `Date()` and `Date.now()`) and `performance.now()` continue from the moment it runs at
+/// `rate`. Dates built from explicit values are left alone.
+pub fn payload(now: SystemTime, rate: f64) -> String {
+    let millis = now.duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+    format!(
+        "(function(){{var fake={},rate={},RealDate=Date,realNow=RealDate.now.bind(RealDate),start=realNow();\
+function now(){{return Math.floor(fake+(realNow()-start)*rate);}}\
+function FakeDate(){{if(!new.target){{return new RealDate(now()).toString();}}\
+return Reflect.construct(RealDate,arguments.length?arguments:[now()],new.target);}}\
+FakeDate.prototype=RealDate.prototype;FakeDate.prototype.constructor=FakeDate;\
+FakeDate.now=now;FakeDate.parse=RealDate.parse;FakeDate.UTC=RealDate.UTC;window.Date=FakeDate;\
+if(window.performance&&performance.now){{var realPerf=performance.now.bind(performance),perfStart=realPerf();\
+try{{performance.now=function(){{return perfStart+(realPerf()-perfStart)*rate;}};}}catch(e){{}}}}}})();",
+        millis, rate
+    )
+}
+
+/// Days from 1970-01-01 to a civil date (Howard Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
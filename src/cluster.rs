@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+use crate::config::ClusterConfig;
+use crate::http_injector::HttpInjector;
+use crate::proxy::ProxyState;
+
+/// Path of the exchange endpoint on each peer's admin API.
+const STATE_PATH: &str = "/admin/cluster/state";
+
+/// How long one exchange with a peer may take.
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A runtime enable or disable of a script. Peers keep the latest by wall-clock time,
+/// with the node name breaking ties, so every node settles on the same decision.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Toggle {
+    pub enabled: bool,
+    /// Unix milliseconds it was made
+    pub at: u64,
+    pub node: String,
+}
+
+impl Toggle {
+    fn newer_than(&self, other: &Toggle) -> bool {
+        (self.at, &self.node) > (other.at, &other.node)
+    }
+}
+
+/// What nodes exchange: each sends its whole view and merges the other's.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Latest toggle by script name
+    #[serde(default)]
+    pub toggles: HashMap<String, Toggle>,
+    /// Hits by node, then script. A node only ever raises its own counts, so merging
+    /// keeps the larger of two
+    #[serde(default)]
+    pub hits: HashMap<String, HashMap<String, u64>>,
+}
+
+/// How the last exchange with a peer went.
+#[derive(Debug, Clone)]
+struct PeerStatus {
+    at: Instant,
+    error: Option<String>,
+}
+
+/// This instance's part in a cluster: proxies behind one load balancer that share
+/// script toggles and hit counters by exchanging `State` with each of their `peers`
+/// over the admin API. Without peers it still keeps toggles and counts for the admin API.
+pub struct Cluster {
+    node: String,
+    config: ClusterConfig,
+    state: Mutex<State>,
+    /// Hit counts last read from the script manager; they restart when scripts reload,
+    /// so only increases since are added to this node's totals
+    seen: Mutex<HashMap<String, u64>>,
+    peers: Mutex<HashMap<String, PeerStatus>>,
+    client: reqwest::Client,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig, port: u16) -> Self {
+        let node = config
+            .node_id
+            .clone()
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| format!("{}:{}", hostname(), port));
+        Cluster {
+            node,
+            config: config.clone(),
+            state: Mutex::new(State::default()),
+            seen: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            client: reqwest::Client::builder().timeout(EXCHANGE_TIMEOUT).build().unwrap_or_default(),
+        }
+    }
+
+    /// Name this instance goes by among peers and to a management server.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Enables or disables a script here, to be passed on to the peers. Returns whether
+    /// the script is loaded on this node.
+    pub fn toggle(&self, injector: &HttpInjector, name: &str, enabled: bool) -> bool {
+        let toggle = Toggle {
+            enabled,
+            at: unix_millis(),
+            node: self.node.clone(),
+        };
+        self.state.lock().unwrap().toggles.insert(name.to_string(), toggle);
+        injector.set_script_enabled(name, enabled)
+    }
+
+    /// This node's view, with its own hit counts brought up to date.
+    pub fn snapshot(&self, injector: &HttpInjector) -> State {
+        self.count_hits(injector);
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Takes in a peer's view: newer toggles are applied to the scripts, and higher
+    /// counts adopted.
+    pub fn merge(&self, injector: &HttpInjector, remote: State) {
+        let mut changed = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            for (name, toggle) in remote.toggles {
+                if state.toggles.get(&name).is_none_or(|current| toggle.newer_than(current)) {
+                    changed.push((name.clone(), toggle.enabled, toggle.node.clone()));
+                    state.toggles.insert(name, toggle);
+                }
+            }
+            // Peers may remember more of our own hits than we do after a restart, so
+            // those are taken back too
+            for (node, counts) in remote.hits {
+                let known = state.hits.entry(node).or_default();
+                for (script, count) in counts {
+                    let entry = known.entry(script).or_default();
+                    *entry = (*entry).max(count);
+                }
+            }
+        }
+        for (name, enabled, node) in changed {
+            info!("Cluster: {} {} by {}", name, if enabled { "enabled" } else { "disabled" }, node);
+            injector.set_script_enabled(&name, enabled);
+        }
+    }
+
+    /// `GET /admin/cluster`: this node, its peers, and each script's toggle and hits here
+    /// and across the cluster.
+    pub fn status(&self, injector: &HttpInjector) -> Value {
+        let state = self.snapshot(injector);
+        let local = state.hits.get(&self.node).cloned().unwrap_or_default();
+        let mut names: Vec<String> = injector.script_manager().list_scripts();
+        names.extend(state.toggles.keys().cloned());
+        names.extend(state.hits.values().flat_map(|counts| counts.keys().cloned()));
+        names.sort();
+        names.dedup();
+
+        let scripts: Vec<Value> = names
+            .iter()
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "toggle": state.toggles.get(name),
+                    "hits": local.get(name).copied().unwrap_or(0),
+                    "cluster_hits": state.hits.values().filter_map(|counts| counts.get(name)).sum::<u64>(),
+                })
+            })
+            .collect();
+        let peers = self.peers.lock().unwrap();
+        let peers: Vec<Value> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer| {
+                let status = peers.get(peer);
+                json!({
+                    "url": peer,
+                    "last_exchange_secs_ago": status.map(|s| s.at.elapsed().as_secs()),
+                    "error": status.and_then(|s| s.error.clone()),
+                })
+            })
+            .collect();
+        json!({ "node": self.node, "peers": peers, "scripts": scripts })
+    }
+
+    async fn exchange(&self, injector: &HttpInjector, peer: &str, token: Option<&str>) -> Result<(), String> {
+        let url = format!("{}{}", peer.trim_end_matches('/'), STATE_PATH);
+        let body = serde_json::to_vec(&self.snapshot(injector)).map_err(|e| e.to_string())?;
+        let mut request = self.client.post(&url).header("content-type", "application/json").body(body);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        let remote: State = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+        self.merge(injector, remote);
+        Ok(())
+    }
+
+    /// Adds the hits since the last look to this node's totals.
+    fn count_hits(&self, injector: &HttpInjector) {
+        let current = injector.script_manager().hit_counts();
+        let mut seen = self.seen.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let totals = state.hits.entry(self.node.clone()).or_default();
+        for (name, count) in &current {
+            let before = seen.get(name).copied().unwrap_or(0);
+            // A count below the last one means scripts reloaded and it started over
+            let added = if *count >= before { count - before } else { *count };
+            *totals.entry(name.clone()).or_default() += added;
+        }
+        *seen = current;
+    }
+}
+
+/// Exchanges state with every peer each `interval`, for as long as the proxy runs.
+/// Peers authenticate each other with the shared `[admin] token`.
+pub async fn run(state: Arc<ProxyState>) {
+    let cluster = &state.cluster;
+    if cluster.config.peers.is_empty() {
+        return;
+    }
+    let token = state.config.admin.token.as_deref().filter(|t| !t.is_empty());
+    info!("Cluster node {} exchanging state with {} peer(s)", cluster.node, cluster.config.peers.len());
+    let mut ticks = tokio::time::interval(Duration::from_secs(cluster.config.interval.max(1)));
+    loop {
+        ticks.tick().await;
+        for peer in &cluster.config.peers {
+            let result = cluster.exchange(&state.injector, peer, token).await;
+            let mut peers = cluster.peers.lock().unwrap();
+            let was_ok = peers.get(peer).is_none_or(|status| status.error.is_none());
+            match &result {
+                // Logged once when a peer goes away, not every round
+                Err(e) if was_ok => warn!("Cluster peer {} unreachable: {}", peer, e),
+                Err(e) => debug!("Cluster exchange with {} failed: {}", peer, e),
+                Ok(()) if !was_ok => info!("Cluster peer {} is back", peer),
+                Ok(()) => {}
+            }
+            peers.insert(
+                peer.clone(),
+                PeerStatus {
+                    at: Instant::now(),
+                    error: result.err(),
+                },
+            );
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is writable for its whole length, and one byte is kept for the NUL
+    let ok = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len() - 1) } == 0;
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(0);
+    if ok && len > 0 {
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    } else {
+        "localhost".to_string()
+    }
+}
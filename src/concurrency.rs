@@ -0,0 +1,199 @@
+use hyper::StatusCode;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{debug, info};
+
+use crate::config::ConcurrencyConfig;
+
+/// `[concurrency]`: how many requests may be in flight to each upstream host at once.
+/// Each host's limit grows by one for every limit's worth of quick, successful answers and
+/// is cut by `backoff` on a 5xx, a 429, a failure to connect or an answer slower than
+/// `latency_target`, so a struggling backend sees less traffic until it recovers.
+pub struct Concurrency {
+    config: ConcurrencyConfig,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+struct Host {
+    state: Mutex<HostState>,
+    /// Woken as slots free up or the limit grows
+    freed: Notify,
+}
+
+struct HostState {
+    limit: f64,
+    in_flight: usize,
+    waiting: usize,
+    /// Requests turned away after `queue_timeout`
+    refused: u64,
+    /// Times the limit was cut
+    backoffs: u64,
+}
+
+/// A slot taken by a request in flight; `complete` reports how the host answered.
+pub struct Permit {
+    host: Arc<Host>,
+    name: String,
+    config: ConcurrencyConfig,
+    started: Instant,
+    done: bool,
+}
+
+impl Concurrency {
+    pub fn open(config: &ConcurrencyConfig) -> Option<Arc<Concurrency>> {
+        if !config.enabled {
+            return None;
+        }
+        let mut config = config.clone();
+        config.min_limit = config.min_limit.max(1);
+        config.max_limit = config.max_limit.max(config.min_limit);
+        config.initial_limit = config.initial_limit.clamp(config.min_limit, config.max_limit);
+        config.backoff = config.backoff.clamp(0.1, 1.0);
+        info!(
+            "Adaptive concurrency: {} requests in flight per upstream to start with, between {} and {}",
+            config.initial_limit, config.min_limit, config.max_limit
+        );
+        Some(Arc::new(Concurrency {
+            config,
+            hosts: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn host(&self, name: &str) -> Arc<Host> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                Arc::new(Host {
+                    state: Mutex::new(HostState {
+                        limit: self.config.initial_limit as f64,
+                        in_flight: 0,
+                        waiting: 0,
+                        refused: 0,
+                        backoffs: 0,
+                    }),
+                    freed: Notify::new(),
+                })
+            })
+            .clone()
+    }
+
+    /// Waits for a slot toward `name` (the upstream's `host:port`), or gives up with `None`
+    /// after `queue_timeout`.
+    pub async fn acquire(&self, name: &str) -> Option<Permit> {
+        let host = self.host(name);
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(self.config.queue_timeout);
+        let mut queued = false;
+        loop {
+            // Registered before the check, so a slot freed in between still wakes us
+            let freed = host.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            {
+                let mut state = host.state.lock().unwrap();
+                if state.in_flight < state.limit as usize {
+                    state.in_flight += 1;
+                    if queued {
+                        state.waiting -= 1;
+                    }
+                    break;
+                }
+                if !queued {
+                    state.waiting += 1;
+                    queued = true;
+                    debug!("Request to {} waits: {} in flight at a limit of {}", name, state.in_flight, state.limit as usize);
+                }
+            }
+            if tokio::time::timeout_at(deadline, freed).await.is_err() {
+                let mut state = host.state.lock().unwrap();
+                state.waiting -= 1;
+                state.refused += 1;
+                return None;
+            }
+        }
+        Some(Permit {
+            host,
+            name: name.to_string(),
+            config: self.config.clone(),
+            started: Instant::now(),
+            done: false,
+        })
+    }
+
+    /// Each host's limit, requests in flight and waiting, and what was turned away.
+    pub fn snapshot(&self) -> Value {
+        let hosts = self.hosts.lock().unwrap();
+        let mut names: Vec<&String> = hosts.keys().collect();
+        names.sort();
+        let hosts: Vec<Value> = names
+            .into_iter()
+            .map(|name| {
+                let state = hosts[name].state.lock().unwrap();
+                json!({
+                    "host": name,
+                    "limit": state.limit as usize,
+                    "in_flight": state.in_flight,
+                    "waiting": state.waiting,
+                    "refused": state.refused,
+                    "backoffs": state.backoffs,
+                })
+            })
+            .collect();
+        json!({
+            "initial_limit": self.config.initial_limit,
+            "min_limit": self.config.min_limit,
+            "max_limit": self.config.max_limit,
+            "latency_target_ms": self.config.latency_target,
+            "hosts": hosts,
+        })
+    }
+}
+
+impl Permit {
+    /// Adjusts the host's limit by how the request went: the status of its answer, or
+    /// `None` when there was none.
+    pub fn complete(mut self, status: Option<StatusCode>) {
+        self.done = true;
+        let elapsed = self.started.elapsed();
+        let overloaded = match status {
+            Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            None => true,
+        } || elapsed > Duration::from_millis(self.config.latency_target);
+        let mut state = self.host.state.lock().unwrap();
+        state.in_flight -= 1;
+        let before = state.limit;
+        if overloaded {
+            state.limit = (state.limit * self.config.backoff).max(self.config.min_limit as f64);
+            state.backoffs += 1;
+        } else {
+            state.limit = (state.limit + 1.0 / state.limit).min(self.config.max_limit as f64);
+        }
+        if state.limit as usize != before as usize {
+            debug!(
+                "Concurrency limit for {} {} to {} ({}, {}ms)",
+                self.name,
+                if state.limit > before { "raised" } else { "cut" },
+                state.limit as usize,
+                status.map(|status| status.as_u16().to_string()).unwrap_or_else(|| "no answer".to_string()),
+                elapsed.as_millis()
+            );
+        }
+        drop(state);
+        self.host.freed.notify_waiters();
+    }
+}
+
+impl Drop for Permit {
+    // A request dropped before it was answered (the client went away) frees its slot
+    // without judging the host
+    fn drop(&mut self) {
+        if !self.done {
+            self.host.state.lock().unwrap().in_flight -= 1;
+            self.host.freed.notify_one();
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
-use anyhow::Result;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use std::net::IpAddr;
+
+use crate::forwarded;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -9,6 +13,55 @@ pub struct Config {
     pub scripts: ScriptConfig,
     pub logging: LoggingConfig,
     pub security: SecurityConfig,
+    #[serde(default)]
+    pub forwarded: ForwardedConfig,
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub diagnostics: DiagnosticsConfig,
+    #[serde(default)]
+    pub features: FeaturesConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    pub optimize: OptimizeConfig,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub tee: TeeConfig,
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+    #[serde(default)]
+    pub agent: AgentConfig,
+    #[serde(default)]
+    pub assertions: AssertionsConfig,
+    #[serde(default)]
+    pub conformance: ConformanceConfig,
+    #[serde(default)]
+    pub sso: SsoConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    /// APIs whose expired access tokens the proxy refreshes itself
+    #[serde(default)]
+    pub oauth: Vec<OAuthClient>,
+    /// Where requests for some domains connect, and the `Host` they carry there
+    #[serde(default)]
+    pub hosts: Vec<HostRule>,
+    /// Pages sent in place of the proxy's own error and refusal pages
+    #[serde(default)]
+    pub error_pages: Vec<ErrorPageRule>,
+    /// Keys `Sign` scripts sign requests with, by name
+    #[serde(default)]
+    pub credentials: HashMap<String, CredentialConfig>,
+    /// File the config was loaded from, if any
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -18,6 +71,77 @@ pub struct ProxyConfig {
     pub upstream_timeout: u64,
     pub max_connections: usize,
     pub buffer_size: usize,
+    /// Seconds an idle keep-alive upstream connection stays pooled before it is closed
+    #[serde(default = "default_pool_idle_timeout")]
+    pub pool_idle_timeout: u64,
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    /// Append a `Server-Timing` header with the proxy's dns/connect/upstream/inject phases
+    #[serde(default)]
+    pub server_timing: bool,
+    /// Seconds to let in-flight requests finish after SIGTERM/SIGINT before exiting
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Seconds without traffic after which upgraded connections are closed; 0 never closes them
+    #[serde(default)]
+    pub tunnel_idle_timeout: u64,
+    /// WebSocket subprotocols (substrings, e.g. `mqtt`) exempt from `tunnel_idle_timeout`
+    #[serde(default = "default_long_lived_subprotocols")]
+    pub long_lived_subprotocols: Vec<String>,
+    /// Redirect hops followed by the proxy before answering the client; 0 passes redirects through
+    #[serde(default)]
+    pub follow_redirects: u32,
+    /// Remove dot-segments (`/a/./b/../c`) and default ports from request URIs before
+    /// scripts match them and the request is forwarded
+    #[serde(default)]
+    pub normalize_uri: bool,
+    /// Destinations the proxy keeps out of, like `NO_PROXY`: domains (and their
+    /// subdomains), addresses or CIDR ranges
+    #[serde(default)]
+    pub bypass: Vec<String>,
+    /// What requests for `bypass` destinations get
+    #[serde(default)]
+    pub bypass_mode: BypassMode,
+}
+
+impl ProxyConfig {
+    /// Whether requests for `host` bypass the proxy's scripts, captures and traces.
+    pub fn bypasses(&self, host: &str) -> bool {
+        if self.bypass.is_empty() {
+            return false;
+        }
+        match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => forwarded::address_matches(ip, &self.bypass),
+            Err(_) => self.bypass.iter().any(|pattern| TunnelPolicy::matches(host, pattern.trim_start_matches('.'))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BypassMode {
+    /// Connected to directly and relayed untouched: no scripts, captures or traces
+    #[default]
+    Direct,
+    /// Refused with `403` and `Proxy-Status: destination_ip_prohibited`, so the client
+    /// has to go there without the proxy
+    Refuse,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    5
+}
+
+fn default_long_lived_subprotocols() -> Vec<String> {
+    vec!["mqtt".to_string(), "stomp".to_string(), "amqp".to_string()]
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    32
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,6 +151,126 @@ pub struct ScriptConfig {
     pub max_execution_time: u64,
     pub allowed_domains: Vec<String>,
     pub blocked_domains: Vec<String>,
+    /// Text bodies larger than this (or without a Content-Length) are rewritten while streaming
+    #[serde(default = "default_stream_threshold")]
+    pub stream_threshold: usize,
+    /// Bytes held back between chunks so replacements can match across chunk boundaries
+    #[serde(default = "default_stream_window")]
+    pub stream_window: usize,
+    /// Bytes injections may add to one response body in all; a script that would add
+    /// more is skipped for that response. 0 is no limit
+    #[serde(default = "default_max_added_bytes")]
+    pub max_added_bytes: usize,
+    /// Matches a `ResponseReplace` pattern may replace in one body; past it the script
+    /// is skipped for that response. 0 is no limit
+    #[serde(default = "default_max_replacements")]
+    pub max_replacements: usize,
+    /// How ETag/Last-Modified are handled once a response body has been modified
+    #[serde(default)]
+    pub validator_mode: ValidatorMode,
+    /// Pass responses marked `Cache-Control: no-transform` through without injection
+    #[serde(default = "default_true")]
+    pub respect_no_transform: bool,
+    /// Mark modified responses `Cache-Control: private` so shared caches don't store them
+    #[serde(default = "default_true")]
+    pub mark_private: bool,
+    /// Header names appended to `Vary` on modified responses
+    #[serde(default)]
+    pub vary: Vec<String>,
+    /// Tag proxy-issued ETags with a hash of the domain's script set and force a full
+    /// upstream fetch when a client revalidates content injected by an older script set
+    #[serde(default = "default_true")]
+    pub refetch_on_script_change: bool,
+    /// How `integrity=` attributes on HTML pages are fixed up for assets the proxy modifies
+    #[serde(default)]
+    pub integrity_mode: IntegrityMode,
+    /// `Accept-Encoding` forced toward upstream domains, by domain pattern, so responses
+    /// arrive in a form scripts can inject into without decompressing
+    #[serde(default)]
+    pub accept_encoding: HashMap<String, AcceptEncoding>,
+    /// Where remote assets declared by scripts are cached
+    #[serde(default = "default_asset_cache_dir")]
+    pub asset_cache_dir: String,
+    /// Local files served under `/__rusty_proxy/assets/` for injected pages to reference
+    #[serde(default = "default_assets_dir")]
+    pub assets_dir: String,
+    /// Write the example scripts into a scripts directory that holds none yet
+    #[serde(default = "default_true")]
+    pub create_examples: bool,
+    /// Values filled into scripts wherever `{{var:NAME}}` appears, so the same script can
+    /// target dev, staging or prod; a script's own `vars` take precedence
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+}
+
+fn default_assets_dir() -> String {
+    "assets".to_string()
+}
+
+fn default_asset_cache_dir() -> String {
+    "asset-cache".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IntegrityMode {
+    /// Leave `integrity=` attributes alone
+    Off,
+    /// Remove the attribute from tags loading assets that scripts may modify
+    Strip,
+    /// Use the hash of the asset as last served by the proxy, stripping when it is unknown
+    #[default]
+    Recompute,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptEncoding {
+    /// Uncompressed responses only
+    Identity,
+    /// gzip or uncompressed, never brotli or zstd
+    Gzip,
+}
+
+impl AcceptEncoding {
+    pub fn header_value(self) -> &'static str {
+        match self {
+            AcceptEncoding::Identity => "identity",
+            AcceptEncoding::Gzip => "gzip, identity",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidatorMode {
+    /// Mark the upstream ETag weak and keep Last-Modified
+    Weaken,
+    /// Remove ETag and Last-Modified
+    Strip,
+    /// Replace the ETag with a hash of the modified body and drop Last-Modified
+    #[default]
+    Recompute,
+}
+
+fn default_stream_threshold() -> usize {
+    1024 * 1024
+}
+
+fn default_stream_window() -> usize {
+    4096
+}
+
+fn default_max_added_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_replacements() -> usize {
+    10_000
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -35,6 +279,66 @@ pub struct LoggingConfig {
     pub file: Option<String>,
     pub max_size: String,
     pub max_files: u32,
+    /// Per-module level overrides, e.g. `proxy = "debug"`
+    #[serde(default)]
+    pub modules: HashMap<String, String>,
+    #[serde(default)]
+    pub backend: LogBackend,
+    /// Datagram socket used by the `syslog` backend
+    #[serde(default = "default_syslog_socket")]
+    pub syslog_socket: String,
+    /// Fraction of requests (0.0-1.0) logged with full headers and bodies
+    #[serde(default)]
+    pub sample_rate: f64,
+    /// Picks sampled requests by a hash of this seed and the request ID instead of at
+    /// random, so reruns sample the same requests
+    #[serde(default)]
+    pub sample_seed: Option<u64>,
+    /// JSON-lines file each sampling decision is appended to; decisions already in it are
+    /// reused for their request IDs
+    #[serde(default)]
+    pub sample_record: Option<String>,
+    /// Requests carrying this header are always logged in full; the header is not forwarded
+    #[serde(default)]
+    pub debug_header: Option<String>,
+    /// Log a SHA-256 of every response body as it came from upstream and as it was sent
+    /// to the client
+    #[serde(default)]
+    pub checksums: bool,
+    /// Descriptor sets used to show sampled protobuf/gRPC bodies as JSON
+    #[serde(default)]
+    pub protobuf: Vec<ProtobufEndpoint>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProtobufEndpoint {
+    /// Request path prefix, e.g. `/helloworld.Greeter/`
+    pub path: String,
+    /// File written by `protoc --include_imports --descriptor_set_out`
+    pub descriptor_set: String,
+    /// Fully qualified message names; gRPC paths fall back to the method's own types
+    #[serde(default)]
+    pub request_message: Option<String>,
+    #[serde(default)]
+    pub response_message: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogBackend {
+    /// Human-readable lines on stdout
+    #[default]
+    Stdout,
+    /// One JSON object per line on stdout, for log shippers
+    StdoutJson,
+    /// Append to `logging.file`
+    File,
+    Syslog,
+    Journald,
+}
+
+fn default_syslog_socket() -> String {
+    "/dev/log".to_string()
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -44,6 +348,904 @@ pub struct SecurityConfig {
     pub rate_limit: u32,
     pub whitelist_ips: Vec<String>,
     pub blacklist_ips: Vec<String>,
+    /// Client headers sent on to upstreams
+    #[serde(default)]
+    pub request_headers: HeaderFilter,
+    /// Upstream headers passed back to clients
+    #[serde(default)]
+    pub response_headers: HeaderFilter,
+    /// Domains CONNECT tunnels may reach, judged by the TLS server name
+    #[serde(default)]
+    pub tunnels: TunnelPolicy,
+    /// HSTS on intercepted domains: kept, stripped, or never intercepted when preloaded
+    #[serde(default)]
+    pub hsts: HstsPolicy,
+}
+
+/// Header names to pass or drop, applied before any script runs. Names match
+/// case-insensitively; a trailing `*` matches any suffix (`x-internal-*`).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct HeaderFilter {
+    /// When non-empty, only these headers pass (framing and upgrade headers always do)
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Dropped even when allowed
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Headers an allow list never removes, since the message can't be framed or upgraded
+/// without them.
+const ALWAYS_ALLOWED_HEADERS: &[&str] = &["host", "content-length", "content-type", "transfer-encoding", "connection", "upgrade"];
+
+impl HeaderFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, name: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.get(..prefix.len()).is_some_and(|head| head.eq_ignore_ascii_case(prefix)),
+            None => name.eq_ignore_ascii_case(pattern),
+        };
+        if self.deny.iter().any(matches) {
+            return false;
+        }
+        self.allow.is_empty()
+            || ALWAYS_ALLOWED_HEADERS.iter().any(|always| name.eq_ignore_ascii_case(always))
+            || self.allow.iter().any(matches)
+    }
+}
+
+/// What becomes of HSTS on domains the proxy intercepts. Domains match themselves and
+/// their subdomains; `*` matches everything.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HstsPolicy {
+    /// Domains whose `Strict-Transport-Security` headers are removed, so browsers don't pin
+    /// them to HTTPS; needs the `hsts-stripping` feature
+    #[serde(default)]
+    pub strip: Vec<String>,
+    /// Tunnel preloaded domains without interception, since browsers reject a substitute
+    /// certificate for them outright
+    #[serde(default = "default_true")]
+    pub tunnel_preloaded: bool,
+    /// Chromium's `transport_security_state_static.json`, listing the preloaded domains
+    #[serde(default)]
+    pub preload_list: Option<String>,
+    /// More domains (and their subdomains) to treat as preloaded
+    #[serde(default)]
+    pub preloaded: Vec<String>,
+}
+
+impl Default for HstsPolicy {
+    fn default() -> Self {
+        HstsPolicy {
+            strip: vec![],
+            tunnel_preloaded: true,
+            preload_list: None,
+            preloaded: vec![],
+        }
+    }
+}
+
+/// Policy for CONNECT tunnels that needs no TLS interception: the domain is the server
+/// name from the client's ClientHello, or the CONNECT host when the tunnel carries no TLS.
+/// Domains match themselves and their subdomains, as do `*.example.com` globs; `*`
+/// matches everything.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TunnelPolicy {
+    /// When non-empty, only tunnels to these domains are relayed
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Refused even when allowed
+    #[serde(default)]
+    pub block: Vec<String>,
+    /// Bytes per second (both directions together) tunnels to a domain may relay
+    #[serde(default)]
+    pub throttle: HashMap<String, u64>,
+    /// Seconds to wait for a ClientHello before judging the tunnel by its CONNECT host
+    #[serde(default = "default_hello_timeout")]
+    pub hello_timeout: u64,
+}
+
+fn default_hello_timeout() -> u64 {
+    3
+}
+
+impl Default for TunnelPolicy {
+    fn default() -> Self {
+        TunnelPolicy {
+            allow: vec![],
+            block: vec![],
+            throttle: HashMap::new(),
+            hello_timeout: default_hello_timeout(),
+        }
+    }
+}
+
+impl TunnelPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.block.is_empty() && self.throttle.is_empty()
+    }
+
+    /// Whether `pattern` covers `domain`; see `domain_covers`.
+    pub fn matches(domain: &str, pattern: &str) -> bool {
+        domain_covers(pattern, domain)
+    }
+
+    pub fn permits(&self, domain: &str) -> bool {
+        if domain_listed(&self.block, domain) {
+            return false;
+        }
+        self.allow.is_empty() || domain_listed(&self.allow, domain)
+    }
+
+    /// The tightest throttle whose domain matches.
+    pub fn throttle(&self, domain: &str) -> Option<u64> {
+        self.throttle
+            .iter()
+            .filter(|(pattern, _)| Self::matches(domain, pattern))
+            .map(|(_, rate)| *rate)
+            .min()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForwardedConfig {
+    /// `append` adds this hop, `strip` removes all forwarding headers (anonymization),
+    /// `passthrough` leaves them as the client sent them
+    #[serde(default)]
+    pub mode: ForwardedMode,
+    /// Send `X-Forwarded-For`/`-Proto`/`-Host`
+    #[serde(default = "default_true")]
+    pub x_forwarded: bool,
+    /// Send the RFC 7239 `Forwarded` header
+    #[serde(default)]
+    pub forwarded: bool,
+    /// Peers (addresses or CIDR ranges) whose incoming forwarding headers are kept and extended
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardedMode {
+    #[default]
+    Append,
+    Strip,
+    Passthrough,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetryConfig {
+    /// Upstream domains (and their subdomains) whose 429 responses are held and retried
+    /// after `Retry-After`; empty disables retrying
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Retries per request before the 429 is passed on to the client
+    #[serde(default = "default_retry_attempts")]
+    pub max_attempts: u32,
+    /// Longest `Retry-After` in seconds worth waiting for; longer ones go to the client
+    #[serde(default = "default_retry_max_wait")]
+    pub max_wait: u64,
+}
+
+fn default_retry_attempts() -> u32 {
+    2
+}
+
+fn default_retry_max_wait() -> u64 {
+    10
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            domains: vec![],
+            max_attempts: default_retry_attempts(),
+            max_wait: default_retry_max_wait(),
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn applies_to(&self, domain: &str) -> bool {
+        domain_listed(&self.domains, domain)
+    }
+}
+
+/// Whether one of `domains` covers `domain`; see `domain_covers`.
+pub fn domain_listed(domains: &[String], domain: &str) -> bool {
+    domains.iter().any(|pattern| domain_covers(pattern, domain))
+}
+
+/// Whether a domain entry of the config covers `domain`: `*` is every domain, and
+/// `example.com` or `*.example.com` that domain and its subdomains, as in a script's
+/// `target_domains`. Case is ignored.
+pub fn domain_covers(pattern: &str, domain: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let suffix = pattern.strip_prefix("*.").unwrap_or(pattern);
+    let Some(start) = domain.len().checked_sub(suffix.len()).filter(|start| domain.is_char_boundary(*start)) else {
+        return false;
+    };
+    let (rest, tail) = domain.split_at(start);
+    tail.eq_ignore_ascii_case(suffix) && (rest.is_empty() || rest.ends_with('.'))
+}
+
+/// `[concurrency]`: a limit on requests in flight to each upstream host that grows while
+/// the host answers quickly and shrinks when it slows down or fails (AIMD).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConcurrencyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Requests in flight each host is allowed at first
+    #[serde(default = "default_initial_limit")]
+    pub initial_limit: usize,
+    #[serde(default = "default_min_limit")]
+    pub min_limit: usize,
+    #[serde(default = "default_max_limit")]
+    pub max_limit: usize,
+    /// Milliseconds after which a response counts as a sign of overload, like a 5xx or 429
+    #[serde(default = "default_latency_target")]
+    pub latency_target: u64,
+    /// Factor the limit is multiplied by on each sign of overload
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+    /// Milliseconds a request waits for a free slot before it is answered `503`
+    #[serde(default = "default_queue_timeout")]
+    pub queue_timeout: u64,
+}
+
+fn default_initial_limit() -> usize {
+    10
+}
+
+fn default_min_limit() -> usize {
+    1
+}
+
+fn default_max_limit() -> usize {
+    200
+}
+
+fn default_latency_target() -> u64 {
+    2000
+}
+
+fn default_backoff() -> f64 {
+    0.75
+}
+
+fn default_queue_timeout() -> u64 {
+    5000
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig {
+            enabled: false,
+            initial_limit: default_initial_limit(),
+            min_limit: default_min_limit(),
+            max_limit: default_max_limit(),
+            latency_target: default_latency_target(),
+            backoff: default_backoff(),
+            queue_timeout: default_queue_timeout(),
+        }
+    }
+}
+
+/// Size optimizations for constrained-bandwidth clients, all opt-in.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OptimizeConfig {
+    /// Minify `JavaScript` and `CSS` script payloads when scripts are loaded
+    #[serde(default)]
+    pub minify_payloads: bool,
+    /// Minify buffered HTML pages
+    #[serde(default)]
+    pub minify_html: bool,
+    /// Re-encode JPEG and PNG responses when that makes them smaller
+    #[serde(default)]
+    pub recompress_images: bool,
+    /// JPEG quality (1-100) used when re-encoding
+    #[serde(default = "default_image_quality")]
+    pub image_quality: u8,
+    /// Compress bodies scripts modified for clients that accept gzip or zstd
+    #[serde(default)]
+    pub compress_modified: bool,
+}
+
+fn default_image_quality() -> u8 {
+    75
+}
+
+impl Default for OptimizeConfig {
+    fn default() -> Self {
+        OptimizeConfig {
+            minify_payloads: false,
+            minify_html: false,
+            recompress_images: false,
+            image_quality: default_image_quality(),
+            compress_modified: false,
+        }
+    }
+}
+
+/// DNS-over-HTTPS endpoint, so devices can send their DNS through the same domain
+/// policy as their tunnels.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsConfig {
+    /// Answer RFC 8484 queries at `/dns-query` on the proxy port
+    #[serde(default)]
+    pub enabled: bool,
+    /// Resolver (`host:port`) queries are forwarded to; the first `nameserver` in
+    /// `/etc/resolv.conf` when unset
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// Seconds to wait for the resolver
+    #[serde(default = "default_dns_timeout")]
+    pub timeout: u64,
+}
+
+fn default_dns_timeout() -> u64 {
+    5
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            enabled: false,
+            upstream: None,
+            timeout: default_dns_timeout(),
+        }
+    }
+}
+
+/// Archiving the exchanges clients see, for replay with web-archive tooling.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CaptureConfig {
+    /// WARC file responses are appended to; a `.gz` name compresses each record. Unset
+    /// turns capturing off.
+    #[serde(default)]
+    pub warc: Option<String>,
+    /// Domain patterns captured; empty means all
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Bodies beyond this many bytes are cut short in the archive
+    #[serde(default = "default_capture_max_body")]
+    pub max_body: usize,
+    /// Start a new file once the current one reaches this many bytes; 0 means never
+    #[serde(default)]
+    pub rotate_size: u64,
+    /// Start a new file after this many seconds; 0 means never
+    #[serde(default)]
+    pub rotate_interval: u64,
+    /// Delete rotated files older than this many days; 0 keeps them
+    #[serde(default)]
+    pub keep_days: u64,
+    /// Delete the oldest rotated files while all files together exceed this many bytes;
+    /// 0 means no limit
+    #[serde(default)]
+    pub keep_bytes: u64,
+}
+
+fn default_capture_max_body() -> usize {
+    10 * 1024 * 1024
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            warc: None,
+            domains: vec![],
+            max_body: default_capture_max_body(),
+            rotate_size: 0,
+            rotate_interval: 0,
+            keep_days: 0,
+            keep_bytes: 0,
+        }
+    }
+}
+
+/// Copying response bodies to files of their own as they stream to the client, for
+/// archiving downloads.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TeeConfig {
+    /// Directory teed bodies are written to; unset turns teeing off
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Domain patterns teed; empty means all
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// `Content-Type` prefixes teed, e.g. `application/zip` or `video/`; empty means all
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Responses whose `Content-Length` is below this many bytes are not teed
+    #[serde(default)]
+    pub min_size: u64,
+    /// Also put finished files in `[storage]`, under `downloads/`. A file is read
+    /// whole to be uploaded.
+    #[serde(default)]
+    pub upload: bool,
+}
+
+/// Where rotated captures, stats snapshots and cached assets are kept beyond the local disk.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageConfig {
+    /// `memory`, `filesystem` or `s3`; empty keeps everything on the local disk only
+    #[serde(default)]
+    pub backend: String,
+    /// Root directory of the `filesystem` backend
+    #[serde(default = "default_storage_path")]
+    pub path: String,
+    /// Bucket of the `s3` backend
+    #[serde(default)]
+    pub bucket: Option<String>,
+    #[serde(default = "default_storage_region")]
+    pub region: String,
+    /// Base URL of an S3-compatible service (MinIO, R2, ...), addressed path-style;
+    /// unset means AWS
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Access key ID; falls back to `AWS_ACCESS_KEY_ID`
+    #[serde(default)]
+    pub access_key: Option<String>,
+    /// Secret access key; falls back to `AWS_SECRET_ACCESS_KEY`
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    /// Prepended to every key, e.g. `rusty-proxy/prod/`
+    #[serde(default)]
+    pub prefix: String,
+    /// Seconds between stats snapshots; 0 writes one only at shutdown
+    #[serde(default = "default_stats_interval")]
+    pub stats_interval: u64,
+    /// Delete stored captures, logs and stats older than this many days; 0 keeps them
+    #[serde(default)]
+    pub keep_days: u64,
+}
+
+fn default_storage_path() -> String {
+    "storage".to_string()
+}
+
+fn default_storage_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_stats_interval() -> u64 {
+    300
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: String::new(),
+            path: default_storage_path(),
+            bucket: None,
+            region: default_storage_region(),
+            endpoint: None,
+            access_key: None,
+            secret_key: None,
+            prefix: String::new(),
+            stats_interval: default_stats_interval(),
+            keep_days: 0,
+        }
+    }
+}
+
+/// Taking scripts and config from a central management server, and reporting back to it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AgentConfig {
+    /// Base URL of the management server; unset turns the agent off
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Bearer token sent to the management server
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Seconds between polls
+    #[serde(default = "default_agent_interval")]
+    pub interval: u64,
+    /// Directory under the scripts directory that holds the managed scripts; the agent
+    /// replaces its contents on every update
+    #[serde(default = "default_agent_directory")]
+    pub directory: String,
+}
+
+fn default_agent_interval() -> u64 {
+    30
+}
+
+fn default_agent_directory() -> String {
+    "managed".to_string()
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            url: None,
+            token: None,
+            interval: default_agent_interval(),
+            directory: default_agent_directory(),
+        }
+    }
+}
+
+/// Expectations checked against the traffic passing through the proxy.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AssertionsConfig {
+    /// Assertions file to check every response against; unset turns checking off
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+/// Traffic checked against an OpenAPI spec as it passes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConformanceConfig {
+    /// OpenAPI 3 or Swagger 2 spec (JSON or YAML) to validate traffic against; unset turns
+    /// validation off
+    #[serde(default)]
+    pub spec: Option<String>,
+    /// JSON response bodies beyond this many bytes are not validated
+    #[serde(default = "default_conformance_max_body")]
+    pub max_body: usize,
+}
+
+fn default_conformance_max_body() -> usize {
+    1024 * 1024
+}
+
+impl Default for ConformanceConfig {
+    fn default() -> Self {
+        ConformanceConfig {
+            spec: None,
+            max_body: default_conformance_max_body(),
+        }
+    }
+}
+
+/// Single sign-on logins followed across redirects, form posts and token requests.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SsoConfig {
+    /// Trace OIDC and SAML login flows for `GET /admin/sso` and `rusty-proxy sso`
+    #[serde(default)]
+    pub trace: bool,
+    /// Seconds a flow stays open to the redirects and form posts of its client
+    #[serde(default = "default_sso_flow_timeout")]
+    pub flow_timeout: u64,
+}
+
+fn default_sso_flow_timeout() -> u64 {
+    300
+}
+
+impl Default for SsoConfig {
+    fn default() -> Self {
+        SsoConfig {
+            trace: false,
+            flow_timeout: default_sso_flow_timeout(),
+        }
+    }
+}
+
+/// An API whose 401s for expired tokens are answered by fetching a new access token from
+/// its token endpoint and sending the request again.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OAuthClient {
+    /// API domains (and their subdomains) this token is for
+    pub domains: Vec<String>,
+    /// Token endpoint the refresh-token grant is sent to
+    pub token_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    /// Send the client credentials as HTTP Basic auth instead of in the form
+    #[serde(default)]
+    pub basic_auth: bool,
+    /// Refresh token to start from; without one the client-credentials grant is used
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+impl OAuthClient {
+    pub fn applies_to(&self, domain: &str) -> bool {
+        domain_listed(&self.domains, domain)
+    }
+}
+
+/// A `[[hosts]]` entry: requests for its domains are sent to `connect` instead of their
+/// own host, with a `Host` header chosen independently, e.g. to try a virtual host on a
+/// staging server or a site's CDN origin directly.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HostRule {
+    /// Domains (and their subdomains) the rule applies to; `*` is every domain
+    pub domains: Vec<String>,
+    /// `host[:port]` connected to in place of the request's own
+    #[serde(default)]
+    pub connect: Option<String>,
+    /// What the upstream is sent as `Host`
+    #[serde(default)]
+    pub host: HostHeader,
+    /// The `Host` sent with `override`, or the replacement for `rewrite`
+    #[serde(default)]
+    pub host_value: Option<String>,
+    /// For `rewrite`: regex replaced by `host_value` in the `Host` the client sent
+    #[serde(default)]
+    pub host_pattern: Option<String>,
+}
+
+impl HostRule {
+    pub fn applies_to(&self, domain: &str) -> bool {
+        domain_listed(&self.domains, domain)
+    }
+}
+
+/// `[[error_pages]]`: what clients see when the proxy itself answers with an error or
+/// refusal (a failed upstream, proxy authentication, a refused destination), for some
+/// domains and clients. The first matching rule is used.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ErrorPageRule {
+    /// Domains (and their subdomains) the page is for; empty or `*` is every domain
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Client addresses or CIDR ranges the page is for; empty means all
+    #[serde(default)]
+    pub clients: Vec<String>,
+    /// Statuses the page replaces, e.g. `[403, 407]`; empty means all
+    #[serde(default)]
+    pub statuses: Vec<u16>,
+    /// Headers added to the response; values may use the body's placeholders
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Body template; `{{status}}`, `{{reason}}`, `{{error}}`, `{{method}}`, `{{url}}`,
+    /// `{{domain}}`, `{{client}}` and `{{time}}` are filled in. Unset keeps the proxy's
+    /// own body.
+    #[serde(default)]
+    pub body: Option<String>,
+    /// File the body template is read from at start, in place of `body`
+    #[serde(default)]
+    pub body_file: Option<String>,
+    #[serde(default = "default_error_page_content_type")]
+    pub content_type: String,
+}
+
+fn default_error_page_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+/// `[credentials.NAME]`: a key `Sign` scripts name in `credential`, kept out of the
+/// scripts so they can be shared. The secret comes from `secret`, `secret_file` or the
+/// output of `secret_command`, e.g. `secret-tool lookup service aws` for the desktop
+/// keyring, read once at start.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CredentialConfig {
+    /// The AWS access key ID, or the key ID an HMAC scheme sends as `{{key_id}}`
+    #[serde(default)]
+    pub key_id: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub secret_file: Option<String>,
+    /// Run with `sh -c`; its output, without the trailing newline, is the secret
+    #[serde(default)]
+    pub secret_command: Option<String>,
+    /// AWS session token of temporary credentials, sent as `X-Amz-Security-Token`
+    #[serde(default)]
+    pub session_token: Option<String>,
+}
+
+impl ErrorPageRule {
+    pub fn applies_to(&self, domain: &str, client: IpAddr, status: u16) -> bool {
+        (self.domains.is_empty() || domain_listed(&self.domains, domain))
+            && (self.clients.is_empty() || forwarded::address_matches(client, &self.clients))
+            && (self.statuses.is_empty() || self.statuses.contains(&status))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HostHeader {
+    /// The `Host` the client sent
+    #[default]
+    Preserve,
+    /// The `connect` address
+    Connect,
+    /// `host_value`
+    Override,
+    /// The client's `Host` with `host_pattern` replaced by `host_value`
+    Rewrite,
+}
+
+/// Instances behind one load balancer that keep script toggles and hit counts in step.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClusterConfig {
+    /// Name this instance is known by to its peers; defaults to `<hostname>:<port>`
+    #[serde(default)]
+    pub node_id: Option<String>,
+    /// Base URLs of the other instances' admin APIs, e.g. `http://10.0.0.2:8080`
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Seconds between exchanges with each peer
+    #[serde(default = "default_cluster_interval")]
+    pub interval: u64,
+}
+
+fn default_cluster_interval() -> u64 {
+    5
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            node_id: None,
+            peers: vec![],
+            interval: default_cluster_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AdminConfig {
+    /// Serve the admin API under `/admin/` on the proxy port
+    #[serde(default)]
+    pub enabled: bool,
+    /// Bearer token required for admin requests; when unset only loopback clients are allowed
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Expose the `/admin/debug/pprof/` CPU and heap profiling endpoints
+    #[serde(default)]
+    pub profiling: bool,
+    /// Seconds a browser session opened with `POST /admin/login` lasts
+    #[serde(default = "default_admin_session_ttl")]
+    pub session_ttl: u64,
+    /// Also serve the admin API over HTTPS on this address, e.g. `0.0.0.0:8443`
+    #[serde(default)]
+    pub tls_listen: Option<String>,
+    /// PEM certificate chain for `tls_listen`
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    /// PEM private key for `tls_listen`
+    #[serde(default)]
+    pub tls_key: Option<String>,
+    /// `[[admin.tls_certs]]`: further certificates for `tls_listen`, each served to
+    /// clients asking (by SNI) for one of its domains; `tls_cert` goes to the rest
+    #[serde(default)]
+    pub tls_certs: Vec<TlsCert>,
+}
+
+/// A certificate `tls_listen` serves for some names.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsCert {
+    /// Server names the certificate is for: exact, `*.suffix` or a regex
+    pub domains: Vec<String>,
+    /// PEM certificate chain
+    pub cert: String,
+    /// PEM private key
+    pub key: String,
+}
+
+fn default_admin_session_ttl() -> u64 {
+    8 * 3600
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enabled: false,
+            token: None,
+            profiling: false,
+            session_ttl: default_admin_session_ttl(),
+            tls_listen: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_certs: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DiagnosticsConfig {
+    /// Where SIGUSR1 dumps are written; defaults to the system temp directory
+    #[serde(default)]
+    pub dump_dir: Option<String>,
+}
+
+/// Capabilities that can intercept or weaken traffic; all of them stay off unless the
+/// operator opts in explicitly.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct FeaturesConfig {
+    /// Intercept TLS inside CONNECT tunnels instead of relaying them untouched
+    #[serde(default)]
+    pub mitm: bool,
+    /// Let scripts inject `Access-Control-*` headers
+    #[serde(default)]
+    pub cors_injection: bool,
+    /// Let scripts remove headers by setting them to an empty value
+    #[serde(default)]
+    pub header_stripping: bool,
+    /// Listen on a non-loopback `bind_address`
+    #[serde(default)]
+    pub open_ports: bool,
+    /// Remove `Strict-Transport-Security` from the domains in `security.hsts.strip`
+    #[serde(default)]
+    pub hsts_stripping: bool,
+    /// Answer `/__rusty_proxy/api/` log, store, fetch and config calls from scripted pages
+    #[serde(default)]
+    pub page_api: bool,
+}
+
+impl FeaturesConfig {
+    pub fn enabled(&self) -> Vec<&'static str> {
+        [
+            ("mitm", self.mitm),
+            ("cors-injection", self.cors_injection),
+            ("header-stripping", self.header_stripping),
+            ("open-ports", self.open_ports),
+            ("hsts-stripping", self.hsts_stripping),
+            ("page-api", self.page_api),
+        ]
+        .into_iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProxyProtocolConfig {
+    /// Require a PROXY v1/v2 header on every accepted connection (behind a TCP load balancer)
+    #[serde(default)]
+    pub accept: bool,
+    /// Seconds to wait for the PROXY header before dropping the connection
+    #[serde(default = "default_proxy_header_timeout")]
+    pub header_timeout: u64,
+    /// Upstream hosts (and subdomains), as dialled after `[[hosts]]`, whose connections
+    /// start with a PROXY header carrying the client address. CONNECT tunnels never get one
+    #[serde(default)]
+    pub send_to: Vec<String>,
+    /// Header version sent upstream (1 or 2)
+    #[serde(default = "default_proxy_protocol_version")]
+    pub version: u8,
+}
+
+fn default_proxy_header_timeout() -> u64 {
+    5
+}
+
+fn default_proxy_protocol_version() -> u8 {
+    1
+}
+
+impl Default for ProxyProtocolConfig {
+    fn default() -> Self {
+        ProxyProtocolConfig {
+            accept: false,
+            header_timeout: default_proxy_header_timeout(),
+            send_to: vec![],
+            version: default_proxy_protocol_version(),
+        }
+    }
+}
+
+impl ProxyProtocolConfig {
+    /// Whether connections to the upstream `host` start with a PROXY header.
+    pub fn sends_to(&self, host: &str) -> bool {
+        domain_listed(&self.send_to, host)
+    }
+}
+
+impl Default for ForwardedConfig {
+    fn default() -> Self {
+        ForwardedConfig {
+            mode: ForwardedMode::default(),
+            x_forwarded: true,
+            forwarded: false,
+            trusted_proxies: vec![],
+        }
+    }
 }
 
 impl Default for Config {
@@ -55,6 +1257,16 @@ impl Default for Config {
                 upstream_timeout: 30,
                 max_connections: 1000,
                 buffer_size: 8192,
+                pool_idle_timeout: default_pool_idle_timeout(),
+                pool_max_idle_per_host: default_pool_max_idle_per_host(),
+                server_timing: false,
+                shutdown_timeout: default_shutdown_timeout(),
+                tunnel_idle_timeout: 0,
+                long_lived_subprotocols: default_long_lived_subprotocols(),
+                follow_redirects: 0,
+                normalize_uri: false,
+                bypass: vec![],
+                bypass_mode: BypassMode::default(),
             },
             scripts: ScriptConfig {
                 directory: "scripts".to_string(),
@@ -62,12 +1274,36 @@ impl Default for Config {
                 max_execution_time: 5000,
                 allowed_domains: vec!["*".to_string()],
                 blocked_domains: vec![],
+                stream_threshold: default_stream_threshold(),
+                stream_window: default_stream_window(),
+                max_added_bytes: default_max_added_bytes(),
+                max_replacements: default_max_replacements(),
+                validator_mode: ValidatorMode::default(),
+                respect_no_transform: true,
+                mark_private: true,
+                vary: vec![],
+                refetch_on_script_change: true,
+                integrity_mode: IntegrityMode::default(),
+                accept_encoding: HashMap::new(),
+                asset_cache_dir: default_asset_cache_dir(),
+                assets_dir: default_assets_dir(),
+                create_examples: true,
+                vars: HashMap::new(),
             },
             logging: LoggingConfig {
                 level: "info".to_string(),
                 file: Some("rusty-proxy.log".to_string()),
                 max_size: "10MB".to_string(),
                 max_files: 5,
+                modules: HashMap::new(),
+                backend: LogBackend::default(),
+                syslog_socket: default_syslog_socket(),
+                sample_rate: 0.0,
+                sample_seed: None,
+                sample_record: None,
+                debug_header: None,
+                checksums: false,
+                protobuf: vec![],
             },
             security: SecurityConfig {
                 require_auth: false,
@@ -75,24 +1311,77 @@ impl Default for Config {
                 rate_limit: 100,
                 whitelist_ips: vec![],
                 blacklist_ips: vec![],
+                request_headers: HeaderFilter::default(),
+                response_headers: HeaderFilter::default(),
+                tunnels: TunnelPolicy::default(),
+                hsts: HstsPolicy::default(),
             },
+            forwarded: ForwardedConfig::default(),
+            proxy_protocol: ProxyProtocolConfig::default(),
+            admin: AdminConfig::default(),
+            diagnostics: DiagnosticsConfig::default(),
+            features: FeaturesConfig::default(),
+            retry: RetryConfig::default(),
+            concurrency: ConcurrencyConfig::default(),
+            optimize: OptimizeConfig::default(),
+            dns: DnsConfig::default(),
+            capture: CaptureConfig::default(),
+            tee: TeeConfig::default(),
+            cluster: ClusterConfig::default(),
+            agent: AgentConfig::default(),
+            assertions: AssertionsConfig::default(),
+            conformance: ConformanceConfig::default(),
+            sso: SsoConfig::default(),
+            storage: StorageConfig::default(),
+            oauth: vec![],
+            hosts: vec![],
+            error_pages: vec![],
+            credentials: HashMap::new(),
+            path: None,
         }
     }
 }
 
 impl Config {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        if !path.as_ref().exists() {
+    /// Reads the config file, falling back to the defaults when it does not exist (and
+    /// writing them out when `write_default` is set), then applies environment overrides.
+    pub fn load<P: AsRef<Path>>(path: P, write_default: bool) -> Result<Self> {
+        let config: Config = if path.as_ref().exists() {
+            let content = fs::read_to_string(&path)?;
+            toml::from_str(&content)?
+        } else {
             let default_config = Config::default();
-            default_config.save(&path)?;
-            return Ok(default_config);
-        }
+            if write_default {
+                default_config.save(&path)?;
+            }
+            default_config
+        };
 
-        let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+        let mut config = config.apply_env_overrides()?;
+        config.path = Some(path.as_ref().to_path_buf());
         Ok(config)
     }
 
+    /// Overrides any setting from `RUSTY_PROXY_<SECTION>__<KEY>` variables, e.g.
+    /// `RUSTY_PROXY_PROXY__PORT=9090` or `RUSTY_PROXY_LOGGING__MODULES__PROXY=debug`.
+    /// Values are parsed as TOML, falling back to a plain string.
+    fn apply_env_overrides(self) -> Result<Self> {
+        let mut vars: Vec<(String, String)> = std::env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.contains("__"))
+            .collect();
+        if vars.is_empty() {
+            return Ok(self);
+        }
+        vars.sort();
+
+        let mut tree = toml::Value::try_from(&self)?;
+        for (name, raw) in vars {
+            let segments: Vec<&str> = name[ENV_PREFIX.len()..].split("__").collect();
+            set_env_value(&mut tree, &segments, &raw).map_err(|e| anyhow!("{}: {}", name, e))?;
+        }
+        tree.try_into().map_err(|e| anyhow!("Invalid environment override: {}", e))
+    }
+
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let content = toml::to_string_pretty(self)?;
         fs::write(path, content)?;
@@ -124,4 +1413,86 @@ impl Config {
 
         self.security.whitelist_ips.contains(&ip.to_string())
     }
-}
\ No newline at end of file
+}
+
+const ENV_PREFIX: &str = "RUSTY_PROXY_";
+
+fn set_env_value(tree: &mut toml::Value, segments: &[&str], raw: &str) -> Result<()> {
+    let table = tree.as_table_mut().ok_or_else(|| anyhow!("not a config section"))?;
+    // Env names cannot contain '-', so RUSTY_PROXY_FEATURES__OPEN_PORTS matches `open-ports`
+    let key = table
+        .keys()
+        .find(|k| k.replace('-', "_").eq_ignore_ascii_case(segments[0]))
+        .cloned()
+        .unwrap_or_else(|| segments[0].to_lowercase());
+
+    if segments.len() > 1 {
+        let child = table
+            .entry(key)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        return set_env_value(child, &segments[1..], raw);
+    }
+
+    let value = match table.get(&key) {
+        Some(toml::Value::String(_)) => toml::Value::String(raw.to_string()),
+        _ => toml::from_str::<toml::Table>(&format!("v = {}", raw))
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or_else(|| toml::Value::String(raw.to_string())),
+    };
+    table.insert(key, value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domain_entries_cover_subdomains() {
+        for pattern in ["example.com", "*.example.com"] {
+            assert!(domain_covers(pattern, "example.com"), "{}", pattern);
+            assert!(domain_covers(pattern, "api.example.com"), "{}", pattern);
+            assert!(domain_covers(pattern, "a.b.example.com"), "{}", pattern);
+            assert!(!domain_covers(pattern, "badexample.com"), "{}", pattern);
+            assert!(!domain_covers(pattern, "example.com.evil"), "{}", pattern);
+        }
+        assert!(domain_covers("*", "anything.test"));
+        assert!(!domain_covers("api.example.com", "example.com"));
+    }
+
+    #[test]
+    fn tunnel_policy_accepts_globs() {
+        let policy = TunnelPolicy {
+            block: vec!["*.tracker.example".to_string()],
+            throttle: HashMap::from([("*.cdn.example".to_string(), 1024)]),
+            ..Default::default()
+        };
+        assert!(!policy.permits("ads.tracker.example"));
+        assert!(!policy.permits("Tracker.Example"));
+        assert!(policy.permits("shop.example"));
+        assert_eq!(policy.throttle("img.cdn.example"), Some(1024));
+        assert_eq!(policy.throttle("cdn.example.org"), None);
+
+        let allowed = TunnelPolicy { allow: vec!["*.example.com".to_string()], ..Default::default() };
+        assert!(allowed.permits("api.example.com"));
+        assert!(!allowed.permits("example.org"));
+    }
+
+    #[test]
+    fn proxy_headers_go_only_to_listed_upstreams() {
+        let mut proxy_protocol = ProxyProtocolConfig::default();
+        assert!(!proxy_protocol.sends_to("backend.internal"));
+        proxy_protocol.send_to = vec!["*.internal".to_string(), "10.0.0.5".to_string()];
+        assert!(proxy_protocol.sends_to("api.backend.internal"));
+        assert!(proxy_protocol.sends_to("10.0.0.5"));
+        assert!(!proxy_protocol.sends_to("example.com"));
+    }
+
+    #[test]
+    fn retry_accepts_globs() {
+        let retry = RetryConfig { domains: vec!["*.example.com".to_string()], ..Default::default() };
+        assert!(retry.applies_to("api.example.com"));
+        assert!(!retry.applies_to("example.org"));
+    }
+}
@@ -0,0 +1,428 @@
+use hyper::body::Bytes;
+use hyper::header::HeaderMap;
+use hyper::{Method, Response};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::body::{self, Body};
+use crate::config::ConformanceConfig;
+use crate::context::RequestContext;
+use crate::openapi::{self, Server, Spec};
+use crate::pattern;
+use crate::streaming::{self, ChunkRewriter};
+
+/// Violations kept per endpoint for the admin API; older ones are only counted.
+const RECENT_LIMIT: usize = 20;
+
+/// Endpoint requests outside the spec are counted under.
+const UNDOCUMENTED: &str = "undocumented";
+
+/// Live traffic checked against an OpenAPI spec: each exchange with one of its servers
+/// is matched to an operation, and the request's parameters and the response's status,
+/// media type and JSON body are checked against what the spec documents. Findings are
+/// logged, counted per endpoint for the metrics, and kept for `GET /admin/openapi`.
+pub struct Conformance {
+    spec: Spec,
+    servers: Vec<Server>,
+    routes: Vec<Route>,
+    max_body: usize,
+    endpoints: Mutex<BTreeMap<String, Endpoint>>,
+}
+
+/// One operation, ready to be matched against requests.
+struct Route {
+    /// `GET /pets/{petId}`, as endpoints are reported
+    key: String,
+    method: String,
+    path: Regex,
+    /// Path parameters, so literal paths win over templates that also match
+    params: usize,
+    operation: Value,
+    parameters: Vec<Value>,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    exchanges: u64,
+    violating: u64,
+    by_kind: BTreeMap<&'static str, u64>,
+    recent: VecDeque<Violation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Violation {
+    /// Unix seconds
+    at: u64,
+    method: String,
+    url: String,
+    status: u16,
+    kind: &'static str,
+    message: String,
+}
+
+/// What one exchange is found to break, gathered until its body (if checked) has passed.
+struct Findings {
+    key: String,
+    method: String,
+    url: String,
+    status: u16,
+    problems: Vec<(&'static str, String)>,
+}
+
+impl Conformance {
+    /// `None` when no spec is configured or it can't be read.
+    pub fn open(config: &ConformanceConfig) -> Option<Arc<Conformance>> {
+        let path = config.spec.as_deref().filter(|path| !path.is_empty())?;
+        let spec = match std::fs::read_to_string(Path::new(path)).map_err(anyhow::Error::from).and_then(|text| Spec::parse(&text)) {
+            Ok(spec) => spec,
+            Err(e) => {
+                warn!("Not validating traffic against {}: {}", path, e);
+                return None;
+            }
+        };
+        let mut routes: Vec<Route> = spec
+            .operations()
+            .iter()
+            .filter_map(|operation| {
+                Some(Route {
+                    key: format!("{} {}", operation.method, operation.path),
+                    method: operation.method.clone(),
+                    path: pattern::compile(&format!("^{}/?$", openapi::path_regex(operation.path))).ok()?,
+                    params: operation.path.matches('{').count(),
+                    operation: operation.operation.clone(),
+                    parameters: spec.parameters(operation).into_iter().cloned().collect(),
+                })
+            })
+            .collect();
+        routes.sort_by_key(|route| route.params);
+        let mut servers = spec.servers();
+        if servers.is_empty() {
+            // Without `servers` the API is at `/` of any host
+            servers.push(Server {
+                host: String::new(),
+                base_path: String::new(),
+            });
+        }
+        let endpoints = routes.iter().map(|route| (route.key.clone(), Endpoint::default())).collect();
+        info!("Validating traffic against {} ({} operations)", spec.title(), routes.len());
+        Some(Arc::new(Conformance {
+            servers,
+            routes,
+            max_body: config.max_body,
+            endpoints: Mutex::new(endpoints),
+            spec,
+        }))
+    }
+
+    /// Checks an upstream response and the request it answers, before any script has
+    /// changed it. The body, when it has a schema to meet, is checked once it has passed.
+    pub fn observe(self: &Arc<Self>, ctx: &RequestContext, res: Response<Body>) -> Response<Body> {
+        let path = ctx.url.path();
+        let mut explicit = false;
+        let mut relative_paths = Vec::new();
+        for server in &self.servers {
+            if !server.host.is_empty() && !server.host.eq_ignore_ascii_case(&ctx.domain) {
+                continue;
+            }
+            if let Some(rest) = path.strip_prefix(server.base_path.as_str()).filter(|rest| rest.is_empty() || rest.starts_with('/')) {
+                relative_paths.push(if rest.is_empty() { "/" } else { rest });
+                explicit |= !server.host.is_empty() || !server.base_path.is_empty();
+            }
+        }
+        if relative_paths.is_empty() {
+            return res;
+        }
+        let method = ctx.method.as_str();
+        let on_path: Vec<&Route> = self
+            .routes
+            .iter()
+            .filter(|route| relative_paths.iter().any(|path| route.path.is_match(path)))
+            .collect();
+        let status = res.status().as_u16();
+        let Some(route) = on_path.iter().find(|route| route.method == method) else {
+            // Traffic to a host of its own that the spec doesn't describe is a finding;
+            // on a shared host it's just other traffic
+            if explicit {
+                let (key, message) = match on_path.first() {
+                    Some(route) => (route.key.replacen(&route.method, method, 1), format!("{} is not documented for this path", method)),
+                    None => (UNDOCUMENTED.to_string(), format!("{} {} is not in the spec", method, path)),
+                };
+                let mut findings = Findings::new(key, ctx, status);
+                findings.problems.push((UNDOCUMENTED, message));
+                self.record(findings);
+            }
+            return res;
+        };
+
+        let mut findings = Findings::new(route.key.clone(), ctx, status);
+        self.check_request(route, ctx, &mut findings);
+        let schema = self.check_response(route, ctx, res.headers(), status, &mut findings);
+        match schema {
+            Some(schema) => {
+                let (parts, body) = res.into_parts();
+                let validator = BodyValidator {
+                    conformance: self.clone(),
+                    headers: parts.headers.clone(),
+                    schema,
+                    body: Vec::new(),
+                    findings: Some(findings),
+                };
+                Response::from_parts(parts, streaming::rewrite_body(body, validator))
+            }
+            None => {
+                self.record(findings);
+                res
+            }
+        }
+    }
+
+    /// Required query and header parameters, and the request body's presence and media type.
+    fn check_request(&self, route: &Route, ctx: &RequestContext, findings: &mut Findings) {
+        let query: Vec<&str> = ctx
+            .url
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split('=').next().unwrap_or(""))
+            .collect();
+        for parameter in &route.parameters {
+            if parameter.get("required").and_then(Value::as_bool) != Some(true) {
+                continue;
+            }
+            let name = parameter.get("name").and_then(Value::as_str).unwrap_or("");
+            let present = match parameter.get("in").and_then(Value::as_str) {
+                Some("query") => query.contains(&name),
+                Some("header") => ctx.headers.contains_key(name),
+                _ => true,
+            };
+            if !present {
+                let place = parameter.get("in").and_then(Value::as_str).unwrap_or("");
+                findings.problems.push(("parameter", format!("required {} parameter {:?} is missing", place, name)));
+            }
+        }
+
+        let Some(request_body) = route.operation.get("requestBody").map(|body| self.spec.resolve(body)) else {
+            return;
+        };
+        let has_body = ctx.headers.contains_key("transfer-encoding")
+            || ctx.headers.get("content-length").and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok()).is_some_and(|len| len > 0);
+        if !has_body {
+            if request_body.get("required").and_then(Value::as_bool) == Some(true) {
+                findings.problems.push(("request_body", "required request body is missing".to_string()));
+            }
+            return;
+        }
+        if let (Some(content), Some(content_type)) = (request_body.get("content").and_then(Value::as_object), header(&ctx.headers, "content-type")) {
+            if !content.keys().any(|documented| media_matches(documented, content_type)) {
+                findings.problems.push(("request_body", format!("request media type {} is not documented", content_type)));
+            }
+        }
+    }
+
+    /// Checks the status and media type; returns the schema the body must meet, if any.
+    fn check_response(&self, route: &Route, ctx: &RequestContext, headers: &HeaderMap, status: u16, findings: &mut Findings) -> Option<Value> {
+        let responses = route.operation.get("responses").and_then(Value::as_object)?;
+        let code = status.to_string();
+        let class = format!("{}XX", status / 100);
+        let Some(response) = responses
+            .get(&code)
+            .or_else(|| responses.iter().find(|(documented, _)| documented.eq_ignore_ascii_case(&class)).map(|(_, response)| response))
+            .or_else(|| responses.get("default"))
+        else {
+            let mut documented: Vec<&str> = responses.keys().map(String::as_str).collect();
+            documented.sort();
+            findings.problems.push(("status", format!("status {} is not documented (expected {})", status, documented.join(", "))));
+            return None;
+        };
+        let response = self.spec.resolve(response);
+
+        let empty = ctx.method == Method::HEAD
+            || matches!(status, 204 | 304)
+            || header(headers, "content-length").is_some_and(|len| len.trim() == "0");
+        if empty {
+            return None;
+        }
+        let content_type = header(headers, "content-type");
+        // OpenAPI 3 documents media types under `content`; Swagger 2 a single `schema`
+        let schema = match response.get("content").and_then(Value::as_object) {
+            Some(content) if !content.is_empty() => {
+                let Some(content_type) = content_type else {
+                    findings.problems.push(("content_type", "response has no Content-Type".to_string()));
+                    return None;
+                };
+                let Some((media_type, media)) = content.iter().find(|(documented, _)| media_matches(documented, content_type)) else {
+                    let documented: Vec<&str> = content.keys().map(String::as_str).collect();
+                    findings.problems.push(("content_type", format!("media type {} is not documented (expected {})", content_type, documented.join(", "))));
+                    return None;
+                };
+                openapi::is_json(media_type).then(|| media.get("schema").cloned()).flatten()
+            }
+            Some(_) => None,
+            None => response.get("schema").filter(|_| content_type.is_some_and(openapi::is_json)).cloned(),
+        };
+        schema.filter(|_| content_type.is_some_and(openapi::is_json))
+    }
+
+    fn record(&self, findings: Findings) {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let endpoint = endpoints.entry(findings.key.clone()).or_default();
+        endpoint.exchanges += 1;
+        if findings.problems.is_empty() {
+            return;
+        }
+        endpoint.violating += 1;
+        for (kind, message) in findings.problems {
+            warn!("OpenAPI: {} {} ({}) {}: {}", findings.method, findings.url, findings.key, kind, message);
+            *endpoint.by_kind.entry(kind).or_default() += 1;
+            if endpoint.recent.len() == RECENT_LIMIT {
+                endpoint.recent.pop_front();
+            }
+            endpoint.recent.push_back(Violation {
+                at,
+                method: findings.method.clone(),
+                url: findings.url.clone(),
+                status: findings.status,
+                kind,
+                message,
+            });
+        }
+    }
+
+    /// `GET /admin/openapi`: exchanges and violations per endpoint, every documented one
+    /// included, so untested operations show up too.
+    pub fn report(&self) -> Value {
+        let endpoints = self.endpoints.lock().unwrap();
+        let endpoints: Vec<Value> = endpoints
+            .iter()
+            .map(|(key, endpoint)| {
+                json!({
+                    "endpoint": key,
+                    "exchanges": endpoint.exchanges,
+                    "violating": endpoint.violating,
+                    "violations": endpoint.by_kind,
+                    "recent": endpoint.recent,
+                })
+            })
+            .collect();
+        json!({ "spec": self.spec.title(), "endpoints": endpoints })
+    }
+
+    /// Starts counting again from nothing.
+    pub fn reset(&self) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        *endpoints = self.routes.iter().map(|route| (route.key.clone(), Endpoint::default())).collect();
+    }
+
+    /// Per-endpoint counters in Prometheus text format, appended to `/admin/metrics`.
+    pub fn render_metrics(&self) -> String {
+        let endpoints = self.endpoints.lock().unwrap();
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP rusty_proxy_openapi_exchanges_total Exchanges checked against the OpenAPI spec, by endpoint");
+        let _ = writeln!(out, "# TYPE rusty_proxy_openapi_exchanges_total counter");
+        for (key, endpoint) in endpoints.iter() {
+            let _ = writeln!(out, "rusty_proxy_openapi_exchanges_total{{endpoint=\"{}\"}} {}", label(key), endpoint.exchanges);
+        }
+        let _ = writeln!(out, "# HELP rusty_proxy_openapi_violations_total Violations of the OpenAPI spec, by endpoint and kind");
+        let _ = writeln!(out, "# TYPE rusty_proxy_openapi_violations_total counter");
+        for (key, endpoint) in endpoints.iter() {
+            for (kind, count) in &endpoint.by_kind {
+                let _ = writeln!(out, "rusty_proxy_openapi_violations_total{{endpoint=\"{}\",kind=\"{}\"}} {}", label(key), kind, count);
+            }
+        }
+        out
+    }
+}
+
+impl Findings {
+    fn new(key: String, ctx: &RequestContext, status: u16) -> Self {
+        Findings {
+            key,
+            method: ctx.method.to_string(),
+            url: ctx.url.to_string(),
+            status,
+            problems: Vec::new(),
+        }
+    }
+}
+
+/// Collects a JSON body as it passes, then validates it against the response schema.
+struct BodyValidator {
+    conformance: Arc<Conformance>,
+    headers: HeaderMap,
+    schema: Value,
+    body: Vec<u8>,
+    /// Taken when recorded
+    findings: Option<Findings>,
+}
+
+impl BodyValidator {
+    fn complete(&mut self, finished: bool) {
+        let Some(mut findings) = self.findings.take() else {
+            return;
+        };
+        // A body the client stopped reading, or too large to hold, is left unchecked
+        if finished && self.body.len() <= self.conformance.max_body {
+            match body::decode(&self.headers, &self.body, self.conformance.max_body).map(|decoded| serde_json::from_slice::<Value>(&decoded)) {
+                Some(Ok(value)) => {
+                    let errors = self.conformance.spec.validate(&self.schema, &value);
+                    if !errors.is_empty() {
+                        findings.problems.push(("schema", errors.join("; ")));
+                    }
+                }
+                Some(Err(e)) => findings.problems.push(("schema", format!("body is not valid JSON: {}", e))),
+                None => {}
+            }
+        }
+        self.conformance.record(findings);
+    }
+}
+
+impl ChunkRewriter for BodyValidator {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        // One byte past the limit marks the body as too large
+        let room = (self.conformance.max_body + 1).saturating_sub(self.body.len());
+        self.body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        Bytes::copy_from_slice(chunk)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.complete(true);
+        Bytes::new()
+    }
+}
+
+impl Drop for BodyValidator {
+    fn drop(&mut self) {
+        self.complete(false);
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Whether a documented media type (`application/json`, `image/*`, `*/*`) covers an
+/// actual `Content-Type`, parameters aside.
+fn media_matches(documented: &str, actual: &str) -> bool {
+    let essence = |media: &str| media.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let (documented, actual) = (essence(documented), essence(actual));
+    match documented.split_once('/') {
+        Some(("*", "*")) => true,
+        Some((kind, "*")) => actual.split('/').next() == Some(kind),
+        _ => documented == actual,
+    }
+}
+
+/// A Prometheus label value, escaped.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
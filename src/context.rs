@@ -0,0 +1,113 @@
+use hyper::header::HeaderMap;
+use hyper::{Method, Request, Uri};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::body::Body;
+use crate::proxy::ClientConnection;
+use crate::script_manager::ScriptManager;
+use crate::trace::Traced;
+
+/// Cookie the proxy sets to recognise a browser session for `once_per = "session"` scripts.
+pub const SESSION_COOKIE: &str = "rusty_proxy_session";
+
+/// Everything known about a proxied request, built once when it arrives and passed
+/// through the request and response injection chain.
+///
+/// The headers are the ones the client sent, before any script touched them, so later
+/// stages can tell injected values from original ones.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub method: Method,
+    pub url: Uri,
+    pub domain: String,
+    pub headers: HeaderMap,
+    pub client_ip: IpAddr,
+    /// Client connection the request arrived on, and its position on that connection
+    /// (1 for a fresh connection, higher when the connection is reused)
+    pub connection_id: u64,
+    pub connection_request: u64,
+    /// Names of the enabled scripts targeting this request's domain (and URL, for scripts
+    /// with a `url_pattern`)
+    pub matched_scripts: Vec<String>,
+    /// From the session cookie, or freshly generated when the client sent none
+    pub session_id: String,
+    pub new_session: bool,
+    /// Set when a `rusty-proxy trace` covers the URL
+    pub trace: Option<Arc<Traced>>,
+    /// When the proxy built the context, as the request arrived
+    pub started: Instant,
+}
+
+impl RequestContext {
+    pub fn new(req: &Request<Body>, conn: &ClientConnection, connection_request: u64, scripts: &ScriptManager) -> Self {
+        let domain = req.uri().host().unwrap_or("unknown").to_string();
+        let matched_scripts = scripts
+            .scripts_for_url(&domain, req.uri())
+            .into_iter()
+            .map(|script| script.name.clone())
+            .collect();
+
+        let existing = session_from_cookies(req.headers());
+        let new_session = existing.is_none();
+        let session_id = existing.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+
+        RequestContext {
+            method: req.method().clone(),
+            url: req.uri().clone(),
+            domain,
+            headers: req.headers().clone(),
+            client_ip: conn.addr.ip(),
+            connection_id: conn.id,
+            connection_request,
+            matched_scripts,
+            session_id,
+            new_session,
+            trace: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// A context for a request from localhost with no session, for tests.
+    #[cfg(test)]
+    pub fn for_request(method: Method, url: &str) -> Self {
+        let url: Uri = url.parse().unwrap();
+        RequestContext {
+            method,
+            domain: url.host().unwrap_or("unknown").to_string(),
+            url,
+            headers: HeaderMap::new(),
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            connection_id: 1,
+            connection_request: 1,
+            matched_scripts: vec![],
+            session_id: String::new(),
+            new_session: false,
+            trace: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Time since the request arrived.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// `Set-Cookie` value that starts the session on the client.
+    pub fn session_cookie(&self) -> String {
+        format!("{}={}; Path=/; HttpOnly; SameSite=Lax", SESSION_COOKIE, self.session_id)
+    }
+}
+
+fn session_from_cookies(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            (name == SESSION_COOKIE && !value.is_empty()).then(|| value.to_string())
+        })
+}
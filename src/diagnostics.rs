@@ -0,0 +1,98 @@
+use anyhow::Result;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+
+use crate::proxy::ProxyState;
+
+/// Point-in-time view of a running instance, for debugging stuck processes.
+pub fn snapshot(state: &ProxyState) -> Value {
+    let metrics = &state.metrics;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    json!({
+        "pid": std::process::id(),
+        "timestamp": timestamp,
+        "version": env!("CARGO_PKG_VERSION"),
+        "config_hash": config_hash(state),
+        "connections": {
+            "active": metrics.active_connections.load(Ordering::Relaxed),
+            "requests_total": metrics.requests_total.load(Ordering::Relaxed),
+            "reused_connection_requests": metrics.reused_connection_requests.load(Ordering::Relaxed),
+        },
+        "upstream_pool": {
+            "idle_timeout_secs": state.config.proxy.pool_idle_timeout,
+            "max_idle_per_host": state.config.proxy.pool_max_idle_per_host,
+            "requests": metrics.upstream_requests.load(Ordering::Relaxed),
+            "connections_opened": metrics.upstream_connections_opened.load(Ordering::Relaxed),
+            "hit_ratio": metrics.pool_hit_ratio(),
+            "flushes": metrics.pool_flushes.load(Ordering::Relaxed),
+        },
+        "scripts": state.injector.script_manager().script_stats(),
+        "memory": memory_stats(),
+    })
+}
+
+fn config_hash(state: &ProxyState) -> String {
+    let serialized = toml::to_string(&state.config).unwrap_or_default();
+    Sha256::digest(serialized.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Resident and peak memory from `/proc/self/status`, in kilobytes.
+fn memory_stats() -> Value {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let field = |name: &str| -> Option<u64> {
+        status
+            .lines()
+            .find(|line| line.starts_with(name))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|v| v.parse().ok())
+    };
+    json!({
+        "rss_kb": field("VmRSS:"),
+        "peak_rss_kb": field("VmHWM:"),
+        "virtual_kb": field("VmSize:"),
+        "threads": field("Threads:"),
+    })
+}
+
+pub fn write_dump(state: &ProxyState) -> Result<PathBuf> {
+    let dir = state
+        .config
+        .diagnostics
+        .dump_dir
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    fs::create_dir_all(&dir)?;
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let path = dir.join(format!("rusty-proxy-dump-{}-{}.json", std::process::id(), timestamp));
+    fs::write(&path, serde_json::to_string_pretty(&snapshot(state))?)?;
+    Ok(path)
+}
+
+/// Writes a dump every time the process receives SIGUSR1.
+pub fn spawn_signal_handler(state: Arc<ProxyState>) {
+    tokio::spawn(async move {
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to install SIGUSR1 handler: {}", e);
+                return;
+            }
+        };
+        while usr1.recv().await.is_some() {
+            match write_dump(&state) {
+                Ok(path) => info!("Wrote diagnostics dump to {:?}", path),
+                Err(e) => error!("Failed to write diagnostics dump: {}", e),
+            }
+        }
+    });
+}
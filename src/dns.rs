@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use hyper::{Method, Request, Response, StatusCode};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::{debug, info, warn};
+
+use crate::body::{self, Body};
+use crate::config::{Config, DnsConfig};
+use crate::proxy::ProxyState;
+
+/// Requests to this path are sent straight to the proxy, like admin requests.
+const DOH_PATH: &str = "/dns-query";
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Largest query accepted from a client; real queries are a few hundred bytes.
+const QUERY_LIMIT: usize = 4096;
+
+const HEADER_LEN: usize = 12;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+
+pub fn is_dns_request(req: &Request<Body>, config: &Config) -> bool {
+    config.dns.enabled && req.uri().authority().is_none() && req.uri().path() == DOH_PATH
+}
+
+/// Answers a DNS-over-HTTPS query (`GET ?dns=<base64url>` or `POST` with a
+/// `application/dns-message` body). Names the tunnel policy refuses get NXDOMAIN; the
+/// rest are forwarded as they are to the resolver.
+pub async fn handle(req: Request<Body>, client_addr: SocketAddr, state: &ProxyState) -> Response<Body> {
+    if req.method() != Method::GET && req.method() != Method::POST {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    }
+    let query = match read_query(req).await {
+        Ok(query) => query,
+        Err(message) => return error_response(StatusCode::BAD_REQUEST, message),
+    };
+    let name = match question_name(&query) {
+        Some((name, _)) => name,
+        None => return error_response(StatusCode::BAD_REQUEST, "malformed DNS query"),
+    };
+
+    if !state.config.security.tunnels.permits(&name) {
+        info!("Blocked DNS query for {} from {}", name, client_addr.ip());
+        return dns_response(failure(&query, RCODE_NXDOMAIN));
+    }
+
+    match resolve(&query, &state.config.dns).await {
+        Ok(answer) => {
+            debug!("Resolved {} for {} ({} bytes)", name, client_addr.ip(), answer.len());
+            dns_response(answer)
+        }
+        Err(e) => {
+            warn!("Failed to resolve {} for {}: {}", name, client_addr.ip(), e);
+            dns_response(failure(&query, RCODE_SERVFAIL))
+        }
+    }
+}
+
+async fn read_query(req: Request<Body>) -> Result<Vec<u8>, &'static str> {
+    let query = if req.method() == Method::GET {
+        let encoded = req
+            .uri()
+            .query()
+            .and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("dns=")))
+            .ok_or("missing dns parameter")?;
+        base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .map_err(|_| "dns parameter is not base64url")?
+    } else {
+        let content_type = req.headers().get("content-type").and_then(|v| v.to_str().ok()).unwrap_or("");
+        if !content_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+            return Err("content type must be application/dns-message");
+        }
+        body::to_bytes(req.into_body()).await.map_err(|_| "failed to read body")?.to_vec()
+    };
+    if query.len() > QUERY_LIMIT {
+        return Err("query too large");
+    }
+    Ok(query)
+}
+
+/// The first question's name, lowercased, and the offset just past the question.
+fn question_name(message: &[u8]) -> Option<(String, usize)> {
+    if message.len() < HEADER_LEN || u16::from_be_bytes([message[4], message[5]]) == 0 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *message.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Queries carry their name uncompressed, and labels are at most 63 bytes
+        if len > 63 {
+            return None;
+        }
+        labels.push(std::str::from_utf8(message.get(pos..pos + len)?).ok()?.to_ascii_lowercase());
+        pos += len;
+    }
+    let end = pos + 4; // QTYPE, QCLASS
+    (end <= message.len()).then(|| (labels.join("."), end))
+}
+
+/// An answer to `query` carrying only its question and `rcode`.
+fn failure(query: &[u8], rcode: u8) -> Vec<u8> {
+    let end = question_name(query).map(|(_, end)| end).unwrap_or(HEADER_LEN);
+    let mut answer = query[..end].to_vec();
+    answer[2] = 0x80 | (query[2] & 0x79); // QR, keeping opcode and RD
+    answer[3] = 0x80 | rcode; // RA
+    answer[4..6].copy_from_slice(&1u16.to_be_bytes());
+    answer[6..12].fill(0);
+    answer
+}
+
+pub fn resolver(config: &DnsConfig) -> Result<String> {
+    if let Some(upstream) = config.upstream.as_deref().filter(|u| !u.is_empty()) {
+        return Ok(upstream.to_string());
+    }
+    let resolv = std::fs::read_to_string("/etc/resolv.conf")?;
+    let nameserver = resolv
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("nameserver"))
+        .map(str::trim)
+        .ok_or_else(|| anyhow!("no nameserver in /etc/resolv.conf"))?;
+    Ok(match nameserver.parse::<std::net::IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 53).to_string(),
+        Err(_) => format!("{}:53", nameserver),
+    })
+}
+
+/// Sends the query to the resolver over UDP, retrying over TCP when the answer was
+/// truncated.
+async fn resolve(query: &[u8], config: &DnsConfig) -> Result<Vec<u8>> {
+    let resolver = resolver(config)?;
+    let addr = tokio::net::lookup_host(&resolver)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("resolver {} has no address", resolver))?;
+    let timeout = Duration::from_secs(config.timeout);
+
+    let answer = tokio::time::timeout(timeout, async {
+        let local: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+        socket.send(query).await?;
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            // Stray datagrams for other queries are skipped
+            if len >= HEADER_LEN && buf[..2] == query[..2] {
+                buf.truncate(len);
+                return Ok::<_, std::io::Error>(buf);
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("resolver {} timed out", resolver))??;
+
+    if answer[2] & 0x02 == 0 {
+        return Ok(answer);
+    }
+    debug!("Answer from {} was truncated, retrying over TCP", resolver);
+    tokio::time::timeout(timeout, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&(query.len() as u16).to_be_bytes()).await?;
+        stream.write_all(query).await?;
+        let len = stream.read_u16().await? as usize;
+        let mut answer = vec![0u8; len];
+        stream.read_exact(&mut answer).await?;
+        Ok::<_, std::io::Error>(answer)
+    })
+    .await
+    .map_err(|_| anyhow!("resolver {} timed out", resolver))?
+    .map_err(Into::into)
+}
+
+fn dns_response(message: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .header("content-type", DNS_MESSAGE)
+        .header("content-length", message.len())
+        .header("cache-control", "no-store")
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain")
+        .body(Body::from(message))
+        .unwrap()
+}
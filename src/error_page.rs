@@ -0,0 +1,137 @@
+use hyper::header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use hyper::{Method, Response, Uri};
+use regex::Regex;
+use std::sync::OnceLock;
+use std::net::IpAddr;
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+use crate::body::Body;
+use crate::config::ErrorPageRule;
+
+/// Marks a response the proxy made up itself, an error or a refusal, with what went
+/// wrong. Only these are replaced by `[[error_pages]]`; errors relayed from upstream
+/// reach the client as the upstream sent them.
+#[derive(Clone)]
+pub struct ProxyError(pub String);
+
+/// Marks `res` as the proxy's own answer to a failure described by `error`.
+pub fn mark(mut res: Response<Body>, error: impl Into<String>) -> Response<Body> {
+    res.extensions_mut().insert(ProxyError(error.into()));
+    res
+}
+
+/// `[[error_pages]]`: the pages that stand in for the proxy's own error responses.
+pub struct ErrorPages {
+    pages: Vec<Page>,
+}
+
+struct Page {
+    config: ErrorPageRule,
+    /// `body`, or what `body_file` holds
+    body: Option<String>,
+}
+
+/// What a page's placeholders are filled in from.
+pub struct Failed<'a> {
+    pub method: &'a Method,
+    pub url: &'a Uri,
+    pub domain: &'a str,
+    pub client: IpAddr,
+}
+
+impl ErrorPages {
+    pub fn new(configs: &[ErrorPageRule]) -> Self {
+        let pages = configs
+            .iter()
+            .filter_map(|config| {
+                let body = match config.body_file.as_deref().filter(|path| !path.is_empty()) {
+                    Some(path) => match std::fs::read_to_string(path) {
+                        Ok(body) => Some(body),
+                        Err(e) => {
+                            warn!("error_pages: cannot read {} for {:?}, ignoring the page: {}", path, config.domains, e);
+                            return None;
+                        }
+                    },
+                    None => config.body.clone(),
+                };
+                Some(Page { config: config.clone(), body })
+            })
+            .collect();
+        ErrorPages { pages }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Replaces the body of a marked response, and adds headers, from the first page for
+    /// its domain, client and status. Other responses pass through.
+    pub fn apply(&self, failed: &Failed, mut res: Response<Body>) -> Response<Body> {
+        let Some(ProxyError(error)) = res.extensions().get::<ProxyError>().cloned() else {
+            return res;
+        };
+        let status = res.status();
+        let Some(page) = self.pages.iter().find(|page| page.config.applies_to(failed.domain, failed.client, status.as_u16())) else {
+            return res;
+        };
+        let fields = [
+            ("status", status.as_u16().to_string()),
+            ("reason", status.canonical_reason().unwrap_or("").to_string()),
+            ("error", error),
+            ("method", failed.method.to_string()),
+            ("url", failed.url.to_string()),
+            ("domain", failed.domain.to_string()),
+            ("client", failed.client.to_string()),
+            ("time", httpdate::fmt_http_date(SystemTime::now())),
+        ];
+        debug!("Answering {} {} with the error page for {:?}", failed.method, failed.url, page.config.domains);
+
+        for (name, value) in &page.config.headers {
+            let value = fill(value, &fields, false);
+            match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+                (Ok(name), Ok(value)) => {
+                    res.headers_mut().insert(name, value);
+                }
+                _ => debug!("error_pages: header {}: {:?} is not a valid header, leaving it out", name, value),
+            }
+        }
+        if let Some(template) = &page.body {
+            let html = page.config.content_type.to_ascii_lowercase().contains("html");
+            let body = fill(template, &fields, html);
+            let headers = res.headers_mut();
+            headers.remove(CONTENT_ENCODING);
+            headers.remove(ETAG);
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+            match HeaderValue::from_str(&page.config.content_type) {
+                Ok(content_type) => {
+                    headers.insert(CONTENT_TYPE, content_type);
+                }
+                Err(_) => {
+                    headers.remove(CONTENT_TYPE);
+                }
+            }
+            *res.body_mut() = if failed.method == Method::HEAD { Body::empty() } else { Body::from(body) };
+        }
+        res
+    }
+}
+
+/// Fills the `{{name}}` placeholders of `template`, escaping the values for HTML when
+/// `html` is set. Unknown names are left in place.
+fn fill(template: &str, fields: &[(&str, String)], html: bool) -> String {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let placeholder = PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{([a-z]+)\}\}").unwrap());
+    let filled = placeholder.replace_all(template, |captures: &regex::Captures| {
+        match fields.iter().find(|(name, _)| *name == &captures[1]) {
+            Some((_, value)) if html => escape(value),
+            Some((_, value)) => value.clone(),
+            None => captures[0].to_string(),
+        }
+    });
+    filled.into_owned()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
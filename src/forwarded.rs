@@ -0,0 +1,109 @@
+use hyper::header::{HeaderMap, HeaderValue};
+use std::net::IpAddr;
+use tracing::debug;
+
+use crate::config::{ForwardedConfig, ForwardedMode};
+
+const FORWARDING_HEADERS: &[&str] = &[
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-real-ip",
+];
+
+/// Adds, strips or passes through forwarding headers on a request bound for the upstream.
+///
+/// Existing forwarding headers are only kept when the connecting peer is a trusted proxy;
+/// from anyone else they are discarded before our own entry is added, so clients cannot
+/// spoof their position in the chain.
+pub fn apply(headers: &mut HeaderMap, client_ip: IpAddr, proto: &str, config: &ForwardedConfig) {
+    match config.mode {
+        ForwardedMode::Passthrough => {}
+        ForwardedMode::Strip => {
+            for name in FORWARDING_HEADERS {
+                headers.remove(*name);
+            }
+            headers.remove("via");
+        }
+        ForwardedMode::Append => {
+            if !address_matches(client_ip, &config.trusted_proxies) {
+                debug!("Discarding forwarding headers from untrusted peer {}", client_ip);
+                for name in FORWARDING_HEADERS {
+                    headers.remove(*name);
+                }
+            }
+
+            let host = headers
+                .get("host")
+                .and_then(|v| v.to_str().ok())
+                .map(|h| h.to_string());
+
+            if config.x_forwarded {
+                append_list(headers, "x-forwarded-for", &client_ip.to_string());
+                if !headers.contains_key("x-forwarded-proto") {
+                    if let Ok(value) = HeaderValue::from_str(proto) {
+                        headers.insert("x-forwarded-proto", value);
+                    }
+                }
+                if let Some(host) = &host {
+                    if !headers.contains_key("x-forwarded-host") {
+                        if let Ok(value) = HeaderValue::from_str(host) {
+                            headers.insert("x-forwarded-host", value);
+                        }
+                    }
+                }
+            }
+
+            if config.forwarded {
+                let node = match client_ip {
+                    IpAddr::V4(ip) => ip.to_string(),
+                    IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+                };
+                let mut element = format!("for={};proto={}", node, proto);
+                if let Some(host) = &host {
+                    element.push_str(&format!(";host=\"{}\"", host));
+                }
+                append_list(headers, "forwarded", &element);
+            }
+        }
+    }
+}
+
+fn append_list(headers: &mut HeaderMap, name: &'static str, entry: &str) {
+    let combined = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .chain(std::iter::once(entry))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&combined) {
+        headers.insert(name, value);
+    }
+}
+
+/// Matches an address against entries that are either plain addresses or CIDR ranges.
+pub fn address_matches(ip: IpAddr, entries: &[String]) -> bool {
+    entries.iter().any(|entry| match entry.split_once('/') {
+        Some((network, bits)) => match (network.parse::<IpAddr>(), bits.parse::<u32>()) {
+            (Ok(network), Ok(bits)) => cidr_contains(network, bits, ip),
+            _ => false,
+        },
+        None => entry.parse::<IpAddr>().map(|t| t == ip).unwrap_or(false),
+    })
+}
+
+fn cidr_contains(network: IpAddr, bits: u32, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) if bits <= 32 => {
+            let mask = if bits == 0 { 0 } else { u32::MAX << (32 - bits) };
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) if bits <= 128 => {
+            let mask = if bits == 0 { 0 } else { u128::MAX << (128 - bits) };
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
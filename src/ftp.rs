@@ -0,0 +1,302 @@
+use anyhow::{anyhow, Result};
+use futures_util::stream;
+use hyper::body::Bytes;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, info};
+
+use crate::assets;
+use crate::body::Body;
+
+const DEFAULT_PORT: u16 = 21;
+
+/// Chunk size read from the data connection while a file is streamed to the client.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+pub fn is_ftp_request(req: &Request<Body>) -> bool {
+    req.uri().scheme_str() == Some("ftp")
+}
+
+/// Answers a `GET`/`HEAD` for an `ftp://` URL the way browsers used to: files are streamed
+/// back, directories rendered as an HTML index. Only passive mode is used, logging in as
+/// the URL's user or anonymously.
+pub async fn fetch(req: Request<Body>, timeout: Duration) -> Result<Response<Body>> {
+    let uri = req.uri().clone();
+    tokio::time::timeout(timeout, respond(req))
+        .await
+        .map_err(|_| anyhow!("FTP server for {} timed out", uri))?
+}
+
+async fn respond(req: Request<Body>) -> Result<Response<Body>> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(plain(StatusCode::METHOD_NOT_ALLOWED, "FTP URLs support only GET and HEAD".to_string()));
+    }
+    let uri = req.uri().clone();
+    let head = req.method() == Method::HEAD;
+    let path = assets::percent_decode(uri.path()).ok_or_else(|| anyhow!("invalid FTP path"))?;
+    let path = if path.is_empty() { "/".to_string() } else { path };
+    // A decoded line break would smuggle a second command onto the control connection
+    if path.contains(['\r', '\n']) {
+        return Ok(plain(StatusCode::BAD_REQUEST, "invalid FTP path".to_string()));
+    }
+
+    let mut control = Control::login(&uri).await?;
+
+    // Directories are told from files by whether the server lets us change into them
+    let is_dir = path.ends_with('/') || control.command(&format!("CWD {}", path)).await?.0 == 250;
+    if is_dir && !path.ends_with('/') {
+        let location = format!("{}/", uri.path());
+        return Ok(Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header("location", location)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    if is_dir {
+        let (code, message) = control.command(&format!("CWD {}", path)).await?;
+        if code != 250 {
+            return Ok(not_found(&path, &message));
+        }
+        let listing = if head {
+            String::new()
+        } else {
+            let mut data = control.passive().await?;
+            control.expect_transfer("LIST").await?;
+            let mut listing = Vec::new();
+            data.read_to_end(&mut listing).await?;
+            control.reply().await?;
+            String::from_utf8_lossy(&listing).into_owned()
+        };
+        control.quit().await;
+        let page = render_index(&uri, &path, &listing);
+        return Ok(Response::builder()
+            .header("content-type", "text/html; charset=utf-8")
+            .header("content-length", page.len())
+            .body(if head { Body::empty() } else { Body::from(page) })
+            .unwrap());
+    }
+
+    let size = match control.command(&format!("SIZE {}", path)).await? {
+        (213, size) => size.trim().parse::<u64>().ok(),
+        (550, message) => return Ok(not_found(&path, &message)),
+        _ => None,
+    };
+    let mut builder = Response::builder().header("content-type", assets::mime_type(&path));
+    if let Some(size) = size {
+        builder = builder.header("content-length", size);
+    }
+    if head {
+        control.quit().await;
+        return Ok(builder.body(Body::empty()).unwrap());
+    }
+
+    let data = control.passive().await?;
+    if let Err(e) = control.expect_transfer(&format!("RETR {}", path)).await {
+        return Ok(not_found(&path, &e.to_string()));
+    }
+    info!("Streaming {} over FTP ({} bytes)", uri, size.map_or("unknown".to_string(), |s| s.to_string()));
+
+    // The control connection stays open until the data connection is drained, then the
+    // transfer is confirmed and the session closed
+    let chunks = stream::unfold(Some((data, control)), |state| async move {
+        let (mut data, mut control) = state?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        match data.read(&mut chunk).await {
+            Ok(0) => {
+                drop(data);
+                if let Err(e) = control.reply().await {
+                    debug!("FTP transfer did not complete cleanly: {}", e);
+                }
+                control.quit().await;
+                None
+            }
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(Bytes::from(chunk)), Some((data, control))))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok(builder.body(Body::wrap_stream(chunks)).unwrap())
+}
+
+/// The FTP control connection.
+struct Control {
+    stream: BufReader<TcpStream>,
+    peer: SocketAddr,
+}
+
+impl Control {
+    async fn login(uri: &Uri) -> Result<Control> {
+        let host = uri.host().ok_or_else(|| anyhow!("FTP URL without a host"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(DEFAULT_PORT);
+        let stream = TcpStream::connect((host, port)).await?;
+        let peer = stream.peer_addr()?;
+        let mut control = Control {
+            stream: BufReader::new(stream),
+            peer,
+        };
+
+        let (code, message) = control.reply().await?;
+        if code != 220 {
+            return Err(anyhow!("FTP server greeted with {} {}", code, message));
+        }
+
+        let (user, password) = credentials(uri);
+        if user.contains(['\r', '\n']) || password.contains(['\r', '\n']) {
+            return Err(anyhow!("invalid FTP credentials"));
+        }
+        let (code, message) = match control.command(&format!("USER {}", user)).await? {
+            (331, _) => control.command(&format!("PASS {}", password)).await?,
+            reply => reply,
+        };
+        if code != 230 {
+            return Err(anyhow!("FTP login as {} failed: {} {}", user, code, message));
+        }
+        control.command("TYPE I").await?;
+        debug!("Logged in to FTP server {} as {}", peer, user);
+        Ok(control)
+    }
+
+    /// Reads one reply, following multi-line (`123-`) replies to their last line.
+    async fn reply(&mut self) -> Result<(u16, String)> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("FTP server closed the control connection"));
+        }
+        let code: u16 = line.get(..3).and_then(|code| code.parse().ok()).ok_or_else(|| anyhow!("malformed FTP reply: {}", line.trim_end()))?;
+        let mut message = line.get(4..).unwrap_or("").trim_end().to_string();
+        if line.as_bytes().get(3) == Some(&b'-') {
+            let last = format!("{} ", code);
+            loop {
+                line.clear();
+                if self.stream.read_line(&mut line).await? == 0 {
+                    return Err(anyhow!("FTP server closed the control connection"));
+                }
+                if let Some(rest) = line.strip_prefix(&last) {
+                    message = rest.trim_end().to_string();
+                    break;
+                }
+            }
+        }
+        Ok((code, message))
+    }
+
+    async fn command(&mut self, command: &str) -> Result<(u16, String)> {
+        self.stream.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+        self.reply().await
+    }
+
+    /// Opens a passive data connection. The address in the `227` reply is ignored except
+    /// for its port: servers behind NAT report unreachable addresses, and honouring it
+    /// would let a server point the proxy at arbitrary hosts.
+    async fn passive(&mut self) -> Result<TcpStream> {
+        let (code, message) = self.command("PASV").await?;
+        if code != 227 {
+            return Err(anyhow!("FTP server refused passive mode: {} {}", code, message));
+        }
+        let numbers: Vec<u16> = message
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|n| !n.is_empty())
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        let port = match numbers.len() {
+            n if n >= 6 => numbers[n - 2] * 256 + numbers[n - 1],
+            _ => return Err(anyhow!("malformed PASV reply: {}", message)),
+        };
+        Ok(TcpStream::connect((self.peer.ip(), port)).await?)
+    }
+
+    /// Starts a transfer, which the server confirms with a 1xx reply.
+    async fn expect_transfer(&mut self, command: &str) -> Result<()> {
+        match self.command(command).await? {
+            (125, _) | (150, _) => Ok(()),
+            (code, message) => Err(anyhow!("{} {}", code, message)),
+        }
+    }
+
+    async fn quit(mut self) {
+        let _ = self.command("QUIT").await;
+    }
+}
+
+/// User and password from the URL, or an anonymous login.
+fn credentials(uri: &Uri) -> (String, String) {
+    let userinfo = uri.authority().and_then(|auth| auth.as_str().rsplit_once('@')).map(|(info, _)| info);
+    match userinfo {
+        Some(info) => {
+            let (user, password) = info.split_once(':').unwrap_or((info, ""));
+            let decode = |s: &str| assets::percent_decode(s).unwrap_or_else(|| s.to_string());
+            (decode(user), decode(password))
+        }
+        None => ("anonymous".to_string(), "rusty-proxy@".to_string()),
+    }
+}
+
+/// An HTML index of a Unix-style `LIST` output, linking each entry.
+fn render_index(uri: &Uri, path: &str, listing: &str) -> String {
+    let mut rows = String::new();
+    if path != "/" {
+        rows.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for line in listing.lines() {
+        // `drwxr-xr-x 2 user group 4096 Jan 1 00:00 name`; other formats are shown as they are
+        let name = nth_field_rest(line, 8);
+        if name.is_empty() {
+            rows.push_str(&format!("<li>{}</li>\n", escape(line)));
+            continue;
+        }
+        let is_dir = line.starts_with('d');
+        let name = if line.starts_with('l') { name.split(" -> ").next().unwrap_or(name) } else { name };
+        if name == "." || name == ".." {
+            continue;
+        }
+        let href = format!("{}{}", escape(&percent_encode(name)), if is_dir { "/" } else { "" });
+        rows.push_str(&format!("<li><a href=\"{}\">{}{}</a></li>\n", href, escape(name), if is_dir { "/" } else { "" }));
+    }
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n{rows}</ul>\n</body>\n</html>\n",
+        title = escape(&format!("{}{}", uri.authority().map(|a| a.host()).unwrap_or(""), path)),
+        rows = rows
+    )
+}
+
+/// The rest of `line` from its `n`th whitespace-separated field on, so names may contain
+/// spaces.
+fn nth_field_rest(line: &str, n: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        rest = rest.find(char::is_whitespace).map(|i| rest[i..].trim_start()).unwrap_or("");
+    }
+    rest
+}
+
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn not_found(path: &str, message: &str) -> Response<Body> {
+    plain(StatusCode::NOT_FOUND, format!("{}: {}", path, message))
+}
+
+fn plain(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(message))
+        .unwrap()
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bundle;
+use crate::capture::warc_date;
+use crate::script_manager::InjectionScript;
+
+/// Directory of the scripts directory holding numbered copies of each script as it was
+/// written, `.history/<name>/<n>.json`; never loaded as scripts.
+pub const HISTORY_DIR: &str = ".history";
+
+/// One recorded version of a script.
+#[derive(Debug, Serialize)]
+pub struct Version {
+    pub number: u32,
+    /// The script's own `version` field, if it parses
+    pub version: Option<String>,
+    pub written: Option<String>,
+    pub size: u64,
+    /// Whether the script file currently holds this version
+    pub current: bool,
+}
+
+/// Writes `content` to `path`, a script file below `scripts_dir`, and records it as the
+/// script's next version, unless it is the same as the last one. A file changed since its
+/// last recorded version (edited by hand, or written before it had a history) gets that
+/// content recorded first, so the edit can be undone.
+pub fn write(scripts_dir: &Path, path: &Path, content: &[u8]) -> Result<()> {
+    let Some(dir) = history_dir_for(scripts_dir, path) else {
+        fs::write(path, content)?;
+        return Ok(());
+    };
+    let mut last = numbers(&dir)?.last().copied();
+    let recorded = |number: Option<u32>, content: &[u8]| number.is_some_and(|number| fs::read(dir.join(format!("{}.json", number))).is_ok_and(|recorded| recorded == content));
+    if let Ok(previous) = fs::read(path) {
+        if previous != content && !recorded(last, &previous) {
+            let number = last.unwrap_or(0) + 1;
+            record(&dir, number, &previous)?;
+            last = Some(number);
+        }
+    }
+    if !recorded(last, content) {
+        record(&dir, last.unwrap_or(0) + 1, content)?;
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// The recorded versions of the script `name` (its path below the scripts directory,
+/// without `.json`), oldest first.
+pub fn list(scripts_dir: &Path, name: &str) -> Result<Vec<Version>> {
+    let (path, dir) = locate(scripts_dir, name)?;
+    let current = fs::read(&path).ok();
+    numbers(&dir)?
+        .into_iter()
+        .map(|number| {
+            let file = dir.join(format!("{}.json", number));
+            let content = fs::read(&file)?;
+            let written = fs::metadata(&file).and_then(|meta| meta.modified()).ok().map(warc_date);
+            Ok(Version {
+                number,
+                version: serde_json::from_slice::<InjectionScript>(&content).ok().map(|script| script.version),
+                written,
+                size: content.len() as u64,
+                current: current.as_deref() == Some(content.as_slice()),
+            })
+        })
+        .collect()
+}
+
+/// Puts version `number` of the script `name` back in place. The rollback is recorded as
+/// a version of its own, so it can be rolled back in turn; returns its number.
+pub fn rollback(scripts_dir: &Path, name: &str, number: u32) -> Result<u32> {
+    let (path, dir) = locate(scripts_dir, name)?;
+    let content = fs::read(dir.join(format!("{}.json", number))).map_err(|_| anyhow!("{} has no version {}", name, number))?;
+    serde_json::from_slice::<InjectionScript>(&content).map_err(|e| anyhow!("Version {} of {} is not a valid script: {}", number, name, e))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write(scripts_dir, &path, &content)?;
+    Ok(numbers(&dir)?.last().copied().unwrap_or(number))
+}
+
+/// The script file for `name` and its history directory.
+fn locate(scripts_dir: &Path, name: &str) -> Result<(PathBuf, PathBuf)> {
+    let path = bundle::safe_join(scripts_dir, &format!("{}.json", name)).ok_or_else(|| anyhow!("Refusing unsafe script name {}", name))?;
+    let dir = history_dir_for(scripts_dir, &path).ok_or_else(|| anyhow!("Refusing unsafe script name {}", name))?;
+    if !dir.exists() {
+        bail!("{} has no history; versions are kept from the first time `script new`, `script import` or `script install` writes it", name);
+    }
+    Ok((path, dir))
+}
+
+fn history_dir_for(scripts_dir: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(scripts_dir).ok()?.with_extension("");
+    relative.file_name()?;
+    Some(scripts_dir.join(HISTORY_DIR).join(relative))
+}
+
+fn record(dir: &Path, number: u32, content: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(format!("{}.json", number)), content)?;
+    Ok(())
+}
+
+/// The version numbers recorded in `dir`, in order.
+fn numbers(dir: &Path) -> Result<Vec<u32>> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(vec![]);
+    };
+    let mut numbers = vec![];
+    for entry in entries {
+        let name = entry?.file_name();
+        if let Some(number) = name.to_str().and_then(|name| name.strip_suffix(".json")).and_then(|n| n.parse().ok()) {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
@@ -0,0 +1,167 @@
+use hyper::header::{HeaderMap, HeaderValue, HOST};
+use hyper::http::uri::Authority;
+use hyper::Uri;
+use regex::Regex;
+use tracing::{debug, warn};
+
+use crate::config::{HostHeader, HostRule};
+use crate::pattern;
+
+/// `[[hosts]]`: requests for some domains connect somewhere else, and carry a `Host`
+/// header of their own choosing there.
+pub struct Hosts {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    config: HostRule,
+    connect: Option<Authority>,
+    /// Compiled `host_pattern`, for `rewrite`
+    pattern: Option<Regex>,
+}
+
+impl Hosts {
+    pub fn new(configs: &[HostRule]) -> Self {
+        let rules = configs
+            .iter()
+            .map(|config| {
+                let connect = config.connect.as_deref().filter(|connect| !connect.is_empty()).and_then(|connect| match connect.parse() {
+                    Ok(authority) => Some(authority),
+                    Err(_) => {
+                        warn!("hosts: connect {:?} for {:?} is not host[:port], ignoring it", connect, config.domains);
+                        None
+                    }
+                });
+                let pattern = match (config.host, config.host_pattern.as_deref()) {
+                    (HostHeader::Rewrite, Some(source)) => match pattern::compile(source) {
+                        Ok(regex) => Some(regex),
+                        Err(e) => {
+                            warn!("hosts: host_pattern for {:?} is unusable, keeping Host as sent: {}", config.domains, e);
+                            None
+                        }
+                    },
+                    _ => None,
+                };
+                let incomplete = match config.host {
+                    HostHeader::Override => config.host_value.is_none(),
+                    HostHeader::Rewrite => config.host_value.is_none() || config.host_pattern.is_none(),
+                    _ => false,
+                };
+                if incomplete {
+                    warn!("hosts: {:?} for {:?} lacks host_value or host_pattern, keeping Host as sent", config.host, config.domains);
+                }
+                Rule {
+                    config: config.clone(),
+                    connect,
+                    pattern,
+                }
+            })
+            .collect();
+        Hosts { rules }
+    }
+
+    /// Points a request at the `connect` address of the first rule covering its host, and
+    /// sets the `Host` the rule asks for.
+    pub fn apply(&self, uri: &mut Uri, headers: &mut HeaderMap) {
+        let Some(domain) = uri.host() else {
+            return;
+        };
+        let Some(rule) = self.rules.iter().find(|rule| rule.config.applies_to(domain)) else {
+            return;
+        };
+        let Some(sent) = headers
+            .get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| uri.authority().map(|authority| authority.to_string()))
+        else {
+            return;
+        };
+        if let Some(connect) = &rule.connect {
+            let mut parts = uri.clone().into_parts();
+            parts.authority = Some(connect.clone());
+            if let Ok(target) = Uri::from_parts(parts) {
+                *uri = target;
+            }
+        }
+        let host = match (rule.config.host, &rule.config.host_value, &rule.pattern) {
+            (HostHeader::Connect, _, _) => uri.authority().map(|authority| authority.to_string()).unwrap_or(sent),
+            (HostHeader::Override, Some(value), _) => value.clone(),
+            (HostHeader::Rewrite, Some(value), Some(pattern)) => pattern.replace_all(&sent, value.as_str()).into_owned(),
+            _ => sent,
+        };
+        match HeaderValue::from_str(&host) {
+            Ok(value) => {
+                debug!("hosts: sending {} with Host: {}", uri, host);
+                headers.insert(HOST, value);
+            }
+            Err(_) => warn!("hosts: {:?} is not a valid Host header, keeping the client's", host),
+        }
+    }
+}
+
+/// `uri` with dot-segments removed from its path (`%2e` counting as a dot), its host in
+/// lower case and a default port (80 for http, 443 for https) dropped; `None` when that
+/// changes nothing.
+pub fn normalize(uri: &Uri) -> Option<Uri> {
+    let mut parts = uri.clone().into_parts();
+    if let Some(authority) = &parts.authority {
+        let userinfo = authority.as_str().rsplit_once('@').map(|(userinfo, _)| format!("{}@", userinfo)).unwrap_or_default();
+        let default_port = match uri.scheme_str() {
+            Some("http") => Some(80),
+            Some("https") => Some(443),
+            _ => None,
+        };
+        let host = authority.host().to_ascii_lowercase();
+        let normalized = match authority.port_u16().filter(|port| Some(*port) != default_port) {
+            Some(port) => format!("{}{}:{}", userinfo, host, port),
+            None => format!("{}{}", userinfo, host),
+        };
+        if normalized != authority.as_str() {
+            parts.authority = Some(normalized.parse().ok()?);
+        }
+    }
+    if let Some(path_and_query) = &parts.path_and_query {
+        let path = path_and_query.path();
+        if path.starts_with('/') {
+            let cleaned = remove_dot_segments(path);
+            if cleaned != path {
+                let rebuilt = match path_and_query.query() {
+                    Some(query) => format!("{}?{}", cleaned, query),
+                    None => cleaned,
+                };
+                parts.path_and_query = Some(rebuilt.parse().ok()?);
+            }
+        }
+    }
+    let normalized = Uri::from_parts(parts).ok()?;
+    (normalized != *uri).then_some(normalized)
+}
+
+/// RFC 3986's remove_dot_segments for an absolute path; `..` never climbs above the root.
+fn remove_dot_segments(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').collect();
+    let mut output: Vec<&str> = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        let dots = segment.to_ascii_lowercase().replace("%2e", ".");
+        match dots.as_str() {
+            "." => {}
+            ".." => {
+                // The leading empty segment stands for the root
+                if output.len() > 1 {
+                    output.pop();
+                }
+            }
+            _ => {
+                output.push(segment);
+                continue;
+            }
+        }
+        // `/a/b/..` is the directory `/a/`
+        if last {
+            output.push("");
+        }
+    }
+    output.join("/")
+}
@@ -0,0 +1,116 @@
+use anyhow::Result;
+use hyper::header::{HeaderMap, STRICT_TRANSPORT_SECURITY};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::config::{HstsPolicy, TunnelPolicy};
+
+/// How a domain's HSTS is dealt with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Choice {
+    /// Preloaded: browsers pin it to HTTPS whatever the proxy does, so it is only tunnelled
+    Tunnel,
+    /// `Strict-Transport-Security` is removed from its responses
+    Strip,
+    /// Its `Strict-Transport-Security` headers reach the client
+    Keep,
+}
+
+/// `[security.hsts]`: whether domains keep their HSTS, lose it, or are never intercepted
+/// because browsers have them preloaded. Each domain's choice is logged the first time.
+pub struct Hsts {
+    policy: HstsPolicy,
+    stripping: bool,
+    /// Preloaded names, and whether the entry covers their subdomains
+    preloaded: HashMap<String, bool>,
+    logged: Mutex<HashSet<String>>,
+}
+
+impl Hsts {
+    /// `stripping` is the `hsts-stripping` feature; without it `strip` is ignored.
+    pub fn new(policy: &HstsPolicy, stripping: bool) -> Self {
+        let mut preloaded: HashMap<String, bool> = policy.preloaded.iter().map(|domain| (domain.to_ascii_lowercase(), true)).collect();
+        if let Some(path) = policy.preload_list.as_deref().filter(|path| !path.is_empty()) {
+            match load_preload_list(path) {
+                Ok(entries) => {
+                    info!("HSTS: {} preloaded domains from {}", entries.len(), path);
+                    preloaded.extend(entries);
+                }
+                Err(e) => warn!("HSTS: preload list {} not loaded: {}", path, e),
+            }
+        }
+        if !policy.strip.is_empty() && !stripping {
+            warn!("security.hsts.strip is ignored unless the hsts-stripping feature is enabled");
+        }
+        Hsts {
+            policy: policy.clone(),
+            stripping,
+            preloaded,
+            logged: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn is_preloaded(&self, domain: &str) -> bool {
+        let domain = domain.to_ascii_lowercase();
+        if self.preloaded.contains_key(&domain) {
+            return true;
+        }
+        let mut parent = domain.as_str();
+        while let Some((_, rest)) = parent.split_once('.') {
+            if self.preloaded.get(rest) == Some(&true) {
+                return true;
+            }
+            parent = rest;
+        }
+        false
+    }
+
+    /// The domain's choice; a preloaded domain is tunnelled even when listed in `strip`,
+    /// since removing the header can't undo a preload.
+    pub fn choose(&self, domain: &str) -> Choice {
+        let choice = if self.policy.tunnel_preloaded && self.is_preloaded(domain) {
+            Choice::Tunnel
+        } else if self.stripping && self.policy.strip.iter().any(|pattern| TunnelPolicy::matches(domain, pattern)) {
+            Choice::Strip
+        } else {
+            Choice::Keep
+        };
+        if self.logged.lock().unwrap().insert(domain.to_ascii_lowercase()) {
+            match choice {
+                Choice::Tunnel => info!("HSTS: {} is preloaded, so it is tunnelled without interception", domain),
+                Choice::Strip => info!("HSTS: stripping Strict-Transport-Security from {}", domain),
+                Choice::Keep => debug!("HSTS: {} keeps its Strict-Transport-Security", domain),
+            }
+        }
+        choice
+    }
+
+    /// Removes `Strict-Transport-Security` from a response of a domain chosen for stripping.
+    pub fn strip(&self, domain: &str, headers: &mut HeaderMap) {
+        if headers.contains_key(STRICT_TRANSPORT_SECURITY) && self.choose(domain) == Choice::Strip {
+            headers.remove(STRICT_TRANSPORT_SECURITY);
+        }
+    }
+}
+
+/// Forced-HTTPS entries of Chromium's `transport_security_state_static.json`, which
+/// interleaves `//` comment lines with the JSON.
+fn load_preload_list(path: &str) -> Result<HashMap<String, bool>> {
+    let content = fs::read_to_string(path)?;
+    let json: String = content.lines().filter(|line| !line.trim_start().starts_with("//")).collect::<Vec<_>>().join("\n");
+    let list: Value = serde_json::from_str(&json)?;
+    Ok(list
+        .get("entries")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.get("mode").and_then(Value::as_str) == Some("force-https"))
+        .filter_map(|entry| {
+            let name = entry.get("name")?.as_str()?.to_ascii_lowercase();
+            Some((name, entry.get("include_subdomains").and_then(Value::as_bool).unwrap_or(false)))
+        })
+        .collect())
+}
@@ -0,0 +1,137 @@
+/// Elements whose content is not markup, or must not receive injected markup.
+const OPAQUE_ELEMENTS: &[&str] = &["script", "style", "noscript", "textarea", "title", "template", "xmp"];
+
+/// Positions (byte offsets) that matter when injecting into a document.
+#[derive(Debug, Default)]
+struct Outline {
+    html_open_end: Option<usize>,
+    head_open_end: Option<usize>,
+    /// End of the last `<base>` or charset `<meta>` inside `<head>`
+    after_base_meta: Option<usize>,
+    body_open: Option<usize>,
+    body_close: Option<usize>,
+    head_close: Option<usize>,
+}
+
+/// Just enough HTML tokenizing to place injected payloads: comments, doctypes and the
+/// contents of raw-text elements (`<script>`, `<style>`, `<noscript>`, ...) are skipped,
+/// so markup-looking text inside them is never mistaken for a real tag.
+fn outline(html: &str) -> Outline {
+    let lower = html.to_ascii_lowercase();
+    let bytes = lower.as_bytes();
+    let len = lower.len();
+    let mut outline = Outline::default();
+    let mut in_head = false;
+    let mut i = 0;
+
+    while let Some(offset) = lower[i..].find('<') {
+        let start = i + offset;
+        let rest = &lower[start..];
+
+        if rest.starts_with("<!--") {
+            i = lower[start + 4..].find("-->").map(|e| start + 4 + e + 3).unwrap_or(len);
+            continue;
+        }
+        if rest.starts_with("<!") || rest.starts_with("<?") {
+            i = lower[start..].find('>').map(|e| start + e + 1).unwrap_or(len);
+            continue;
+        }
+
+        let closing = bytes.get(start + 1) == Some(&b'/');
+        let name_start = start + 1 + closing as usize;
+        let name_end = name_start + bytes[name_start..].iter().take_while(|b| b.is_ascii_alphanumeric()).count();
+        if name_end == name_start {
+            i = start + 1;
+            continue;
+        }
+        let name = &lower[name_start..name_end];
+        let tag_end = match tag_end(bytes, name_end) {
+            Some(end) => end,
+            None => break,
+        };
+
+        match (closing, name) {
+            (false, "html") => outline.html_open_end = Some(tag_end),
+            (false, "head") => {
+                outline.head_open_end = Some(tag_end);
+                in_head = true;
+            }
+            (true, "head") => {
+                outline.head_close = Some(start);
+                in_head = false;
+            }
+            (false, "body") => {
+                outline.body_open = Some(start);
+                in_head = false;
+            }
+            (true, "body") => outline.body_close = Some(start),
+            (false, "base") if in_head => outline.after_base_meta = Some(tag_end),
+            (false, "meta") if in_head && lower[start..tag_end].contains("charset") => {
+                outline.after_base_meta = Some(tag_end)
+            }
+            _ => {}
+        }
+
+        if !closing && OPAQUE_ELEMENTS.contains(&name) {
+            let close = format!("</{}", name);
+            i = lower[tag_end..].find(&close).map(|e| tag_end + e).unwrap_or(len);
+            continue;
+        }
+        i = tag_end;
+    }
+
+    outline
+}
+
+/// Offset just past the `>` closing a tag, honouring quoted attribute values.
+fn tag_end(bytes: &[u8], from: usize) -> Option<usize> {
+    let mut quote = None;
+    for (offset, &b) in bytes[from..].iter().enumerate() {
+        match quote {
+            Some(q) if b == q => quote = None,
+            Some(_) => {}
+            None if b == b'"' || b == b'\'' => quote = Some(b),
+            None if b == b'>' => return Some(from + offset + 1),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Whether the document's `<head>` has ended, by a real `</head>` or the start of
+/// `<body>`, so where `insert_in_head` puts a payload can no longer change.
+pub fn head_complete(html: &str) -> bool {
+    let outline = outline(html);
+    outline.head_close.is_some() || outline.body_open.is_some()
+}
+
+/// Inserts `payload` early in `<head>`, after any `<base>` and charset `<meta>` so URLs
+/// and the encoding are settled first. A document without `<head>` gets one. Returns
+/// `false` when the body does not look like an HTML document.
+pub fn insert_in_head(html: &mut String, payload: &str) -> bool {
+    let outline = outline(html);
+    if let Some(head) = outline.head_open_end {
+        html.insert_str(outline.after_base_meta.unwrap_or(head), payload);
+        return true;
+    }
+
+    match outline.html_open_end.or(outline.body_open) {
+        Some(pos) => {
+            html.insert_str(pos, &format!("<head>{}</head>", payload));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Inserts `payload` before the real closing `</body>`, ignoring any inside comments or
+/// scripts. Returns `false` when there is none.
+pub fn insert_before_body_end(html: &mut String, payload: &str) -> bool {
+    match outline(html).body_close {
+        Some(pos) => {
+            html.insert_str(pos, payload);
+            true
+        }
+        None => false,
+    }
+}
@@ -1,51 +1,167 @@
-use anyhow::Result;
-use hyper::{Request, Response, Body, Uri, Method};
+use anyhow::{anyhow, Result};
+use hyper::{Method, Request, Response, StatusCode, Uri};
 use hyper::header::{HeaderMap, HeaderName, HeaderValue};
-use std::collections::HashMap;
+use regex::Regex;
+use base64::Engine;
+use sha2::{Digest, Sha256, Sha384};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::{debug, error, info, warn};
-use crate::script_manager::{ScriptManager, InjectionResult};
-use crate::config::Config;
+use crate::body::{self, Body};
+use crate::script_manager::{InjectionResult, ScriptManager, SharedScripts, StreamRewrites};
+use crate::config::{AcceptEncoding, Config, IntegrityMode, ValidatorMode};
+use crate::context::RequestContext;
+use crate::error_page;
+use crate::logging::{self, VerboseLog};
+use crate::optimize;
+use crate::page_api;
+use crate::protobuf::ProtobufDecoder;
+use crate::signing;
+use crate::streaming::{self, Fixup, HeadInjector, Limits, RollingReplacer, SseRewriter};
+
+/// Bound on remembered asset hashes; past it the map starts over.
+const INTEGRITY_CACHE_LIMIT: usize = 10_000;
+
+/// Separates the opaque part of a proxy-issued ETag from the script set hash.
+const ETAG_MARKER: &str = ".rp-";
+
+/// Bound on remembered scripted page origins; past it the set starts over.
+const INJECTED_ORIGIN_LIMIT: usize = 10_000;
+
+/// Injection passes kept for `/admin/injections`; past it the oldest is dropped.
+const INJECTION_LOG_LIMIT: usize = 100;
+
+/// How long responses are kept for `rusty-proxy repl` after it last asked for one; other
+/// times no copy is made.
+const EXCHANGE_WATCH: Duration = Duration::from_secs(600);
+
+/// Snippets are cut to this many bytes in the injection log.
+const SNIPPET_LOG_LIMIT: usize = 1024;
 
 pub struct HttpInjector {
-    script_manager: ScriptManager,
+    script_manager: SharedScripts,
     config: Config,
+    /// SRI hashes of assets as last served after modification, by URL
+    asset_integrity: Arc<Mutex<HashMap<String, String>>>,
+    protobuf: ProtobufDecoder,
+    /// Origins of the pages scripts were injected into, the only callers the page API answers
+    injected_origins: Arc<Mutex<HashSet<String>>>,
+    /// What recent injection passes changed, newest last
+    recent_injections: Mutex<VecDeque<Value>>,
+    /// The last response scripts ran on, kept while a `rusty-proxy repl` is attached
+    last_exchange: Mutex<Option<Arc<Exchange>>>,
+    /// When a REPL last asked for the exchange; see `EXCHANGE_WATCH`
+    exchange_watched: Mutex<Option<Instant>>,
+}
+
+/// A response as it came from upstream, before scripts ran, with the request it answered;
+/// `rusty-proxy repl` tries scripts on it.
+struct Exchange {
+    ctx: RequestContext,
+    status: u16,
+    headers: HashMap<String, String>,
+    body: String,
 }
 
 impl HttpInjector {
-    pub fn new(script_manager: ScriptManager, config: Config) -> Self {
+    pub fn new(script_manager: SharedScripts, config: Config) -> Self {
+        {
+            let mut scripts = script_manager.write().unwrap();
+            scripts.set_features(config.features.clone());
+            scripts.set_vars(config.scripts.vars.clone());
+            scripts.set_credentials(signing::load(&config.credentials));
+            scripts.set_minify_payloads(config.optimize.minify_payloads);
+            scripts.set_max_execution_time(config.scripts.max_execution_time);
+            scripts.set_growth_limits(config.scripts.max_added_bytes, config.scripts.max_replacements);
+        }
         HttpInjector {
             script_manager,
+            protobuf: ProtobufDecoder::load(&config.logging.protobuf),
             config,
+            asset_integrity: Arc::new(Mutex::new(HashMap::new())),
+            injected_origins: Arc::new(Mutex::new(HashSet::new())),
+            recent_injections: Mutex::new(VecDeque::new()),
+            last_exchange: Mutex::new(None),
+            exchange_watched: Mutex::new(None),
         }
     }
 
-    pub async fn process_request(&self, mut req: Request<Body>) -> Result<Request<Body>> {
-        let uri = req.uri().clone();
-        let domain = self.extract_domain(&uri);
+    pub fn script_manager(&self) -> RwLockReadGuard<'_, ScriptManager> {
+        self.script_manager.read().unwrap()
+    }
+
+    /// Re-reads the scripts directory. Injection passes already running finish with the
+    /// old scripts; later ones, even for a request already in flight, see the new ones.
+    pub fn reload_scripts(&self) -> Result<usize> {
+        let mut scripts = self.script_manager.write().unwrap();
+        scripts.load_scripts()?;
+        Ok(scripts.list_scripts().len())
+    }
+
+    /// Lets `update` change the scripts directory, then reloads it. Injections wait
+    /// meanwhile, so none runs against a half-written set.
+    pub fn update_scripts(&self, update: impl FnOnce(&Path) -> Result<()>) -> Result<usize> {
+        let mut scripts = self.script_manager.write().unwrap();
+        let dir = scripts.scripts_dir().to_path_buf();
+        update(&dir)?;
+        scripts.load_scripts()?;
+        Ok(scripts.list_scripts().len())
+    }
+
+    /// Enables or disables a script at runtime; see `ScriptManager::set_enabled`.
+    pub fn set_script_enabled(&self, name: &str, enabled: bool) -> bool {
+        self.script_manager.write().unwrap().set_enabled(name, enabled)
+    }
+
+    pub async fn process_request(&self, req: Request<Body>, ctx: &RequestContext) -> Result<Request<Body>> {
+        let domain = &ctx.domain;
         
-        if !self.config.is_domain_allowed(&domain) {
-            warn!("Domain {} is not allowed", domain);
+        if let Some(reason) = self.domain_refusal(domain) {
+            warn!("{}", reason);
             return Ok(req);
         }
 
         // Convert headers to HashMap for easier manipulation
-        let mut headers_map = self.headers_to_map(req.headers());
+        let (mut parts, body) = req.into_parts();
+        let mut headers_map = self.headers_to_map(&parts.headers);
         let mut body_string = String::new();
 
-        // Read body if present
-        if req.method() == Method::POST || req.method() == Method::PUT {
-            let body_bytes = hyper::body::to_bytes(req.into_body()).await?;
+        // Any method may carry a body; whether one follows is told by the framing
+        // (Content-Length, chunked encoding, or an open HTTP/2 stream), not the method
+        let verbose = parts.extensions.get::<VerboseLog>().is_some();
+        let mut original_body = None;
+        if !hyper::body::Body::is_end_stream(&body) {
+            let content_type = headers_map.get("content-type").cloned();
+            let body_bytes = body::to_bytes(body).await?;
+            if verbose {
+                let decoded = self.protobuf.decode_request(ctx.url.path(), content_type.as_deref(), &body_bytes);
+                logging::log_sampled_body("request", &body_bytes, decoded);
+            }
             body_string = String::from_utf8_lossy(&body_bytes).to_string();
+            original_body = Some(body_bytes);
+        }
+
+        if self.config.scripts.enabled {
+            self.rewrite_conditional_headers(ctx, &mut headers_map);
+        }
+
+        if let Some(encoding) = self.forced_accept_encoding(domain) {
+            headers_map.insert("accept-encoding".to_string(), encoding.header_value().to_string());
         }
 
         // Apply request injections
+        let mut body_modified = false;
         if self.config.scripts.enabled {
-            match self.script_manager.apply_request_injections(&domain, &mut headers_map, &mut body_string) {
+            let injections = self.script_manager().apply_request_injections(ctx, &headers_map, &body_string);
+            match injections {
                 Ok(injection_result) => {
-                    if injection_result.modified {
-                        info!("Applied request injections for domain: {}", domain);
-                    }
+                    injection_result.apply_to(&mut headers_map, &mut body_string);
+                    body_modified = injection_result.body.is_some();
+                    self.record_injections(ctx, "request", &injection_result);
                 }
                 Err(e) => {
                     error!("Failed to apply request injections: {}", e);
@@ -53,57 +169,792 @@ impl HttpInjector {
             }
         }
 
+        // Bodies scripts left alone go out byte for byte, so binary uploads survive; a
+        // rewritten one is re-framed with its new length
+        let new_body = match original_body {
+            Some(bytes) if !body_modified => Body::from(bytes),
+            _ if !body_modified => Body::empty(),
+            _ => {
+                headers_map.remove("transfer-encoding");
+                headers_map.insert("content-length".to_string(), body_string.len().to_string());
+                Body::from(body_string)
+            }
+        };
+
         // Rebuild request with modified headers
-        let (mut parts, _) = Request::from(req).into_parts();
         parts.headers = self.map_to_headers(&headers_map)?;
-        
-        let new_body = if body_string.is_empty() {
-            Body::empty()
-        } else {
-            Body::from(body_string)
-        };
 
         Ok(Request::from_parts(parts, new_body))
     }
 
-    pub async fn process_response(&self, mut res: Response<Body>, domain: &str) -> Result<Response<Body>> {
-        if !self.config.is_domain_allowed(domain) {
+    /// Signs a request with the `Sign` scripts matching the context it carries, as the
+    /// last thing before it is sent: the URI, `Host` and body signed are the ones the
+    /// upstream receives. Called once per attempt, so retries and followed redirects are
+    /// signed afresh.
+    pub async fn sign_request(&self, req: Request<Body>) -> Result<Request<Body>> {
+        if !self.config.scripts.enabled {
+            return Ok(req);
+        }
+        match req.extensions().get::<RequestContext>() {
+            Some(ctx) if self.domain_refusal(&ctx.domain).is_none() && self.script_manager().signs(ctx) => {}
+            _ => return Ok(req),
+        }
+        let (mut parts, body) = req.into_parts();
+        let body = body::to_bytes(body).await?;
+        if let Some(ctx) = parts.extensions.get::<RequestContext>() {
+            let headers_map = self.headers_to_map(&parts.headers);
+            let signed = self.script_manager().sign_request(ctx, &parts.method, &parts.uri, &headers_map, &body);
+            for name in &signed.headers_removed {
+                parts.headers.remove(name.as_str());
+            }
+            for (name, value) in &signed.headers_set {
+                parts.headers.insert(HeaderName::from_str(name)?, HeaderValue::from_str(value)?);
+            }
+            self.record_injections(ctx, "signing", &signed);
+        }
+        Ok(Request::from_parts(parts, Body::from(body)))
+    }
+
+    pub async fn process_response(&self, res: Response<Body>, ctx: &RequestContext) -> Result<Response<Body>> {
+        let domain = ctx.domain.as_str();
+        if self.domain_refusal(domain).is_some() {
             return Ok(res);
         }
 
+        if self.config.scripts.respect_no_transform && self.has_no_transform(res.headers()) {
+            debug!("Skipping injection for {}: upstream sent no-transform", domain);
+            return Ok(res);
+        }
+
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return self.process_not_modified(res, ctx);
+        }
+
+        // HEAD and OPTIONS answers (and 204s) have no body to inject into
+        if ctx.method == Method::HEAD || ctx.method == Method::OPTIONS || res.status() == StatusCode::NO_CONTENT {
+            return self.process_headers_only(res, ctx);
+        }
+
+        // Event streams are long-lived; never buffer them
+        if self.is_event_stream(res.headers()) {
+            return Ok(self.process_event_stream(res, ctx));
+        }
+
+        // Large or open-ended text bodies are rewritten chunk by chunk when every
+        // matching script can work on a stream
+        if self.config.scripts.enabled && self.should_stream(res.headers()) {
+            let rewrites = self.script_manager().get_stream_rewrites(ctx, res.status().as_u16());
+            if let Some(rewrites) = rewrites {
+                return self.process_streamed(res, ctx, rewrites);
+            }
+        }
+
         // Convert headers to HashMap for easier manipulation
-        let mut headers_map = self.headers_to_map(res.headers());
+        let (mut parts, body) = res.into_parts();
+        let mut headers_map = self.headers_to_map(&parts.headers);
+        let status = parts.status.as_u16();
         
         // Read response body
-        let body_bytes = hyper::body::to_bytes(res.into_body()).await?;
+        let verbose = parts.extensions.get::<VerboseLog>().is_some();
+        let body_bytes = body::to_bytes(body).await?;
+        if verbose {
+            let decoded = self
+                .protobuf
+                .decode_response(ctx.url.path(), headers_map.get("content-type").map(String::as_str), &body_bytes);
+            logging::log_sampled_body("response", &body_bytes, decoded);
+        }
+
+        if self.config.optimize.recompress_images && !headers_map.contains_key("content-encoding") {
+            if let Some(content_type) = headers_map.get("content-type").filter(|ct| ct.starts_with("image/")).cloned() {
+                let quality = self.config.optimize.image_quality;
+                let original = body_bytes.clone();
+                let recompressed = tokio::task::spawn_blocking(move || optimize::recompress_image(&original, &content_type, quality))
+                    .await
+                    .ok()
+                    .flatten();
+                if let Some(image) = recompressed {
+                    debug!("Recompressed image from {} to {} bytes for {}", body_bytes.len(), image.len(), ctx.url);
+                    headers_map.insert("content-length".to_string(), image.len().to_string());
+                    self.mark_modified(&mut headers_map, None, ctx);
+                    let mut response = Response::new(Body::from(image));
+                    *response.status_mut() = StatusCode::from_u16(status)?;
+                    *response.headers_mut() = self.map_to_headers(&headers_map)?;
+                    return Ok(response);
+                }
+            }
+        }
+
         let mut body_string = String::from_utf8_lossy(&body_bytes).to_string();
 
         // Apply response injections
+        let mut modified = false;
         if self.config.scripts.enabled {
-            match self.script_manager.apply_response_injections(domain, &mut headers_map, &mut body_string) {
+            if self.watching_exchanges() {
+                *self.last_exchange.lock().unwrap() = Some(Arc::new(Exchange {
+                    ctx: ctx.clone(),
+                    status,
+                    headers: headers_map.clone(),
+                    body: body_string.clone(),
+                }));
+            }
+            let injections = self.script_manager().apply_response_injections(ctx, status, &headers_map, &body_string);
+            modified = match injections {
                 Ok(injection_result) => {
-                    if injection_result.modified {
-                        info!("Applied response injections for domain: {}", domain);
-                        
-                        // Update content length if body was modified
-                        headers_map.insert("content-length".to_string(), body_string.len().to_string());
-                    }
+                    injection_result.apply_to(&mut headers_map, &mut body_string);
+                    self.record_injections(ctx, "response", &injection_result);
+                    injection_result.modified()
                 }
                 Err(e) => {
                     error!("Failed to apply response injections: {}", e);
+                    false
+                }
+            };
+
+            let is_html = headers_map
+                .get("content-type")
+                .map(|ct| ct.to_lowercase().starts_with("text/html"))
+                .unwrap_or(false);
+            if is_html && modified {
+                self.remember_page(ctx);
+            }
+            if is_html {
+                modified |= self.fix_integrity(&mut body_string, ctx);
+            } else if modified {
+                self.remember_integrity(ctx, &body_string);
+            }
+
+            if is_html && self.config.optimize.minify_html {
+                let minified = optimize::minify_html(&body_string);
+                if minified.len() < body_string.len() {
+                    body_string = minified;
+                    modified = true;
                 }
             }
+
+            if modified {
+                // Update content length, validators and caching if body was modified
+                headers_map.insert("content-length".to_string(), body_string.len().to_string());
+                self.mark_modified(&mut headers_map, Some(&body_string), ctx);
+            }
         }
 
+        // Untouched bodies go out byte for byte; the lossy text copy is only for scripts
+        let body = if !modified {
+            Body::from(body_bytes)
+        } else if let Some((encoding, compressed)) = self.compress_modified(&mut headers_map, &body_string, ctx).await {
+            debug!("Compressed modified body from {} to {} bytes ({}) for {}", body_string.len(), compressed.len(), encoding, ctx.url);
+            Self::mark_encoded(&mut headers_map, encoding, compressed.len());
+            Body::from(compressed)
+        } else {
+            Body::from(body_string)
+        };
+
         // Rebuild response with modified headers and body
-        let (mut parts, _) = Response::from(res).into_parts();
         parts.headers = self.map_to_headers(&headers_map)?;
-        
-        Ok(Response::from_parts(parts, Body::from(body_string)))
+        self.start_session(&mut parts.headers, ctx);
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// Only header scripts run, and the body passes through with its framing headers as
+    /// they are, so a HEAD answer keeps the Content-Length of the GET it describes.
+    fn process_headers_only(&self, res: Response<Body>, ctx: &RequestContext) -> Result<Response<Body>> {
+        if !self.config.scripts.enabled {
+            return Ok(res);
+        }
+        let (mut parts, body) = res.into_parts();
+        let mut headers_map = self.headers_to_map(&parts.headers);
+        let injection_result = self.script_manager().apply_response_header_injections(ctx, parts.status.as_u16(), &headers_map);
+        injection_result.apply_to(&mut headers_map, &mut String::new());
+        self.record_injections(ctx, "response", &injection_result);
+        parts.headers = self.map_to_headers(&headers_map)?;
+        self.start_session(&mut parts.headers, ctx);
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// Answers a CORS preflight (`OPTIONS` with `Access-Control-Request-Method`) itself
+    /// when `answer_preflight` scripts for the domain supply `Access-Control-Allow-Origin`.
+    /// `Allow` mirrors the allowed methods.
+    pub fn answer_preflight(&self, req: &Request<Body>, ctx: &RequestContext) -> Option<Response<Body>> {
+        if !self.config.scripts.enabled
+            || req.method() != Method::OPTIONS
+            || !req.headers().contains_key("access-control-request-method")
+            || !self.config.is_domain_allowed(&ctx.domain)
+        {
+            return None;
+        }
+        let result = self.script_manager().preflight_headers(ctx)?;
+        if !result.headers_set.contains_key("access-control-allow-origin") {
+            return None;
+        }
+        self.record_injections(ctx, "preflight", &result);
+
+        let mut headers_map = result.headers_set.clone();
+        let allow = headers_map
+            .get("access-control-allow-methods")
+            .cloned()
+            .unwrap_or_else(|| "GET, HEAD, POST, OPTIONS".to_string());
+        headers_map.entry("allow".to_string()).or_insert(allow);
+        headers_map.insert("content-length".to_string(), "0".to_string());
+        debug!("Answered preflight for {} ({})", ctx.url, result.applied.join(", "));
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        *response.headers_mut() = self.map_to_headers(&headers_map).ok()?;
+        Some(response)
+    }
+
+    /// Answers the request from a matching `Mock` script instead of the upstream. The
+    /// answer still goes through the response injections like any other.
+    pub fn answer_mock(&self, ctx: &RequestContext) -> Option<Response<Body>> {
+        if !self.config.scripts.enabled || !self.config.is_domain_allowed(&ctx.domain) {
+            return None;
+        }
+        let script = self.script_manager().mock_for(ctx)?;
+        let mut headers_map: HashMap<String, String> = script.headers.iter().map(|(name, value)| (name.to_ascii_lowercase(), value.clone())).collect();
+        headers_map.insert("content-length".to_string(), script.script_content.len().to_string());
+        let mut result = InjectionResult {
+            applied: vec![script.name.clone()],
+            ..Default::default()
+        };
+        result.headers_set.clone_from(&headers_map);
+        self.record_injections(ctx, "mock", &result);
+        debug!("Answered {} {} from mock {}", ctx.method, ctx.url, script.name);
+
+        let body = if ctx.method == Method::HEAD { Body::empty() } else { Body::from(script.script_content) };
+        let mut response = Response::new(body);
+        *response.status_mut() = StatusCode::from_u16(script.status.unwrap_or(200)).unwrap_or(StatusCode::OK);
+        *response.headers_mut() = self.map_to_headers(&headers_map).ok()?;
+        Some(response)
+    }
+
+    /// Logs what a pass of injections changed and keeps it for `/admin/injections`.
+    fn record_injections(&self, ctx: &RequestContext, phase: &str, result: &InjectionResult) {
+        if let Some(traced) = &ctx.trace {
+            traced.decision(phase, result);
+        }
+        if !result.modified() {
+            return;
+        }
+        info!("Applied {} injections for domain: {} ({})", phase, ctx.domain, result.applied.join(", "));
+        let body_len_after = result.body.as_ref().map(String::len).unwrap_or(result.body_len_before);
+        debug!(
+            "Injections for {}: set {:?}, removed {:?}, body {} -> {} bytes",
+            ctx.url,
+            result.headers_set.keys().collect::<Vec<_>>(),
+            result.headers_removed,
+            result.body_len_before,
+            body_len_after
+        );
+
+        let snippets = Self::logged_snippets(result);
+        let record = json!({
+            "at": SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            "phase": phase,
+            "method": ctx.method.as_str(),
+            "url": ctx.url.to_string(),
+            "connection_id": ctx.connection_id,
+            "scripts": result.applied,
+            "headers_set": result.headers_set,
+            "headers_removed": result.headers_removed,
+            "body_bytes_before": result.body_len_before,
+            "body_bytes_after": body_len_after,
+            "snippets": snippets,
+        });
+
+        let mut recent = self.recent_injections.lock().unwrap();
+        if recent.len() >= INJECTION_LOG_LIMIT {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+
+    /// The content scripts put into a body, cut to `SNIPPET_LOG_LIMIT` bytes each.
+    fn logged_snippets(result: &InjectionResult) -> Vec<Value> {
+        result
+            .snippets
+            .iter()
+            .map(|(script, snippet)| {
+                let mut cut = snippet.len().min(SNIPPET_LOG_LIMIT);
+                while !snippet.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                json!({ "script": script, "content": &snippet[..cut], "truncated": cut < snippet.len() })
+            })
+            .collect()
+    }
+
+    /// Notes that scripts were injected into the page, so it may call the page API.
+    fn remember_page(&self, ctx: &RequestContext) {
+        remember_origin(&self.injected_origins, &ctx.url);
+    }
+
+    /// Whether scripts were injected into a page of `origin` (`scheme://host[:port]`).
+    pub fn injected_into(&self, origin: &str) -> bool {
+        self.injected_origins.lock().unwrap().contains(origin)
+    }
+
+    /// Recent injection passes, oldest first.
+    pub fn recent_injections(&self) -> Value {
+        Value::Array(self.recent_injections.lock().unwrap().iter().cloned().collect())
+    }
+
+    /// Starts, or extends, keeping the last response for `rusty-proxy repl`, for
+    /// `EXCHANGE_WATCH`. Asking for the exchange or trying a script on it does too.
+    pub fn watch_exchanges(&self) {
+        *self.exchange_watched.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn watching_exchanges(&self) -> bool {
+        self.exchange_watched.lock().unwrap().is_some_and(|at| at.elapsed() < EXCHANGE_WATCH)
+    }
+
+    /// The last response scripts ran on, as it came from upstream, for
+    /// `GET /admin/exchange`; the body is left out.
+    pub fn last_exchange(&self) -> Option<Value> {
+        self.watch_exchanges();
+        let exchange = self.last_exchange.lock().unwrap().clone()?;
+        Some(json!({
+            "method": exchange.ctx.method.as_str(),
+            "url": exchange.ctx.url.to_string(),
+            "client": exchange.ctx.client_ip.to_string(),
+            "status": exchange.status,
+            "headers": exchange.headers,
+            "body_bytes": exchange.body.len(),
+            "matched_scripts": exchange.ctx.matched_scripts,
+        }))
+    }
+
+    /// Dry-runs the script `name` on the last response scripts ran on, for
+    /// `POST /admin/scripts/<name>/try`: what it would change, with the whole rewritten
+    /// body. Nothing is sent, counted or logged.
+    pub fn try_script(&self, name: &str) -> Result<Value> {
+        self.watch_exchanges();
+        let exchange = self
+            .last_exchange
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| anyhow!("No response has reached the scripts since the REPL attached"))?;
+        let result = self
+            .script_manager()
+            .try_response_script(name, &exchange.ctx, exchange.status, &exchange.headers, &exchange.body)?;
+        Ok(json!({
+            "script": name,
+            "method": exchange.ctx.method.as_str(),
+            "url": exchange.ctx.url.to_string(),
+            "status": exchange.status,
+            "applied": result.modified(),
+            "headers_set": result.headers_set,
+            "headers_removed": result.headers_removed,
+            "body_bytes_before": result.body_len_before,
+            "body_bytes_after": result.body.as_ref().map(String::len).unwrap_or(result.body_len_before),
+            "snippets": Self::logged_snippets(&result),
+            "body": result.body,
+        }))
+    }
+
+    /// Which scripts would run for a request and why, for `GET /admin/explain`: the checks
+    /// that apply to every script first, then each script's own.
+    pub fn explain(&self, ctx: &RequestContext, status: Option<u16>) -> Value {
+        let domain = &ctx.domain;
+        let mut checks = vec![json!({
+            "check": "scripts.enabled",
+            "passed": self.config.scripts.enabled,
+            "detail": if self.config.scripts.enabled { "scripts are enabled" } else { "scripts are disabled in the config" },
+        })];
+        let refusal = self.domain_refusal(domain);
+        checks.push(json!({
+            "check": "scripts.allowed_domains",
+            "passed": refusal.is_none(),
+            "detail": refusal.clone().unwrap_or_else(|| format!("{} is in scripts.allowed_domains", domain)),
+        }));
+
+        let scripts = self.script_manager().explain(ctx, status);
+        let fire: Vec<&str> = scripts
+            .iter()
+            .filter(|script| script.fires && self.config.scripts.enabled && refusal.is_none())
+            .map(|script| script.script.as_str())
+            .collect();
+        json!({
+            "method": ctx.method.as_str(),
+            "url": ctx.url.to_string(),
+            "domain": domain,
+            "status": status,
+            "fire": fire,
+            "checks": checks,
+            "scripts": scripts,
+        })
     }
 
-    fn extract_domain(&self, uri: &Uri) -> String {
-        uri.host().unwrap_or("unknown").to_string()
+    /// Why no script at all runs on `domain`, if none does: `process_request`,
+    /// `process_response` and `sign_request` pass such exchanges through untouched.
+    fn domain_refusal(&self, domain: &str) -> Option<String> {
+        if self.config.scripts.blocked_domains.iter().any(|blocked| blocked == domain) {
+            Some(format!("{} is in scripts.blocked_domains; no scripts run", domain))
+        } else if !self.config.is_domain_allowed(domain) {
+            Some(format!("{} is not in scripts.allowed_domains; no scripts run", domain))
+        } else {
+            None
+        }
+    }
+
+    /// A 304 has no body to inject into, but a weakened ETag it carries must keep the
+    /// script set suffix or the client would store the bare upstream tag.
+    fn process_not_modified(&self, res: Response<Body>, ctx: &RequestContext) -> Result<Response<Body>> {
+        if !self.config.scripts.enabled
+            || !self.config.scripts.refetch_on_script_change
+            || self.config.scripts.validator_mode != ValidatorMode::Weaken
+        {
+            return Ok(res);
+        }
+
+        let (mut parts, body) = res.into_parts();
+        let mut headers_map = self.headers_to_map(&parts.headers);
+        if headers_map.contains_key("etag") {
+            self.invalidate_validators(&mut headers_map, None, ctx);
+            parts.headers = self.map_to_headers(&headers_map)?;
+        }
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// With `optimize.compress_modified`, the modified body compressed for a client that
+    /// advertised gzip or zstd. Bodies the upstream had encoded are left alone. Either way
+    /// the response now varies by `Accept-Encoding`.
+    async fn compress_modified(&self, headers: &mut HashMap<String, String>, body: &str, ctx: &RequestContext) -> Option<(&'static str, Vec<u8>)> {
+        if !self.config.optimize.compress_modified || headers.get("content-encoding").is_some_and(|e| !e.eq_ignore_ascii_case("identity")) {
+            return None;
+        }
+        let mut vary: Vec<String> = headers
+            .get("vary")
+            .map(|v| v.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()).collect())
+            .unwrap_or_default();
+        if !vary.iter().any(|h| h == "*" || h.eq_ignore_ascii_case("accept-encoding")) {
+            vary.push("Accept-Encoding".to_string());
+        }
+        headers.insert("vary".to_string(), vary.join(", "));
+        let accept_encoding = ctx.headers.get("accept-encoding")?.to_str().ok()?.to_string();
+        let body = body.as_bytes().to_vec();
+        tokio::task::spawn_blocking(move || optimize::compress(&body, &accept_encoding)).await.ok().flatten()
+    }
+
+    /// Headers for a body compressed by the proxy. A strong ETag names the encoding too,
+    /// since each encoding is a different representation.
+    fn mark_encoded(headers: &mut HashMap<String, String>, encoding: &str, length: usize) {
+        headers.insert("content-encoding".to_string(), encoding.to_string());
+        headers.insert("content-length".to_string(), length.to_string());
+        if let Some(etag) = headers.get_mut("etag").filter(|etag| !etag.starts_with("W/")) {
+            let opaque = etag.trim_matches('"');
+            let tagged = match opaque.rsplit_once(ETAG_MARKER) {
+                Some((original, hash)) => format!("\"{}-{}{}{}\"", original, encoding, ETAG_MARKER, hash),
+                None => format!("\"{}-{}\"", opaque, encoding),
+            };
+            *etag = tagged;
+        }
+    }
+
+    /// The `scripts.accept_encoding` entry for the domain; the longest matching pattern
+    /// wins when several do.
+    fn forced_accept_encoding(&self, domain: &str) -> Option<AcceptEncoding> {
+        self.config
+            .scripts
+            .accept_encoding
+            .iter()
+            .filter(|(pattern, _)| ScriptManager::domain_matches(domain, std::slice::from_ref(*pattern)))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, encoding)| *encoding)
+    }
+
+    fn is_event_stream(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .map(|ct| ct.trim().to_lowercase().starts_with("text/event-stream"))
+            .unwrap_or(false)
+    }
+
+    fn process_event_stream(&self, res: Response<Body>, ctx: &RequestContext) -> Response<Body> {
+        let domain = &ctx.domain;
+        let rules = if self.config.scripts.enabled {
+            self.script_manager().get_sse_rewrites(ctx, res.status().as_u16())
+        } else {
+            Vec::new()
+        };
+
+        if rules.is_empty() {
+            debug!("Streaming event-stream response from {} untouched", domain);
+            return res;
+        }
+
+        info!("Applying {} SSE rewrite rule(s) for domain: {}", rules.len(), domain);
+        let (mut parts, body) = res.into_parts();
+        parts.headers.remove("content-length");
+        parts.headers.remove("etag");
+        parts.headers.remove("last-modified");
+        let body = streaming::rewrite_body(body, SseRewriter::new(rules, self.stream_limits(ctx)));
+        Response::from_parts(parts, body)
+    }
+
+    fn should_stream(&self, headers: &HeaderMap) -> bool {
+        let encoded = headers
+            .get("content-encoding")
+            .and_then(|v| v.to_str().ok())
+            .map(|enc| !enc.eq_ignore_ascii_case("identity"))
+            .unwrap_or(false);
+        if encoded || !self.is_text_content(headers) {
+            return false;
+        }
+
+        match headers
+            .get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            Some(length) => length > self.config.scripts.stream_threshold,
+            None => true,
+        }
+    }
+
+    fn is_text_content(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .map(|ct| {
+                let ct = ct.to_lowercase();
+                ct.starts_with("text/") || ct.contains("json") || ct.contains("javascript") || ct.contains("xml")
+            })
+            .unwrap_or(false)
+    }
+
+    /// Streams a body through the rules, and for HTML pages through the head scripts and
+    /// the `integrity=` fix as well. Hits and `once_per` injections are recorded as the
+    /// scripts actually change the body.
+    fn process_streamed(&self, res: Response<Body>, ctx: &RequestContext, rewrites: StreamRewrites) -> Result<Response<Body>> {
+        let domain = &ctx.domain;
+        let (mut parts, body) = res.into_parts();
+        let status = parts.status.as_u16();
+
+        let mut headers_map = self.headers_to_map(&parts.headers);
+        let injection_result = self.script_manager().apply_response_header_injections(ctx, status, &headers_map);
+        injection_result.apply_to(&mut headers_map, &mut String::new());
+        self.record_injections(ctx, "response", &injection_result);
+
+        let is_html = headers_map.get("content-type").is_some_and(|ct| ct.to_lowercase().contains("html"));
+        let StreamRewrites { rules, replacing, head } = rewrites;
+        let head = if is_html { head } else { Vec::new() };
+        let fix_integrity = is_html && self.config.scripts.integrity_mode != IntegrityMode::Off && self.script_manager().modifies_any_assets();
+        let rewritten = !rules.is_empty() || !head.is_empty() || fix_integrity;
+        if rewritten {
+            headers_map.remove("content-length");
+            self.mark_modified(&mut headers_map, None, ctx);
+        }
+        parts.headers = self.map_to_headers(&headers_map)?;
+        self.start_session(&mut parts.headers, ctx);
+
+        if !rewritten {
+            return Ok(Response::from_parts(parts, body));
+        }
+
+        debug!("Streaming {} rewrite rule(s) and {} head script(s) for domain: {}", rules.len(), head.len(), domain);
+        let mut rewriter = RollingReplacer::new(rules, self.config.scripts.stream_window, self.stream_limits(ctx));
+        if !replacing.is_empty() {
+            let (scripts, origins, ctx) = (self.script_manager.clone(), self.injected_origins.clone(), ctx.clone());
+            rewriter = rewriter.on_applied(Box::new(move |index| {
+                scripts.read().unwrap().mark_streamed(&replacing[index], &ctx);
+                if is_html {
+                    remember_origin(&origins, &ctx.url);
+                }
+            }));
+        }
+        if fix_integrity {
+            let (scripts, hashes, page) = (self.script_manager.clone(), self.asset_integrity.clone(), ctx.url.clone());
+            let mode = self.config.scripts.integrity_mode;
+            rewriter = rewriter.with_fixup(Fixup {
+                span: integrity_patterns().0.clone(),
+                apply: Box::new(move |text| {
+                    fix_integrity_with(text, &page, mode, &hashes, &scripts);
+                }),
+            });
+        }
+        if head.is_empty() {
+            return Ok(Response::from_parts(parts, streaming::rewrite_body(body, rewriter)));
+        }
+
+        let (scripts, origins, ctx) = (self.script_manager.clone(), self.injected_origins.clone(), ctx.clone());
+        let inject = Box::new(move |page: &mut String| {
+            let result = scripts.read().unwrap().inject_head(&head, &ctx, status, page);
+            if result.body.is_some() {
+                remember_origin(&origins, &ctx.url);
+                info!("Applied response injections for domain: {} ({})", ctx.domain, result.applied.join(", "));
+            }
+            if let Some(injected) = result.body {
+                *page = injected;
+            }
+        });
+        let rewriter = HeadInjector::new(inject, self.config.scripts.stream_threshold, rewriter);
+        Ok(Response::from_parts(parts, streaming::rewrite_body(body, rewriter)))
+    }
+
+    /// `scripts.max_added_bytes` and `scripts.max_replacements` for a streamed body. Scripts'
+    /// own limits need the whole body; streamed ones share the global limits.
+    fn stream_limits(&self, ctx: &RequestContext) -> Limits {
+        Limits::new(self.config.scripts.max_added_bytes, self.config.scripts.max_replacements, ctx.url.to_string())
+    }
+
+    /// Records the SRI hash of an asset as modified by the proxy, for pages loaded later.
+    fn remember_integrity(&self, ctx: &RequestContext, body: &str) {
+        if self.config.scripts.integrity_mode != IntegrityMode::Recompute {
+            return;
+        }
+        let digest = Sha384::digest(body.as_bytes());
+        let hash = format!("sha384-{}", base64::engine::general_purpose::STANDARD.encode(digest));
+        let mut hashes = self.asset_integrity.lock().unwrap();
+        if hashes.len() >= INTEGRITY_CACHE_LIMIT {
+            hashes.clear();
+        }
+        hashes.insert(ctx.url.to_string(), hash);
+    }
+
+    /// Fixes `integrity=` on `<script>`/`<link>` tags whose asset may be modified by a
+    /// script, since browsers refuse assets that no longer match. Returns whether the
+    /// page changed.
+    fn fix_integrity(&self, body: &mut String, ctx: &RequestContext) -> bool {
+        fix_integrity_with(body, &ctx.url, self.config.scripts.integrity_mode, &self.asset_integrity, &self.script_manager)
+    }
+
+    /// Hands out the session cookie `once_per = "session"` scripts are tracked by.
+    fn start_session(&self, headers: &mut HeaderMap, ctx: &RequestContext) {
+        if ctx.new_session && self.script_manager().uses_sessions(ctx) {
+            if let Ok(value) = HeaderValue::from_str(&ctx.session_cookie()) {
+                headers.append("set-cookie", value);
+            }
+        }
+    }
+
+    fn has_no_transform(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all("cache-control")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+    }
+
+    fn mark_modified(&self, headers: &mut HashMap<String, String>, body: Option<&str>, ctx: &RequestContext) {
+        self.invalidate_validators(headers, body, ctx);
+        self.adjust_cache_headers(headers);
+    }
+
+    /// Keeps shared caches from serving an injected variant to other users.
+    fn adjust_cache_headers(&self, headers: &mut HashMap<String, String>) {
+        if self.config.scripts.mark_private {
+            let mut directives: Vec<String> = headers
+                .get("cache-control")
+                .map(|v| {
+                    v.split(',')
+                        .map(|d| d.trim().to_string())
+                        .filter(|d| {
+                            let name = d.split('=').next().unwrap_or("").to_lowercase();
+                            !d.is_empty() && name != "public" && name != "s-maxage"
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            if !directives.iter().any(|d| d.eq_ignore_ascii_case("private")) {
+                directives.push("private".to_string());
+            }
+            headers.insert("cache-control".to_string(), directives.join(", "));
+        }
+
+        if !self.config.scripts.vary.is_empty() {
+            let mut vary: Vec<String> = headers
+                .get("vary")
+                .map(|v| v.split(',').map(|h| h.trim().to_string()).filter(|h| !h.is_empty()).collect())
+                .unwrap_or_default();
+            for name in &self.config.scripts.vary {
+                if !vary.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+                    vary.push(name.clone());
+                }
+            }
+            headers.insert("vary".to_string(), vary.join(", "));
+        }
+    }
+
+    /// Keeps caches from pairing upstream validators with injected content. `body` is `None`
+    /// when the body is streamed, in which case a recomputed ETag is not possible and the
+    /// validators are stripped instead.
+    ///
+    /// With `refetch_on_script_change`, proxy-issued ETags end in `.rp-<script set hash>`
+    /// and Last-Modified is dropped so clients revalidate through the ETag only.
+    fn invalidate_validators(&self, headers: &mut HashMap<String, String>, body: Option<&str>, ctx: &RequestContext) {
+        let track = self.config.scripts.refetch_on_script_change;
+        let suffix = if track {
+            format!("{}{}", ETAG_MARKER, self.script_manager().script_set_hash(&ctx.domain))
+        } else {
+            String::new()
+        };
+
+        match (self.config.scripts.validator_mode, body) {
+            (ValidatorMode::Weaken, _) => {
+                if let Some(etag) = headers.get_mut("etag") {
+                    let opaque = etag.trim_start_matches("W/").trim_matches('"');
+                    *etag = format!("W/\"{}{}\"", opaque, suffix);
+                }
+                if track {
+                    headers.remove("last-modified");
+                }
+            }
+            (ValidatorMode::Recompute, Some(body)) => {
+                let digest = Sha256::digest(body.as_bytes());
+                let hash: String = digest.iter().take(16).map(|b| format!("{:02x}", b)).collect();
+                headers.insert("etag".to_string(), format!("\"{}{}\"", hash, suffix));
+                headers.remove("last-modified");
+            }
+            (ValidatorMode::Strip, _) | (ValidatorMode::Recompute, None) => {
+                headers.remove("etag");
+                headers.remove("last-modified");
+            }
+        }
+    }
+
+    /// Translates proxy-issued ETags in `If-None-Match` back into something the upstream
+    /// understands. If any of them was issued for a different script set, the conditional
+    /// headers are dropped so the upstream answers with a full 200 instead of a 304 that
+    /// would leave the client on stale injected content.
+    fn rewrite_conditional_headers(&self, ctx: &RequestContext, headers: &mut HashMap<String, String>) {
+        if !self.config.scripts.refetch_on_script_change {
+            return;
+        }
+        let if_none_match = match headers.get("if-none-match") {
+            Some(value) if value.contains(ETAG_MARKER) => value.clone(),
+            _ => return,
+        };
+
+        let current = self.script_manager().script_set_hash(&ctx.domain);
+        let mut upstream_tags = Vec::new();
+        for tag in if_none_match.split(',').map(|t| t.trim()) {
+            let weak = tag.starts_with("W/");
+            let opaque = tag.trim_start_matches("W/").trim_matches('"');
+            match opaque.rsplit_once(ETAG_MARKER) {
+                Some((_, hash)) if hash != current => {
+                    debug!("Script set for {} changed since {}; forcing full fetch", ctx.domain, tag);
+                    headers.remove("if-none-match");
+                    headers.remove("if-modified-since");
+                    return;
+                }
+                // Weakened upstream tags are passed back in their original form; recomputed
+                // tags mean nothing to the upstream and are dropped
+                Some((original, _)) if weak => upstream_tags.push(format!("W/\"{}\"", original)),
+                Some(_) => {}
+                None => upstream_tags.push(tag.to_string()),
+            }
+        }
+
+        if upstream_tags.is_empty() {
+            headers.remove("if-none-match");
+        } else {
+            headers.insert("if-none-match".to_string(), upstream_tags.join(", "));
+        }
     }
 
     fn headers_to_map(&self, headers: &HeaderMap) -> HashMap<String, String> {
@@ -126,34 +977,31 @@ impl HttpInjector {
         Ok(headers)
     }
 
-    pub fn create_blocked_response(&self, reason: &str) -> Response<Body> {
-        let body = format!(
-            r#"<!DOCTYPE html>
+    pub fn create_auth_required_response(&self) -> Response<Body> {
+        let body = r#"<!DOCTYPE html>
 <html>
 <head>
-    <title>Blocked by Rusty Proxy</title>
+    <title>Proxy Authentication Required</title>
     <style>
-        body {{ font-family: Arial, sans-serif; margin: 40px; }}
-        .error {{ color: #d32f2f; }}
-        .info {{ color: #1976d2; }}
+        body { font-family: Arial, sans-serif; margin: 40px; }
+        .error { color: #d32f2f; }
     </style>
 </head>
 <body>
-    <h1 class="error">Access Blocked</h1>
-    <p class="info">This request was blocked by Rusty Proxy.</p>
-    <p><strong>Reason:</strong> {}</p>
+    <h1 class="error">Proxy Authentication Required</h1>
+    <p>This proxy requires credentials. Use the configured auth token as the password.</p>
     <p><em>Powered by Rusty Proxy v0.1.0</em></p>
 </body>
-</html>"#,
-            reason
-        );
+</html>"#;
 
-        Response::builder()
-            .status(403)
+        let response = Response::builder()
+            .status(407)
+            .header("proxy-authenticate", "Basic realm=\"rusty-proxy\"")
             .header("content-type", "text/html")
             .header("content-length", body.len())
             .body(Body::from(body))
-            .unwrap()
+            .unwrap();
+        error_page::mark(response, "proxy authentication required")
     }
 
     pub fn create_error_response(&self, error: &str) -> Response<Body> {
@@ -177,11 +1025,283 @@ impl HttpInjector {
             error
         );
 
-        Response::builder()
+        let response = Response::builder()
             .status(500)
             .header("content-type", "text/html")
             .header("content-length", body.len())
             .body(Body::from(body))
-            .unwrap()
+            .unwrap();
+        error_page::mark(response, error)
+    }
+}
+
+/// `HttpInjector::fix_integrity` for the page at `page`, with what it needs passed in so
+/// a streamed body's rewriter can run it too.
+fn fix_integrity_with(body: &mut String, page: &Uri, mode: IntegrityMode, hashes: &Mutex<HashMap<String, String>>, scripts: &SharedScripts) -> bool {
+    if mode == IntegrityMode::Off || !body.contains("integrity") {
+        return false;
     }
-}
\ No newline at end of file
+
+    let (tag_re, url_re, integrity_re) = integrity_patterns();
+    let hashes = hashes.lock().unwrap();
+
+    let mut changed = false;
+    let fixed = tag_re.replace_all(body, |caps: &regex::Captures| {
+        let tag = &caps[0];
+        let url = match url_re.captures(tag).and_then(|c| resolve_url(page, &c[1])) {
+            Some(url) => url,
+            None => return tag.to_string(),
+        };
+        if !url.host().map(|host| scripts.read().unwrap().modifies_assets(host)).unwrap_or(false) {
+            return tag.to_string();
+        }
+
+        changed = true;
+        match hashes.get(&url.to_string()).filter(|_| mode == IntegrityMode::Recompute) {
+            Some(hash) => integrity_re
+                .replace(tag, format!(" integrity=\"{}\"", hash).as_str())
+                .to_string(),
+            None => integrity_re.replace(tag, "").to_string(),
+        }
+    });
+
+    if changed {
+        debug!("Fixed integrity attributes on {}", page);
+        *body = fixed.into_owned();
+    }
+    changed
+}
+
+/// Notes that scripts were injected into a page of `url`'s origin, so it may call the
+/// page API.
+fn remember_origin(origins: &Mutex<HashSet<String>>, url: &Uri) {
+    let Some(origin) = page_api::origin(url) else {
+        return;
+    };
+    let mut origins = origins.lock().unwrap();
+    if origins.len() >= INJECTED_ORIGIN_LIMIT {
+        origins.clear();
+    }
+    origins.insert(origin);
+}
+
+/// The tag, URL and attribute patterns `fix_integrity` works with.
+fn integrity_patterns() -> (&'static Regex, &'static Regex, &'static Regex) {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    static URL: OnceLock<Regex> = OnceLock::new();
+    static INTEGRITY: OnceLock<Regex> = OnceLock::new();
+    (
+        TAG.get_or_init(|| Regex::new(r#"(?is)<(?:script|link)\b[^>]*\bintegrity\s*=[^>]*>"#).unwrap()),
+        URL.get_or_init(|| Regex::new(r#"(?i)\b(?:src|href)\s*=\s*["']([^"']+)["']"#).unwrap()),
+        INTEGRITY.get_or_init(|| Regex::new(r#"(?i)\s+integrity\s*=\s*(?:"[^"]*"|'[^']*'|[^\s>]+)"#).unwrap()),
+    )
+}
+
+/// Resolves a reference (asset URL, redirect `Location`) relative to `base` to an absolute URL.
+pub fn resolve_url(base: &Uri, reference: &str) -> Option<Uri> {
+    let scheme = base.scheme_str().unwrap_or("http");
+    let authority = base.authority()?.as_str();
+    let absolute = if reference.contains("://") {
+        reference.to_string()
+    } else if let Some(rest) = reference.strip_prefix("//") {
+        format!("{}://{}", scheme, rest)
+    } else if reference.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, reference)
+    } else {
+        let dir = base.path().rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+        format!("{}://{}{}/{}", scheme, authority, dir, reference)
+    };
+    absolute.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Bytes;
+    use std::sync::RwLock;
+
+    fn injector() -> HttpInjector {
+        let scripts = ScriptManager::open("/nonexistent/rusty-proxy-scripts", false).unwrap();
+        HttpInjector::new(Arc::new(RwLock::new(scripts)), Config::default())
+    }
+
+    async fn send(injector: &HttpInjector, method: Method, body: Option<&[u8]>) -> Request<Body> {
+        let url = "http://dav.example.com/files/report.xml";
+        let mut builder = Request::builder().method(method.clone()).uri(url).header("host", "dav.example.com");
+        let body = match body {
+            Some(body) => {
+                builder = builder.header("content-length", body.len());
+                Body::from(body.to_vec())
+            }
+            None => Body::empty(),
+        };
+        let req = builder.body(body).unwrap();
+        injector.process_request(req, &RequestContext::for_request(method, url)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_bodies_pass_through_for_every_method() {
+        let injector = injector();
+        // Not UTF-8, so a lossy round trip through a String would show
+        let body: &[u8] = b"<?xml version=\"1.0\"?><propfind xmlns=\"DAV:\"/>\xff\x00\xfe";
+        let methods = ["PROPFIND", "MKCOL", "REPORT", "PATCH", "DELETE", "PURGE-CACHE"];
+        for method in methods.map(|name| Method::from_bytes(name.as_bytes()).unwrap()) {
+            let sent = send(&injector, method.clone(), Some(body)).await;
+            assert_eq!(sent.method(), method);
+            assert_eq!(sent.headers()["content-length"], body.len().to_string().as_str(), "{}", method);
+            assert_eq!(body::to_bytes(sent.into_body()).await.unwrap(), body, "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_kept_only_while_a_repl_watches() {
+        let injector = injector();
+        let ctx = RequestContext::for_request(Method::GET, "http://shop.example.com/");
+        let respond = || Response::builder().header("content-type", "text/html").header("content-length", 13).body(Body::from("<html></html>")).unwrap();
+        injector.process_response(respond(), &ctx).await.unwrap();
+        assert!(injector.last_exchange.lock().unwrap().is_none());
+
+        injector.watch_exchanges();
+        injector.process_response(respond(), &ctx).await.unwrap();
+        assert_eq!(injector.last_exchange().unwrap()["body_bytes"], 13);
+    }
+
+    #[tokio::test]
+    async fn pages_are_remembered_once_scripts_go_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = r#"{"name": "probe", "description": "", "version": "1", "author": "", "target_domains": ["shop.example.com"],
+            "inject_type": "JavaScript", "script_content": "console.log(1)", "headers": {}, "enabled": true}"#;
+        std::fs::write(dir.path().join("probe.json"), script).unwrap();
+        let injector = HttpInjector::new(Arc::new(RwLock::new(ScriptManager::open(dir.path(), false).unwrap())), Config::default());
+        let page = "<html><head></head><body></body></html>";
+        for url in ["http://shop.example.com:80/cart", "http://news.example.com/"] {
+            let response = Response::builder().header("content-type", "text/html").header("content-length", page.len()).body(Body::from(page)).unwrap();
+            injector.process_response(response, &RequestContext::for_request(Method::GET, url)).await.unwrap();
+        }
+        assert!(injector.injected_into("http://shop.example.com"));
+        assert!(!injector.injected_into("http://news.example.com"));
+        assert!(!injector.injected_into("https://shop.example.com"));
+    }
+
+    fn scripted(scripts: &[(&str, &str)]) -> (tempfile::TempDir, HttpInjector) {
+        scripted_with(Config::default(), scripts)
+    }
+
+    fn scripted_with(config: Config, scripts: &[(&str, &str)]) -> (tempfile::TempDir, HttpInjector) {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, fields) in scripts {
+            let headers = if fields.contains(r#""headers":"#) { "" } else { r#""headers": {}, "# };
+            let script = format!(
+                r#"{{"name": "{}", "description": "", "version": "1", "author": "", {}"enabled": true, {}}}"#,
+                name, headers, fields
+            );
+            std::fs::write(dir.path().join(format!("{}.json", name)), script).unwrap();
+        }
+        let injector = HttpInjector::new(Arc::new(RwLock::new(ScriptManager::open(dir.path(), false).unwrap())), config);
+        (dir, injector)
+    }
+
+    #[tokio::test]
+    async fn explain_names_the_scripts_a_request_runs() {
+        let mut config = Config::default();
+        config.scripts.allowed_domains = vec!["shop.example.com".to_string()];
+        config.scripts.blocked_domains = vec!["ads.shop.example.com".to_string()];
+        let (_dir, injector) = scripted_with(
+            config,
+            &[("tag", r#""target_domains": ["*"], "inject_type": "Header", "headers": {"x-tag": "1"}"#)],
+        );
+        for url in ["http://shop.example.com/cart", "http://other.example.com/", "http://ads.shop.example.com/"] {
+            let ctx = RequestContext::for_request(Method::GET, url);
+            let req = Request::builder().uri(url).body(Body::empty()).unwrap();
+            let ran = injector.process_request(req, &ctx).await.unwrap().headers().contains_key("x-tag");
+            let fire = injector.explain(&ctx, None)["fire"].clone();
+            assert_eq!(fire == json!(["tag"]), ran, "{}: explain says {}", url, fire);
+        }
+    }
+
+    /// Streams `chunks` as a chunked HTML response through the injector.
+    async fn stream(injector: &HttpInjector, url: &str, chunks: &[&str]) -> String {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = chunks.iter().map(|chunk| Ok(Bytes::copy_from_slice(chunk.as_bytes()))).collect();
+        let response = Response::builder()
+            .header("content-type", "text/html; charset=utf-8")
+            .body(Body::wrap_stream(futures_util::stream::iter(chunks)))
+            .unwrap();
+        let response = injector.process_response(response, &RequestContext::for_request(Method::GET, url)).await.unwrap();
+        assert!(!response.headers().contains_key("content-length"));
+        String::from_utf8(body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn streamed_pages_get_payloads_where_buffered_ones_do() {
+        let (_dir, injector) = scripted(&[(
+            "probe",
+            r#""target_domains": ["shop.example.com"], "inject_type": "JavaScript", "script_content": "probe()""#,
+        )]);
+        let page = stream(
+            &injector,
+            "http://shop.example.com/",
+            &["<!doctype html><HTML><Head><!-- </head> --><noscript></head></noscr", "ipt><meta charset=\"utf-8\"><ti", "tle>Shop</title></HEAD><body></body></html>"],
+        )
+        .await;
+        assert_eq!(page.matches("probe()").count(), 1);
+        let payload = page.find("probe()").unwrap();
+        assert!(payload > page.find("<meta charset").unwrap() && payload < page.find("<ti").unwrap(), "{}", page);
+
+        let page = stream(&injector, "http://shop.example.com/bare", &["<html><bo", "dy>hi</body></html>"]).await;
+        assert!(page.starts_with("<html><head><!--rusty-proxy:") && page.ends_with("</head><body>hi</body></html>"), "{}", page);
+    }
+
+    #[tokio::test]
+    async fn streamed_pages_already_carrying_the_payload_are_left_alone() {
+        let (_dir, injector) = scripted(&[(
+            "probe",
+            r#""target_domains": ["shop.example.com"], "inject_type": "JavaScript", "script_content": "probe()""#,
+        )]);
+        let once = stream(&injector, "http://shop.example.com/", &["<html><head></head><body></body></html>"]).await;
+        let twice = stream(&injector, "http://shop.example.com/", &[&once]).await;
+        assert_eq!(twice, once);
+    }
+
+    #[tokio::test]
+    async fn once_per_is_spent_only_by_a_real_insertion() {
+        let (_dir, injector) = scripted(&[(
+            "probe",
+            r#""target_domains": ["shop.example.com"], "inject_type": "JavaScript", "script_content": "probe()", "once_per": "client""#,
+        )]);
+        let fragment = stream(&injector, "http://shop.example.com/fragment", &["<p>no document here</p>"]).await;
+        assert_eq!(fragment, "<p>no document here</p>");
+        assert_eq!(injector.script_manager().hit_counts()["probe"], 0);
+
+        let page = stream(&injector, "http://shop.example.com/", &["<html><head></head>", "<body></body></html>"]).await;
+        assert!(page.contains("probe()"), "{}", page);
+        assert_eq!(injector.script_manager().hit_counts()["probe"], 1);
+        let again = stream(&injector, "http://shop.example.com/", &["<html><head></head><body></body></html>"]).await;
+        assert!(!again.contains("probe()"));
+    }
+
+    #[tokio::test]
+    async fn streamed_pages_drop_integrity_of_modified_assets() {
+        let (_dir, injector) = scripted(&[(
+            "lib",
+            r#""target_domains": ["cdn.example.com"], "inject_type": "ResponseReplace", "pattern": "old", "script_content": "new""#,
+        )]);
+        let page = stream(
+            &injector,
+            "http://shop.example.com/",
+            &["<html><head><script src=\"https://cdn.example.com/lib.js\" integ", "rity=\"sha384-abc\"></script></head></html>"],
+        )
+        .await;
+        assert_eq!(page, "<html><head><script src=\"https://cdn.example.com/lib.js\"></script></head></html>");
+    }
+
+    #[tokio::test]
+    async fn bodiless_requests_stay_empty() {
+        let injector = injector();
+        for method in ["PROPFIND", "MKCOL", "DELETE", "GET", "PURGE-CACHE"].map(|name| Method::from_bytes(name.as_bytes()).unwrap()) {
+            let sent = send(&injector, method.clone(), None).await;
+            assert!(!sent.headers().contains_key("content-length"), "{}", method);
+            assert!(!sent.headers().contains_key("transfer-encoding"), "{}", method);
+            assert!(body::to_bytes(sent.into_body()).await.unwrap().is_empty(), "{}", method);
+        }
+    }
+}
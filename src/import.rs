@@ -0,0 +1,611 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use sxd_document::dom::{ChildOfElement, ChildOfRoot, Element};
+use sxd_document::parser;
+
+use crate::history;
+use crate::openapi::{self, Spec};
+use crate::pattern;
+use crate::script_manager::{InjectType, InjectionScript};
+
+/// Tools whose rules `script import` understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Charles Proxy rewrite sets, as exported from Tools > Rewrite (XML)
+    Charles,
+    /// Fiddler AutoResponder rules (`.farx`)
+    Fiddler,
+    /// `map_local` / `map_remote` options from a mitmproxy `config.yaml`
+    Mitmproxy,
+    /// An OpenAPI 3 or Swagger 2 spec (JSON or YAML), stubbed with a `Mock` per operation
+    OpenApi,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "charles" => Some(Format::Charles),
+            "fiddler" => Some(Format::Fiddler),
+            "mitmproxy" => Some(Format::Mitmproxy),
+            "openapi" => Some(Format::OpenApi),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Charles => "charles",
+            Format::Fiddler => "fiddler",
+            Format::Mitmproxy => "mitmproxy",
+            Format::OpenApi => "openapi",
+        }
+    }
+
+    fn author(self) -> &'static str {
+        match self {
+            Format::Charles => "Imported from Charles Proxy",
+            Format::Fiddler => "Imported from Fiddler",
+            Format::Mitmproxy => "Imported from mitmproxy",
+            Format::OpenApi => "Generated from OpenAPI",
+        }
+    }
+}
+
+/// The scripts converted from a rules file, and why the other rules weren't.
+#[derive(Debug, Default)]
+pub struct Imported {
+    pub scripts: Vec<InjectionScript>,
+    pub skipped: Vec<String>,
+}
+
+impl Imported {
+    fn skip(&mut self, rule: &str, reason: impl std::fmt::Display) {
+        self.skipped.push(format!("{}: {}", rule, reason));
+    }
+}
+
+/// Converts the rules in `path`. Rules with no equivalent among the script types (status
+/// overrides, redirects, rerouting to another host) are reported, not approximated.
+pub fn import(format: Format, path: &Path) -> Result<Imported> {
+    let content = fs::read_to_string(path)?;
+    let content = content.trim_start_matches('\u{feff}');
+    // Local files named by the rules are relative to the rules file
+    let base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut imported = match format {
+        Format::Charles => charles(content)?,
+        Format::Fiddler => fiddler(content, &base)?,
+        Format::Mitmproxy => mitmproxy(content, &base),
+        Format::OpenApi => openapi(content)?,
+    };
+    for script in &mut imported.scripts {
+        script.version = "1.0.0".to_string();
+        script.author = format.author().to_string();
+    }
+    Ok(imported)
+}
+
+/// Writes imported scripts to `dir`, below `scripts_dir`, as `<name>.json`, keeping
+/// existing files unless `force` is set. Returns the names written and the names kept.
+pub fn write(scripts: &[InjectionScript], scripts_dir: &Path, dir: &Path, force: bool) -> Result<(Vec<String>, Vec<String>)> {
+    fs::create_dir_all(dir)?;
+    let (mut written, mut kept) = (Vec::new(), Vec::new());
+    for script in scripts {
+        let path = dir.join(format!("{}.json", script.name));
+        if path.exists() && !force {
+            kept.push(script.name.clone());
+            continue;
+        }
+        history::write(scripts_dir, &path, serde_json::to_string_pretty(script)?.as_bytes())?;
+        written.push(script.name.clone());
+    }
+    Ok((written, kept))
+}
+
+fn charles(content: &str) -> Result<Imported> {
+    let package = parser::parse(content).map_err(|e| anyhow!("Invalid XML: {:?}", e))?;
+    let doc = package.as_document();
+    let Some(root) = doc.root().children().into_iter().find_map(|child| match child {
+        ChildOfRoot::Element(element) => Some(element),
+        _ => None,
+    }) else {
+        bail!("No rewrite sets found");
+    };
+
+    let mut imported = Imported::default();
+    let sets = match root.name().local_part() {
+        "rewriteSet" => vec![root],
+        _ => children(root, "rewriteSet"),
+    };
+    for (set_index, set) in sets.into_iter().enumerate() {
+        let set_name = match text(set, "name") {
+            name if name.is_empty() => format!("set {}", set_index + 1),
+            name => name,
+        };
+        let set_active = text(set, "active") != "false";
+
+        let locations: Vec<Element> = child(set, "hosts")
+            .and_then(|hosts| child(hosts, "locationPatterns"))
+            .map(|patterns| children(patterns, "locationMatch"))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|matched| text(*matched, "enabled") != "false")
+            .filter_map(|matched| child(matched, "location"))
+            .collect();
+        let (target_domains, url_pattern) = charles_locations(&locations);
+
+        let rules = child(set, "rules").map(|rules| children(rules, "rewriteRule")).unwrap_or_default();
+        for (rule_index, rule) in rules.into_iter().enumerate() {
+            let label = format!("{} rule {}", set_name, rule_index + 1);
+            let base = InjectionScript {
+                name: slug(&label),
+                description: format!("Charles rewrite \"{}\", rule {}", set_name, rule_index + 1),
+                target_domains: target_domains.clone(),
+                url_pattern: url_pattern.clone(),
+                enabled: set_active && text(rule, "active") != "false",
+                ..Default::default()
+            };
+            let on_request = text(rule, "matchRequest") == "true";
+            let on_response = text(rule, "matchResponse") == "true";
+            let rule_type = text(rule, "ruleType");
+            match rule_type.as_str() {
+                // Add, modify and remove header
+                "1" | "2" | "3" => {
+                    let removed = text(rule, "matchHeader");
+                    let header = text(rule, "newHeader");
+                    let mut headers = HashMap::new();
+                    if header.is_empty() {
+                        if removed.is_empty() {
+                            imported.skip(&label, "no header named");
+                            continue;
+                        }
+                        // An empty value strips the header (with `header-stripping`)
+                        headers.insert(removed, String::new());
+                    } else {
+                        if !removed.is_empty() && !removed.eq_ignore_ascii_case(&header) {
+                            headers.insert(removed, String::new());
+                        }
+                        headers.insert(header, text(rule, "newValue"));
+                    }
+                    if !on_request && !on_response {
+                        imported.skip(&label, "applies to neither requests nor responses");
+                        continue;
+                    }
+                    for (on, inject_type, suffix) in [
+                        (on_request, InjectType::Header, "request"),
+                        (on_response, InjectType::ResponseHeader, "response"),
+                    ] {
+                        if on {
+                            imported.scripts.push(InjectionScript {
+                                name: if on_request && on_response { format!("{}-{}", base.name, suffix) } else { base.name.clone() },
+                                inject_type,
+                                headers: headers.clone(),
+                                ..base.clone()
+                            });
+                        }
+                    }
+                }
+                // Body
+                "7" => {
+                    if !on_response {
+                        imported.skip(&label, "request bodies can only be appended to, not rewritten");
+                        continue;
+                    }
+                    let value = text(rule, "matchValue");
+                    let mut regex = if text(rule, "matchValueRegex") == "true" { value } else { regex::escape(&value) };
+                    if text(rule, "matchWholeValue") == "true" {
+                        regex = format!(r"\A(?:{})\z", regex);
+                    }
+                    if text(rule, "caseSensitive") != "true" {
+                        regex = format!("(?i){}", regex);
+                    }
+                    if let Err(e) = pattern::compile(&regex) {
+                        imported.skip(&label, format!("unusable pattern: {}", e));
+                        continue;
+                    }
+                    let replacement = text(rule, "newValue");
+                    imported.scripts.push(InjectionScript {
+                        inject_type: InjectType::ResponseReplace,
+                        pattern: Some(regex),
+                        script_content: if text(rule, "newValueRegex") == "true" { replacement } else { literal(&replacement) },
+                        ..base
+                    });
+                    if on_request {
+                        imported.skip(&label, "only the response half was imported; request bodies can't be rewritten");
+                    }
+                }
+                other => imported.skip(&label, format!("rule type {} (hosts, paths, query parameters, statuses) has no script equivalent", other)),
+            }
+        }
+    }
+    Ok(imported)
+}
+
+/// Target domains and a URL regex covering a rewrite set's locations. No locations, or
+/// one matching every host, applies the set everywhere.
+fn charles_locations(locations: &[Element]) -> (Vec<String>, Option<String>) {
+    if locations.is_empty() {
+        return (vec!["*".to_string()], None);
+    }
+    let mut domains = Vec::new();
+    let mut alternatives = Vec::new();
+    for location in locations {
+        let protocol = text(*location, "protocol");
+        let host = text(*location, "host");
+        let port = text(*location, "port");
+        let path = text(*location, "path");
+        let query = text(*location, "query");
+
+        let domain = if host.is_empty() { "*".to_string() } else { host.clone() };
+        let exact = !domain[domain.strip_prefix("*.").map_or(0, |_| 2)..].contains(['*', '?']);
+        domains.push(if exact { domain } else { "*".to_string() });
+
+        let scheme = if protocol.is_empty() { "https?".to_string() } else { regex::escape(&protocol) };
+        let host = if host.is_empty() { "[^/:]*".to_string() } else { wildcard(&host, "[^/:]") };
+        let port = if port.is_empty() { "(?::\\d+)?".to_string() } else { format!(":{}", regex::escape(&port)) };
+        let path = if path.is_empty() { "(?:/[^?]*)?".to_string() } else { wildcard(&path, "[^?]") };
+        let query = if query.is_empty() { "(?:\\?.*)?".to_string() } else { format!("\\?{}", wildcard(&query, ".")) };
+        alternatives.push(format!("{}://{}{}{}{}", scheme, host, port, path, query));
+    }
+    domains.sort();
+    domains.dedup();
+    if domains.contains(&"*".to_string()) {
+        domains = vec!["*".to_string()];
+    }
+    // Charles matches hosts case-insensitively, and a location covers the whole URL
+    let url_pattern = format!("(?i)^(?:{})$", alternatives.join("|"));
+    (domains, Some(url_pattern))
+}
+
+fn fiddler(content: &str, base: &Path) -> Result<Imported> {
+    let package = parser::parse(content).map_err(|e| anyhow!("Invalid XML: {:?}", e))?;
+    let doc = package.as_document();
+    let mut rules = Vec::new();
+    for child in doc.root().children() {
+        if let ChildOfRoot::Element(element) = child {
+            collect(element, "ResponseRule", &mut rules);
+        }
+    }
+    if rules.is_empty() {
+        bail!("No AutoResponder rules found");
+    }
+
+    let mut imported = Imported::default();
+    for (index, rule) in rules.into_iter().enumerate() {
+        let label = format!("rule {}", index + 1);
+        let matcher = rule.attribute_value("Match").unwrap_or_default();
+        let action = rule.attribute_value("Action").unwrap_or_default();
+        let url_pattern = match fiddler_match(matcher) {
+            Ok(url_pattern) => url_pattern,
+            Err(reason) => {
+                imported.skip(&label, reason);
+                continue;
+            }
+        };
+        let base_script = InjectionScript {
+            name: format!("rule-{}", index + 1),
+            description: format!("Fiddler AutoResponder: {} -> {}", matcher, action),
+            target_domains: vec!["*".to_string()],
+            url_pattern,
+            enabled: rule.attribute_value("Enabled") != Some("false"),
+            ..Default::default()
+        };
+
+        if let Some(header) = action.strip_prefix("*header:") {
+            let Some((name, value)) = header.split_once('=') else {
+                imported.skip(&label, "malformed *header action");
+                continue;
+            };
+            imported.scripts.push(InjectionScript {
+                inject_type: InjectType::ResponseHeader,
+                headers: HashMap::from([(name.to_string(), value.to_string())]),
+                ..base_script
+            });
+        } else if action.eq_ignore_ascii_case("*CORSPreflightAllow") {
+            imported.scripts.push(InjectionScript {
+                inject_type: InjectType::ResponseHeader,
+                headers: HashMap::from([
+                    ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+                    ("Access-Control-Allow-Methods".to_string(), "GET, POST, PUT, PATCH, DELETE, OPTIONS".to_string()),
+                ]),
+                answer_preflight: true,
+                echo_origin: true,
+                ..base_script
+            });
+        } else if action.starts_with('*') {
+            imported.skip(&label, format!("action {} has no script equivalent", action));
+        } else if action.starts_with("http://") || action.starts_with("https://") {
+            imported.skip(&label, "serving another URL's response needs rerouting, which scripts can't do");
+        } else {
+            match local_body(&base.join(action.replace('\\', "/"))) {
+                Ok(body) => imported.scripts.push(replace_body(base_script, body)),
+                Err(reason) => imported.skip(&label, reason),
+            }
+        }
+    }
+    Ok(imported)
+}
+
+/// A Fiddler `Match` as a URL regex: `regex:`, `EXACT:`, `*` for everything, or else a
+/// case-insensitive substring.
+fn fiddler_match(matcher: &str) -> std::result::Result<Option<String>, String> {
+    let url_pattern = if let Some(regex) = matcher.strip_prefix("regex:") {
+        // .NET inline options; `n` (explicit capture) has no counterpart and changes nothing here
+        match regex.strip_prefix("(?").and_then(|rest| rest.split_once(')')) {
+            Some((flags, rest)) if flags.chars().all(|c| c.is_ascii_alphabetic()) => {
+                let flags: String = flags.chars().filter(|c| "imsx".contains(*c)).collect();
+                if flags.is_empty() { rest.to_string() } else { format!("(?{}){}", flags, rest) }
+            }
+            _ => regex.to_string(),
+        }
+    } else if let Some(url) = matcher.strip_prefix("EXACT:") {
+        format!("^{}$", regex::escape(url))
+    } else if matcher.starts_with("NOT:") || matcher.starts_with("METHOD:") || matcher.starts_with("HEADER:") || matcher.starts_with("URLWithBody:") {
+        return Err(format!("match {} has no script equivalent", matcher));
+    } else if matcher == "*" || matcher.is_empty() {
+        return Ok(None);
+    } else {
+        format!("(?i){}", regex::escape(matcher))
+    };
+    match pattern::compile(&url_pattern) {
+        Ok(_) => Ok(Some(url_pattern)),
+        Err(e) => Err(format!("unusable pattern: {}", e)),
+    }
+}
+
+fn mitmproxy(content: &str, base: &Path) -> Imported {
+    let mut imported = Imported::default();
+    let options = yaml_lists(content, &["map_local", "map_remote"]);
+    if options.is_empty() {
+        imported.skipped.push("no map_local or map_remote options found".to_string());
+    }
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (option, spec) in options {
+        let count = counts.entry(option).or_default();
+        *count += 1;
+        let label = format!("{} {}", option, count);
+        if option == "map_remote" {
+            imported.skip(&label, "rerouting requests to another URL has no script equivalent");
+            continue;
+        }
+
+        // `|filter|url-regex|path`, where the first character is the separator and the
+        // filter is optional
+        let mut chars = spec.chars();
+        let Some(separator) = chars.next() else {
+            continue;
+        };
+        let parts: Vec<&str> = chars.as_str().split(separator).collect();
+        let (filter, regex, path) = match parts[..] {
+            [regex, path] => ("", regex, path),
+            [filter, regex, path] => (filter, regex, path),
+            _ => {
+                imported.skip(&label, format!("malformed spec {}", spec));
+                continue;
+            }
+        };
+        let target_domains = match filter.trim() {
+            "" | ".*" => vec!["*".to_string()],
+            filter => match filter.strip_prefix("~d ") {
+                Some(domain) if !domain.contains(' ') => vec![domain.trim().to_string()],
+                _ => {
+                    imported.skip(&label, format!("filter {} has no script equivalent (only ~d is supported)", filter));
+                    continue;
+                }
+            },
+        };
+        if let Err(e) = pattern::compile(regex) {
+            imported.skip(&label, format!("unusable pattern: {}", e));
+            continue;
+        }
+
+        let path = expand_home(path);
+        let path = if path.is_absolute() { path } else { base.join(path) };
+        if path.is_dir() {
+            imported.skip(&label, "directories map each URL to a different file, which one script can't");
+            continue;
+        }
+        let script = InjectionScript {
+            name: format!("{}-{}", option.replace('_', "-"), count),
+            description: format!("mitmproxy {}: {}", option, spec),
+            target_domains,
+            url_pattern: Some(regex.to_string()),
+            enabled: true,
+            ..Default::default()
+        };
+        match local_body(&path) {
+            Ok(body) => imported.scripts.push(replace_body(script, body)),
+            Err(reason) => imported.skip(&label, reason),
+        }
+    }
+    imported
+}
+
+/// The items of the top-level YAML lists named in `keys`, in block (`- item`) or flow
+/// (`[a, b]`) style, as `(key, item)` in file order. Enough YAML for mitmproxy's options
+/// file, where these lists hold plain or quoted strings.
+fn yaml_lists<'k>(content: &str, keys: &[&'k str]) -> Vec<(&'k str, String)> {
+    let mut items = Vec::new();
+    let mut current = None;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with([' ', '\t', '-']) {
+            current = None;
+            if let Some((key, rest)) = trimmed.split_once(':') {
+                current = keys.iter().find(|k| **k == key.trim()).copied();
+                let rest = rest.trim();
+                if let (Some(key), Some(flow)) = (current, rest.strip_prefix('[').and_then(|r| r.strip_suffix(']'))) {
+                    items.extend(flow.split(',').map(unquote).filter(|item| !item.is_empty()).map(|item| (key, item)));
+                }
+            }
+        } else if let (Some(key), Some(item)) = (current, trimmed.strip_prefix('-')) {
+            items.push((key, unquote(item)));
+        }
+    }
+    items
+}
+
+/// A `Mock` per operation answering with its first success response and an example body
+/// built from the schema, for the spec's server hosts. Path parameters become named
+/// groups, so a body can use `{{url:petId}}`.
+fn openapi(content: &str) -> Result<Imported> {
+    let spec = Spec::parse(content)?;
+    let servers: Vec<openapi::Server> = spec.servers().into_iter().filter(|server| !server.host.is_empty()).collect();
+    if servers.is_empty() {
+        bail!("The spec names no server host; add an absolute `servers` URL (or `host` for Swagger 2)");
+    }
+    let mut target_domains: Vec<String> = servers.iter().map(|server| server.host.clone()).collect();
+    target_domains.dedup();
+    let mut base_paths: Vec<String> = servers.iter().map(|server| regex::escape(&server.base_path)).collect();
+    base_paths.sort();
+    base_paths.dedup();
+    let base = match &base_paths[..] {
+        [only] => only.clone(),
+        paths => format!("(?:{})", paths.join("|")),
+    };
+
+    let api = slug(spec.title());
+    let mut imported = Imported::default();
+    for operation in spec.operations() {
+        let label = format!("{} {}", operation.method, operation.path);
+        let Some(mock) = spec.mock_response(&operation) else {
+            imported.skip(&label, "no responses documented");
+            continue;
+        };
+        let mut headers = HashMap::new();
+        if let Some(content_type) = mock.content_type {
+            headers.insert("Content-Type".to_string(), content_type);
+        }
+        imported.scripts.push(InjectionScript {
+            name: slug(&format!("{} {}", api, operation.id().unwrap_or(&label))),
+            description: match operation.summary() {
+                Some(summary) => format!("Mock of {} ({}): {}", label, spec.title(), summary),
+                None => format!("Mock of {} ({})", label, spec.title()),
+            },
+            target_domains: target_domains.clone(),
+            url_pattern: Some(format!("^[a-z]+://[^/]+{}{}/?(?:[?#].*)?$", base, openapi::path_regex(operation.path))),
+            inject_type: InjectType::Mock,
+            script_content: mock.body,
+            headers,
+            status: Some(mock.status),
+            methods: vec![operation.method.clone()],
+            enabled: true,
+            ..Default::default()
+        });
+    }
+    if imported.scripts.is_empty() {
+        imported.skipped.push("no operations found under `paths`".to_string());
+    }
+    Ok(imported)
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            return inner.to_string();
+        }
+    }
+    value.to_string()
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// A local response file as script content. Fiddler's captured responses (`.dat`) start
+/// with a status line and headers, which are dropped.
+fn local_body(path: &Path) -> std::result::Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("can't read {:?}: {}", path, e))?;
+    let body = String::from_utf8(bytes).map_err(|_| format!("{:?} is not UTF-8 text, which script content must be", path))?;
+    if body.starts_with("HTTP/") {
+        if let Some((_, rest)) = body.split_once("\r\n\r\n").or_else(|| body.split_once("\n\n")) {
+            return Ok(rest.to_string());
+        }
+    }
+    Ok(body)
+}
+
+/// Serves `body` in place of the upstream's. The request still goes upstream, and its
+/// status and headers are kept.
+fn replace_body(script: InjectionScript, body: String) -> InjectionScript {
+    InjectionScript {
+        inject_type: InjectType::ResponseReplace,
+        pattern: Some(r"(?s)\A.*\z".to_string()),
+        script_content: literal(&body),
+        ..script
+    }
+}
+
+/// `text` as a replacement taken literally.
+fn literal(text: &str) -> String {
+    text.replace('$', "$$")
+}
+
+/// A glob with `*` and `?` as a regex, the wildcards matching `class` characters.
+fn wildcard(glob: &str, class: &str) -> String {
+    glob.split('*')
+        .map(|part| part.split('?').map(regex::escape).collect::<Vec<_>>().join(class))
+        .collect::<Vec<_>>()
+        .join(&format!("{}*", class))
+}
+
+fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+fn child<'d>(element: Element<'d>, name: &str) -> Option<Element<'d>> {
+    children(element, name).into_iter().next()
+}
+
+fn children<'d>(element: Element<'d>, name: &str) -> Vec<Element<'d>> {
+    element
+        .children()
+        .into_iter()
+        .filter_map(|child| match child {
+            ChildOfElement::Element(e) if e.name().local_part() == name => Some(e),
+            _ => None,
+        })
+        .collect()
+}
+
+fn collect<'d>(element: Element<'d>, name: &str, found: &mut Vec<Element<'d>>) {
+    if element.name().local_part() == name {
+        found.push(element);
+    }
+    for child in element.children() {
+        if let ChildOfElement::Element(e) = child {
+            collect(e, name, found);
+        }
+    }
+}
+
+/// Text of the named child element; empty when it is missing.
+fn text(element: Element, name: &str) -> String {
+    child(element, name)
+        .map(|e| {
+            e.children()
+                .into_iter()
+                .filter_map(|child| match child {
+                    ChildOfElement::Text(t) => Some(t.text().to_string()),
+                    _ => None,
+                })
+                .collect::<String>()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::{hmac, signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::PrivateKeyDer;
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Claims whose values are shown in full when claims are logged; other strings are cut
+/// to their first characters.
+const VISIBLE_CLAIMS: [&str; 8] = ["iss", "aud", "azp", "scope", "scp", "roles", "typ", "token_use"];
+
+/// A decoded JSON Web Token (JWS compact form).
+pub struct Token {
+    pub header: Map<String, Value>,
+    pub claims: Map<String, Value>,
+    signature: String,
+}
+
+impl Token {
+    /// `None` when `token` isn't three base64url parts with JSON objects for the first two.
+    pub fn decode(token: &str) -> Option<Token> {
+        let mut parts = token.trim().split('.');
+        let (header, claims, signature) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        let object = |part: &str| match serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part.trim_end_matches('=')).ok()?) {
+            Ok(Value::Object(map)) => Some(map),
+            _ => None,
+        };
+        Some(Token {
+            header: object(header)?,
+            claims: object(claims)?,
+            signature: signature.to_string(),
+        })
+    }
+
+    pub fn alg(&self) -> &str {
+        self.header.get("alg").and_then(Value::as_str).unwrap_or("none")
+    }
+
+    /// Sets each of `changes` (a `null` removes the claim). Strings `now`, `now+N` and
+    /// `now-N` become Unix seconds, so `"exp": "now-60"` expires the token.
+    pub fn apply(&mut self, changes: &Map<String, Value>) {
+        for (name, value) in changes {
+            match value {
+                Value::Null => {
+                    self.claims.remove(name);
+                }
+                Value::String(text) if relative_time(text).is_some() => {
+                    self.claims.insert(name.clone(), Value::from(relative_time(text).unwrap()));
+                }
+                value => {
+                    self.claims.insert(name.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    /// The token in compact form. With a key it is signed afresh: an HMAC secret signs
+    /// HS256 (or the token's own HS384 / HS512), a PEM RSA key RS256 (or RS384 / RS512)
+    /// and a PEM PKCS#8 P-256 key ES256. Without one the old signature is kept, which no
+    /// longer verifies if claims changed.
+    pub fn encode(&mut self, key: Option<&str>) -> Result<String> {
+        let Some(key) = key.filter(|key| !key.is_empty()) else {
+            let input = self.signing_input()?;
+            return Ok(format!("{}.{}", input, self.signature));
+        };
+        let signer = Signer::new(key, self.alg())?;
+        self.header.insert("alg".to_string(), Value::from(signer.alg()));
+        let input = self.signing_input()?;
+        let signature = signer.sign(input.as_bytes())?;
+        self.signature = URL_SAFE_NO_PAD.encode(signature);
+        Ok(format!("{}.{}", input, self.signature))
+    }
+
+    fn signing_input(&self) -> Result<String> {
+        let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&self.header)?);
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&self.claims)?);
+        Ok(format!("{}.{}", header, claims))
+    }
+
+    /// The header and claims for the log: times with how far off they are, the claims in
+    /// `VISIBLE_CLAIMS` in full, and only the start of other strings.
+    pub fn redacted(&self) -> String {
+        let now = unix_now();
+        let claims: Vec<String> = self
+            .claims
+            .iter()
+            .map(|(name, value)| {
+                let shown = match value {
+                    Value::Number(n) if matches!(name.as_str(), "exp" | "iat" | "nbf" | "auth_time") => match n.as_i64() {
+                        Some(at) if at >= now => format!("{} (in {}s)", at, at - now),
+                        Some(at) => format!("{} ({}s ago)", at, now - at),
+                        None => n.to_string(),
+                    },
+                    _ if VISIBLE_CLAIMS.contains(&name.as_str()) => value.to_string(),
+                    Value::String(text) => redact(text),
+                    Value::Array(items) => format!("[{} items]", items.len()),
+                    Value::Object(fields) => format!("{{{} fields}}", fields.len()),
+                    other => other.to_string(),
+                };
+                format!("{}={}", name, shown)
+            })
+            .collect();
+        let kid = self.header.get("kid").and_then(Value::as_str).map(|kid| format!(" kid={}", kid)).unwrap_or_default();
+        format!("alg={}{} {}", self.alg(), kid, claims.join(" "))
+    }
+}
+
+/// What signs a re-issued token.
+enum Signer {
+    Hmac(hmac::Algorithm, &'static str, Vec<u8>),
+    Rsa(&'static dyn signature::RsaEncoding, &'static str, signature::RsaKeyPair),
+    Ecdsa(signature::EcdsaKeyPair),
+}
+
+impl Signer {
+    fn new(key: &str, current_alg: &str) -> Result<Signer> {
+        if !key.trim_start().starts_with("-----BEGIN") {
+            let (algorithm, alg) = match current_alg {
+                "HS384" => (hmac::HMAC_SHA384, "HS384"),
+                "HS512" => (hmac::HMAC_SHA512, "HS512"),
+                _ => (hmac::HMAC_SHA256, "HS256"),
+            };
+            return Ok(Signer::Hmac(algorithm, alg, key.as_bytes().to_vec()));
+        }
+        let der = PrivateKeyDer::from_pem_slice(key.trim().as_bytes()).map_err(|e| anyhow!("signing_key is not a PEM private key: {:?}", e))?;
+        let (encoding, alg): (&'static dyn signature::RsaEncoding, &'static str) = match current_alg {
+            "RS384" => (&signature::RSA_PKCS1_SHA384, "RS384"),
+            "RS512" => (&signature::RSA_PKCS1_SHA512, "RS512"),
+            _ => (&signature::RSA_PKCS1_SHA256, "RS256"),
+        };
+        match der {
+            PrivateKeyDer::Pkcs1(der) => {
+                let pair = signature::RsaKeyPair::from_der(der.secret_pkcs1_der()).map_err(|e| anyhow!("bad RSA key: {}", e))?;
+                Ok(Signer::Rsa(encoding, alg, pair))
+            }
+            PrivateKeyDer::Pkcs8(der) => {
+                if let Ok(pair) = signature::RsaKeyPair::from_pkcs8(der.secret_pkcs8_der()) {
+                    return Ok(Signer::Rsa(encoding, alg, pair));
+                }
+                let pair = signature::EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, der.secret_pkcs8_der(), &SystemRandom::new())
+                    .map_err(|e| anyhow!("signing_key is neither an RSA nor a P-256 key: {}", e))?;
+                Ok(Signer::Ecdsa(pair))
+            }
+            _ => bail!("EC keys must be PKCS#8 (`openssl pkcs8 -topk8 -nocrypt`)"),
+        }
+    }
+
+    fn alg(&self) -> &'static str {
+        match self {
+            Signer::Hmac(_, alg, _) | Signer::Rsa(_, alg, _) => alg,
+            Signer::Ecdsa(_) => "ES256",
+        }
+    }
+
+    fn sign(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        match self {
+            Signer::Hmac(algorithm, _, secret) => Ok(hmac::sign(&hmac::Key::new(*algorithm, secret), input).as_ref().to_vec()),
+            Signer::Rsa(encoding, _, pair) => {
+                let mut signature = vec![0; pair.public().modulus_len()];
+                pair.sign(*encoding, &rng, input, &mut signature).map_err(|e| anyhow!("RSA signing failed: {}", e))?;
+                Ok(signature)
+            }
+            Signer::Ecdsa(pair) => Ok(pair.sign(&rng, input).map_err(|e| anyhow!("ECDSA signing failed: {}", e))?.as_ref().to_vec()),
+        }
+    }
+}
+
+/// Unix seconds for `now`, `now+N` or `now-N`.
+fn relative_time(text: &str) -> Option<i64> {
+    let offset = text.trim().strip_prefix("now")?;
+    let offset: i64 = match offset.trim() {
+        "" => 0,
+        offset => offset.replace(' ', "").parse().ok()?,
+    };
+    Some(unix_now() + offset)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// The first characters of a secret and its length.
+pub fn redact(text: &str) -> String {
+    let shown: String = text.chars().take(3).collect();
+    if shown.len() == text.len() {
+        "***".to_string()
+    } else {
+        format!("{}...({} chars)", shown, text.chars().count())
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::Config;
+use crate::proxy::ProxyServer;
+use crate::script_manager::{InjectType, ScriptManager};
+
+enum Severity {
+    /// Exposes the proxy or the sites behind it; `start` refuses without `--force`
+    Danger,
+    /// Worth knowing, but the proxy does what it is told
+    Risk,
+}
+
+struct Finding {
+    severity: Severity,
+    name: &'static str,
+    detail: String,
+    hint: &'static str,
+}
+
+/// Looks for settings that are each fine but risky together, prints what it finds, and
+/// returns whether the proxy may start: nothing dangerous was found, or `force` is set.
+pub fn run(port: u16, config: &Config, scripts: &ScriptManager, force: bool) -> bool {
+    let findings = lint(port, config, scripts);
+    if findings.is_empty() {
+        return true;
+    }
+    eprintln!("Configuration warnings:");
+    let width = findings.iter().map(|finding| finding.name.len()).max().unwrap_or(0);
+    let mut dangerous = false;
+    for finding in &findings {
+        let severity = match finding.severity {
+            Severity::Danger => {
+                dangerous = true;
+                "DANGER"
+            }
+            Severity::Risk => "RISK",
+        };
+        eprintln!("  {:<6}  {:<width$}  {}", severity, finding.name, finding.detail, width = width);
+        eprintln!("  {:<6}  {:<width$}  -> {}", "", "", finding.hint, width = width);
+    }
+    !dangerous || force
+}
+
+fn lint(port: u16, config: &Config, scripts: &ScriptManager) -> Vec<Finding> {
+    let mut findings = vec![];
+    let addr = ProxyServer::listen_addr(port, config);
+    let public = !addr.ip().is_loopback();
+
+    if public && !config.security.require_auth && config.security.whitelist_ips.is_empty() {
+        findings.push(Finding {
+            severity: Severity::Danger,
+            name: "open proxy",
+            detail: format!("listening on {} without security.require_auth or whitelist_ips; anyone who can reach it can relay through it", addr),
+            hint: "set security.require_auth with an auth_token, list the clients in security.whitelist_ips, or bind to 127.0.0.1",
+        });
+    }
+
+    if config.features.cors_injection && config.scripts.enabled && config.scripts.allowed_domains.iter().any(|domain| domain == "*") {
+        let mut everywhere: Vec<&str> = scripts
+            .list_scripts()
+            .iter()
+            .filter_map(|name| scripts.get_script(name))
+            .filter(|script| script.enabled && matches!(script.inject_type, InjectType::ResponseHeader) && script.target_domains.iter().any(|domain| domain == "*"))
+            .filter(|script| script.echo_origin || script.answer_preflight || script.headers.keys().any(|name| name.to_ascii_lowercase().starts_with("access-control-")))
+            .map(|script| script.name.as_str())
+            .collect();
+        everywhere.sort_unstable();
+        if !everywhere.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Danger,
+                name: "CORS everywhere",
+                detail: format!(
+                    "script{} {} add{} CORS headers on every domain, so any page a client opens may read its responses from every other site",
+                    if everywhere.len() == 1 { "" } else { "s" },
+                    everywhere.join(", "),
+                    if everywhere.len() == 1 { "s" } else { "" }
+                ),
+                hint: "narrow the scripts' target_domains to the APIs under test, or disable the cors-injection feature",
+            });
+        }
+    }
+
+    if config.features.mitm {
+        findings.push(Finding {
+            severity: Severity::Risk,
+            name: "TLS interception",
+            detail: "the mitm feature is on, but this build cannot intercept TLS and has no CA to sign with; tunnels are relayed untouched".to_string(),
+            hint: "turn the mitm feature off, or expect HTTPS traffic to pass through unmodified",
+        });
+    }
+
+    if public {
+        findings.push(Finding {
+            severity: Severity::Risk,
+            name: "rate limit",
+            detail: format!("listening on {}, but security.rate_limit is not enforced by this build", addr),
+            hint: "limit clients with security.whitelist_ips or a firewall in front of the proxy",
+        });
+    }
+    findings
+}
@@ -0,0 +1,109 @@
+use regex::Regex;
+use std::sync::OnceLock;
+use serde::{Deserialize, Serialize};
+
+/// Position reported to pages by a `Locale` script.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Geolocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Accuracy in metres
+    #[serde(default = "default_accuracy")]
+    pub accuracy: f64,
+}
+
+fn default_accuracy() -> f64 {
+    10.0
+}
+
+/// A BCP 47 language tag, loosely: subtags of letters and digits.
+fn language_tag() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^[A-Za-z]{2,8}(-[A-Za-z0-9]{1,8})*$").unwrap())
+}
+
+/// The characters of an IANA zone name.
+fn zone_name() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"^[A-Za-z0-9_+\-/]+$").unwrap())
+}
+
+/// Checks a `Locale` script's settings: a BCP 47 tag such as `fr-CA`, an IANA zone
+/// such as `America/Toronto`, and coordinates on the globe.
+pub fn validate(locale: Option<&str>, timezone: Option<&str>, geolocation: Option<&Geolocation>) -> Result<(), String> {
+    if locale.is_none() && timezone.is_none() && geolocation.is_none() {
+        return Err("Locale script sets none of locale, timezone and geolocation".to_string());
+    }
+    if let Some(locale) = locale {
+        if !language_tag().is_match(locale) {
+            return Err(format!("Unusable locale {}", locale));
+        }
+    }
+    if let Some(timezone) = timezone {
+        if !zone_name().is_match(timezone) {
+            return Err(format!("Unusable timezone {}", timezone));
+        }
+    }
+    if let Some(geo) = geolocation {
+        if !(-90.0..=90.0).contains(&geo.latitude) || !(-180.0..=180.0).contains(&geo.longitude) || geo.accuracy.is_nan() || geo.accuracy < 0.0 {
+            return Err("geolocation is off the globe".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// The languages a page sees for `locale`: the tag itself, then its language alone
+/// (`fr-CA` gives `fr-CA, fr`).
+fn languages(locale: &str) -> Vec<String> {
+    let mut languages = vec![locale.to_string()];
+    if let Some((language, _)) = locale.split_once('-') {
+        languages.push(language.to_string());
+    }
+    languages
+}
+
+/// `Accept-Language` matching what the page is told: `fr-CA,fr;q=0.9`.
+pub fn accept_language(locale: &str) -> String {
+    languages(locale)
+        .iter()
+        .enumerate()
+        .map(|(i, language)| if i == 0 { language.clone() } else { format!("{};q=0.9", language) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Script run first in the page: `navigator.language(s)`, the default locale and time
+/// zone of `Intl` and the `toLocale*` methods, and `navigator.geolocation` answer with
+/// the configured values. Settings left unset keep the browser's own.
+pub fn payload(locale: Option<&str>, timezone: Option<&str>, geolocation: Option<&Geolocation>) -> String {
+    let languages = locale.map(languages);
+    let geolocation = geolocation.map(|geo| format!("{{latitude:{},longitude:{},accuracy:{}}}", geo.latitude, geo.longitude, geo.accuracy));
+    format!(
+        "(function(){{var langs={},tz={},geo={},lang=langs&&langs[0];\
+function define(o,k,v){{try{{Object.defineProperty(o,k,{{get:function(){{return v;}},configurable:true}});}}catch(e){{}}}}\
+if(lang){{define(Navigator.prototype,'language',lang);define(Navigator.prototype,'languages',Object.freeze(langs.slice()));}}\
+function opts(o,zoned){{return zoned&&tz?Object.assign({{timeZone:tz}},o):o;}}\
+['DateTimeFormat','NumberFormat','Collator','PluralRules','RelativeTimeFormat','ListFormat','DisplayNames'].forEach(function(name){{\
+var Real=Intl[name];if(!Real)return;var zoned=name==='DateTimeFormat';\
+var Fake=function(l,o){{l=l===undefined?lang:l;o=opts(o,zoned);return new.target?Reflect.construct(Real,[l,o],new.target):Real(l,o);}};\
+Fake.prototype=Real.prototype;Fake.supportedLocalesOf=Real.supportedLocalesOf;Intl[name]=Fake;}});\
+function patch(proto,names,zoned){{names.forEach(function(m){{var real=proto[m];if(!real)return;\
+proto[m]=function(l,o){{return real.call(this,l===undefined?lang:l,opts(o,zoned));}};}});}}\
+patch(Date.prototype,['toLocaleString','toLocaleDateString','toLocaleTimeString'],true);\
+patch(Number.prototype,['toLocaleString'],false);\
+var compare=String.prototype.localeCompare;String.prototype.localeCompare=function(that,l,o){{return compare.call(this,that,l===undefined?lang:l,o);}};\
+if(geo&&navigator.geolocation){{var watches=0;\
+function position(){{return {{coords:{{latitude:geo.latitude,longitude:geo.longitude,accuracy:geo.accuracy,altitude:null,altitudeAccuracy:null,heading:null,speed:null}},timestamp:Date.now()}};}}\
+var fakeGeo={{getCurrentPosition:function(ok){{setTimeout(function(){{ok(position());}},0);}},\
+watchPosition:function(ok){{setTimeout(function(){{ok(position());}},0);return ++watches;}},clearWatch:function(){{}}}};\
+define(Navigator.prototype,'geolocation',fakeGeo);}}}})();",
+        languages.map(|l| js_value(&l)).unwrap_or_else(|| "null".to_string()),
+        timezone.map(js_value).unwrap_or_else(|| "null".to_string()),
+        geolocation.unwrap_or_else(|| "null".to_string()),
+    )
+}
+
+/// A JSON value that is also safe inside an inline `<script>`.
+fn js_value<T: Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default().replace('<', "\\u003c")
+}
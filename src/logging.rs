@@ -0,0 +1,457 @@
+use anyhow::{anyhow, Result};
+use hyper::header::HeaderMap;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::capture::warc_date;
+use crate::config::{LogBackend, LoggingConfig};
+
+type FilteredRegistry = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type OutputLayer = Box<dyn Layer<FilteredRegistry> + Send + Sync>;
+
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+static CURRENT_FILTER: Mutex<String> = Mutex::new(String::new());
+
+type RotateHook = Box<dyn Fn(PathBuf) + Send + Sync>;
+
+/// Told of each log file rotated out; see `on_rotate`.
+static ON_ROTATE: OnceLock<RotateHook> = OnceLock::new();
+
+/// What `logging.max_size` means when it doesn't parse.
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Installs the global subscriber for the configured backend with a reloadable filter.
+/// `RUST_LOG`, when set, takes precedence over the configured levels.
+pub fn init(config: &LoggingConfig) {
+    let initial = std::env::var("RUST_LOG").unwrap_or_else(|_| directives_from_config(config));
+    let initial = qualify_directives(&initial);
+    let filter = EnvFilter::try_new(&initial).unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter_layer, handle) = reload::Layer::new(filter);
+
+    let (output, fallback_reason) = match output_layer(config) {
+        Ok(layer) => (layer, None),
+        Err(e) => (fmt::layer().boxed(), Some(e)),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(output)
+        .init();
+
+    let _ = FILTER_HANDLE.set(handle);
+    *CURRENT_FILTER.lock().unwrap() = initial;
+
+    if let Some(e) = fallback_reason {
+        tracing::warn!("Logging to stdout, {:?} backend unavailable: {}", config.backend, e);
+    }
+}
+
+fn output_layer(config: &LoggingConfig) -> Result<OutputLayer> {
+    let layer = match config.backend {
+        LogBackend::Stdout => fmt::layer().boxed(),
+        LogBackend::StdoutJson => fmt::layer().json().boxed(),
+        LogBackend::File => {
+            let path = config.file.as_deref().ok_or_else(|| anyhow!("logging.file is not set"))?;
+            let file = RotatingFile::open(PathBuf::from(path), parse_size(&config.max_size), config.max_files)?;
+            fmt::layer().with_ansi(false).with_writer(Mutex::new(file)).boxed()
+        }
+        LogBackend::Syslog => {
+            let writer = SyslogMakeWriter::connect(&config.syslog_socket)?;
+            fmt::layer()
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .with_writer(writer)
+                .boxed()
+        }
+        LogBackend::Journald => tracing_journald::layer()?.boxed(),
+    };
+    Ok(layer)
+}
+
+/// Calls `hook` with each log file rotated out. It runs while the log is locked, so it
+/// must hand the file on rather than log anything itself.
+pub fn on_rotate(hook: impl Fn(PathBuf) + Send + Sync + 'static) {
+    let _ = ON_ROTATE.set(Box::new(hook));
+}
+
+/// `10MB`, `512KB`, `1GB` or a plain number of bytes.
+fn parse_size(size: &str) -> u64 {
+    let size = size.trim().to_ascii_uppercase();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => size.split_at(at),
+        None => (size.as_str(), ""),
+    };
+    let unit = match unit.trim().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        _ => return DEFAULT_MAX_SIZE,
+    };
+    number.parse::<u64>().map(|n| n * unit).unwrap_or(DEFAULT_MAX_SIZE)
+}
+
+/// `logging.file`, moved aside once it reaches `max_size` as `<stem>-<time><ext>`, so
+/// `rusty-proxy.log` becomes `rusty-proxy-20260131T120000Z.log`. Only the newest
+/// `max_files` rotated files are kept.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: u32) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let name = self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let (stem, extension) = match name.find('.') {
+            Some(dot) if dot > 0 => (&name[..dot], &name[dot..]),
+            _ => (name.as_str(), ""),
+        };
+        let stamp = warc_date(SystemTime::now()).replace(['-', ':'], "");
+        let mut rotated = self.path.with_file_name(format!("{}-{}{}", stem, stamp, extension));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = self.path.with_file_name(format!("{}-{}-{}{}", stem, stamp, n, extension));
+            n += 1;
+        }
+        std::fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.prune(&format!("{}-", stem), extension);
+        if let Some(hook) = ON_ROTATE.get() {
+            hook(rotated);
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest rotated files beyond `max_files`; their names sort by time.
+    fn prune(&self, prefix: &str, extension: &str) {
+        let dir = match self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::from("."),
+        };
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            return;
+        };
+        let mut rotated: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.starts_with(prefix) && name.ends_with(extension)
+            })
+            .map(|entry| entry.path())
+            .collect();
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.max_files as usize);
+        for file in &rotated[..excess] {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.max_size > 0 && self.size >= self.max_size {
+            // A failed rotation must not lose the log: keep appending, and try again
+            // after another `max_size`
+            if self.rotate().is_err() {
+                self.size = 0;
+            }
+        }
+        let written = self.file.write(data)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Builds a filter from `logging.level` plus the `[logging.modules]` overrides.
+pub fn directives_from_config(config: &LoggingConfig) -> String {
+    let mut directives = vec![config.level.clone()];
+    let mut modules: Vec<_> = config.modules.iter().collect();
+    modules.sort();
+    for (module, level) in modules {
+        directives.push(format!("{}={}", module, level));
+    }
+    directives.join(",")
+}
+
+/// Replaces the active filter, e.g. `info,proxy=debug,script_manager=warn`.
+pub fn set_filter(directives: &str) -> Result<()> {
+    let qualified = qualify_directives(directives);
+    let filter = EnvFilter::try_new(&qualified).map_err(|e| anyhow!("Invalid log filter: {}", e))?;
+    let handle = FILTER_HANDLE.get().ok_or_else(|| anyhow!("Logging is not initialized"))?;
+    handle.reload(filter)?;
+    *CURRENT_FILTER.lock().unwrap() = qualified;
+    Ok(())
+}
+
+pub fn current_filter() -> String {
+    CURRENT_FILTER.lock().unwrap().clone()
+}
+
+/// Module names without a `::` are taken to be modules of this crate.
+fn qualify_directives(directives: &str) -> String {
+    directives
+        .split(',')
+        .map(|d| d.trim())
+        .filter(|d| !d.is_empty())
+        .map(|d| match d.split_once('=') {
+            Some((target, level)) => format!("{}={}", qualify_target(target.trim()), level.trim()),
+            None => d.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn qualify_target(target: &str) -> String {
+    if target.contains("::") || target == env!("CARGO_CRATE_NAME") {
+        target.to_string()
+    } else {
+        format!("{}::{}", env!("CARGO_CRATE_NAME"), target)
+    }
+}
+
+/// Marker stored in request/response extensions when a transaction was picked for
+/// verbose logging, so every stage can check it without extra parameters.
+#[derive(Debug, Clone, Copy)]
+pub struct VerboseLog;
+
+/// Bytes of each body included in a sampled transaction's log.
+const SAMPLED_BODY_LIMIT: usize = 16 * 1024;
+
+/// Header whose value identifies a request to `logging.sample_seed` and the decision
+/// record; requests without one are numbered in the order they arrive.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Picks the requests that get full header/body logging: those carrying the configured
+/// debug header, and those falling within `logging.sample_rate`. With `sample_seed` the
+/// pick is a hash of the seed and the request ID rather than a random draw, so a rerun
+/// of the same requests samples the same ones; with `sample_record` each decision is
+/// appended to a file, and decisions already in it are reused for their request IDs.
+pub struct Sampler {
+    rate: f64,
+    debug_header: Option<String>,
+    seed: Option<u64>,
+    /// Decisions read from `sample_record`, by request ID
+    recorded: HashMap<String, bool>,
+    record: Option<Mutex<File>>,
+    sequence: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(config: &LoggingConfig) -> Self {
+        let mut recorded = HashMap::new();
+        let mut record = None;
+        if let Some(path) = config.sample_record.as_deref().filter(|path| !path.is_empty()) {
+            if let Ok(content) = std::fs::read_to_string(path) {
+                for line in content.lines() {
+                    let Ok(decision) = serde_json::from_str::<Value>(line) else {
+                        continue;
+                    };
+                    if let (Some(id), Some(sampled)) = (decision["request_id"].as_str(), decision["sampled"].as_bool()) {
+                        recorded.insert(id.to_string(), sampled);
+                    }
+                }
+                tracing::info!("Sampling: replaying {} recorded decisions from {}", recorded.len(), path);
+            }
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => record = Some(Mutex::new(file)),
+                Err(e) => tracing::warn!("Sampling decisions not recorded to {}: {}", path, e),
+            }
+        }
+        Sampler {
+            rate: config.sample_rate,
+            debug_header: config.debug_header.clone().filter(|name| !name.is_empty()),
+            seed: config.sample_seed,
+            recorded,
+            record,
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether this request is logged in full.
+    pub fn sample(&self, headers: &HeaderMap) -> bool {
+        if self.debug_header.as_deref().is_some_and(|name| headers.contains_key(name)) {
+            return true;
+        }
+        if self.rate <= 0.0 {
+            return false;
+        }
+        if self.seed.is_none() && self.record.is_none() {
+            return rand::random::<f64>() < self.rate;
+        }
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let id = match headers.get(REQUEST_ID_HEADER).and_then(|value| value.to_str().ok()) {
+            Some(id) => id.to_string(),
+            None => format!("#{}", sequence),
+        };
+        if let Some(&sampled) = self.recorded.get(&id) {
+            return sampled;
+        }
+        let draw = match self.seed {
+            Some(seed) => seeded_draw(seed, &id),
+            None => rand::random::<f64>(),
+        };
+        let sampled = draw < self.rate;
+        if let Some(record) = &self.record {
+            let mut line = serde_json::json!({ "request_id": id, "sampled": sampled, "draw": draw, "rate": self.rate }).to_string();
+            line.push('\n');
+            if let Err(e) = record.lock().unwrap().write_all(line.as_bytes()) {
+                tracing::warn!("Failed to record sampling decision: {}", e);
+            }
+        }
+        sampled
+    }
+}
+
+/// A number in [0, 1) fixed by the seed and request ID.
+fn seeded_draw(seed: u64, id: &str) -> f64 {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update(id.as_bytes());
+    let digest = hasher.finalize();
+    let bits = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Sampled logs go to their own target (`rusty_proxy::sampled`) at info level, so they
+/// show up without enabling debug logging everywhere and can be silenced with a filter.
+pub fn log_sampled_headers(label: &str, headers: &HeaderMap) {
+    for (name, value) in headers {
+        let value = match name.as_str() {
+            "authorization" | "proxy-authorization" => "<redacted>",
+            _ => value.to_str().unwrap_or("<binary>"),
+        };
+        tracing::info!(target: "rusty_proxy::sampled", "{} header {}: {}", label, name, value);
+    }
+}
+
+/// `decoded` is the body rendered as JSON from a registered protobuf descriptor, logged
+/// in place of the raw bytes.
+pub fn log_sampled_body(label: &str, body: &[u8], decoded: Option<String>) {
+    if let Some(decoded) = decoded {
+        tracing::info!(
+            target: "rusty_proxy::sampled",
+            "{} body ({} bytes, protobuf): {}",
+            label,
+            body.len(),
+            decoded
+        );
+        return;
+    }
+    let shown = &body[..body.len().min(SAMPLED_BODY_LIMIT)];
+    let truncated = if body.len() > SAMPLED_BODY_LIMIT { " (truncated)" } else { "" };
+    tracing::info!(
+        target: "rusty_proxy::sampled",
+        "{} body ({} bytes){}: {}",
+        label,
+        body.len(),
+        truncated,
+        String::from_utf8_lossy(shown)
+    );
+}
+
+/// Sends each formatted event as one datagram to the local syslog socket.
+struct SyslogMakeWriter {
+    socket: Arc<UnixDatagram>,
+}
+
+impl SyslogMakeWriter {
+    fn connect(path: &str) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(SyslogMakeWriter {
+            socket: Arc::new(socket),
+        })
+    }
+
+    fn writer(&self, level: Level) -> SyslogWriter {
+        // Facility 3 (daemon) combined with the event severity
+        let severity = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        SyslogWriter {
+            socket: self.socket.clone(),
+            priority: 3 * 8 + severity,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogMakeWriter {
+    type Writer = SyslogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.writer(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.writer(*meta.level())
+    }
+}
+
+struct SyslogWriter {
+    socket: Arc<UnixDatagram>,
+    priority: u8,
+    buf: Vec<u8>,
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogWriter {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.buf);
+        let line = format!(
+            "<{}>{}[{}]: {}",
+            self.priority,
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+            message.trim_end()
+        );
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
@@ -1,24 +1,89 @@
-use clap::{Arg, Command};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::io::IsTerminal;
 use std::process;
-use tracing::{error, info, Level};
-use tracing_subscriber;
+use tracing::{error, info, warn};
 
+mod admin;
+mod agent;
+mod alert;
+mod assertions;
+mod assets;
+mod body;
+mod bundle;
+mod capture;
+mod checksum;
+mod clock;
+mod cluster;
+mod concurrency;
 mod config;
+mod conformance;
+mod context;
+mod diagnostics;
+mod dns;
+mod error_page;
+mod forwarded;
+mod ftp;
+mod history;
+mod hosts;
+mod hsts;
+mod html;
+mod page_api;
+mod pattern;
+mod pipeline;
+mod preflight;
+mod profiling;
+mod protobuf;
 mod proxy;
+mod proxy_protocol;
+mod repl;
+mod report;
 mod script_manager;
+mod selftest;
+mod setup;
+mod signing;
+mod snapshot;
+mod sso;
+mod storage;
 mod http_injector;
+mod import;
+mod jwt;
+mod lint;
+mod locale;
+mod logging;
+mod metrics;
+mod oauth;
+mod openapi;
+mod optimize;
+mod streaming;
+mod tee;
+mod testserver;
+mod tls;
+mod trace;
+mod tunnel;
+mod upgrade;
+mod upstream;
+mod xml;
 
+use body::Body;
 use config::Config;
+
+#[cfg(feature = "heap-profiling")]
+#[global_allocator]
+static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+/// Turns on jemalloc heap sampling so `/admin/debug/pprof/heap` has data to dump.
+#[cfg(feature = "heap-profiling")]
+#[allow(non_upper_case_globals)]
+#[export_name = "malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
 use proxy::ProxyServer;
 use script_manager::ScriptManager;
 
 #[tokio::main]
 async fn main() {
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .init();
-
     let matches = Command::new("rusty-proxy")
         .version("0.1.0")
         .about("HTTP Proxy Script Manager for Traffic Injection")
@@ -46,44 +111,358 @@ async fn main() {
                 .help("Directory containing injection scripts")
                 .default_value("scripts"),
         )
+        .arg(
+            Arg::new("no-config-write")
+                .long("no-config-write")
+                .action(ArgAction::SetTrue)
+                .help("Never create the config file or scripts directory (read-only filesystems)"),
+        )
+        .arg(
+            Arg::new("no-examples")
+                .long("no-examples")
+                .action(ArgAction::SetTrue)
+                .help("Don't write example scripts into an empty scripts directory"),
+        )
+        .subcommand(
+            Command::new("init")
+                .about("Generate a config file and scripts directory, then print client setup instructions")
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .value_name("ADDR")
+                        .help("Listen address written to the config"),
+                )
+                .arg(
+                    Arg::new("auth")
+                        .long("auth")
+                        .action(ArgAction::SetTrue)
+                        .help("Require proxy authentication with a generated token"),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .short('y')
+                        .long("yes")
+                        .action(ArgAction::SetTrue)
+                        .help("Do not prompt; use flags and defaults"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Overwrite an existing config file"),
+                )
+        )
         .subcommand(
             Command::new("start")
                 .about("Start the proxy server")
+                .arg(
+                    Arg::new("skip-preflight")
+                        .long("skip-preflight")
+                        .action(ArgAction::SetTrue)
+                        .help("Start without checking ports, certificates, the scripts directory and DNS first"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Start even with a dangerous configuration, such as an open proxy on a public address"),
+                )
         )
         .subcommand(
             Command::new("list-scripts")
                 .about("List available injection scripts")
         )
+        .subcommand(
+            Command::new("script")
+                .about("Manage injection scripts")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("new")
+                        .about("Write a new disabled script to fill in, or with --example the example scripts")
+                        .arg(
+                            Arg::new("example")
+                                .long("example")
+                                .action(ArgAction::SetTrue)
+                                .help("Write the example script NAME, or all of them when no name is given"),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .help("Overwrite an existing script file"),
+                        )
+                        .arg(
+                            Arg::new("name")
+                                .value_name("NAME")
+                                .required_unless_present("example")
+                                .help("Script name; `team/api/name` writes into a subdirectory"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Convert another proxy tool's rules into scripts under <scripts-dir>/<format>/")
+                        .arg(
+                            Arg::new("format")
+                                .long("format")
+                                .value_name("FORMAT")
+                                .required(true)
+                                .value_parser(["charles", "fiddler", "mitmproxy", "openapi"])
+                                .help("charles (rewrite XML export), fiddler (AutoResponder .farx), mitmproxy (config.yaml with map_local/map_remote) or openapi (spec to mock)"),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .help("Overwrite scripts imported before"),
+                        )
+                        .arg(Arg::new("file").value_name("FILE").required(true).help("Rules file to import")),
+                )
+                .subcommand(
+                    Command::new("export")
+                        .about("Package scripts and the local assets they use into a bundle with a manifest of versions and checksums")
+                        .arg(
+                            Arg::new("bundle")
+                                .long("bundle")
+                                .value_name("FILE")
+                                .required(true)
+                                .help("Bundle to write, e.g. out.tar.zst"),
+                        )
+                        .arg(
+                            Arg::new("names")
+                                .value_name("SCRIPT")
+                                .num_args(0..)
+                                .help("Scripts to include (default: all)"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("install")
+                        .about("Install a bundle from `script export`, or a single script file")
+                        .arg(
+                            Arg::new("force")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .help("Overwrite existing scripts and assets"),
+                        )
+                        .arg(Arg::new("file").value_name("FILE").required(true).help("Bundle (.tar.zst) or script (.json)")),
+                )
+                .subcommand(
+                    Command::new("history")
+                        .about("List the recorded versions of a script")
+                        .arg(Arg::new("name").value_name("NAME").required(true).help("Script name, e.g. team/api/banner")),
+                )
+                .subcommand(
+                    Command::new("rollback")
+                        .about("Put an earlier version of a script back in place")
+                        .arg(
+                            Arg::new("to")
+                                .long("to")
+                                .value_name("VERSION")
+                                .required(true)
+                                .value_parser(clap::value_parser!(u32))
+                                .help("Version number, as listed by `script history`"),
+                        )
+                        .arg(Arg::new("name").value_name("NAME").required(true).help("Script name, e.g. team/api/banner")),
+                )
+        )
+        .subcommand(
+            Command::new("self-test")
+                .about("Run end-to-end checks against a temporary proxy instance")
+        )
+        .subcommand(
+            Command::new("test-server")
+                .about("Serve a local test site (HTML, JSON, SSE, WebSocket echo, slow and gzip endpoints)")
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .value_name("ADDR")
+                        .help("Address to serve the test site on")
+                        .default_value("127.0.0.1:8000"),
+                )
+        )
         .subcommand(
             Command::new("install")
                 .about("Install as system service")
+                .arg(
+                    Arg::new("socket")
+                        .long("socket")
+                        .action(ArgAction::SetTrue)
+                        .help("Also install a .socket unit so systemd binds the port and starts the proxy on demand"),
+                )
+        )
+        .subcommand(
+            Command::new("upgrade")
+                .about("Hand the running proxy's listener to a new binary without dropping connections (requires the admin API)")
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("Print a diagnostics snapshot from the running proxy (requires the admin API)")
+        )
+        .subcommand(
+            Command::new("assert")
+                .about("Check traffic against an assertions file; exits non-zero on violations")
+                .arg(
+                    Arg::new("warc")
+                        .long("warc")
+                        .value_name("FILE")
+                        .action(ArgAction::Append)
+                        .help("Check the responses recorded in a WARC file instead of the running proxy's traffic"),
+                )
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .value_name("ASSERTIONS")
+                        .help("Assertions to check the WARC files against [default: assertions.file]"),
+                )
+        )
+        .subcommand(
+            Command::new("repl")
+                .about("Evaluate matchers, dry-run scripts on the last response and edit the page store on the running proxy, interactively (requires the admin API)")
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Summarize captured traffic: top domains, statuses, bytes, matched scripts and slowest endpoints")
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("AGE")
+                        .help("Only exchanges captured in the last AGE, e.g. 30m, 1h or 2d [default: everything]"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .value_parser(["text", "json", "html"])
+                        .default_value("text")
+                        .help("Report format"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .long("top")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10")
+                        .help("Entries listed per table"),
+                )
+                .arg(
+                    Arg::new("warc")
+                        .long("warc")
+                        .value_name("FILE")
+                        .action(ArgAction::Append)
+                        .help("Read these WARC files instead of capture.warc and its rotated files"),
+                )
+        )
+        .subcommand(
+            Command::new("sso")
+                .about("Print the single sign-on login flows the running proxy has traced (requires the admin API and sso.trace)")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the report as JSON"),
+                )
+        )
+        .subcommand(
+            Command::new("trace")
+                .about("Record exchanges matching a URL pattern in full (headers, bodies, timings, script decisions) on the running proxy (requires the admin API)")
+                .arg(
+                    Arg::new("url")
+                        .long("url")
+                        .value_name("PATTERN")
+                        .help("URL pattern where `*` matches anything, e.g. 'http://api.example.com/*'"),
+                )
+                .arg(
+                    Arg::new("file")
+                        .long("file")
+                        .value_name("NAME")
+                        .requires("url")
+                        .help("Trace file name in diagnostics.dump_dir (default rusty-proxy-trace-<time>.jsonl)"),
+                )
+                .arg(
+                    Arg::new("seconds")
+                        .long("seconds")
+                        .value_name("SECONDS")
+                        .requires("url")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Stop tracing after this many seconds"),
+                )
+                .arg(
+                    Arg::new("stop")
+                        .long("stop")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("url")
+                        .help("Stop the running trace"),
+                )
         )
         .get_matches();
 
     let config_path = matches.get_one::<String>("config").unwrap();
     let port: u16 = matches.get_one::<String>("port").unwrap().parse().unwrap_or(8080);
     let scripts_dir = matches.get_one::<String>("scripts-dir").unwrap();
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    // `init` runs before the config is loaded, since loading writes a default config
+    if let Some(("init", init)) = matches.subcommand() {
+        let interactive = !init.get_flag("yes") && std::io::stdin().is_terminal();
+        let opts = setup::InitOptions {
+            config_path: config_path.clone(),
+            scripts_dir: given("scripts-dir").then(|| scripts_dir.clone()),
+            port: given("port").then_some(port),
+            bind_address: init.get_one::<String>("bind").cloned(),
+            require_auth: (init.get_flag("auth") || !interactive).then(|| init.get_flag("auth")),
+            no_examples: matches.get_flag("no-examples"),
+            interactive,
+            force: init.get_flag("force"),
+        };
+        if let Err(e) = setup::run(opts) {
+            eprintln!("Setup failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
     // Load configuration
-    let config = match Config::load(config_path) {
+    let no_config_write = matches.get_flag("no-config-write") || std::env::var_os("RUSTY_PROXY_NO_CONFIG_WRITE").is_some();
+    let config = match Config::load(config_path, !no_config_write) {
         Ok(cfg) => cfg,
         Err(e) => {
-            error!("Failed to load config: {}", e);
+            eprintln!("Failed to load config: {}", e);
             process::exit(1);
         }
     };
 
+    // Flags win over the config file (and its environment overrides) only when given
+    let port = if given("port") { port } else { config.proxy.port };
+    let scripts_dir = if given("scripts-dir") { scripts_dir.clone() } else { config.scripts.directory.clone() };
+
+    // Initialize logging
+    logging::init(&config.logging);
+
     // Initialize script manager
-    let script_manager = match ScriptManager::new(scripts_dir) {
+    let script_manager = match ScriptManager::open(&scripts_dir, !no_config_write) {
         Ok(sm) => sm,
         Err(e) => {
             error!("Failed to initialize script manager: {}", e);
             process::exit(1);
         }
     };
+    // `script` subcommands write scripts themselves; examples would only get mixed in
+    let managing_scripts = matches.subcommand_name() == Some("script");
+    if !no_config_write && !matches.get_flag("no-examples") && config.scripts.create_examples && !managing_scripts {
+        if let Err(e) = script_manager.create_example_scripts() {
+            warn!("Example scripts not written: {}", e);
+        }
+    }
 
     match matches.subcommand() {
-        Some(("start", _)) => {
+        Some(("start", args)) => {
+            if !lint::run(port, &config, &script_manager, args.get_flag("force")) {
+                eprintln!("Refusing to start with a dangerous configuration; fix the problems above, or pass --force to start anyway");
+                process::exit(1);
+            }
+            if !args.get_flag("skip-preflight") && !preflight::run(port, &config, &scripts_dir).await {
+                eprintln!("Preflight failed; fix the problems above, or pass --skip-preflight to start anyway");
+                process::exit(1);
+            }
             info!("Starting proxy server on port {}", port);
             let proxy = ProxyServer::new(port, config, script_manager);
             if let Err(e) = proxy.run().await {
@@ -98,8 +477,183 @@ async fn main() {
                 println!("  - {}", script);
             }
         }
-        Some(("install", _)) => {
-            if let Err(e) = install_service() {
+        Some(("script", script)) => match script.subcommand() {
+            Some(("new", args)) => {
+                let name = args.get_one::<String>("name").map(String::as_str);
+                if let Err(e) = new_scripts(&scripts_dir, name, args.get_flag("example"), args.get_flag("force")) {
+                    error!("Failed to write script: {}", e);
+                    process::exit(1);
+                }
+            }
+            Some(("import", args)) => {
+                let format = import::Format::parse(args.get_one::<String>("format").unwrap()).unwrap();
+                let file = args.get_one::<String>("file").unwrap();
+                if let Err(e) = import_scripts(format, file, &scripts_dir, args.get_flag("force")) {
+                    error!("Failed to import {}: {}", file, e);
+                    process::exit(1);
+                }
+            }
+            Some(("export", args)) => {
+                let out = args.get_one::<String>("bundle").unwrap();
+                let names: Vec<String> = args.get_many::<String>("names").unwrap_or_default().cloned().collect();
+                match bundle::export(&script_manager, &names, std::path::Path::new(&config.scripts.assets_dir), std::path::Path::new(out)) {
+                    Ok(manifest) => {
+                        for script in &manifest.scripts {
+                            println!("  + {} {}", script.name, script.version.as_deref().unwrap_or(""));
+                        }
+                        for asset in &manifest.assets {
+                            println!("  + assets/{}", asset.name);
+                        }
+                        println!("Exported {} scripts and {} assets to {}", manifest.scripts.len(), manifest.assets.len(), out);
+                    }
+                    Err(e) => {
+                        error!("Failed to export scripts: {}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Some(("install", args)) => {
+                let file = args.get_one::<String>("file").unwrap();
+                let assets_dir = std::path::Path::new(&config.scripts.assets_dir);
+                match bundle::install(std::path::Path::new(file), std::path::Path::new(&scripts_dir), assets_dir, args.get_flag("force")) {
+                    Ok(installed) => {
+                        for (name, version) in &installed.written {
+                            println!("  + {} {}", name, version);
+                        }
+                        for name in &installed.kept {
+                            println!("  = {} exists, not overwritten (use --force)", name);
+                        }
+                        println!(
+                            "Installed {} scripts and {} assets; reload with POST /admin/scripts/reload or restart the proxy",
+                            installed.written.len(),
+                            installed.assets
+                        );
+                    }
+                    Err(e) => {
+                        error!("Failed to install {}: {}", file, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Some(("history", args)) => {
+                let name = args.get_one::<String>("name").unwrap();
+                match history::list(std::path::Path::new(&scripts_dir), name) {
+                    Ok(versions) => {
+                        for version in &versions {
+                            println!(
+                                "{} {:>4}  {:<20}  {:<10}  {} bytes",
+                                if version.current { "*" } else { " " },
+                                version.number,
+                                version.written.as_deref().unwrap_or("-"),
+                                version.version.as_deref().unwrap_or("-"),
+                                version.size
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to read the history of {}: {}", name, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            Some(("rollback", args)) => {
+                let name = args.get_one::<String>("name").unwrap();
+                let to = *args.get_one::<u32>("to").unwrap();
+                match history::rollback(std::path::Path::new(&scripts_dir), name, to) {
+                    Ok(recorded) => println!(
+                        "Rolled {} back to version {} (recorded as version {}); reload with POST /admin/scripts/reload or restart the proxy",
+                        name, to, recorded
+                    ),
+                    Err(e) => {
+                        error!("Failed to roll back {}: {}", name, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            _ => unreachable!("clap requires a script subcommand"),
+        },
+        Some(("dump", _)) => {
+            if let Err(e) = request_dump(port, &config).await {
+                error!("Failed to fetch diagnostics: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(("assert", args)) => {
+            let warcs: Vec<String> = args.get_many::<String>("warc").map(|w| w.cloned().collect()).unwrap_or_default();
+            let file = args.get_one::<String>("file").cloned().or_else(|| config.assertions.file.clone());
+            match check_assertions(port, &config, &warcs, file.as_deref()).await {
+                Ok(true) => {}
+                Ok(false) => process::exit(1),
+                Err(e) => {
+                    error!("Assertions could not be checked: {}", e);
+                    process::exit(2);
+                }
+            }
+        }
+        Some(("repl", _)) => {
+            if let Err(e) = repl::run(port, &config).await {
+                error!("REPL failed: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(("report", args)) => {
+            let warcs: Vec<String> = args.get_many::<String>("warc").map(|w| w.cloned().collect()).unwrap_or_default();
+            let format = report::Format::parse(args.get_one::<String>("format").unwrap()).unwrap();
+            let top = *args.get_one::<usize>("top").unwrap();
+            if let Err(e) = print_report(&config, &warcs, args.get_one::<String>("since").map(String::as_str), format, top) {
+                error!("Failed to build the report: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(("sso", args)) => {
+            if let Err(e) = print_sso_flows(port, &config, args.get_flag("json")).await {
+                error!("Failed to fetch SSO flows: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(("trace", args)) => {
+            let url = args.get_one::<String>("url").map(String::as_str);
+            let file = args.get_one::<String>("file").map(String::as_str);
+            let seconds = args.get_one::<u64>("seconds").copied();
+            if let Err(e) = request_trace(port, &config, url, file, seconds, args.get_flag("stop")).await {
+                error!("Failed to control the trace: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(("self-test", _)) => match selftest::run(&config).await {
+            Ok(true) => println!("All self-test checks passed"),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                error!("Self-test could not run: {}", e);
+                process::exit(1);
+            }
+        },
+        Some(("test-server", args)) => {
+            let listen = args.get_one::<String>("listen").unwrap();
+            let addr = match listen.parse() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!("Invalid listen address {}: {}", listen, e);
+                    process::exit(1);
+                }
+            };
+            if let Err(e) = testserver::run(addr).await {
+                error!("Test server error: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(("upgrade", _)) => {
+            if let Err(e) = request_upgrade(port, &config).await {
+                error!("Failed to start upgrade: {}", e);
+                process::exit(1);
+            }
+        }
+        Some(("install", args)) => {
+            let listen = args.get_flag("socket").then(|| match config.proxy.bind_address.parse::<std::net::IpAddr>() {
+                Ok(ip) => std::net::SocketAddr::new(ip, port).to_string(),
+                Err(_) => format!("{}:{}", config.proxy.bind_address, port),
+            });
+            if let Err(e) = install_service(listen.as_deref()) {
                 error!("Failed to install service: {}", e);
                 process::exit(1);
             }
@@ -116,13 +670,193 @@ async fn main() {
     }
 }
 
-fn install_service() -> anyhow::Result<()> {
+/// `script new`: a disabled `JavaScript` script named `name`, or with `example` the
+/// example script of that name (all of them without one).
+fn new_scripts(scripts_dir: &str, name: Option<&str>, example: bool, force: bool) -> anyhow::Result<()> {
+    let scripts = match (example, name) {
+        (true, None) => script_manager::example_scripts(),
+        (true, Some(name)) => {
+            let examples = script_manager::example_scripts();
+            let names: Vec<String> = examples.iter().map(|script| script.name.clone()).collect();
+            let example = examples.into_iter().find(|script| script.name == name);
+            vec![example.ok_or_else(|| anyhow::anyhow!("No example script {}; there are {}", name, names.join(", ")))?]
+        }
+        (false, name) => vec![script_manager::InjectionScript {
+            name: name.unwrap_or_default().to_string(),
+            version: "1.0.0".to_string(),
+            target_domains: vec!["*.example.com".to_string()],
+            inject_type: script_manager::InjectType::JavaScript,
+            script_content: "console.log('injected by rusty-proxy');".to_string(),
+            ..Default::default()
+        }],
+    };
+    let dir = std::path::Path::new(scripts_dir);
+    let mut written = 0;
+    for mut script in scripts {
+        let path = bundle::safe_join(dir, &format!("{}.json", script.name)).ok_or_else(|| anyhow::anyhow!("Refusing unsafe script name {}", script.name))?;
+        if path.exists() && !force {
+            println!("  = {} exists, not overwritten (use --force)", script.name);
+            continue;
+        }
+        let name = std::mem::take(&mut script.name);
+        // Nested scripts are named after their directory, and their file holds the name without it
+        script.name = name.rsplit('/').next().unwrap_or(&name).to_string();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        history::write(dir, &path, serde_json::to_string_pretty(&script)?.as_bytes())?;
+        println!("  + {} ({})", name, path.display());
+        written += 1;
+    }
+    if written > 0 {
+        println!("Wrote {} scripts, disabled; set \"enabled\": true and reload with POST /admin/scripts/reload or restart the proxy", written);
+    }
+    Ok(())
+}
+
+/// Converts a rules file and writes the scripts to their own directory, so re-importing
+/// an updated file replaces them without touching other scripts.
+fn import_scripts(format: import::Format, file: &str, scripts_dir: &str, force: bool) -> anyhow::Result<()> {
+    let imported = import::import(format, std::path::Path::new(file))?;
+    let dir = std::path::Path::new(scripts_dir).join(format.name());
+    let (written, kept) = import::write(&imported.scripts, std::path::Path::new(scripts_dir), &dir, force)?;
+    for name in &written {
+        println!("  + {}/{}", format.name(), name);
+    }
+    for name in &kept {
+        println!("  = {}/{} exists, not overwritten (use --force)", format.name(), name);
+    }
+    for reason in &imported.skipped {
+        println!("  - skipped {}", reason);
+    }
+    println!(
+        "Imported {} scripts into {}; reload with POST /admin/scripts/reload or restart the proxy",
+        written.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Asks the running instance for `/admin/diagnostics`; sending SIGUSR1 writes the same
+/// snapshot to a file instead.
+async fn request_dump(port: u16, config: &Config) -> anyhow::Result<()> {
+    let body = admin_request(port, config, "GET", "/admin/diagnostics").await?;
+    println!("{}", body);
+    Ok(())
+}
+
+/// Asks the running instance to start the installed binary and hand over its listener;
+/// sending SIGUSR2 does the same.
+async fn request_upgrade(port: u16, config: &Config) -> anyhow::Result<()> {
+    admin_request(port, config, "POST", "/admin/upgrade").await?;
+    println!("Upgrade started; the old process exits once its connections have drained");
+    Ok(())
+}
+
+/// Checks recorded WARC files against an assertions file, or, without any, prints what
+/// the running instance found checking `[assertions] file` against live traffic.
+async fn check_assertions(port: u16, config: &Config, warcs: &[String], file: Option<&str>) -> anyhow::Result<bool> {
+    let report = if warcs.is_empty() {
+        let body = admin_request(port, config, "GET", "/admin/assertions").await?;
+        serde_json::from_str(&body)?
+    } else {
+        let file = file.filter(|f| !f.is_empty()).ok_or_else(|| anyhow::anyhow!("no assertions file; pass --file or set assertions.file"))?;
+        let assertions = assertions::load(std::path::Path::new(file))?;
+        assertions::check_warc(&assertions, warcs)?
+    };
+    Ok(assertions::print_report(&report))
+}
+
+fn print_report(config: &Config, warcs: &[String], since: Option<&str>, format: report::Format, top: usize) -> anyhow::Result<()> {
+    let since = since.map(report::parse_age).transpose()?;
+    let report = report::build(&report::files(warcs, &config.capture), since, top)?;
+    print!("{}", report::render(&report, format)?);
+    Ok(())
+}
+
+async fn print_sso_flows(port: u16, config: &Config, json: bool) -> anyhow::Result<()> {
+    let body = admin_request(port, config, "GET", "/admin/sso").await?;
+    if json {
+        println!("{}", body);
+    } else {
+        sso::print_report(&serde_json::from_str(&body)?);
+    }
+    Ok(())
+}
+
+/// Starts a trace of `url` on the running instance, stops it, or without either shows
+/// the trace in progress.
+async fn request_trace(port: u16, config: &Config, url: Option<&str>, file: Option<&str>, seconds: Option<u64>, stop: bool) -> anyhow::Result<()> {
+    let body = match (url, stop) {
+        (Some(url), _) => {
+            let mut path = format!("/admin/trace?url={}", oauth::form_encode(url));
+            if let Some(file) = file {
+                path.push_str(&format!("&file={}", oauth::form_encode(file)));
+            }
+            if let Some(seconds) = seconds {
+                path.push_str(&format!("&seconds={}", seconds));
+            }
+            admin_request(port, config, "PUT", &path).await?
+        }
+        (None, true) => admin_request(port, config, "DELETE", "/admin/trace").await?,
+        (None, false) => admin_request(port, config, "GET", "/admin/trace").await?,
+    };
+    let status: serde_json::Value = serde_json::from_str(&body)?;
+    let Some(pattern) = status["url"].as_str() else {
+        println!("No trace is running");
+        return Ok(());
+    };
+    let file = status["file"].as_str().unwrap_or("");
+    let traced = status["traced"].as_u64().unwrap_or(0);
+    if status["active"] == true {
+        println!("Tracing {} into {} ({} exchanges so far)", pattern, file, traced);
+        match status["remaining_seconds"].as_u64() {
+            Some(left) => println!("The trace stops in {}s, or with `rusty-proxy trace --stop`", left),
+            None => println!("Stop it with `rusty-proxy trace --stop`"),
+        }
+    } else {
+        println!("Stopped tracing {}: {} exchanges in {}", pattern, traced, file);
+    }
+    Ok(())
+}
+
+async fn admin_request(port: u16, config: &Config, method: &str, path: &str) -> anyhow::Result<String> {
+    admin_send(port, config, method, path, String::new()).await
+}
+
+async fn admin_send(port: u16, config: &Config, method: &str, path: &str, body: String) -> anyhow::Result<String> {
+    let mut request = hyper::Request::builder()
+        .method(method)
+        .uri(format!("http://127.0.0.1:{}{}", port, path));
+    if let Some(token) = config.admin.token.as_deref().filter(|t| !t.is_empty()) {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+    let response = client.request(request.body(Body::from(body))?).await?;
+    let status = response.status();
+    let body = body::to_bytes(response.into_body()).await?;
+    let body = String::from_utf8_lossy(&body).to_string();
+    if !status.is_success() {
+        anyhow::bail!("admin API answered {}: {}", status, body);
+    }
+    Ok(body)
+}
+
+/// With `socket_listen` set, systemd owns the listening socket (so privileged ports need
+/// no root) and starts the service on the first connection instead of at boot.
+fn install_service(socket_listen: Option<&str>) -> anyhow::Result<()> {
     use std::fs;
     use std::path::Path;
 
-    let service_content = r#"[Unit]
+    let socket_deps = if socket_listen.is_some() {
+        "Requires=rusty-proxy.socket\nAfter=network.target rusty-proxy.socket"
+    } else {
+        "After=network.target"
+    };
+    let service_content = format!(r#"[Unit]
 Description=Rusty Proxy HTTP Injector
-After=network.target
+{}
 
 [Service]
 Type=simple
@@ -134,7 +868,7 @@ RestartSec=10
 
 [Install]
 WantedBy=multi-user.target
-"#;
+"#, socket_deps);
 
     let service_path = "/etc/systemd/system/rusty-proxy.service";
     if Path::new(service_path).exists() {
@@ -143,16 +877,30 @@ WantedBy=multi-user.target
     }
 
     fs::write(service_path, service_content)?;
+
+    if let Some(listen) = socket_listen {
+        let socket_content = format!(r#"[Unit]
+Description=Rusty Proxy listening socket
+
+[Socket]
+ListenStream={}
+
+[Install]
+WantedBy=sockets.target
+"#, listen);
+        fs::write("/etc/systemd/system/rusty-proxy.socket", socket_content)?;
+    }
     
     // Enable and start the service
     std::process::Command::new("systemctl")
         .args(["daemon-reload"])
         .status()?;
     
+    let unit = if socket_listen.is_some() { "rusty-proxy.socket" } else { "rusty-proxy" };
     std::process::Command::new("systemctl")
-        .args(["enable", "rusty-proxy"])
+        .args(["enable", unit])
         .status()?;
 
-    println!("Systemd service installed. Start with: sudo systemctl start rusty-proxy");
+    println!("Systemd {} installed. Start with: sudo systemctl start {}", if socket_listen.is_some() { "socket" } else { "service" }, unit);
     Ok(())
-}
\ No newline at end of file
+}
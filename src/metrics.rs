@@ -0,0 +1,87 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Process-wide counters, rendered in Prometheus text format by the admin API.
+#[derive(Default)]
+pub struct Metrics {
+    pub requests_total: AtomicU64,
+    pub upstream_requests: AtomicU64,
+    pub upstream_connections_opened: AtomicU64,
+    pub pool_flushes: AtomicU64,
+    /// Requests sent again after a 429 with `Retry-After`
+    pub upstream_retries: AtomicU64,
+    /// Access tokens fetched after an API refused an expired one
+    pub oauth_refreshes: AtomicU64,
+    pub active_connections: AtomicU64,
+    /// Requests that arrived on a client connection which had already served one
+    pub reused_connection_requests: AtomicU64,
+    /// Bytes relayed through upgraded connections, from and to clients
+    pub tunnel_bytes_up: AtomicU64,
+    pub tunnel_bytes_down: AtomicU64,
+}
+
+/// Counts a client connection as active for as long as it is held.
+pub struct ActiveConnection {
+    metrics: Arc<Metrics>,
+}
+
+impl ActiveConnection {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection { metrics }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fraction of upstream requests served on an already open connection.
+    pub fn pool_hit_ratio(&self) -> f64 {
+        let requests = self.upstream_requests.load(Ordering::Relaxed);
+        let opened = self.upstream_connections_opened.load(Ordering::Relaxed);
+        if requests == 0 {
+            return 0.0;
+        }
+        requests.saturating_sub(opened) as f64 / requests as f64
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("rusty_proxy_requests_total", "Requests received from clients", &self.requests_total),
+            ("rusty_proxy_upstream_requests_total", "Requests sent to upstreams", &self.upstream_requests),
+            ("rusty_proxy_upstream_connections_opened_total", "New upstream connections", &self.upstream_connections_opened),
+            ("rusty_proxy_reused_connection_requests_total", "Requests on already used client connections", &self.reused_connection_requests),
+            ("rusty_proxy_pool_flushes_total", "Upstream pool flushes", &self.pool_flushes),
+            ("rusty_proxy_upstream_retries_total", "Requests retried after an upstream 429", &self.upstream_retries),
+            ("rusty_proxy_oauth_refreshes_total", "Access tokens refreshed after an expired-token 401", &self.oauth_refreshes),
+            ("rusty_proxy_tunnel_bytes_up_total", "Bytes sent by clients over CONNECT tunnels and upgraded connections", &self.tunnel_bytes_up),
+            ("rusty_proxy_tunnel_bytes_down_total", "Bytes sent to clients over CONNECT tunnels and upgraded connections", &self.tunnel_bytes_down),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "# HELP rusty_proxy_active_connections Open client connections");
+        let _ = writeln!(out, "# TYPE rusty_proxy_active_connections gauge");
+        let _ = writeln!(out, "rusty_proxy_active_connections {}", self.active_connections.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP rusty_proxy_pool_hit_ratio Upstream requests that reused a pooled connection");
+        let _ = writeln!(out, "# TYPE rusty_proxy_pool_hit_ratio gauge");
+        let _ = writeln!(out, "rusty_proxy_pool_hit_ratio {:.4}", self.pool_hit_ratio());
+        out
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::HeaderMap;
+use serde_json::Value;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::config::OAuthClient;
+use crate::jwt;
+
+/// How long one request to a token endpoint may take.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A cached token this close to its `expires_in` is no longer used.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+/// Access tokens for the APIs listed under `[[oauth]]`, refreshed when an API turns one
+/// down as expired.
+pub struct OAuth {
+    sources: Vec<TokenSource>,
+    client: reqwest::Client,
+}
+
+/// One `[[oauth]]` entry and the last token its endpoint issued.
+pub struct TokenSource {
+    config: OAuthClient,
+    token: Mutex<Option<Cached>>,
+}
+
+struct Cached {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: Option<Instant>,
+}
+
+impl OAuth {
+    pub fn new(configs: &[OAuthClient]) -> Self {
+        OAuth {
+            sources: configs
+                .iter()
+                .map(|config| TokenSource {
+                    config: config.clone(),
+                    token: Mutex::new(None),
+                })
+                .collect(),
+            client: reqwest::Client::builder().timeout(TOKEN_TIMEOUT).build().unwrap_or_default(),
+        }
+    }
+
+    /// The first entry whose `domains` cover `host`.
+    pub fn source_for(&self, host: &str) -> Option<&TokenSource> {
+        self.sources.iter().find(|source| source.config.applies_to(host))
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+}
+
+impl TokenSource {
+    /// The token issued by the last refresh, unless it is about to expire.
+    pub async fn current(&self) -> Option<String> {
+        let token = self.token.lock().await;
+        let cached = token.as_ref()?;
+        match cached.expires_at {
+            Some(at) if Instant::now() + EXPIRY_MARGIN >= at => None,
+            _ => Some(cached.access_token.clone()),
+        }
+    }
+
+    /// A new access token in place of `stale`. Requests that were refused at the same time
+    /// share one refresh: whoever comes second gets the token the first one fetched.
+    pub async fn refresh(&self, client: &reqwest::Client, stale: &str) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some(cached) = token.as_ref().filter(|cached| cached.access_token != stale) {
+            return Ok(cached.access_token.clone());
+        }
+
+        let config = &self.config;
+        let refresh_token = token.as_ref().and_then(|cached| cached.refresh_token.clone()).or_else(|| config.refresh_token.clone());
+        let mut form = match &refresh_token {
+            Some(refresh_token) => vec![("grant_type", "refresh_token"), ("refresh_token", refresh_token.as_str())],
+            None => vec![("grant_type", "client_credentials")],
+        };
+        if let Some(scope) = &config.scope {
+            form.push(("scope", scope));
+        }
+        let mut request = client
+            .post(&config.token_url)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("accept", "application/json");
+        match (&config.client_secret, config.basic_auth) {
+            (Some(secret), true) => {
+                let credentials = STANDARD.encode(format!("{}:{}", form_encode(&config.client_id), form_encode(secret)));
+                request = request.header("authorization", format!("Basic {}", credentials));
+            }
+            (secret, _) => {
+                form.push(("client_id", &config.client_id));
+                if let Some(secret) = secret {
+                    form.push(("client_secret", secret));
+                }
+            }
+        }
+        let body: Vec<String> = form.iter().map(|(name, value)| format!("{}={}", name, form_encode(value))).collect();
+
+        let response = request.body(body.join("&")).send().await?;
+        let status = response.status();
+        let answer: Value = serde_json::from_slice(&response.bytes().await?).unwrap_or(Value::Null);
+        if !status.is_success() {
+            match answer.get("error").and_then(Value::as_str) {
+                Some(error) => bail!("{} answered {}: {}", config.token_url, status, error),
+                None => bail!("{} answered {}", config.token_url, status),
+            }
+        }
+        let access_token = answer
+            .get("access_token")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("{} sent no access_token", config.token_url))?
+            .to_string();
+        let expires_in = answer.get("expires_in").and_then(Value::as_u64);
+        info!(
+            "OAuth: new access token for {} from {}{}",
+            config.domains.join(", "),
+            config.token_url,
+            expires_in.map(|seconds| format!(", valid for {}s", seconds)).unwrap_or_default()
+        );
+        *token = Some(Cached {
+            access_token: access_token.clone(),
+            // Endpoints that rotate refresh tokens send a new one; the others keep theirs
+            refresh_token: answer.get("refresh_token").and_then(Value::as_str).map(str::to_string).or(refresh_token),
+            expires_at: expires_in.map(|seconds| Instant::now() + Duration::from_secs(seconds)),
+        });
+        Ok(access_token)
+    }
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(hyper::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+}
+
+/// Whether a 401 was about `token` having expired: the API says `invalid_token` in
+/// `WWW-Authenticate` (RFC 6750), or the token is a JWT whose `exp` has passed.
+pub fn is_expired(headers: &HeaderMap, token: &str) -> bool {
+    let invalid_token = headers
+        .get_all(hyper::header::WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("invalid_token"));
+    if invalid_token {
+        return true;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    jwt::Token::decode(token)
+        .and_then(|token| token.claims.get("exp").and_then(Value::as_i64))
+        .is_some_and(|exp| exp <= now)
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986.
+pub fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,526 @@
+use anyhow::{bail, Result};
+use serde_json::{json, Map, Value};
+use yaml_rust::{Yaml, YamlLoader};
+
+/// `$ref`s followed this deep.
+const REF_DEPTH_LIMIT: usize = 8;
+
+/// Problems `validate` reports for one value before it stops looking.
+const VALIDATION_LIMIT: usize = 10;
+
+/// Schemas nested deeper than this (through `$ref`s or otherwise) are not checked.
+const VALIDATION_DEPTH_LIMIT: usize = 64;
+
+/// Methods an OpenAPI path item can describe.
+const METHODS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+/// An OpenAPI 3 or Swagger 2 document, read from JSON or YAML.
+pub struct Spec {
+    doc: Value,
+}
+
+/// Where the API is served, from `servers` (OpenAPI 3) or `host` and `basePath` (Swagger 2).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Server {
+    /// Empty for a relative server URL, which is served on whatever host the spec is
+    pub host: String,
+    /// Without a trailing `/`; empty for the root
+    pub base_path: String,
+}
+
+/// One method on one path.
+pub struct Operation<'a> {
+    /// Uppercase, e.g. `GET`
+    pub method: String,
+    /// As written in the spec, e.g. `/pets/{petId}`
+    pub path: &'a str,
+    pub operation: &'a Value,
+    /// The path item the operation is on, which may hold parameters shared by its methods
+    pub item: &'a Value,
+}
+
+impl Operation<'_> {
+    pub fn id(&self) -> Option<&str> {
+        self.operation.get("operationId").and_then(Value::as_str)
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.operation.get("summary").and_then(Value::as_str).filter(|s| !s.is_empty())
+    }
+}
+
+/// The answer a mock gives for an operation.
+pub struct MockResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+impl Spec {
+    pub fn parse(text: &str) -> Result<Spec> {
+        let doc = if text.trim_start().starts_with('{') {
+            serde_json::from_str(text)?
+        } else {
+            let docs = YamlLoader::load_from_str(text).map_err(|e| anyhow::anyhow!("Invalid YAML: {}", e))?;
+            docs.first().map(yaml_to_json).unwrap_or(Value::Null)
+        };
+        if doc.get("openapi").is_none() && doc.get("swagger").is_none() {
+            bail!("Not an OpenAPI or Swagger document (no `openapi` or `swagger` field)");
+        }
+        if !doc.get("paths").is_some_and(Value::is_object) {
+            bail!("The spec has no `paths`");
+        }
+        Ok(Spec { doc })
+    }
+
+    pub fn title(&self) -> &str {
+        self.doc.pointer("/info/title").and_then(Value::as_str).unwrap_or("API")
+    }
+
+    /// The servers the spec names, `{variables}` taking their defaults.
+    pub fn servers(&self) -> Vec<Server> {
+        let mut urls = Vec::new();
+        if let Some(host) = self.doc.get("host").and_then(Value::as_str) {
+            let base_path = self.doc.get("basePath").and_then(Value::as_str).unwrap_or("");
+            urls.push(format!("//{}{}", host, base_path));
+        }
+        for server in self.doc.get("servers").and_then(Value::as_array).into_iter().flatten() {
+            let Some(mut url) = server.get("url").and_then(Value::as_str).map(str::to_string) else {
+                continue;
+            };
+            if let Some(variables) = server.get("variables").and_then(Value::as_object) {
+                for (name, variable) in variables {
+                    let value = variable.get("default").and_then(Value::as_str).unwrap_or("");
+                    url = url.replace(&format!("{{{}}}", name), value);
+                }
+            }
+            urls.push(url);
+        }
+
+        let mut servers: Vec<Server> = Vec::new();
+        for url in urls {
+            let rest = match url.split_once("//") {
+                Some((_, rest)) => rest,
+                None if url.starts_with('/') => &url,
+                None => continue,
+            };
+            let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            // The port, if any, is not part of the domain scripts target
+            let host = match authority.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => host,
+                _ => authority,
+            };
+            let server = Server {
+                host: host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase(),
+                base_path: path.trim_end_matches('/').to_string(),
+            };
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+        servers
+    }
+
+    pub fn operations(&self) -> Vec<Operation<'_>> {
+        let mut operations = Vec::new();
+        for (path, item) in self.doc.get("paths").and_then(Value::as_object).into_iter().flatten() {
+            let item = self.resolve(item);
+            for method in METHODS {
+                if let Some(operation) = item.get(method).filter(|o| o.is_object()) {
+                    operations.push(Operation {
+                        method: method.to_ascii_uppercase(),
+                        path,
+                        operation,
+                        item,
+                    });
+                }
+            }
+        }
+        operations
+    }
+
+    /// What a mock of the operation answers: the lowest documented 2xx response, else
+    /// `default`, else the first, with an example body for it. `None` when the operation
+    /// documents no responses.
+    pub fn mock_response(&self, operation: &Operation) -> Option<MockResponse> {
+        let responses = operation.operation.get("responses")?.as_object()?;
+        let mut codes: Vec<&String> = responses.keys().collect();
+        codes.sort();
+        let code = codes
+            .iter()
+            .find(|code| code.starts_with('2'))
+            .or_else(|| codes.iter().find(|code| code.as_str() == "default"))
+            .or_else(|| codes.first())?;
+        let status = match code.parse() {
+            Ok(status) => status,
+            // `default` and ranges like `2XX`
+            Err(_) => code.chars().next().and_then(|c| c.to_digit(10)).map_or(200, |class| class as u16 * 100),
+        };
+        let response = self.resolve(&responses[code.as_str()]);
+
+        let (content_type, example) = match response.get("content").and_then(Value::as_object) {
+            // OpenAPI 3: examples and schemas by media type
+            Some(content) => {
+                let Some((media_type, media)) = content.iter().find(|(media_type, _)| is_json(media_type)).or_else(|| content.iter().next()) else {
+                    return Some(MockResponse { status, content_type: None, body: String::new() });
+                };
+                let example = media
+                    .get("example")
+                    .cloned()
+                    .or_else(|| {
+                        let examples = media.get("examples")?.as_object()?;
+                        let first = self.resolve(examples.values().next()?);
+                        first.get("value").cloned()
+                    })
+                    .or_else(|| media.get("schema").map(|schema| self.example(schema)));
+                (Some(media_type.clone()), example)
+            }
+            // Swagger 2: one schema, examples by media type
+            None => {
+                let produces = operation.operation.get("produces").or_else(|| self.doc.get("produces")).and_then(Value::as_array);
+                let media_type = produces
+                    .and_then(|types| types.iter().filter_map(Value::as_str).find(|t| is_json(t)).or_else(|| types.first()?.as_str()))
+                    .unwrap_or("application/json")
+                    .to_string();
+                let example = response
+                    .get("examples")
+                    .and_then(|examples| examples.get(&media_type))
+                    .cloned()
+                    .or_else(|| response.get("schema").map(|schema| self.example(schema)));
+                (example.is_some().then_some(media_type), example)
+            }
+        };
+        let body = match (&content_type, example) {
+            (_, None) => String::new(),
+            (Some(media_type), Some(Value::String(text))) if !is_json(media_type) => text,
+            (_, Some(example)) => serde_json::to_string_pretty(&example).unwrap_or_default(),
+        };
+        Some(MockResponse { status, content_type, body })
+    }
+
+    /// The operation's parameters and those of its path item, which it may override.
+    pub fn parameters<'a>(&'a self, operation: &Operation<'a>) -> Vec<&'a Value> {
+        let mut parameters: Vec<&Value> = Vec::new();
+        for list in [operation.operation, operation.item] {
+            for parameter in list.get("parameters").and_then(Value::as_array).into_iter().flatten() {
+                let parameter = self.resolve(parameter);
+                let key = |p: &Value| (p.get("name").cloned(), p.get("in").cloned());
+                if !parameters.iter().any(|known| key(known) == key(parameter)) {
+                    parameters.push(parameter);
+                }
+            }
+        }
+        parameters
+    }
+
+    /// Where `value` breaks the schema, as `$.path: problem`, at most `VALIDATION_LIMIT`.
+    /// Covers types (with `nullable`), `enum`, `required`, `properties`,
+    /// `additionalProperties`, `items`, `allOf` / `anyOf` / `oneOf`, lengths and bounds;
+    /// `format` and `pattern` are not checked.
+    pub fn validate(&self, schema: &Value, value: &Value) -> Vec<String> {
+        let mut errors = Vec::new();
+        self.validate_at(schema, value, "$", &mut errors, 0);
+        errors
+    }
+
+    fn validate_at(&self, schema: &Value, value: &Value, at: &str, errors: &mut Vec<String>, depth: usize) {
+        if errors.len() >= VALIDATION_LIMIT || depth > VALIDATION_DEPTH_LIMIT {
+            return;
+        }
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if let Some(target) = self.lookup(reference) {
+                self.validate_at(target, value, at, errors, depth + 1);
+            }
+            return;
+        }
+        if value.is_null() && schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+            return;
+        }
+
+        for part in schema.get("allOf").and_then(Value::as_array).into_iter().flatten() {
+            self.validate_at(part, value, at, errors, depth + 1);
+        }
+        for key in ["anyOf", "oneOf"] {
+            let Some(options) = schema.get(key).and_then(Value::as_array) else {
+                continue;
+            };
+            let matching = options
+                .iter()
+                .filter(|option| {
+                    let mut option_errors = Vec::new();
+                    self.validate_at(option, value, at, &mut option_errors, depth + 1);
+                    option_errors.is_empty()
+                })
+                .count();
+            if matching == 0 {
+                errors.push(format!("{}: matches none of the {} schemas", at, key));
+            } else if key == "oneOf" && matching > 1 {
+                errors.push(format!("{}: matches {} of the oneOf schemas, not exactly one", at, matching));
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                errors.push(format!("{}: {} is not one of {}", at, brief(value), Value::Array(allowed.clone())));
+                return;
+            }
+        }
+
+        let types: Vec<&str> = match schema.get("type") {
+            Some(Value::String(kind)) => vec![kind.as_str()],
+            Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|kind| is_type(value, kind)) {
+            errors.push(format!("{}: expected {}, found {}", at, types.join(" or "), type_name(value)));
+            return;
+        }
+
+        match value {
+            Value::Object(fields) => {
+                for name in schema.get("required").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str) {
+                    if !fields.contains_key(name) {
+                        errors.push(format!("{}: missing required property {:?}", at, name));
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, field) in fields {
+                    let field_at = format!("{}.{}", at, name);
+                    match (properties.and_then(|p| p.get(name)), schema.get("additionalProperties")) {
+                        (Some(property), _) => self.validate_at(property, field, &field_at, errors, depth + 1),
+                        (None, Some(Value::Bool(false))) => errors.push(format!("{}: property not allowed", field_at)),
+                        (None, Some(additional)) if additional.is_object() => self.validate_at(additional, field, &field_at, errors, depth + 1),
+                        _ => {}
+                    }
+                }
+            }
+            Value::Array(items) => {
+                let count = items.len() as u64;
+                if let Some(min) = schema.get("minItems").and_then(Value::as_u64).filter(|min| count < *min) {
+                    errors.push(format!("{}: {} items, fewer than {}", at, count, min));
+                }
+                if let Some(max) = schema.get("maxItems").and_then(Value::as_u64).filter(|max| count > *max) {
+                    errors.push(format!("{}: {} items, more than {}", at, count, max));
+                }
+                if let Some(item_schema) = schema.get("items").filter(|items| items.is_object()) {
+                    for (index, item) in items.iter().enumerate() {
+                        self.validate_at(item_schema, item, &format!("{}[{}]", at, index), errors, depth + 1);
+                    }
+                }
+            }
+            Value::String(text) => {
+                let length = text.chars().count() as u64;
+                if let Some(min) = schema.get("minLength").and_then(Value::as_u64).filter(|min| length < *min) {
+                    errors.push(format!("{}: {} characters, fewer than {}", at, length, min));
+                }
+                if let Some(max) = schema.get("maxLength").and_then(Value::as_u64).filter(|max| length > *max) {
+                    errors.push(format!("{}: {} characters, more than {}", at, length, max));
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(0.0);
+                if let Some(min) = schema.get("minimum").and_then(Value::as_f64).filter(|min| number < *min) {
+                    errors.push(format!("{}: {} is below the minimum {}", at, number, min));
+                }
+                if let Some(max) = schema.get("maximum").and_then(Value::as_f64).filter(|max| number > *max) {
+                    errors.push(format!("{}: {} is above the maximum {}", at, number, max));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// A value that fits the schema: its `example`, `default` or first `enum` value where
+    /// it has one, otherwise one built from its type, with every property filled in.
+    pub fn example(&self, schema: &Value) -> Value {
+        self.example_at(schema, &mut Vec::new())
+    }
+
+    /// `within` holds the `$ref`s being expanded; a schema that contains itself ends in
+    /// `null` the second time round.
+    fn example_at<'a>(&'a self, schema: &'a Value, within: &mut Vec<&'a str>) -> Value {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            if within.contains(&reference) || within.len() >= REF_DEPTH_LIMIT {
+                return Value::Null;
+            }
+            let Some(target) = self.lookup(reference) else {
+                return Value::Null;
+            };
+            within.push(reference);
+            let example = self.example_at(target, within);
+            within.pop();
+            return example;
+        }
+        for key in ["example", "default", "const"] {
+            if let Some(value) = schema.get(key) {
+                return value.clone();
+            }
+        }
+        for key in ["examples", "enum"] {
+            if let Some(value) = schema.get(key).and_then(Value::as_array).and_then(|values| values.first()) {
+                return value.clone();
+            }
+        }
+        if let Some(parts) = schema.get("allOf").and_then(Value::as_array) {
+            let mut merged = Map::new();
+            for part in parts {
+                match self.example_at(part, within) {
+                    Value::Object(fields) => merged.extend(fields),
+                    other if merged.is_empty() => return other,
+                    _ => {}
+                }
+            }
+            return Value::Object(merged);
+        }
+        for key in ["oneOf", "anyOf"] {
+            if let Some(first) = schema.get(key).and_then(Value::as_array).and_then(|options| options.first()) {
+                return self.example_at(first, within);
+            }
+        }
+
+        match schema_type(schema) {
+            Some("object") => {
+                let properties = schema.get("properties").and_then(Value::as_object);
+                Value::Object(
+                    properties
+                        .into_iter()
+                        .flatten()
+                        .map(|(name, property)| (name.clone(), self.example_at(property, within)))
+                        .collect(),
+                )
+            }
+            Some("array") => match schema.get("items") {
+                Some(items) => json!([self.example_at(items, within)]),
+                None => json!([]),
+            },
+            Some("string") => json!(match schema.get("format").and_then(Value::as_str).unwrap_or("") {
+                "date-time" => "2024-01-01T00:00:00Z",
+                "date" => "2024-01-01",
+                "time" => "00:00:00Z",
+                "email" => "user@example.com",
+                "uuid" => "00000000-0000-0000-0000-000000000000",
+                "uri" | "url" => "https://example.com/",
+                "hostname" => "example.com",
+                "ipv4" => "192.0.2.1",
+                "ipv6" => "2001:db8::1",
+                "byte" | "binary" | "password" => "",
+                _ => "string",
+            }),
+            Some("integer") => schema.get("minimum").and_then(Value::as_i64).map_or(json!(0), |minimum| json!(minimum)),
+            Some("number") => schema.get("minimum").and_then(Value::as_f64).map_or(json!(0.0), |minimum| json!(minimum)),
+            Some("boolean") => json!(true),
+            _ => Value::Null,
+        }
+    }
+
+    /// Follows `$ref`s until a value that isn't one; unresolvable ones are left as they are.
+    pub fn resolve<'a>(&'a self, mut value: &'a Value) -> &'a Value {
+        for _ in 0..REF_DEPTH_LIMIT {
+            match value.get("$ref").and_then(Value::as_str).and_then(|reference| self.lookup(reference)) {
+                Some(target) => value = target,
+                None => break,
+            }
+        }
+        value
+    }
+
+    /// A `#/...` reference within the document; other files aren't read.
+    fn lookup(&self, reference: &str) -> Option<&Value> {
+        self.doc.pointer(reference.strip_prefix('#')?)
+    }
+}
+
+/// An OpenAPI path template as a regex, each `{param}` matching one segment as a named
+/// group where the name allows.
+pub fn path_regex(template: &str) -> String {
+    let mut pattern = String::new();
+    let mut groups: Vec<String> = Vec::new();
+    let mut rest = template.trim_end_matches('/');
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        pattern.push_str(&regex::escape(&rest[..start]));
+        let name: String = rest[start + 1..end].chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        if name.starts_with(|c: char| c.is_ascii_alphabetic()) && !groups.contains(&name) {
+            pattern.push_str(&format!("(?P<{}>[^/?#]+)", name));
+            groups.push(name);
+        } else {
+            pattern.push_str("[^/?#]+");
+        }
+        rest = &rest[end + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern
+}
+
+/// The schema's type: `type` (the first besides `null` when it is a list, as in OpenAPI
+/// 3.1), or what `properties` and `items` imply.
+fn schema_type(schema: &Value) -> Option<&str> {
+    match schema.get("type") {
+        Some(Value::String(kind)) => Some(kind),
+        Some(Value::Array(kinds)) => kinds.iter().filter_map(Value::as_str).find(|kind| *kind != "null"),
+        _ if schema.get("properties").is_some() => Some("object"),
+        _ if schema.get("items").is_some() => Some("array"),
+        _ => None,
+    }
+}
+
+fn is_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "number" => value.is_number(),
+        "null" => value.is_null(),
+        _ => type_name(value) == kind,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// A value for a message, cut short when long.
+fn brief(value: &Value) -> String {
+    let text = value.to_string();
+    match text.char_indices().nth(40) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+pub fn is_json(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+fn yaml_to_json(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Real(text) => text.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map_or_else(|| json!(text), Value::Number),
+        Yaml::Integer(number) => json!(number),
+        Yaml::String(text) => json!(text),
+        Yaml::Boolean(value) => json!(value),
+        Yaml::Array(items) => Value::Array(items.iter().map(yaml_to_json).collect()),
+        Yaml::Hash(entries) => Value::Object(
+            entries
+                .iter()
+                .filter_map(|(key, value)| {
+                    // Unquoted response codes are integers in YAML
+                    let key = match key {
+                        Yaml::String(text) | Yaml::Real(text) => text.clone(),
+                        Yaml::Integer(number) => number.to_string(),
+                        Yaml::Boolean(value) => value.to_string(),
+                        _ => return None,
+                    };
+                    Some((key, yaml_to_json(value)))
+                })
+                .collect(),
+        ),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => Value::Null,
+    }
+}
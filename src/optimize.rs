@@ -0,0 +1,108 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, ImageFormat};
+use tracing::warn;
+
+use crate::script_manager::{InjectType, InjectionScript};
+
+/// Minifies a `JavaScript` or `CSS` script's payload in place; other types and payloads
+/// the minifier rejects are left as written.
+pub fn minify_payload(script: &mut InjectionScript) {
+    match script.inject_type {
+        InjectType::JavaScript => script.script_content = minifier::js::minify(&script.script_content).to_string(),
+        InjectType::Css => match minifier::css::minify(&script.script_content) {
+            Ok(minified) => script.script_content = minified.to_string(),
+            Err(e) => warn!("Not minifying CSS of script {}: {}", script.name, e),
+        },
+        _ => {}
+    }
+}
+
+/// Collapses whitespace and optional markup in an HTML page. Comments are kept, since
+/// they carry the injection markers, and inline scripts and styles are left alone.
+pub fn minify_html(html: &str) -> String {
+    let mut cfg = minify_html::Cfg::spec_compliant();
+    cfg.keep_comments = true;
+    cfg.keep_html_and_head_opening_tags = true;
+    cfg.keep_closing_tags = true;
+    String::from_utf8_lossy(&minify_html::minify(html.as_bytes(), &cfg)).into_owned()
+}
+
+/// Re-encodes a JPEG at `quality` or a PNG at maximum compression. Returns `None` when
+/// the image can't be decoded or the result would not be smaller.
+pub fn recompress_image(data: &[u8], content_type: &str, quality: u8) -> Option<Vec<u8>> {
+    let format = match content_type.split(';').next()?.trim().to_lowercase().as_str() {
+        "image/jpeg" | "image/jpg" => ImageFormat::Jpeg,
+        "image/png" => ImageFormat::Png,
+        _ => return None,
+    };
+    let image = image::load_from_memory_with_format(data, format).ok()?;
+
+    let mut out = Vec::new();
+    let encoded = match format {
+        ImageFormat::Jpeg => {
+            let image = match image {
+                DynamicImage::ImageLuma8(_) => image,
+                other => DynamicImage::ImageRgb8(other.to_rgb8()),
+            };
+            image.write_with_encoder(JpegEncoder::new_with_quality(&mut out, quality.clamp(1, 100)))
+        }
+        _ => image.write_with_encoder(PngEncoder::new_with_quality(&mut out, CompressionType::Best, FilterType::Adaptive)),
+    };
+    match encoded {
+        Ok(()) if out.len() < data.len() => Some(out),
+        Ok(()) => None,
+        Err(e) => {
+            warn!("Failed to re-encode {} image: {}", content_type, e);
+            None
+        }
+    }
+}
+
+/// Bodies shorter than this aren't worth compressing.
+const COMPRESS_MIN: usize = 1024;
+
+/// Compresses a body in the best encoding a client's `Accept-Encoding` allows: zstd,
+/// then gzip, at equal q-values. Returns `None` for short bodies, clients that accept
+/// neither (brotli can't be produced in this build), or results that are no smaller.
+pub fn compress(body: &[u8], accept_encoding: &str) -> Option<(&'static str, Vec<u8>)> {
+    if body.len() < COMPRESS_MIN {
+        return None;
+    }
+    let quality = |encoding: &str| {
+        let mut wildcard = None;
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if name == encoding || (encoding == "gzip" && name == "x-gzip") {
+                return q;
+            }
+            if name == "*" {
+                wildcard = Some(q);
+            }
+        }
+        wildcard.unwrap_or(0.0)
+    };
+    let encoding = ["zstd", "gzip"]
+        .into_iter()
+        .map(|encoding| (encoding, quality(encoding)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(None, |best: Option<(&'static str, f32)>, (encoding, q)| match best {
+            Some((_, best_q)) if best_q >= q => best,
+            _ => Some((encoding, q)),
+        })?
+        .0;
+    let compressed = match encoding {
+        "zstd" => zstd::stream::encode_all(body, 3).ok()?,
+        _ => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(6));
+            std::io::Write::write_all(&mut encoder, body).ok()?;
+            encoder.finish().ok()?
+        }
+    };
+    (compressed.len() < body.len()).then_some((encoding, compressed))
+}
@@ -0,0 +1,427 @@
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::assets;
+use crate::body::Body;
+use crate::proxy::ProxyState;
+use crate::script_manager::{InjectType, ScriptManager};
+use crate::snapshot;
+
+/// Endpoints injected JavaScript can call as a supported backchannel to the proxy.
+const API_PREFIX: &str = "/__rusty_proxy/api/";
+
+/// Bounds on the shared key-value store; past `STORE_LIMIT` keys it starts over.
+const STORE_LIMIT: usize = 10_000;
+const STORE_VALUE_LIMIT: usize = 64 * 1024;
+
+/// Largest request body accepted by `log` and `store`; snapshots may be as large as
+/// capture's `max_body`.
+const BODY_LIMIT: usize = 256 * 1024;
+
+pub fn is_api_request(req: &Request<Body>) -> bool {
+    req.uri().path().starts_with(API_PREFIX)
+}
+
+/// `scheme://host[:port]` of a URL as browsers send it in `Origin`: lowercased, without
+/// the scheme's default port.
+pub fn origin(uri: &Uri) -> Option<String> {
+    let scheme = uri.scheme_str()?.to_ascii_lowercase();
+    let host = uri.host()?.to_ascii_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    Some(match uri.port_u16().filter(|port| *port != default_port) {
+        Some(port) => format!("{}://{}:{}", scheme, host, port),
+        None => format!("{}://{}", scheme, host),
+    })
+}
+
+/// State behind the page API: the key-value store shared by all pages.
+pub struct PageApi {
+    store: Mutex<HashMap<String, String>>,
+}
+
+impl PageApi {
+    pub fn new() -> Self {
+        PageApi {
+            store: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Everything in the key-value store, by key.
+    pub fn store_entries(&self) -> BTreeMap<String, String> {
+        self.store.lock().unwrap().iter().map(|(key, value)| (key.clone(), value.clone())).collect()
+    }
+
+    pub fn store_get(&self, key: &str) -> Option<String> {
+        self.store.lock().unwrap().get(key).cloned()
+    }
+
+    /// Stores `value` under `key`, unless it is larger than `STORE_VALUE_LIMIT`.
+    pub fn store_set(&self, key: &str, value: String) -> bool {
+        if value.len() > STORE_VALUE_LIMIT {
+            return false;
+        }
+        let mut store = self.store.lock().unwrap();
+        if store.len() >= STORE_LIMIT && !store.contains_key(key) {
+            store.clear();
+        }
+        store.insert(key.to_string(), value);
+        true
+    }
+
+    pub fn store_remove(&self, key: &str) -> bool {
+        self.store.lock().unwrap().remove(key).is_some()
+    }
+}
+
+/// Answers `/__rusty_proxy/api/*`:
+///
+/// - `POST log`: writes the body to the proxy log (target `rusty_proxy::page`)
+/// - `GET|PUT|DELETE store/<key>`: a small key-value store shared across pages
+/// - `GET fetch?url=<url>`: fetches a URL on the calling page's script domains server-side,
+///   bypassing CORS
+/// - `GET config`: proxy version and the scripts targeting the calling page
+/// - `POST snapshot`: archives a page's rendered DOM and canvases, sent by `Snapshot` scripts
+///
+/// Only pages scripts were injected into are answered, and all but `snapshot` need the
+/// `page-api` feature. Answers carry CORS headers for the calling origin, and preflights
+/// are handled.
+pub async fn handle(req: Request<Body>, client_addr: SocketAddr, state: &ProxyState) -> Response<Body> {
+    let endpoint = &req.uri().path()[API_PREFIX.len()..];
+    if !state.config.features.page_api && endpoint != "snapshot" {
+        return json_response(StatusCode::NOT_FOUND, json!({ "error": "the page-api feature is not enabled" }));
+    }
+    let Some(page) = calling_origin(&req).filter(|origin| state.injector.injected_into(origin)) else {
+        warn!("Page API refused {} {} for {}: not called from a scripted page", req.method(), req.uri().path(), client_addr.ip());
+        return json_response(StatusCode::FORBIDDEN, json!({ "error": "page not scripted" }));
+    };
+    let cross_origin = req.headers().contains_key("origin");
+    let mut response = if req.method() == Method::OPTIONS {
+        Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+    } else {
+        route(req, &page, client_addr, state).await
+    };
+
+    if !cross_origin {
+        return response;
+    }
+    let headers = response.headers_mut();
+    if let Ok(value) = page.parse() {
+        headers.insert("access-control-allow-origin", value);
+    }
+    headers.insert("access-control-allow-methods", "GET, POST, PUT, DELETE, OPTIONS".parse().unwrap());
+    headers.insert("access-control-allow-headers", "content-type".parse().unwrap());
+    headers.insert("vary", "origin".parse().unwrap());
+    response
+}
+
+async fn route(req: Request<Body>, page: &str, client_addr: SocketAddr, state: &ProxyState) -> Response<Body> {
+    let endpoint = req.uri().path()[API_PREFIX.len()..].to_string();
+    let api = &state.page_api;
+
+    match (req.method().clone(), endpoint.as_str()) {
+        (Method::POST, "log") => {
+            let page = page_of(&req);
+            match read_body(req).await {
+                Ok(body) => {
+                    info!(target: "rusty_proxy::page", "[{} {}] {}", client_addr.ip(), page, body.trim_end());
+                    json_response(StatusCode::OK, json!({ "logged": true }))
+                }
+                Err(response) => response,
+            }
+        }
+        (method, key) if key.starts_with("store/") => {
+            let key = key["store/".len()..].to_string();
+            if key.is_empty() {
+                return json_response(StatusCode::BAD_REQUEST, json!({ "error": "missing key" }));
+            }
+            match method {
+                Method::GET => match api.store_get(&key) {
+                    Some(value) => json_response(StatusCode::OK, json!({ "key": key, "value": value })),
+                    None => json_response(StatusCode::NOT_FOUND, json!({ "error": "no such key" })),
+                },
+                Method::PUT => {
+                    let value = match read_body(req).await {
+                        Ok(value) => value,
+                        Err(response) => return response,
+                    };
+                    if !api.store_set(&key, value) {
+                        return json_response(StatusCode::PAYLOAD_TOO_LARGE, json!({ "error": "value too large" }));
+                    }
+                    json_response(StatusCode::OK, json!({ "key": key, "stored": true }))
+                }
+                Method::DELETE => {
+                    let removed = api.store_remove(&key);
+                    json_response(StatusCode::OK, json!({ "key": key, "removed": removed }))
+                }
+                _ => json_response(StatusCode::METHOD_NOT_ALLOWED, json!({ "error": "method not allowed" })),
+            }
+        }
+        (Method::GET, "fetch") => {
+            let url = match query_param(&req, "url").and_then(|url| url.parse::<hyper::Uri>().ok()) {
+                Some(url) => url,
+                None => return json_response(StatusCode::BAD_REQUEST, json!({ "error": "missing or invalid url" })),
+            };
+            // Only domains the page's own scripts target, so pages can't use the proxy as an
+            // open relay
+            let host = url.host().unwrap_or("").to_ascii_lowercase();
+            let page_domain = page.parse::<Uri>().ok().and_then(|uri| uri.host().map(str::to_string)).unwrap_or_default();
+            let targeted = state
+                .injector
+                .script_manager()
+                .get_scripts_for_domain(&page_domain)
+                .iter()
+                .any(|script| ScriptManager::domain_matches(&host, &script.target_domains));
+            if !matches!(url.scheme_str(), Some("http") | Some("https")) || !targeted || !state.config.is_domain_allowed(&host) {
+                warn!("Page API refused fetch of {} from {} for {}", url, page, client_addr.ip());
+                return json_response(StatusCode::FORBIDDEN, json!({ "error": "domain not allowed" }));
+            }
+            let client = match public_client(&url, &host).await {
+                Ok(client) => client,
+                Err(message) => {
+                    warn!("Page API refused fetch of {} from {} for {}: {}", url, page, client_addr.ip(), message);
+                    return json_response(StatusCode::FORBIDDEN, json!({ "error": message }));
+                }
+            };
+            match client.get(url.to_string()).send().await {
+                Ok(upstream) => {
+                    let status = StatusCode::from_u16(upstream.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+                    let content_type = upstream.headers().get("content-type").and_then(|v| v.to_str().ok()).map(str::to_string);
+                    match upstream.bytes().await {
+                        Ok(body) => {
+                            let mut builder = Response::builder().status(status);
+                            if let Some(content_type) = content_type {
+                                builder = builder.header("content-type", content_type);
+                            }
+                            builder.body(Body::from(body)).unwrap()
+                        }
+                        Err(e) => json_response(StatusCode::BAD_GATEWAY, json!({ "error": e.to_string() })),
+                    }
+                }
+                Err(e) => json_response(StatusCode::BAD_GATEWAY, json!({ "error": e.to_string() })),
+            }
+        }
+        (Method::GET, "config") => {
+            let page = page_of(&req);
+            let domain = page.parse::<hyper::Uri>().ok().and_then(|uri| uri.host().map(str::to_string));
+            let scripts: Vec<String> = domain
+                .as_deref()
+                .map(|domain| {
+                    state
+                        .injector
+                        .script_manager()
+                        .get_scripts_for_domain(domain)
+                        .into_iter()
+                        .map(|script| script.name.clone())
+                        .collect()
+                })
+                .unwrap_or_default();
+            json_response(
+                StatusCode::OK,
+                json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "domain": domain,
+                    "scripts": scripts,
+                }),
+            )
+        }
+        (Method::POST, "snapshot") => {
+            let Some(capture) = state.capture.as_ref() else {
+                return json_response(StatusCode::CONFLICT, json!({ "error": "capture not enabled" }));
+            };
+            let posted: Value = match read_body_limited(req, capture.max_body()).await.map(|body| serde_json::from_str(&body)) {
+                Ok(Ok(posted)) => posted,
+                Ok(Err(e)) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+                Err(response) => return response,
+            };
+            let (Some(page), Some(html)) = (posted["url"].as_str(), posted["html"].as_str()) else {
+                return json_response(StatusCode::BAD_REQUEST, json!({ "error": "missing url or html" }));
+            };
+            // Only pages a Snapshot script was injected into, so the capture can't be filled at will
+            let Some(uri) = page.parse::<hyper::Uri>().ok().filter(|uri| uri.host().is_some()) else {
+                return json_response(StatusCode::BAD_REQUEST, json!({ "error": "invalid url" }));
+            };
+            let domain = uri.host().unwrap_or_default();
+            let scripted = state
+                .injector
+                .script_manager()
+                .scripts_for_url(domain, &uri)
+                .iter()
+                .any(|script| matches!(script.inject_type, InjectType::Snapshot));
+            if !scripted || !capture.wants_domain(domain) {
+                warn!("Page API refused snapshot of {} for {}", page, client_addr.ip());
+                return json_response(StatusCode::FORBIDDEN, json!({ "error": "page not snapshotted" }));
+            }
+            let images: Vec<_> = posted["canvases"]
+                .as_array()
+                .map(|canvases| canvases.iter().filter_map(|c| c.as_str().and_then(snapshot::decode_data_url)).collect())
+                .unwrap_or_default();
+            capture.record_snapshot(page, html.as_bytes(), &images);
+            json_response(StatusCode::OK, json!({ "archived": true, "images": images.len() }))
+        }
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "unknown page API endpoint" })),
+    }
+}
+
+/// The origin of the page calling the API: its `Origin`, which browsers send on every
+/// cross-origin or state-changing request, or else the one the request itself is for.
+fn calling_origin(req: &Request<Body>) -> Option<String> {
+    if let Some(sent) = req.headers().get("origin") {
+        return origin(&sent.to_str().ok()?.parse().ok()?);
+    }
+    if req.uri().authority().is_some() {
+        return origin(req.uri());
+    }
+    let host = req.headers().get(hyper::header::HOST)?.to_str().ok()?;
+    origin(&format!("http://{}", host).parse().ok()?)
+}
+
+/// A client for one fetch-through request, bound to the addresses `host` resolves to now
+/// so a second lookup can't send it elsewhere. Hosts resolving to loopback, link-local or
+/// private addresses are refused, and redirects are not followed.
+async fn public_client(url: &Uri, host: &str) -> Result<reqwest::Client, String> {
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    let port = url.port_u16().unwrap_or(if url.scheme_str() == Some("https") { 443 } else { 80 });
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("can't resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{} has no address", host));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(format!("{} resolves to the non-public address {}", host, addr.ip()));
+    }
+    reqwest::Client::builder()
+        .no_proxy()
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(host, &addrs)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Whether `ip` is routed on the public internet, rather than being loopback, link-local,
+/// private (including carrier-grade NAT and IPv6 unique local), unspecified or multicast.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The calling page, from `Referer` or else `Origin`.
+fn page_of(req: &Request<Body>) -> String {
+    ["referer", "origin"]
+        .iter()
+        .find_map(|name| req.headers().get(*name).and_then(|v| v.to_str().ok()))
+        .unwrap_or("unknown page")
+        .to_string()
+}
+
+async fn read_body(req: Request<Body>) -> Result<String, Response<Body>> {
+    read_body_limited(req, BODY_LIMIT).await
+}
+
+/// The body as text; reading stops as soon as it passes `limit`.
+async fn read_body_limited(req: Request<Body>, limit: usize) -> Result<String, Response<Body>> {
+    match Limited::new(req.into_body(), limit).collect().await {
+        Ok(body) => Ok(String::from_utf8_lossy(&body.to_bytes()).into_owned()),
+        Err(e) if e.is::<LengthLimitError>() => Err(json_response(StatusCode::PAYLOAD_TOO_LARGE, json!({ "error": "body too large" }))),
+        Err(e) => Err(json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() }))),
+    }
+}
+
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| assets::percent_decode(value)).flatten()
+    })
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<Body> {
+    let body = value.to_string();
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_drop_default_ports() {
+        let origin_of = |url: &str| origin(&url.parse().unwrap());
+        assert_eq!(origin_of("http://Shop.Example.com:80/cart?x=1").as_deref(), Some("http://shop.example.com"));
+        assert_eq!(origin_of("https://shop.example.com:8443/").as_deref(), Some("https://shop.example.com:8443"));
+        assert_eq!(origin_of("https://[::1]:443/").as_deref(), Some("https://[::1]"));
+        assert_eq!(origin_of("/relative"), None);
+        assert_eq!(origin_of("ftp://files.example.com/"), None);
+    }
+
+    #[test]
+    fn callers_are_their_origin_or_the_page_asked() {
+        let req = |uri: &str, origin: Option<&str>| {
+            let mut builder = Request::get(uri).header("host", "shop.example.com");
+            if let Some(origin) = origin {
+                builder = builder.header("origin", origin);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let api = "http://shop.example.com/__rusty_proxy/api/store/x";
+        assert_eq!(calling_origin(&req(api, None)).as_deref(), Some("http://shop.example.com"));
+        assert_eq!(calling_origin(&req(api, Some("https://evil.example"))).as_deref(), Some("https://evil.example"));
+        assert_eq!(calling_origin(&req(api, Some("null"))), None);
+        assert_eq!(calling_origin(&req("/__rusty_proxy/api/store/x", None)).as_deref(), Some("http://shop.example.com"));
+    }
+
+    #[test]
+    fn only_public_addresses_are_fetched() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        for ip in ["93.184.216.34", "2606:2800:220:1::1", "::ffff:93.184.216.34"] {
+            assert!(public(ip), "{} was refused", ip);
+        }
+        let refused = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "::",
+            "fe80::1", "fd00::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+        ];
+        for ip in refused {
+            assert!(!public(ip), "{} was allowed", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_past_the_limit_are_refused() {
+        let req = |body: &'static str| Request::post("/__rusty_proxy/api/log").body(Body::from(body)).unwrap();
+        assert_eq!(read_body_limited(req("checkout"), 8).await.unwrap(), "checkout");
+        let refused = read_body_limited(req("checkout reached"), 8).await.unwrap_err();
+        assert_eq!(refused.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,77 @@
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+/// Compiled program size allowed for a script's regex, so a pattern like `(a{1000}){1000}`
+/// is refused instead of exhausting memory.
+const SIZE_LIMIT: usize = 256 * 1024;
+
+/// Cache the lazy DFA may build per search before falling back to the slower engines.
+const DFA_SIZE_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Deepest nesting of groups and repetitions accepted.
+const NEST_LIMIT: u32 = 32;
+
+/// Matches replaced between deadline checks.
+const DEADLINE_STRIDE: usize = 64;
+
+/// Distinct patterns `cached` keeps compiled; past it the cache starts over.
+const CACHE_LIMIT: usize = 4096;
+
+/// Compiles a regex supplied by a script within the complexity limits. The `regex`
+/// crate never backtracks, so a search is linear in the input; these bound the rest.
+pub fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .size_limit(SIZE_LIMIT)
+        .dfa_size_limit(DFA_SIZE_LIMIT)
+        .nest_limit(NEST_LIMIT)
+        .build()
+}
+
+/// `compile`, remembering the result for patterns matched over and over, like the domain
+/// lists of scripts and config sections. `None` if the pattern doesn't compile.
+pub fn cached(pattern: &str) -> Option<Regex> {
+    static CACHE: OnceLock<Mutex<HashMap<String, Option<Regex>>>> = OnceLock::new();
+    let mut cache = CACHE.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(compiled) = cache.get(pattern) {
+        return compiled.clone();
+    }
+    if cache.len() >= CACHE_LIMIT {
+        cache.clear();
+    }
+    let compiled = compile(pattern).ok();
+    cache.insert(pattern.to_string(), compiled.clone());
+    compiled
+}
+
+/// Why `replace_all` left a body alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// The replacements ran past the deadline
+    Deadline,
+    /// The pattern matched more often than allowed
+    Replacements,
+}
+
+/// `Regex::replace_all` that gives up once `deadline` passes or the pattern has matched
+/// more than `max_replacements` times (0 is no limit), so a pattern matching a huge body
+/// thousands of times can't hold up or balloon the response.
+pub fn replace_all(regex: &Regex, text: &str, replacement: &str, deadline: Instant, max_replacements: usize) -> Result<String, Stop> {
+    let mut output = String::with_capacity(text.len());
+    let mut last = 0;
+    for (i, captures) in regex.captures_iter(text).enumerate() {
+        if max_replacements > 0 && i >= max_replacements {
+            return Err(Stop::Replacements);
+        }
+        if i % DEADLINE_STRIDE == 0 && Instant::now() >= deadline {
+            return Err(Stop::Deadline);
+        }
+        let whole = captures.get(0).unwrap();
+        output.push_str(&text[last..whole.start()]);
+        captures.expand(replacement, &mut output);
+        last = whole.end();
+    }
+    output.push_str(&text[last..]);
+    Ok(output)
+}
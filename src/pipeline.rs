@@ -0,0 +1,95 @@
+use hyper::{Request, Response};
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::util::BoxCloneService;
+use tower::{Layer, Service, ServiceExt};
+
+use crate::body::Body;
+use crate::proxy::{ClientConnection, ProxyState};
+
+/// A proxied request's trip through the stages, ending in the upstream answer. Stages
+/// never fail: errors are turned into responses where they happen.
+pub type ProxyService = BoxCloneService<Request<Body>, Response<Body>, Infallible>;
+
+pub type StageFuture = Pin<Box<dyn Future<Output = Response<Body>> + Send>>;
+
+/// One stage: gets the request and the rest of the chain, and either answers itself or
+/// hands the request on with `next.run`, possibly working on the response it gets back.
+pub type StageFn = fn(Request<Body>, Env, Next) -> StageFuture;
+
+/// What every stage can reach besides the request: proxy-wide state and the client
+/// connection the request arrived on. Data one stage hands to later ones (the
+/// `RequestContext`, timings) travels in the request's extensions.
+#[derive(Clone)]
+pub struct Env {
+    pub state: Arc<ProxyState>,
+    pub conn: Arc<ClientConnection>,
+}
+
+/// The stages after the current one.
+pub struct Next(ProxyService);
+
+impl Next {
+    pub async fn run(self, req: Request<Body>) -> Response<Body> {
+        match self.0.oneshot(req).await {
+            Ok(res) => res,
+            Err(never) => match never {},
+        }
+    }
+}
+
+/// Wraps a `StageFn` as a Tower layer, so stages stack with `ServiceBuilder` next to any
+/// other Tower middleware.
+#[derive(Clone)]
+pub struct StageLayer {
+    stage: StageFn,
+    env: Env,
+}
+
+impl StageLayer {
+    pub fn new(stage: StageFn, env: Env) -> Self {
+        StageLayer { stage, env }
+    }
+}
+
+impl<S> Layer<S> for StageLayer
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Service = StageService;
+
+    fn layer(&self, inner: S) -> StageService {
+        StageService {
+            stage: self.stage,
+            env: self.env.clone(),
+            inner: BoxCloneService::new(inner),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StageService {
+    stage: StageFn,
+    env: Env,
+    inner: ProxyService,
+}
+
+impl Service<Request<Body>> for StageService {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        // The rest of the chain is readied by `Next::run` when the stage calls it
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let response = (self.stage)(req, self.env.clone(), Next(self.inner.clone()));
+        Box::pin(async move { Ok(response.await) })
+    }
+}
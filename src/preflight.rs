@@ -0,0 +1,188 @@
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::admin;
+use crate::config::Config;
+use crate::dns;
+use crate::proxy::ProxyServer;
+use crate::storage;
+use crate::upgrade;
+
+/// Name looked up to check that origins can be resolved.
+const DNS_PROBE: &str = "example.com";
+
+/// How long the DNS probe may take before it counts as failed.
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long listing the storage backend may take.
+const STORAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// File created and removed again to check the scripts directory is writable.
+const WRITE_PROBE: &str = ".rusty-proxy-preflight";
+
+enum Status {
+    Ok,
+    /// The proxy runs, but something will not work
+    Warn,
+    /// Starting would fail, or break traffic as soon as it arrives
+    Fail,
+    /// Nothing to check with this config
+    Skip,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    /// What to do about a warning or failure
+    hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Checks what `start` depends on before it binds anything and prints the results as a
+/// table. Returns whether the proxy may start, i.e. no check failed.
+pub async fn run(port: u16, config: &Config, scripts_dir: &str) -> bool {
+    let mut checks = vec![check_listen(ProxyServer::listen_addr(port, config))];
+    if config.admin.enabled {
+        if let Some(listen) = config.admin.tls_listen.as_deref().filter(|l| !l.is_empty()) {
+            checks.push(check_admin_tls(config, listen));
+        }
+    }
+    checks.push(check_scripts_dir(Path::new(scripts_dir)));
+    checks.push(Check::new("upstream proxy", Status::Skip, "none configured; requests go straight to origins"));
+    checks.push(check_dns(config).await);
+    checks.push(check_storage(config).await);
+
+    println!("Preflight:");
+    let width = checks.iter().map(|check| check.name.len()).max().unwrap_or(0);
+    let mut passed = true;
+    for check in &checks {
+        let status = match check.status {
+            Status::Ok => "OK",
+            Status::Warn => "WARN",
+            Status::Fail => {
+                passed = false;
+                "FAIL"
+            }
+            Status::Skip => "SKIP",
+        };
+        println!("  {:<4}  {:<width$}  {}", status, check.name, check.detail, width = width);
+        if let Some(hint) = &check.hint {
+            println!("  {:<4}  {:<width$}  -> {}", "", "", hint, width = width);
+        }
+    }
+    passed
+}
+
+fn check_listen(addr: SocketAddr) -> Check {
+    if upgrade::has_inherited_listener() {
+        return Check::new("listen port", Status::Ok, "listening socket handed over by the previous process or systemd");
+    }
+    match bind_probe(addr) {
+        Ok(()) => Check::new("listen port", Status::Ok, format!("{} is free", addr)),
+        Err(check) => check,
+    }
+}
+
+fn check_admin_tls(config: &Config, listen: &str) -> Check {
+    let addr: SocketAddr = match listen.parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            return Check::new("admin TLS", Status::Fail, format!("admin.tls_listen {} is not an address", listen))
+                .hint("use an address and port such as 0.0.0.0:8443");
+        }
+    };
+    if let Err(e) = admin::tls_acceptor(&config.admin) {
+        return Check::new("admin TLS", Status::Fail, e.to_string())
+            .hint("point admin.tls_cert and admin.tls_key, and the cert and key of each admin.tls_certs, at readable PEM files holding a certificate chain and its key");
+    }
+    match bind_probe(addr) {
+        Ok(()) => Check::new("admin TLS", Status::Ok, format!("certificate loads, {} is free", addr)),
+        Err(check) => Check { name: "admin TLS", ..check },
+    }
+}
+
+/// Binds `addr` and lets go of it again.
+fn bind_probe(addr: SocketAddr) -> Result<(), Check> {
+    match TcpListener::bind(addr) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => Err(Check::new("listen port", Status::Fail, format!("{} is already in use", addr))
+            .hint(format!("stop whatever listens there (`ss -ltnp 'sport = :{}'`) or pick another port with --port", addr.port()))),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Err(Check::new("listen port", Status::Fail, format!("not allowed to bind {}", addr))
+            .hint("ports below 1024 need root or CAP_NET_BIND_SERVICE (`setcap cap_net_bind_service=+ep`); or use a higher port")),
+        Err(e) if e.kind() == ErrorKind::AddrNotAvailable => Err(Check::new("listen port", Status::Fail, format!("{} is not an address of this host", addr.ip()))
+            .hint("set proxy.bind_address to an address of one of this host's interfaces, or 0.0.0.0")),
+        Err(e) => Err(Check::new("listen port", Status::Fail, format!("cannot bind {}: {}", addr, e))),
+    }
+}
+
+fn check_scripts_dir(dir: &Path) -> Check {
+    if !dir.exists() {
+        return Check::new("scripts directory", Status::Warn, format!("{} does not exist, so no scripts load", dir.display()))
+            .hint("create it, run `rusty-proxy init`, or point --scripts-dir at your scripts");
+    }
+    if !dir.is_dir() {
+        return Check::new("scripts directory", Status::Fail, format!("{} is not a directory", dir.display()))
+            .hint("point --scripts-dir or scripts.directory at a directory");
+    }
+    let probe = dir.join(WRITE_PROBE);
+    match std::fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+            Check::new("scripts directory", Status::Ok, format!("{} is writable", dir.display()))
+        }
+        Err(e) => Check::new("scripts directory", Status::Warn, format!("{} is read-only ({})", dir.display(), e.kind()))
+            .hint("scripts still load, but the management agent and `script new/import/install` can't write there"),
+    }
+}
+
+async fn check_dns(config: &Config) -> Check {
+    if config.dns.enabled {
+        if let Err(e) = dns::resolver(&config.dns) {
+            return Check::new("DNS", Status::Fail, format!("DNS-over-HTTPS has no resolver: {}", e))
+                .hint("set dns.upstream to a resolver such as 1.1.1.1:53");
+        }
+    }
+    let lookup = tokio::net::lookup_host((DNS_PROBE, 80));
+    match tokio::time::timeout(DNS_TIMEOUT, lookup).await.map(|result| result.map(|addrs| addrs.count())) {
+        Ok(Ok(0)) => Check::new("DNS", Status::Warn, format!("{} resolved to no addresses", DNS_PROBE))
+            .hint("check the nameservers in /etc/resolv.conf"),
+        Ok(Ok(_)) => Check::new("DNS", Status::Ok, format!("{} resolves", DNS_PROBE)),
+        Ok(Err(e)) => Check::new("DNS", Status::Warn, format!("{} does not resolve: {}", DNS_PROBE, e))
+            .hint("check the nameservers in /etc/resolv.conf; until names resolve, only origins given by IP can be reached"),
+        Err(_) => Check::new("DNS", Status::Warn, format!("no answer for {} within {}s", DNS_PROBE, DNS_TIMEOUT.as_secs()))
+            .hint("check the nameservers in /etc/resolv.conf and that UDP port 53 is not blocked"),
+    }
+}
+
+async fn check_storage(config: &Config) -> Check {
+    let storage = match storage::open(&config.storage) {
+        Ok(Some(storage)) => storage,
+        Ok(None) => return Check::new("storage", Status::Skip, "none configured; captures and stats stay on this host"),
+        Err(e) => return Check::new("storage", Status::Fail, e.to_string()).hint("set storage.backend to memory, filesystem or s3, or leave it empty"),
+    };
+    match tokio::time::timeout(STORAGE_TIMEOUT, storage.list("stats/")).await {
+        Ok(Ok(_)) => Check::new("storage", Status::Ok, format!("{} is reachable", storage.describe())),
+        Ok(Err(e)) => Check::new("storage", Status::Warn, format!("{}: {}", storage.describe(), e))
+            .hint("check storage.bucket, endpoint, region and the credentials; failed uploads are retried a few times, and the local files are kept"),
+        Err(_) => Check::new("storage", Status::Warn, format!("{} gave no answer within {}s", storage.describe(), STORAGE_TIMEOUT.as_secs()))
+            .hint("check that storage.endpoint is reachable from this host"),
+    }
+}
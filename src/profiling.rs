@@ -0,0 +1,57 @@
+use anyhow::{anyhow, Result};
+use pprof::protos::Message;
+use std::time::Duration;
+
+/// Longest CPU profile the admin API will collect in one request.
+const MAX_PROFILE_SECONDS: u64 = 60;
+
+/// Sampling frequency for CPU profiles, in Hz.
+const PROFILE_FREQUENCY: i32 = 99;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileFormat {
+    /// Uncompressed pprof protobuf, for `go tool pprof`
+    Protobuf,
+    /// Rendered SVG flamegraph
+    Flamegraph,
+}
+
+/// Samples the whole process for `seconds` and returns the encoded profile.
+pub async fn cpu_profile(seconds: u64, format: ProfileFormat) -> Result<Vec<u8>> {
+    let seconds = seconds.clamp(1, MAX_PROFILE_SECONDS);
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(PROFILE_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| anyhow!("Failed to start profiler (is another profile running?): {}", e))?;
+
+    tokio::time::sleep(Duration::from_secs(seconds)).await;
+
+    let report = guard.report().build()?;
+    let mut body = Vec::new();
+    match format {
+        ProfileFormat::Flamegraph => report.flamegraph(&mut body)?,
+        ProfileFormat::Protobuf => report.pprof()?.encode(&mut body)?,
+    }
+    Ok(body)
+}
+
+/// Dumps a jemalloc heap profile in pprof format. Only available when built with the
+/// `heap-profiling` feature, which makes jemalloc the global allocator.
+#[cfg(feature = "heap-profiling")]
+pub async fn heap_profile() -> Result<Vec<u8>> {
+    let mut prof_ctl = jemalloc_pprof::PROF_CTL
+        .as_ref()
+        .ok_or_else(|| anyhow!("jemalloc profiling is not available"))?
+        .lock()
+        .await;
+    if !prof_ctl.activated() {
+        return Err(anyhow!("jemalloc profiling is not activated"));
+    }
+    prof_ctl.dump_pprof()
+}
+
+#[cfg(not(feature = "heap-profiling"))]
+pub async fn heap_profile() -> Result<Vec<u8>> {
+    Err(anyhow!("heap profiling requires building with --features heap-profiling"))
+}
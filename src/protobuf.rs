@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use serde_json::Value;
+use tracing::{error, info};
+
+use crate::config::ProtobufEndpoint;
+
+/// Refuses to decode gRPC frames claiming more than this, rather than trusting the prefix.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+struct Endpoint {
+    path: String,
+    pool: DescriptorPool,
+    request: Option<MessageDescriptor>,
+    response: Option<MessageDescriptor>,
+}
+
+/// Turns captured protobuf and gRPC bodies into JSON for the logs, using the descriptor
+/// sets registered in `logging.protobuf`. Decoding is read-only: bodies are always
+/// forwarded as received.
+pub struct ProtobufDecoder {
+    endpoints: Vec<Endpoint>,
+}
+
+impl ProtobufDecoder {
+    /// Loads every configured descriptor set; endpoints that fail to load are logged and
+    /// left out so the proxy still starts.
+    pub fn load(config: &[ProtobufEndpoint]) -> Self {
+        let mut endpoints = Vec::new();
+        for entry in config {
+            match load_endpoint(entry) {
+                Ok(endpoint) => {
+                    info!("Decoding protobuf bodies under {} with {}", entry.path, entry.descriptor_set);
+                    endpoints.push(endpoint);
+                }
+                Err(e) => error!("Failed to load protobuf descriptors for {}: {}", entry.path, e),
+            }
+        }
+        ProtobufDecoder { endpoints }
+    }
+
+    pub fn decode_request(&self, path: &str, content_type: Option<&str>, body: &[u8]) -> Option<String> {
+        let endpoint = self.endpoint(path)?;
+        let message = endpoint.request.clone().or_else(|| grpc_method_types(&endpoint.pool, path).map(|(i, _)| i))?;
+        decode_body(message, content_type, body)
+    }
+
+    pub fn decode_response(&self, path: &str, content_type: Option<&str>, body: &[u8]) -> Option<String> {
+        let endpoint = self.endpoint(path)?;
+        let message = endpoint.response.clone().or_else(|| grpc_method_types(&endpoint.pool, path).map(|(_, o)| o))?;
+        decode_body(message, content_type, body)
+    }
+
+    /// The registration with the longest matching path prefix.
+    fn endpoint(&self, path: &str) -> Option<&Endpoint> {
+        self.endpoints
+            .iter()
+            .filter(|endpoint| path.starts_with(&endpoint.path))
+            .max_by_key(|endpoint| endpoint.path.len())
+    }
+}
+
+fn load_endpoint(entry: &ProtobufEndpoint) -> Result<Endpoint> {
+    let bytes = std::fs::read(&entry.descriptor_set)?;
+    let pool = DescriptorPool::decode(bytes.as_slice())?;
+    let lookup = |name: &Option<String>| -> Result<Option<MessageDescriptor>> {
+        match name {
+            Some(name) => pool
+                .get_message_by_name(name)
+                .map(Some)
+                .ok_or_else(|| anyhow!("Message {} not found in {}", name, entry.descriptor_set)),
+            None => Ok(None),
+        }
+    };
+    Ok(Endpoint {
+        path: entry.path.clone(),
+        request: lookup(&entry.request_message)?,
+        response: lookup(&entry.response_message)?,
+        pool,
+    })
+}
+
+/// Input and output types of the gRPC method addressed as `/package.Service/Method`.
+fn grpc_method_types(pool: &DescriptorPool, path: &str) -> Option<(MessageDescriptor, MessageDescriptor)> {
+    let (service, method) = path.trim_start_matches('/').split_once('/')?;
+    let service = pool.get_service_by_name(service)?;
+    let method = service.methods().find(|m| m.name() == method)?;
+    Some((method.input(), method.output()))
+}
+
+/// gRPC bodies are a sequence of length-prefixed messages and become a JSON array; other
+/// bodies are decoded as a single message.
+fn decode_body(message: MessageDescriptor, content_type: Option<&str>, body: &[u8]) -> Option<String> {
+    let grpc = content_type.map(|ct| ct.to_lowercase().starts_with("application/grpc")).unwrap_or(false);
+    if !grpc {
+        let decoded = DynamicMessage::decode(message, body).ok()?;
+        return serde_json::to_string(&decoded).ok();
+    }
+
+    let mut messages = Vec::new();
+    let mut rest = body;
+    while rest.len() >= 5 {
+        let compressed = rest[0] == 1;
+        let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        if len > MAX_FRAME_LEN || rest.len() < 5 + len {
+            break;
+        }
+        let frame = &rest[5..5 + len];
+        messages.push(if compressed {
+            Value::String(format!("<compressed message, {} bytes>", len))
+        } else {
+            let decoded = DynamicMessage::decode(message.clone(), frame).ok()?;
+            serde_json::to_value(&decoded).ok()?
+        });
+        rest = &rest[5 + len..];
+    }
+    if messages.is_empty() && !body.is_empty() {
+        return None;
+    }
+    Some(Value::Array(messages).to_string())
+}
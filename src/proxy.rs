@@ -1,166 +1,1278 @@
-use anyhow::{anyhow, Result};
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Client, Request, Response, Server, Uri};
-use hyper_util::rt::TokioIo;
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use hyper::body::Incoming;
+use hyper::http::uri::Authority;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tracing::{debug, error, info, warn};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
+use tower::util::BoxCloneService;
+use tower::{service_fn, ServiceBuilder, ServiceExt};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::config::Config;
-use crate::http_injector::HttpInjector;
+use crate::admin;
+use crate::agent;
+use crate::alert::Alerts;
+use crate::assertions::Assertions;
+use crate::assets::{self, AssetStore};
+use crate::body::{self, Body};
+use crate::capture::Capture;
+use crate::checksum::Checksums;
+use crate::cluster::{self, Cluster};
+use crate::conformance::Conformance;
+use crate::diagnostics;
+use crate::dns;
+use crate::error_page::{self, ErrorPages, Failed};
+use crate::concurrency::Concurrency;
+use crate::config::{BypassMode, Config, HeaderFilter};
+use crate::context::RequestContext;
+use crate::forwarded;
+use crate::hosts::{self, Hosts};
+use crate::hsts::Hsts;
+use crate::ftp;
+use crate::logging::{self, Sampler, VerboseLog};
+use crate::http_injector::{resolve_url, HttpInjector};
+use crate::metrics::{ActiveConnection, Metrics};
+use crate::oauth::{self, OAuth};
+use crate::page_api::{self, PageApi};
+use crate::pipeline::{Env, Next, ProxyService, StageFn, StageFuture, StageLayer};
+use crate::proxy_protocol;
 use crate::script_manager::ScriptManager;
+use crate::sso::Sso;
+use crate::storage::{self, Storage, Uploader};
+use crate::tee::Tee;
+use crate::trace::Tracer;
+use crate::tunnel::{self, Tunnels};
+use crate::upgrade;
+use crate::upstream::{PhaseTimings, UpstreamPool, PHASE_TIMINGS};
+
+/// Per-connection state; verdicts reached on one request carry over to later requests on
+/// the same keep-alive connection.
+pub struct ClientConnection {
+    /// Process-unique; every log line of the connection carries it in a `conn` span
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub authenticated: AtomicBool,
+    pub requests: AtomicU64,
+    /// CONNECT tunnels and protocol upgrades started on the connection
+    pub upgrades: AtomicU64,
+    pub opened: Instant,
+}
+
+/// Time spent in the injector and waiting on the upstream for one request, filled in by
+/// the stages and reported in `Server-Timing`.
+#[derive(Default)]
+struct StageTimings {
+    phases: Arc<PhaseTimings>,
+    inject_us: AtomicU64,
+    upstream_us: AtomicU64,
+}
+
+impl StageTimings {
+    fn add_inject(&self, elapsed: Duration) {
+        self.inject_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn set_upstream(&self, elapsed: Duration) {
+        self.upstream_us.store(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn inject(&self) -> Duration {
+        Duration::from_micros(self.inject_us.load(Ordering::Relaxed))
+    }
+
+    fn upstream(&self) -> Duration {
+        Duration::from_micros(self.upstream_us.load(Ordering::Relaxed))
+    }
+}
+
+/// Everything a connection handler needs, shared across all connections.
+pub struct ProxyState {
+    pub config: Config,
+    pub injector: HttpInjector,
+    pub upstream: UpstreamPool,
+    pub metrics: Arc<Metrics>,
+    /// Notified to hand the listener over to a freshly started binary (SIGUSR2 or the admin API)
+    pub upgrade: Notify,
+    pub tunnels: Tunnels,
+    pub assets: AssetStore,
+    pub page_api: PageApi,
+    pub capture: Option<Arc<Capture>>,
+    /// `[tee]`: response bodies also written to files of their own as they stream
+    pub tee: Option<Arc<Tee>>,
+    /// `[storage]`: where rotated captures and logs, stats snapshots and cached assets
+    /// also go
+    pub storage: Option<Arc<dyn Storage>>,
+    pub assertions: Option<Arc<Assertions>>,
+    pub conformance: Option<Arc<Conformance>>,
+    pub sso: Option<Arc<Sso>>,
+    pub hsts: Hsts,
+    /// `[[hosts]]`: where some domains' requests connect, and the `Host` they carry
+    pub hosts: Hosts,
+    pub error_pages: ErrorPages,
+    /// `[concurrency]`: adaptive in-flight limits per upstream host
+    pub concurrency: Option<Arc<Concurrency>>,
+    pub oauth: OAuth,
+    /// `rusty-proxy trace`: full records of the exchanges one URL pattern matches
+    pub tracer: Tracer,
+    /// Which requests `logging.sample_rate` logs in full
+    pub sampler: Sampler,
+    pub alerts: Arc<Alerts>,
+    pub admin_sessions: admin::Sessions,
+    pub cluster: Cluster,
+    next_connection_id: AtomicU64,
+}
 
 pub struct ProxyServer {
     port: u16,
-    config: Config,
-    injector: Arc<HttpInjector>,
-    client: Client<hyper::client::HttpConnector>,
+    state: Arc<ProxyState>,
 }
 
 impl ProxyServer {
     pub fn new(port: u16, config: Config, script_manager: ScriptManager) -> Self {
-        let injector = Arc::new(HttpInjector::new(script_manager, config.clone()));
-        let client = Client::new();
+        let metrics = Arc::new(Metrics::new());
+        let injector = HttpInjector::new(Arc::new(RwLock::new(script_manager)), config.clone());
+        let upstream = UpstreamPool::new(&config.proxy, metrics.clone());
+        let tunnels = Tunnels::new(metrics.clone());
+        let cluster = Cluster::new(&config.cluster, port);
+        let storage = match storage::open(&config.storage) {
+            Ok(storage) => storage,
+            Err(e) => {
+                warn!("Not using storage: {}", e);
+                None
+            }
+        };
+        if let Some(storage) = &storage {
+            info!("Storage: {}", storage.describe());
+        }
+        let uploader = storage.clone().map(|storage| Uploader::new(storage, cluster.node()));
+        if let Some(uploader) = uploader.clone() {
+            logging::on_rotate(move |path| uploader.upload("logs", path));
+        }
+        let assets = AssetStore::new(&config.scripts.asset_cache_dir, &config.scripts.assets_dir, storage.clone());
+        let capture = Capture::open(&config.capture, uploader.clone());
+        let tee = Tee::open(&config.tee, uploader);
+        let assertions = Assertions::open(&config.assertions);
+        let conformance = Conformance::open(&config.conformance);
+        let sso = Sso::open(&config.sso);
+        let hsts = Hsts::new(&config.security.hsts, config.features.hsts_stripping);
+        let hosts = Hosts::new(&config.hosts);
+        let error_pages = ErrorPages::new(&config.error_pages);
+        let concurrency = Concurrency::open(&config.concurrency);
+        let oauth = OAuth::new(&config.oauth);
+        let tracer = Tracer::new(config.diagnostics.dump_dir.as_deref());
+        let sampler = Sampler::new(&config.logging);
 
         ProxyServer {
             port,
-            config,
-            injector,
-            client,
+            state: Arc::new(ProxyState {
+                config,
+                injector,
+                upstream,
+                metrics,
+                upgrade: Notify::new(),
+                tunnels,
+                assets,
+                page_api: PageApi::new(),
+                capture,
+                tee,
+                storage,
+                assertions,
+                conformance,
+                sso,
+                hsts,
+                hosts,
+                error_pages,
+                concurrency,
+                oauth,
+                tracer,
+                sampler,
+                alerts: Arc::new(Alerts::new()),
+                admin_sessions: admin::Sessions::new(),
+                cluster,
+                next_connection_id: AtomicU64::new(1),
+            }),
         }
     }
 
     pub async fn run(self) -> Result<()> {
-        let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-        let injector = self.injector.clone();
-        let client = self.client.clone();
-        let config = Arc::new(self.config.clone());
-
-        let make_svc = make_service_fn(move |_conn| {
-            let injector = injector.clone();
-            let client = client.clone();
-            let config = config.clone();
+        let addr = Self::listen_addr(self.port, &self.state.config);
+        // A socket handed over by an upgrade or by systemd wins over binding our own
+        let inherited = match upgrade::inherited_listener()? {
+            Some(listener) => Some(listener),
+            None => upgrade::systemd_listener()?,
+        };
+        let listener = match inherited {
+            Some(listener) => TcpListener::from_std(listener)?,
+            None => TcpListener::bind(addr).await?,
+        };
+        let fd = listener.as_raw_fd();
+        upgrade::spawn_signal_handler(self.state.clone());
 
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    Self::handle_request(req, injector.clone(), client.clone(), config.clone())
-                }))
+        // Stop accepting on SIGTERM/SIGINT, or once a successor shares the listener
+        let state = self.state.clone();
+        let shutdown = async move {
+            loop {
+                tokio::select! {
+                    _ = Self::shutdown_signal() => return,
+                    _ = state.upgrade.notified() => match upgrade::spawn_successor(fd).await {
+                        Ok(pid) => {
+                            info!("Upgrade: process {} took over the listener", pid);
+                            return;
+                        }
+                        Err(e) => error!("Upgrade failed, still serving: {}", e),
+                    },
+                }
             }
-        });
+        };
+        self.serve(listener, shutdown).await
+    }
 
-        let server = Server::bind(&addr).serve(make_svc);
+    /// Accepts clients on an already bound listener (e.g. an ephemeral port in `self-test`)
+    /// until `shutdown` resolves, then drains open connections.
+    pub async fn serve(self, listener: TcpListener, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let config = &self.state.config;
+        let addr = listener.local_addr()?;
+        diagnostics::spawn_signal_handler(self.state.clone());
 
         info!("Rusty Proxy listening on http://{}", addr);
         info!("Proxy configuration:");
-        info!("  - Scripts enabled: {}", self.config.scripts.enabled);
-        info!("  - Max connections: {}", self.config.proxy.max_connections);
-        info!("  - Upstream timeout: {}s", self.config.proxy.upstream_timeout);
-        info!("  - Rate limit: {} req/min", self.config.security.rate_limit);
+        info!("  - Scripts enabled: {}", config.scripts.enabled);
+        info!("  - Max connections: {}", config.proxy.max_connections);
+        info!("  - Upstream timeout: {}s", config.proxy.upstream_timeout);
+        info!("  - Rate limit: {} req/min", config.security.rate_limit);
+        if config.admin.enabled {
+            info!("  - Admin API enabled under /admin/");
+            if config.admin.tls_listen.as_deref().is_some_and(|listen| !listen.is_empty()) {
+                let state = self.state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = admin::serve_tls(state).await {
+                        error!("Admin HTTPS listener not started: {}", e);
+                    }
+                });
+            }
+        }
+        if config.proxy_protocol.accept {
+            info!("  - Expecting PROXY protocol headers from clients");
+        }
+        let features = config.features.enabled();
+        if !features.is_empty() {
+            warn!("Risky features enabled: {}", features.join(", "));
+        }
+        if config.features.mitm {
+            warn!("TLS interception is not available in this build; CONNECT requests are tunnelled untouched");
+        }
 
-        if let Err(e) = server.await {
-            error!("Server error: {}", e);
+        tokio::spawn(cluster::run(self.state.clone()));
+        tokio::spawn(agent::run(self.state.clone()));
+        tokio::spawn(storage::run(self.state.clone()));
+
+        let pinned = self.state.injector.script_manager().pinned_assets();
+        if !pinned.is_empty() {
+            let state = self.state.clone();
+            tokio::spawn(async move { state.assets.prefetch(pinned).await });
         }
 
+        let (drain_tx, drain_rx) = watch::channel(false);
+        tokio::pin!(shutdown);
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut shutdown => break,
+            };
+            let (stream, peer_addr) = match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            let state = self.state.clone();
+            let mut drain_rx = drain_rx.clone();
+            let conn_id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+
+            let task = async move {
+                let _active = ActiveConnection::new(state.metrics.clone());
+                let mut stream = stream;
+                let client_addr = if state.config.proxy_protocol.accept {
+                    let timeout = Duration::from_secs(state.config.proxy_protocol.header_timeout);
+                    match tokio::time::timeout(timeout, proxy_protocol::read_header(&mut stream)).await {
+                        Ok(Ok(Some(addr))) => addr,
+                        Ok(Ok(None)) => peer_addr,
+                        Ok(Err(e)) => {
+                            warn!("Rejecting connection from {}: {}", peer_addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("Timed out waiting for PROXY header from {}", peer_addr);
+                            return;
+                        }
+                    }
+                } else {
+                    peer_addr
+                };
+
+                // The IP verdict holds for the whole connection, so it is made once here and
+                // refused clients are dropped before any HTTP is parsed
+                if !state.config.is_ip_allowed(&client_addr.ip().to_string()) {
+                    warn!("Rejected connection from blocked IP: {}", client_addr.ip());
+                    return;
+                }
+
+                let conn = Arc::new(ClientConnection {
+                    id: conn_id,
+                    addr: client_addr,
+                    authenticated: AtomicBool::new(false),
+                    requests: AtomicU64::new(0),
+                    upgrades: AtomicU64::new(0),
+                    opened: Instant::now(),
+                });
+                let service = Self::pipeline(state, conn.clone()).map_request(|req: Request<Incoming>| req.map(Body::from));
+
+                // HTTP/1.1 with upgrades, or HTTP/2 for clients that speak it from the start
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+                tokio::pin!(connection);
+                let result = tokio::select! {
+                    result = connection.as_mut() => result,
+                    _ = drain_rx.changed() => {
+                        // Finish the in-flight request, then close instead of keeping alive
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(e) = result {
+                    debug!("Connection from {} closed with error: {}", client_addr, e);
+                }
+                debug!(
+                    "Connection from {} closed after {} request(s), {} upgrade(s) in {:?}",
+                    client_addr,
+                    conn.requests.load(Ordering::Relaxed),
+                    conn.upgrades.load(Ordering::Relaxed),
+                    conn.opened.elapsed()
+                );
+            };
+            tokio::spawn(task.instrument(info_span!("conn", id = conn_id)));
+        }
+
+        drop(listener);
+        let _ = drain_tx.send(true);
+        let timeout = Duration::from_secs(self.state.config.proxy.shutdown_timeout);
+        let active = &self.state.metrics.active_connections;
+        info!("Shutting down, draining {} connections (up to {}s)", active.load(Ordering::Relaxed), timeout.as_secs());
+
+        let drained = tokio::time::timeout(timeout, async {
+            while active.load(Ordering::Relaxed) > 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!("Shutdown timeout reached with {} connections still open", active.load(Ordering::Relaxed));
+        }
+        storage::save_stats(&self.state).await;
         Ok(())
     }
 
-    async fn handle_request(
-        req: Request<Body>,
-        injector: Arc<HttpInjector>,
-        client: Client<hyper::client::HttpConnector>,
-        config: Arc<Config>,
-    ) -> Result<Response<Body>, Infallible> {
-        let client_ip = "127.0.0.1"; // In a real implementation, extract from connection
-        
-        // Check IP whitelist/blacklist
-        if !config.is_ip_allowed(client_ip) {
-            warn!("Blocked request from IP: {}", client_ip);
-            return Ok(injector.create_blocked_response("IP address not allowed"));
-        }
-
-        let uri = req.uri().clone();
-        let method = req.method().clone();
-        
-        info!("{} {}", method, uri);
-        debug!("Processing request for: {}", uri);
-
-        // Process the request through the injector
-        let processed_req = match injector.process_request(req).await {
-            Ok(req) => req,
+    /// Resolves on SIGTERM (e.g. `docker stop`) or SIGINT.
+    async fn shutdown_signal() {
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
             Err(e) => {
-                error!("Failed to process request: {}", e);
-                return Ok(injector.create_error_response(&e.to_string()));
+                warn!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        }
+    }
+
+    /// Listens on `proxy.bind_address`, but only loopback addresses are honoured unless the
+    /// `open-ports` feature is enabled.
+    pub fn listen_addr(port: u16, config: &Config) -> SocketAddr {
+        let loopback = SocketAddr::from(([127, 0, 0, 1], port));
+        let ip: IpAddr = match config.proxy.bind_address.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                warn!("Invalid bind_address {}, using 127.0.0.1", config.proxy.bind_address);
+                return loopback;
             }
         };
+        if !ip.is_loopback() && !config.features.open_ports {
+            warn!("Not listening on {}: enable the open-ports feature to accept remote clients", ip);
+            return loopback;
+        }
+        SocketAddr::new(ip, port)
+    }
+
+    /// The stages a request passes through, outermost first. Each may answer on its own
+    /// (admin, auth, local) or pass the request on; the response comes back out through
+    /// the same stages in reverse.
+    fn pipeline(state: Arc<ProxyState>, conn: Arc<ClientConnection>) -> ProxyService {
+        let env = Env { state, conn };
+        let stage = |f: StageFn| StageLayer::new(f, env.clone());
+        let forward_env = env.clone();
+        let service = ServiceBuilder::new()
+            .layer(stage(Self::log_stage))
+            .layer(stage(Self::error_page_stage))
+            .layer(stage(Self::admin_stage))
+            .layer(stage(Self::dns_stage))
+            .layer(stage(Self::auth_stage))
+            .layer(stage(Self::local_stage))
+            .layer(stage(Self::context_stage))
+            .layer(stage(Self::response_inject_stage))
+            .layer(stage(Self::header_filter_stage))
+            .layer(stage(Self::request_inject_stage))
+            .service(service_fn(move |req| {
+                let env = forward_env.clone();
+                async move { Ok::<_, Infallible>(Self::forward_stage(req, env).await) }
+            }));
+        BoxCloneService::new(service)
+    }
+
+    fn log_stage(req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            Metrics::incr(&env.state.metrics.requests_total);
+            let started = Instant::now();
+            let method = req.method().clone();
+            let uri = req.uri().clone();
+            let res = next.run(req).await;
+            debug!("{} {} -> {} in {:?}", method, uri, res.status(), started.elapsed());
+            res
+        })
+    }
+
+    /// The proxy's own error and refusal responses become the `[[error_pages]]` page for
+    /// their domain and client, if there is one.
+    fn error_page_stage(req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            let pages = &env.state.error_pages;
+            if pages.is_empty() {
+                return next.run(req).await;
+            }
+            let method = req.method().clone();
+            let url = req.uri().clone();
+            let domain = Self::request_domain(&req);
+            let res = next.run(req).await;
+            let failed = Failed {
+                method: &method,
+                url: &url,
+                domain: &domain,
+                client: env.conn.addr.ip(),
+            };
+            pages.apply(&failed, res)
+        })
+    }
+
+    fn admin_stage(req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            if admin::is_admin_request(&req, &env.state.config) {
+                return admin::handle(req, env.conn.addr, &env.state, false).await;
+            }
+            next.run(req).await
+        })
+    }
+
+    /// DoH clients (phones, browsers) can't send proxy credentials, so DNS queries are
+    /// answered before authentication, like admin requests.
+    fn dns_stage(req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            if dns::is_dns_request(&req, &env.state.config) {
+                return dns::handle(req, env.conn.addr, &env.state).await;
+            }
+            next.run(req).await
+        })
+    }
+
+    fn auth_stage(mut req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            let config = &env.state.config;
+            if config.security.require_auth && !env.conn.authenticated.load(Ordering::Relaxed) {
+                if !Self::is_proxy_authorized(&req, config) {
+                    warn!("Proxy authentication failed for {}", env.conn.addr.ip());
+                    return env.state.injector.create_auth_required_response();
+                }
+                env.conn.authenticated.store(true, Ordering::Relaxed);
+            }
+            req.headers_mut().remove(hyper::header::PROXY_AUTHORIZATION);
+            next.run(req).await
+        })
+    }
+
+    /// Paths under `/__rusty_proxy/` are answered by the proxy itself on any host.
+    fn local_stage(req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            if !assets::is_reserved(&req) {
+                return next.run(req).await;
+            }
+            if page_api::is_api_request(&req) {
+                return page_api::handle(req, env.conn.addr, &env.state).await;
+            }
+            assets::handle(req, &env.state).await
+        })
+    }
+
+    /// Builds the `RequestContext` later stages find in the request extensions, and sets up
+    /// sampled logging.
+    fn context_stage(mut req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            let (state, conn) = (&env.state, &env.conn);
+            let config = &state.config;
+            let request_number = conn.requests.fetch_add(1, Ordering::Relaxed) + 1;
+            if request_number > 1 {
+                Metrics::incr(&state.metrics.reused_connection_requests);
+            }
+
+            info!("{} {} (request {} on connection)", req.method(), req.uri(), request_number);
+            debug!("Processing request for: {}", req.uri());
+
+            if config.proxy.normalize_uri {
+                if let Some(uri) = hosts::normalize(req.uri()) {
+                    debug!("Normalized {} to {}", req.uri(), uri);
+                    let authority = req.uri().authority().map(|authority| authority.to_string());
+                    if let (Some(authority), Some(normalized)) = (authority, uri.authority()) {
+                        // A `Host` repeating the URI's authority changes along with it
+                        if req.headers().get(hyper::header::HOST).is_some_and(|host| host.as_bytes().eq_ignore_ascii_case(authority.as_bytes())) {
+                            if let Ok(host) = normalized.as_str().parse() {
+                                req.headers_mut().insert(hyper::header::HOST, host);
+                            }
+                        }
+                    }
+                    *req.uri_mut() = uri;
+                }
+            }
+            let sampled = state.sampler.sample(req.headers());
+            if let Some(name) = config.logging.debug_header.as_deref().filter(|n| !n.is_empty()) {
+                req.headers_mut().remove(name);
+            }
+            if let Some(host) = req.uri().host().filter(|host| config.proxy.bypasses(host)) {
+                if config.proxy.bypass_mode == BypassMode::Refuse {
+                    info!("Refused {} {}: {} is in proxy.bypass", req.method(), req.uri(), host);
+                    let refusal = Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .header("proxy-status", "rusty-proxy; error=destination_ip_prohibited")
+                        .body(Body::from(format!("This proxy does not carry requests for {}; connect to it directly\n", host)))
+                        .unwrap();
+                    return error_page::mark(refusal, format!("{} is in proxy.bypass", host));
+                }
+                // Without a context, scripts, captures and traces leave the request alone
+                debug!("Relaying {} {} untouched: {} is in proxy.bypass", req.method(), req.uri(), host);
+                return next.run(req).await;
+            }
+            let mut ctx = RequestContext::new(&req, conn, request_number, &state.injector.script_manager());
+            if let Some(traced) = state.tracer.begin(&ctx) {
+                req = req.map(|body| traced.request_body(body));
+                ctx.trace = Some(traced);
+            }
+            if sampled {
+                info!(
+                    target: "rusty_proxy::sampled",
+                    "request {} {} from {} (connection {}, request {}) matching scripts {:?}",
+                    ctx.method,
+                    ctx.url,
+                    ctx.client_ip,
+                    ctx.connection_id,
+                    ctx.connection_request,
+                    ctx.matched_scripts
+                );
+                logging::log_sampled_headers("request", &ctx.headers);
+                req.extensions_mut().insert(VerboseLog);
+            }
+            req.extensions_mut().insert(ctx);
+            req.extensions_mut().insert(Arc::new(StageTimings::default()));
+            next.run(req).await
+        })
+    }
+
+    fn response_inject_stage(mut req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            let (ctx, timings) = match Self::exchange(&req) {
+                Some(exchange) => exchange,
+                None => return next.run(req).await,
+            };
+            let state = &env.state;
+            if let Some(preflight) = state.injector.answer_preflight(&req, &ctx) {
+                return preflight;
+            }
+            let sampled = req.extensions().get::<VerboseLog>().is_some();
+            let mut form = None;
+            if state.sso.is_some() {
+                (req, form) = match Sso::read_form(req).await {
+                    Ok(read) => read,
+                    Err(e) => return state.injector.create_error_response(&e.to_string()),
+                };
+            }
+            let mut response = next.run(req).await;
+
+            // Tunnels carry no HTTP response to work on
+            if ctx.method == hyper::Method::CONNECT || response.status() == hyper::StatusCode::SWITCHING_PROTOCOLS {
+                if let Some(traced) = &ctx.trace {
+                    traced.finish_tunnel(&response, timings.upstream());
+                }
+                return response;
+            }
+            if let Some(traced) = &ctx.trace {
+                traced.upstream(response.status(), response.headers());
+            }
+            if sampled {
+                info!(target: "rusty_proxy::sampled", "response status {} for {} {}", response.status(), ctx.method, ctx.url);
+                logging::log_sampled_headers("response", response.headers());
+                response.extensions_mut().insert(VerboseLog);
+            }
+
+            if state.config.scripts.enabled {
+                let alerts = state.injector.script_manager().alert_scripts(&ctx, response.status().as_u16());
+                if !alerts.is_empty() {
+                    response = state.alerts.watch(alerts, &ctx, response);
+                }
+            }
+            if let Some(conformance) = &state.conformance {
+                response = conformance.observe(&ctx, response);
+            }
+            if let Some(sso) = &state.sso {
+                response = sso.observe(&ctx, form, response);
+            }
+
+            let checksums = state.config.logging.checksums.then(|| Checksums::new(&ctx));
+            if let Some(checksums) = &checksums {
+                response = checksums.upstream(response);
+            }
+
+            let inject_started = Instant::now();
+            let mut processed_res = match state.injector.process_response(response, &ctx).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("Failed to process response: {}", e);
+                    return state.injector.create_error_response(&e.to_string());
+                }
+            };
+            timings.add_inject(inject_started.elapsed());
+
+            if state.config.proxy.server_timing {
+                Self::append_server_timing(&mut processed_res, &timings);
+            }
+            if let Some(capture) = state.capture.as_ref().filter(|capture| capture.wants(&ctx)) {
+                processed_res = capture.record(&ctx, processed_res, timings.upstream());
+            }
+            if let Some(tee) = state.tee.as_ref().filter(|tee| tee.wants(&ctx, &processed_res)) {
+                processed_res = tee.attach(&ctx, processed_res);
+            }
+            if let Some(traced) = ctx.trace.clone() {
+                processed_res = traced.finish(processed_res, timings.upstream(), timings.inject());
+            }
+            if let Some(assertions) = &state.assertions {
+                processed_res = assertions.observe(&ctx, processed_res);
+            }
+            match checksums {
+                Some(checksums) => checksums.delivered(processed_res),
+                None => processed_res,
+            }
+        })
+    }
+
+    /// Applies `security.request_headers` and `security.response_headers`, so scripts
+    /// only ever see headers the filters let through, and strips HSTS per `security.hsts`.
+    fn header_filter_stage(mut req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            let security = &env.state.config.security;
+            let host = req.uri().host().unwrap_or("").to_string();
+            Self::filter_headers(req.headers_mut(), &security.request_headers, "request");
+            let mut res = next.run(req).await;
+            Self::filter_headers(res.headers_mut(), &security.response_headers, "response");
+            env.state.hsts.strip(&host, res.headers_mut());
+            res
+        })
+    }
+
+    fn filter_headers(headers: &mut hyper::HeaderMap, filter: &HeaderFilter, direction: &str) {
+        if filter.is_empty() {
+            return;
+        }
+        let denied: Vec<_> = headers.keys().filter(|name| !filter.permits(name.as_str())).cloned().collect();
+        for name in denied {
+            debug!("Dropping {} header {}", direction, name);
+            headers.remove(name);
+        }
+    }
+
+    fn request_inject_stage(req: Request<Body>, env: Env, next: Next) -> StageFuture {
+        Box::pin(async move {
+            let (ctx, timings) = match Self::exchange(&req) {
+                Some(exchange) => exchange,
+                None => return next.run(req).await,
+            };
+            let injector = &env.state.injector;
+
+            let inject_started = Instant::now();
+            let mut processed_req = match injector.process_request(req, &ctx).await {
+                Ok(req) => req,
+                Err(e) => {
+                    error!("Failed to process request: {}", e);
+                    return injector.create_error_response(&e.to_string());
+                }
+            };
+            timings.add_inject(inject_started.elapsed());
+
+            // The injector rebuilds the request; keep what later stages look up
+            processed_req.extensions_mut().insert(ctx);
+            processed_req.extensions_mut().insert(timings);
+            next.run(processed_req).await
+        })
+    }
+
+    /// The innermost stage: CONNECT tunnels, upgrades, and everything else sent upstream.
+    async fn forward_stage(mut req: Request<Body>, env: Env) -> Response<Body> {
+        let (state, conn) = (&env.state, &env.conn);
+        let client_addr = conn.addr;
 
         // Handle CONNECT method for HTTPS tunneling
-        if processed_req.method() == hyper::Method::CONNECT {
-            return Self::handle_connect(processed_req).await;
+        if req.method() == hyper::Method::CONNECT {
+            conn.upgrades.fetch_add(1, Ordering::Relaxed);
+            return match Self::handle_connect(req, client_addr, state).await {
+                Ok(res) => res,
+                Err(never) => match never {},
+            };
+        }
+
+        if ftp::is_ftp_request(&req) {
+            let timeout = Duration::from_secs(state.config.proxy.upstream_timeout);
+            return match ftp::fetch(req, timeout).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("Failed to fetch FTP URL: {}", e);
+                    state.injector.create_error_response(&e.to_string())
+                }
+            };
+        }
+
+        if let Some(mock) = req.extensions().get::<RequestContext>().and_then(|ctx| state.injector.answer_mock(ctx)) {
+            return mock;
+        }
+
+        forwarded::apply(req.headers_mut(), client_addr.ip(), "http", &state.config.forwarded);
+
+        // Upgraded connections (WebSocket, h2c, custom protocols) become raw tunnels after the 101
+        if Self::is_upgrade_request(&req) {
+            conn.upgrades.fetch_add(1, Ordering::Relaxed);
+            return match Self::handle_upgrade(req, client_addr, state).await {
+                Ok(res) => res,
+                Err(never) => match never {},
+            };
         }
 
         // Forward the request to the target server
-        let response = match Self::forward_request(processed_req, &client, &config).await {
+        let timings = req.extensions().get::<Arc<StageTimings>>().cloned().unwrap_or_default();
+        let upstream_started = Instant::now();
+        let forwarded = PHASE_TIMINGS.scope(timings.phases.clone(), Self::forward_following_redirects(req, client_addr, state));
+        let result = forwarded.await;
+        timings.set_upstream(upstream_started.elapsed());
+        match result {
             Ok(res) => res,
             Err(e) => {
                 error!("Failed to forward request: {}", e);
-                return Ok(injector.create_error_response(&e.to_string()));
+                state.injector.create_error_response(&e.to_string())
             }
+        }
+    }
+
+    /// The context and timings `context_stage` put on the request; absent only for
+    /// requests that never passed through it.
+    fn exchange(req: &Request<Body>) -> Option<(RequestContext, Arc<StageTimings>)> {
+        let ctx = req.extensions().get::<RequestContext>()?.clone();
+        let timings = req.extensions().get::<Arc<StageTimings>>()?.clone();
+        Some((ctx, timings))
+    }
+
+    /// Adds the proxy's phases to `Server-Timing` so browser devtools show where latency is
+    /// added. `upstream` is the time waiting on the upstream excluding DNS and connect; `inject`
+    /// covers request and response processing (for streamed bodies, only up to the headers).
+    fn append_server_timing(res: &mut Response<Body>, timings: &StageTimings) {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let dns = timings.phases.dns();
+        let connect = timings.phases.connect();
+        let (upstream, inject) = (timings.upstream(), timings.inject());
+
+        let mut entries = Vec::new();
+        if !dns.is_zero() {
+            entries.push(format!("dns;dur={:.1}", ms(dns)));
+        }
+        if !connect.is_zero() {
+            entries.push(format!("connect;dur={:.1}", ms(connect)));
+        }
+        entries.push(format!("upstream;dur={:.1}", ms(upstream.saturating_sub(dns + connect))));
+        entries.push(format!("inject;dur={:.1}", ms(inject)));
+
+        let mut value = entries.join(", ");
+        if let Some(existing) = res.headers().get("server-timing").and_then(|v| v.to_str().ok()) {
+            value = format!("{}, {}", existing, value);
+        }
+        if let Ok(value) = hyper::header::HeaderValue::from_str(&value) {
+            res.headers_mut().insert("server-timing", value);
+        }
+    }
+
+    /// Accepts `Proxy-Authorization: Bearer <token>` or Basic credentials whose password is
+    /// the token (so browsers can answer the 407 prompt).
+    fn is_proxy_authorized(req: &Request<Body>, config: &Config) -> bool {
+        let token = match config.security.auth_token.as_deref().filter(|t| !t.is_empty()) {
+            Some(token) => token,
+            None => return false,
+        };
+        let header = match req.headers().get(hyper::header::PROXY_AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+            Some(header) => header,
+            None => return false,
         };
 
-        // Extract domain for response processing
-        let domain = uri.host().unwrap_or("unknown").to_string();
+        if let Some(presented) = header.strip_prefix("Bearer ") {
+            return presented.trim() == token;
+        }
+        if let Some(encoded) = header.strip_prefix("Basic ") {
+            return base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|credentials| credentials.split_once(':').map(|(_, password)| password == token))
+                .unwrap_or(false);
+        }
+        false
+    }
+
+    fn is_upgrade_request(req: &Request<Body>) -> bool {
+        let connection_upgrade = req
+            .headers()
+            .get_all(hyper::header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+        connection_upgrade && req.headers().contains_key(hyper::header::UPGRADE)
+    }
+
+    async fn handle_upgrade(
+        mut req: Request<Body>,
+        client_addr: SocketAddr,
+        state: &ProxyState,
+    ) -> Result<Response<Body>, Infallible> {
+        let protocol = req
+            .headers()
+            .get(hyper::header::UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+        let target = req.uri().to_string();
+        let client_upgrade = hyper::upgrade::on(&mut req);
 
-        // Process the response through the injector
-        let processed_res = match injector.process_response(response, &domain).await {
+        let mut response = match Self::forward_request(req, client_addr, state).await {
             Ok(res) => res,
             Err(e) => {
-                error!("Failed to process response: {}", e);
-                return Ok(injector.create_error_response(&e.to_string()));
+                error!("Failed to forward {} upgrade request: {}", protocol, e);
+                return Ok(state.injector.create_error_response(&e.to_string()));
             }
         };
 
-        Ok(processed_res)
+        // Upstream declined the upgrade; relay its answer as a normal response
+        if response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
+            return Ok(response);
+        }
+
+        // MQTT, STOMP and similar subprotocols keep quiet connections open for hours, so
+        // they are never timed out
+        let subprotocol = response
+            .headers()
+            .get("sec-websocket-protocol")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string());
+        let long_lived = tunnel::is_long_lived(subprotocol.as_deref(), &state.config.proxy.long_lived_subprotocols);
+        let idle_timeout = Duration::from_secs(state.config.proxy.tunnel_idle_timeout);
+        let guard = state.tunnels.register(target, None, protocol, subprotocol, client_addr, long_lived);
+
+        let upstream_upgrade = hyper::upgrade::on(&mut response);
+        tokio::spawn(async move {
+            let stats = guard.stats.clone();
+            let name = match &stats.subprotocol {
+                Some(subprotocol) => format!("{} ({})", stats.protocol, subprotocol),
+                None => stats.protocol.clone(),
+            };
+            match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                Ok((client_io, upstream_io)) => {
+                    info!("Switched {} to {} tunnel", stats.target, name);
+                    let result = tunnel::relay(TokioIo::new(client_io), TokioIo::new(upstream_io), stats.clone(), idle_timeout, Vec::new(), None).await;
+                    stats.log_closed(&result);
+                }
+                Err(e) => error!("Failed to complete {} upgrade for {}: {}", name, stats.target, e),
+            }
+        });
+
+        Ok(response)
+    }
+
+    /// Forwards the request and, with `follow_redirects` set, follows up to that many
+    /// redirects itself so the client gets the final response. 307/308 resend the method
+    /// and body; 301-303 turn anything but HEAD into a bodyless GET. Credentials are not
+    /// sent on to another host.
+    async fn forward_following_redirects(
+        req: Request<Body>,
+        client_addr: SocketAddr,
+        state: &ProxyState,
+    ) -> Result<Response<Body>> {
+        let max_hops = state.config.proxy.follow_redirects;
+        if max_hops == 0 {
+            return Self::forward_with_oauth(req, client_addr, state).await;
+        }
+
+        let (mut parts, body) = req.into_parts();
+        let mut body = body::to_bytes(body).await?;
+        let mut hops = 0;
+        loop {
+            let mut builder = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version);
+            if let Some(headers) = builder.headers_mut() {
+                *headers = parts.headers.clone();
+            }
+            if let Some(extensions) = builder.extensions_mut() {
+                *extensions = parts.extensions.clone();
+            }
+            let response = Self::forward_with_oauth(builder.body(Body::from(body.clone()))?, client_addr, state).await?;
+
+            let status = response.status().as_u16();
+            if !matches!(status, 301 | 302 | 303 | 307 | 308) || hops >= max_hops {
+                return Ok(response);
+            }
+            let location = match response
+                .headers()
+                .get(hyper::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|location| resolve_url(&parts.uri, location))
+            {
+                Some(location) if location.scheme_str() == Some("http") => location,
+                // HTTPS targets can only be reached through a CONNECT tunnel by the client
+                _ => return Ok(response),
+            };
+
+            hops += 1;
+            debug!("Following {} from {} to {} (hop {} of {})", status, parts.uri, location, hops, max_hops);
+            if location.authority() != parts.uri.authority() {
+                parts.headers.remove(hyper::header::AUTHORIZATION);
+                parts.headers.remove(hyper::header::COOKIE);
+                // Nor is another origin's request signed
+                parts.extensions.remove::<RequestContext>();
+            }
+            if let Some(host) = location.authority().and_then(|a| a.as_str().parse().ok()) {
+                parts.headers.insert(hyper::header::HOST, host);
+            }
+            if matches!(status, 301..=303) && parts.method != hyper::Method::HEAD {
+                parts.method = hyper::Method::GET;
+                body = hyper::body::Bytes::new();
+                parts.headers.remove(hyper::header::CONTENT_LENGTH);
+                parts.headers.remove(hyper::header::CONTENT_TYPE);
+            }
+            parts.uri = location;
+        }
+    }
+
+    /// Forwards the request, and when an API listed under `[[oauth]]` refuses its bearer
+    /// token with a 401 for having expired, fetches a new one from the API's token endpoint
+    /// and sends the request again with that. The new token is cached and takes the place
+    /// of the client's stale one on later requests, until it expires in turn.
+    async fn forward_with_oauth(
+        req: Request<Body>,
+        client_addr: SocketAddr,
+        state: &ProxyState,
+    ) -> Result<Response<Body>> {
+        let host = req.uri().host().unwrap_or("").to_string();
+        let (Some(source), Some(sent)) = (state.oauth.source_for(&host), oauth::bearer(req.headers())) else {
+            return Self::forward_with_retry(req, client_addr, state).await;
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let body = body::to_bytes(body).await?;
+        let mut token = source.current().await.unwrap_or(sent.clone());
+        let mut refreshed = false;
+        loop {
+            parts.headers.insert(hyper::header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+            let mut builder = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version);
+            if let Some(headers) = builder.headers_mut() {
+                *headers = parts.headers.clone();
+            }
+            if let Some(extensions) = builder.extensions_mut() {
+                *extensions = parts.extensions.clone();
+            }
+            let response = Self::forward_with_retry(builder.body(Body::from(body.clone()))?, client_addr, state).await?;
+            // A cached token that is turned down is taken to have been revoked
+            let expired = token != sent || oauth::is_expired(response.headers(), &token);
+            if response.status() != hyper::StatusCode::UNAUTHORIZED || refreshed || !expired {
+                return Ok(response);
+            }
+
+            token = match source.refresh(state.oauth.client(), &token).await {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("OAuth: couldn't refresh the access token for {}: {}", host, e);
+                    return Ok(response);
+                }
+            };
+            refreshed = true;
+            Metrics::incr(&state.metrics.oauth_refreshes);
+            debug!("{} refused an expired token, retrying {} with a new one", host, parts.uri);
+        }
+    }
+
+    /// Forwards the request, holding it and sending it again when a domain listed under
+    /// `[retry]` answers 429 with a `Retry-After` of at most `max_wait`. The request body is
+    /// buffered so it can be resent.
+    async fn forward_with_retry(
+        req: Request<Body>,
+        client_addr: SocketAddr,
+        state: &ProxyState,
+    ) -> Result<Response<Body>> {
+        let retry = &state.config.retry;
+        let domain = req.uri().host().unwrap_or("").to_string();
+        if retry.max_attempts == 0 || !retry.applies_to(&domain) {
+            return Self::forward_request(req, client_addr, state).await;
+        }
+
+        let (parts, body) = req.into_parts();
+        let body = body::to_bytes(body).await?;
+        let mut attempt = 0;
+        loop {
+            let mut builder = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version);
+            if let Some(headers) = builder.headers_mut() {
+                *headers = parts.headers.clone();
+            }
+            if let Some(extensions) = builder.extensions_mut() {
+                *extensions = parts.extensions.clone();
+            }
+            let response = Self::forward_request(builder.body(Body::from(body.clone()))?, client_addr, state).await?;
+            if response.status() != hyper::StatusCode::TOO_MANY_REQUESTS || attempt >= retry.max_attempts {
+                return Ok(response);
+            }
+            let wait = match Self::retry_after(response.headers()) {
+                Some(wait) if wait <= Duration::from_secs(retry.max_wait) => wait,
+                _ => return Ok(response),
+            };
+
+            attempt += 1;
+            Metrics::incr(&state.metrics.upstream_retries);
+            info!(
+                "{} answered 429, retrying in {:?} (attempt {} of {})",
+                domain, wait, attempt, retry.max_attempts
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// `Retry-After` as a delay, given either in seconds or as an HTTP date.
+    fn retry_after(headers: &hyper::HeaderMap) -> Option<Duration> {
+        let value = headers.get(hyper::header::RETRY_AFTER)?.to_str().ok()?.trim();
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        let at = httpdate::parse_http_date(value).ok()?;
+        Some(at.duration_since(std::time::SystemTime::now()).unwrap_or(Duration::ZERO))
     }
 
     async fn forward_request(
-        mut req: Request<Body>,
-        client: &Client<hyper::client::HttpConnector>,
-        config: &Config,
+        req: Request<Body>,
+        client_addr: SocketAddr,
+        state: &ProxyState,
     ) -> Result<Response<Body>> {
-        // Ensure the request has a proper scheme
+        let config = &state.config;
+        let req = Self::upstream_request(req, &state.hosts, &state.injector).await?;
+
+        // Set timeout
+        let timeout = Duration::from_secs(config.proxy.upstream_timeout);
+
+        let upstream = req.uri().authority().map(|authority| authority.to_string()).unwrap_or_default();
+        let permit = match &state.concurrency {
+            Some(concurrency) => match concurrency.acquire(&upstream).await {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("Refused {} {}: {} stayed at its concurrency limit for concurrency.queue_timeout", req.method(), req.uri(), upstream);
+                    let busy = Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .header(hyper::header::RETRY_AFTER, "1")
+                        .body(Body::from(format!("{} is busy; try again shortly\n", upstream)))?;
+                    return Ok(error_page::mark(busy, format!("{} is at its concurrency limit", upstream)));
+                }
+            },
+            None => None,
+        };
+
+        let sent = async {
+            // A PROXY header describes exactly one client, so those upstream connections
+            // can't come from the shared pool
+            if config.proxy_protocol.sends_to(&Self::request_domain(&req)) {
+                return tokio::time::timeout(timeout, Self::send_with_proxy_header(req, client_addr, state)).await?;
+            }
+
+            // Forward the request
+            let response = tokio::time::timeout(timeout, state.upstream.client().request(req)).await??;
+            Ok(response.map(Body::from))
+        };
+        let result: Result<Response<Body>> = sent.await;
+        if let Some(permit) = permit {
+            permit.complete(result.as_ref().ok().map(Response::status));
+        }
+        result
+    }
+
+    /// The request as it goes upstream: with a scheme, routed by `[[hosts]]`, and signed
+    /// last, over that final URI and `Host`. Done for each hop, so redirects followed and
+    /// retries go the same way.
+    async fn upstream_request(mut req: Request<Body>, hosts: &Hosts, injector: &HttpInjector) -> Result<Request<Body>> {
         let uri = req.uri();
-        let new_uri = if uri.scheme().is_none() {
-            let scheme = if uri.port() == Some(443) { "https" } else { "http" };
+        let mut new_uri = if uri.scheme().is_none() {
+            let scheme = if uri.port_u16() == Some(443) { "https" } else { "http" };
             Uri::builder()
                 .scheme(scheme)
-                .authority(uri.authority().unwrap().as_str())
+                .authority(uri.authority().ok_or_else(|| anyhow!("Request has no host"))?.as_str())
                 .path_and_query(uri.path_and_query().map(|x| x.as_str()).unwrap_or("/"))
                 .build()?
         } else {
             uri.clone()
         };
-
+        hosts.apply(&mut new_uri, req.headers_mut());
         *req.uri_mut() = new_uri;
+        injector.sign_request(req).await
+    }
 
-        // Set timeout
-        let timeout = std::time::Duration::from_secs(config.proxy.upstream_timeout);
-        
-        // Forward the request
-        let response = tokio::time::timeout(timeout, client.request(req)).await??;
-        
-        Ok(response)
+    async fn send_with_proxy_header(
+        mut req: Request<Body>,
+        client_addr: SocketAddr,
+        state: &ProxyState,
+    ) -> Result<Response<Body>> {
+        let config = &state.config;
+        Metrics::incr(&state.metrics.upstream_requests);
+        Metrics::incr(&state.metrics.upstream_connections_opened);
+        let host = req.uri().host().ok_or_else(|| anyhow!("Request has no host"))?.to_string();
+        let port = req.uri().port_u16().unwrap_or(80);
+
+        let mut stream = Self::connect_upstream(&host, port).await?;
+        let header = proxy_protocol::encode_header(config.proxy_protocol.version, client_addr, stream.peer_addr()?);
+        stream.write_all(&header).await?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Upstream connection closed with error: {}", e);
+            }
+        });
+
+        // Dedicated connections take origin-form request targets
+        let path = req.uri().path_and_query().map(|x| x.as_str()).unwrap_or("/").parse()?;
+        *req.uri_mut() = path;
+
+        Ok(sender.send_request(req).await?.map(Body::from))
     }
 
-    async fn handle_connect(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        // For HTTPS tunneling, we need to establish a TCP connection
-        // This is a simplified implementation
-        let uri = req.uri();
-        let host_port = uri.authority().map(|auth| auth.as_str()).unwrap_or("");
-        
-        match Self::establish_tunnel(host_port).await {
-            Ok(_) => {
+    /// Opens a TCP connection to the upstream, timing the lookup and the connect.
+    async fn connect_upstream(host: &str, port: u16) -> Result<TcpStream> {
+        let timings = PhaseTimings::current();
+
+        let dns_started = Instant::now();
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        if let Some(timings) = &timings {
+            PhaseTimings::record(&timings.dns_us, dns_started);
+        }
+        let connect_started = Instant::now();
+        let stream = TcpStream::connect(&addrs[..]).await?;
+        if let Some(timings) = &timings {
+            PhaseTimings::record(&timings.connect_us, connect_started);
+        }
+        Ok(stream)
+    }
+
+    async fn handle_connect(req: Request<Body>, client_addr: SocketAddr, state: &ProxyState) -> Result<Response<Body>, Infallible> {
+        let host_port = req.uri().authority().map(|auth| auth.as_str()).unwrap_or("").to_string();
+        let host = req.uri().host().unwrap_or("").to_ascii_lowercase();
+
+        // The CONNECT host alone already settles some tunnels, before anything is dialled
+        let policy = state.config.security.tunnels.clone();
+        if !policy.permits(&host) {
+            warn!("Refused CONNECT to {} from {}: domain is blocked", host_port, client_addr);
+            let refusal = Response::builder()
+                .status(hyper::StatusCode::FORBIDDEN)
+                .body(Body::from("Tunnel to this domain is not allowed"))
+                .unwrap();
+            return Ok(error_page::mark(refusal, format!("tunnels to {} are not allowed", host)));
+        }
+
+        // Interception isn't available in this build, so every tunnel is relayed untouched;
+        // the HSTS choice is still made and logged, so the policy can be checked
+        if state.config.features.mitm && !state.config.proxy.bypasses(&host) {
+            state.hsts.choose(&host);
+        }
+
+        match Self::establish_tunnel(&host_port).await {
+            Ok(server) => {
+                let port = server.peer_addr().ok().map(|addr| addr.port());
+                let guard = state
+                    .tunnels
+                    .register(host_port.clone(), port, "CONNECT".to_string(), None, client_addr, false);
+
+                // Bridge the client and upstream once hyper hands over the raw connection
+                tokio::spawn(async move {
+                    let mut client = match hyper::upgrade::on(req).await {
+                        Ok(upgraded) => TokioIo::new(upgraded),
+                        Err(e) => {
+                            error!("Failed to upgrade CONNECT to {}: {}", host_port, e);
+                            return;
+                        }
+                    };
+
+                    // Judge the tunnel by the name the client asks the server for, which
+                    // may differ from the CONNECT host
+                    let (early, throttle) = if policy.is_empty() {
+                        (Vec::new(), None)
+                    } else {
+                        let timeout = Duration::from_secs(policy.hello_timeout);
+                        let (hello, sni) = match tunnel::read_client_hello(&mut client, timeout).await {
+                            Ok(read) => read,
+                            Err(e) => {
+                                debug!("Tunnel to {} closed before its ClientHello: {}", host_port, e);
+                                return;
+                            }
+                        };
+                        let domain = sni.as_deref().unwrap_or(&host);
+                        if !policy.permits(domain) {
+                            warn!("Closed CONNECT tunnel to {} from {}: SNI {} is blocked", host_port, client_addr, domain);
+                            return;
+                        }
+                        let throttle = policy.throttle(domain);
+                        if let Some(rate) = throttle {
+                            info!("Throttling CONNECT tunnel to {} (SNI {}) to {} bytes/s", host_port, domain, rate);
+                        }
+                        (hello, throttle)
+                    };
+
+                    let stats = guard.stats.clone();
+                    let result = tunnel::relay(client, server, stats.clone(), Duration::ZERO, early, throttle).await;
+                    stats.log_closed(&result);
+                });
+
                 // Return 200 Connection Established
                 let response = Response::builder()
                     .status(200)
@@ -179,17 +1291,124 @@ impl ProxyServer {
         }
     }
 
-    async fn establish_tunnel(host_port: &str) -> Result<()> {
-        // Parse host and port
-        let parts: Vec<&str> = host_port.split(':').collect();
-        let host = parts.get(0).ok_or_else(|| anyhow!("Invalid host"))?;
-        let port: u16 = parts.get(1).unwrap_or(&"443").parse()?;
+    async fn establish_tunnel(host_port: &str) -> Result<TcpStream> {
+        let (host, port) = Self::tunnel_target(host_port)?;
 
-        // Establish TCP connection
-        let _stream = tokio::net::TcpStream::connect((host.to_string(), port)).await?;
-        
-        // In a full implementation, you would bridge the client and server connections
-        info!("Established tunnel to {}:{}", host, port);
-        Ok(())
+        // Tunnels go to arbitrary servers, which don't expect a PROXY header
+        let stream = Self::connect_upstream(&host, port).await?;
+
+        info!("Established tunnel to {}", host_port);
+        Ok(stream)
+    }
+
+    /// The lowercase host a request is for, from its URI or else its `Host` header; IPv6
+    /// literals without their brackets, as `tunnel_target` gives them.
+    fn request_domain(req: &Request<Body>) -> String {
+        let host = req.uri().host().map(str::to_string).or_else(|| {
+            let host = req.headers().get(hyper::header::HOST)?.to_str().ok()?;
+            Some(host.parse::<Authority>().ok()?.host().to_string())
+        });
+        host.map(|host| bare_host(&host).to_ascii_lowercase()).unwrap_or_default()
+    }
+
+    /// The host and port a CONNECT names, as in `example.com:443` or `[::1]:8443`; IPv6
+    /// literals lose their brackets for the lookup. CONNECT is for TLS, so a target without
+    /// a port gets 443.
+    fn tunnel_target(host_port: &str) -> Result<(String, u16)> {
+        let authority: Authority = host_port.parse().map_err(|e| anyhow!("Invalid CONNECT target {:?}: {}", host_port, e))?;
+        // `Authority` takes user info and leaves a port it can't read to `port_u16() == None`
+        if authority.as_str().contains('@') || (authority.port().is_none() && authority.as_str() != authority.host()) {
+            bail!("Invalid CONNECT target {:?}: expected host[:port]", host_port);
+        }
+        let host = bare_host(authority.host());
+        if host.is_empty() {
+            bail!("Invalid CONNECT target {:?}: no host", host_port);
+        }
+        Ok((host.to_string(), authority.port_u16().unwrap_or(443)))
+    }
+}
+
+/// `host` without the brackets of an IPv6 literal.
+fn bare_host(host: &str) -> &str {
+    host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CredentialConfig, HostHeader, HostRule};
+    use crate::signing::{hex, SigV4};
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn tunnel_target_keeps_ipv6_literals_whole() {
+        assert_eq!(ProxyServer::tunnel_target("[::1]:8443").unwrap(), ("::1".to_string(), 8443));
+        assert_eq!(ProxyServer::tunnel_target("[2001:db8::1]:443").unwrap(), ("2001:db8::1".to_string(), 443));
+        assert_eq!(ProxyServer::tunnel_target("[::1]").unwrap(), ("::1".to_string(), 443));
+    }
+
+    #[test]
+    fn tunnel_target_defaults_to_443() {
+        assert_eq!(ProxyServer::tunnel_target("example.com").unwrap(), ("example.com".to_string(), 443));
+        assert_eq!(ProxyServer::tunnel_target("example.com:8443").unwrap(), ("example.com".to_string(), 8443));
+    }
+
+    #[test]
+    fn tunnel_target_refuses_what_names_no_host() {
+        for target in [":443", "", "[::1", "example.com:port", "exa mple.com:443", "user@example.com:443", "/path"] {
+            assert!(ProxyServer::tunnel_target(target).is_err(), "{:?} was accepted", target);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn request_domain_reads_ipv6_hosts() {
+        let with_host = |uri: &str, host: &str| Request::builder().uri(uri).header("host", host).body(Body::empty()).unwrap();
+        assert_eq!(ProxyServer::request_domain(&with_host("/", "[::1]:8080")), "::1");
+        assert_eq!(ProxyServer::request_domain(&with_host("/", "[2001:DB8::1]")), "2001:db8::1");
+        assert_eq!(ProxyServer::request_domain(&with_host("/", "Example.com:8080")), "example.com");
+        assert_eq!(ProxyServer::request_domain(&with_host("http://[::1]:8080/x", "other.test")), "::1");
+        assert_eq!(ProxyServer::request_domain(&with_host("/", "not a host")), "");
+    }
+
+    #[tokio::test]
+    async fn signs_the_host_hosts_sends() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = r#"{"name": "sign", "description": "", "version": "1", "author": "", "target_domains": ["api.example.com"],
+            "inject_type": "Sign", "script_content": "", "headers": {}, "enabled": true,
+            "credential": "aws", "aws_region": "eu-west-1", "aws_service": "execute-api"}"#;
+        std::fs::write(dir.path().join("sign.json"), script).unwrap();
+        let mut config = Config::default();
+        config.credentials.insert("aws".to_string(), CredentialConfig { key_id: "AKIDEXAMPLE".to_string(), secret: Some("secret".to_string()), ..Default::default() });
+        let injector = HttpInjector::new(Arc::new(RwLock::new(ScriptManager::open(dir.path(), false).unwrap())), config);
+        let hosts = Hosts::new(&[HostRule {
+            domains: vec!["api.example.com".to_string()],
+            connect: Some("127.0.0.1:8080".to_string()),
+            host: HostHeader::Override,
+            host_value: Some("origin.internal".to_string()),
+            host_pattern: None,
+        }]);
+
+        let mut req = Request::get("http://api.example.com/items?b=2&a=1").header("host", "api.example.com").body(Body::empty()).unwrap();
+        req.extensions_mut().insert(RequestContext::for_request(hyper::Method::GET, "http://api.example.com/items?b=2&a=1"));
+        let req = ProxyServer::upstream_request(req, &hosts, &injector).await.unwrap();
+
+        assert_eq!(req.uri(), "http://127.0.0.1:8080/items?b=2&a=1");
+        let headers = req.headers();
+        assert_eq!(headers["host"], "origin.internal");
+        let stamp = headers["x-amz-date"].to_str().unwrap();
+        let signed = [("host".to_string(), "origin.internal".to_string()), ("x-amz-date".to_string(), stamp.to_string())];
+        let payload_hash = hex(&Sha256::digest(b""));
+        let sigv4 = SigV4 {
+            method: "GET",
+            path: "/items",
+            query: "a=1&b=2",
+            headers: &signed,
+            payload_hash: &payload_hash,
+            stamp,
+            region: "eu-west-1",
+            service: "execute-api",
+        };
+        assert!(sigv4.canonical_request().contains("\nhost:origin.internal\n"));
+        assert_eq!(headers["authorization"], sigv4.authorization("AKIDEXAMPLE", "secret").as_str());
+    }
+}
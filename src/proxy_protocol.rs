@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Twelve-byte signature that opens every PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header from the start of a connection.
+///
+/// Exactly the header bytes are consumed, so the stream can be handed to the HTTP
+/// server afterwards. Returns the original client address, or `None` when the load
+/// balancer sent `UNKNOWN`/`LOCAL` (health checks) and the peer address should be used.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY " {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(anyhow!("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line);
+    }
+
+    if prefix == V2_SIGNATURE[..6] {
+        let mut rest = [0u8; 10];
+        stream.read_exact(&mut rest).await?;
+        if rest[..6] != V2_SIGNATURE[6..] {
+            return Err(anyhow!("Invalid PROXY v2 signature"));
+        }
+        let ver_cmd = rest[6];
+        let family = rest[7];
+        let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        return parse_v2(ver_cmd, family, &payload);
+    }
+
+    Err(anyhow!("Connection did not start with a PROXY protocol header"))
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)?.trim_end();
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let ip: IpAddr = fields[2].parse()?;
+            let port: u16 = fields[4].parse()?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("Malformed PROXY v1 header: {}", line)),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(anyhow!("Unsupported PROXY protocol version {}", ver_cmd >> 4));
+    }
+    // LOCAL command: the balancer itself is talking, not a relayed client
+    if ver_cmd & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        2 if payload.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        // AF_UNSPEC / AF_UNIX carry no usable client address
+        _ => Ok(None),
+    }
+}
+
+/// Builds the header sent ahead of our own traffic to an upstream that expects it.
+pub fn encode_header(version: u8, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    // Both ends must share an address family; widen IPv4 to IPv4-mapped IPv6 if needed
+    let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V6(d)) => (IpAddr::V6(s.to_ipv6_mapped()), IpAddr::V6(d)),
+        (IpAddr::V6(s), IpAddr::V4(d)) => (IpAddr::V6(s), IpAddr::V6(d.to_ipv6_mapped())),
+        pair => pair,
+    };
+
+    if version == 2 {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x21); // version 2, PROXY command
+        match (src_ip, dst_ip) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                header.push(0x11);
+                header.extend_from_slice(&12u16.to_be_bytes());
+                header.extend_from_slice(&s.octets());
+                header.extend_from_slice(&d.octets());
+            }
+            (IpAddr::V6(s), IpAddr::V6(d)) => {
+                header.push(0x21);
+                header.extend_from_slice(&36u16.to_be_bytes());
+                header.extend_from_slice(&s.octets());
+                header.extend_from_slice(&d.octets());
+            }
+            _ => unreachable!("address families were unified above"),
+        }
+        header.extend_from_slice(&src.port().to_be_bytes());
+        header.extend_from_slice(&dst.port().to_be_bytes());
+        return header;
+    }
+
+    let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        src_ip,
+        dst_ip,
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
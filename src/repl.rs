@@ -0,0 +1,241 @@
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use std::io::{IsTerminal, Write};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::config::Config;
+use crate::oauth::form_encode;
+use crate::pattern;
+use crate::script_manager::ScriptManager;
+use crate::{admin_request, admin_send};
+
+const HELP: &str = "\
+Matching:
+  match <url> [status]      which scripts would run for a GET of <url>, and why the others would not
+  domain <pattern> <host>   whether a target_domains pattern matches <host>
+  url <regex> <url>         whether a url_pattern matches <url>, with its named groups
+Scripts:
+  last                      the last response scripts ran on, as it came from upstream
+  run <script> [body]       dry-run a script on that response; `body` prints the rewritten body
+  reload                    reload the scripts directory
+Store (shared with pages through /__rusty_proxy/api/store/):
+  store                     list every key and value
+  get <key>                 one value
+  set <key> <value>         store a value; the rest of the line is the value
+  del <key>                 remove a key
+  help                      this list
+  quit                      leave (end of input does too)";
+
+/// `rusty-proxy repl`: reads commands line by line and runs them on the running proxy
+/// through the admin API, until `quit` or the end of input. `domain` and `url` need no
+/// proxy; they use the same matching as scripts do.
+pub async fn run(port: u16, config: &Config) -> Result<()> {
+    admin_request(port, config, "POST", "/admin/exchange/watch").await?;
+    let interactive = std::io::stdin().is_terminal();
+    if interactive {
+        println!("Connected to the proxy on port {}; `help` lists the commands", port);
+    }
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        if interactive {
+            print!("rusty> ");
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next_line().await? else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match eval(port, config, line).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Runs one command; false once the operator asks to leave.
+async fn eval(port: u16, config: &Config, line: &str) -> Result<bool> {
+    let (command, rest) = split(line);
+    match command {
+        "help" | "?" => println!("{}", HELP),
+        "quit" | "exit" => return Ok(false),
+        "match" => {
+            let (url, status) = split(rest);
+            if url.is_empty() {
+                bail!("usage: match <url> [status]");
+            }
+            let mut path = format!("/admin/explain?url={}", form_encode(url));
+            if !status.is_empty() {
+                let status: u16 = status.parse().map_err(|_| anyhow!("{} is not a status code", status))?;
+                path.push_str(&format!("&status={}", status));
+            }
+            print_explain(&fetch(port, config, "GET", &path).await?);
+        }
+        "domain" => {
+            let (pattern, host) = split(rest);
+            if host.is_empty() {
+                bail!("usage: domain <pattern> <host>");
+            }
+            let matches = ScriptManager::domain_matches(host, &[pattern.to_string()]);
+            println!("{} {} {}", pattern, if matches { "matches" } else { "does not match" }, host);
+        }
+        "url" => {
+            let (regex, url) = split(rest);
+            if url.is_empty() {
+                bail!("usage: url <regex> <url>");
+            }
+            let regex = pattern::compile(regex)?;
+            match regex.captures(url) {
+                Some(captures) => {
+                    println!("matches {}", &captures[0]);
+                    for name in regex.capture_names().flatten() {
+                        println!("  {} = {}", name, captures.name(name).map(|m| m.as_str()).unwrap_or(""));
+                    }
+                }
+                None => println!("does not match"),
+            }
+        }
+        "last" => {
+            let exchange = fetch(port, config, "GET", "/admin/exchange").await?;
+            println!("{} {} -> {} ({} bytes)", text(&exchange["method"]), text(&exchange["url"]), exchange["status"], exchange["body_bytes"]);
+            if let Some(headers) = exchange["headers"].as_object() {
+                let mut names: Vec<&String> = headers.keys().collect();
+                names.sort();
+                for name in names {
+                    println!("  {}: {}", name, text(&headers[name]));
+                }
+            }
+            let matched: Vec<&str> = exchange["matched_scripts"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+            println!("matched scripts: {}", if matched.is_empty() { "none".to_string() } else { matched.join(", ") });
+        }
+        "run" => {
+            let (name, option) = split(rest);
+            if name.is_empty() || !matches!(option, "" | "body") {
+                bail!("usage: run <script> [body]");
+            }
+            let result = fetch(port, config, "POST", &format!("/admin/scripts/{}/try", name)).await?;
+            print_try(&result, option == "body");
+        }
+        "reload" => {
+            let reloaded = fetch(port, config, "POST", "/admin/scripts/reload").await?;
+            println!("reloaded {} scripts", reloaded["scripts"]);
+        }
+        "store" => {
+            let store = fetch(port, config, "GET", "/admin/store").await?;
+            match store.as_object().filter(|entries| !entries.is_empty()) {
+                Some(entries) => {
+                    for (key, value) in entries {
+                        println!("{} = {}", key, text(value));
+                    }
+                }
+                None => println!("the store is empty"),
+            }
+        }
+        "get" => {
+            let key = store_key(rest)?;
+            let entry = fetch(port, config, "GET", &format!("/admin/store/{}", key)).await?;
+            println!("{}", text(&entry["value"]));
+        }
+        "set" => {
+            let (key, value) = split(rest);
+            let key = store_key(key)?;
+            let path = format!("/admin/store/{}", key);
+            admin_send(port, config, "PUT", &path, value.to_string()).await.map_err(api_error)?;
+            println!("{} = {}", key, value);
+        }
+        "del" => {
+            let key = store_key(rest)?;
+            let removed = fetch(port, config, "DELETE", &format!("/admin/store/{}", key)).await?;
+            println!("{}", if removed["removed"] == true { "removed" } else { "no such key" });
+        }
+        _ => bail!("unknown command {}; `help` lists the commands", command),
+    }
+    Ok(true)
+}
+
+/// The first word of `line`, and the rest with surrounding whitespace removed.
+fn split(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (line, ""),
+    }
+}
+
+/// Keys go into the admin URL as they are, as pages send them.
+fn store_key(key: &str) -> Result<&str> {
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        bail!("a key is one word");
+    }
+    Ok(key)
+}
+
+async fn fetch(port: u16, config: &Config, method: &str, path: &str) -> Result<Value> {
+    let body = admin_request(port, config, method, path).await.map_err(api_error)?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// The admin API's own `error` message, when it sent one.
+fn api_error(e: anyhow::Error) -> anyhow::Error {
+    let message = e.to_string();
+    let explained = message
+        .split_once(": ")
+        .and_then(|(_, body)| serde_json::from_str::<Value>(body).ok())
+        .and_then(|body| body["error"].as_str().map(str::to_string));
+    explained.map(|error| anyhow!(error)).unwrap_or(e)
+}
+
+fn text(value: &Value) -> String {
+    value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string())
+}
+
+fn print_explain(explained: &Value) {
+    for check in explained["checks"].as_array().into_iter().flatten() {
+        if check["passed"] == false {
+            println!("! {}", text(&check["detail"]));
+        }
+    }
+    let fire: Vec<&str> = explained["fire"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+    println!("fire: {}", if fire.is_empty() { "none".to_string() } else { fire.join(", ") });
+    for script in explained["scripts"].as_array().into_iter().flatten() {
+        let checks: Vec<&Value> = script["checks"].as_array().into_iter().flatten().collect();
+        let (mark, reasons): (&str, Vec<&Value>) = if script["fires"] == true {
+            ("+", checks.into_iter().filter(|check| check["passed"].is_null()).collect())
+        } else {
+            ("-", checks.into_iter().filter(|check| check["passed"] == false).take(1).collect())
+        };
+        println!("{} {} ({})", mark, text(&script["script"]), text(&script["inject_type"]));
+        for check in reasons {
+            println!("    {}: {}", text(&check["check"]), text(&check["detail"]));
+        }
+    }
+}
+
+fn print_try(result: &Value, body: bool) {
+    println!("{} on {} {} -> {}", text(&result["script"]), text(&result["method"]), text(&result["url"]), result["status"]);
+    if result["applied"] != true {
+        println!("no change");
+        return;
+    }
+    if let Some(headers) = result["headers_set"].as_object() {
+        for (name, value) in headers {
+            println!("  + {}: {}", name, text(value));
+        }
+    }
+    for name in result["headers_removed"].as_array().into_iter().flatten() {
+        println!("  - {}", text(name));
+    }
+    println!("body {} -> {} bytes", result["body_bytes_before"], result["body_bytes_after"]);
+    if body {
+        if let Some(body) = result["body"].as_str() {
+            println!("{}", body);
+        }
+    } else {
+        for snippet in result["snippets"].as_array().into_iter().flatten() {
+            println!("  inserted: {}{}", text(&snippet["content"]), if snippet["truncated"] == true { "..." } else { "" });
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use anyhow::{anyhow, bail, Result};
+use hyper::Uri;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::capture::{self, parse_response, read_warc, warc_date, BODY_LENGTH_FIELD, SCRIPTS_FIELD, UPSTREAM_FIELD};
+use crate::config::CaptureConfig;
+
+pub enum Format {
+    Text,
+    Json,
+    Html,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "html" => Some(Format::Html),
+            _ => None,
+        }
+    }
+}
+
+/// A summary of the exchanges captured in a time window, for `rusty-proxy report`.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Oldest `WARC-Date` counted; `None` counts everything in the files
+    pub since: Option<String>,
+    pub until: String,
+    pub files: Vec<String>,
+    pub exchanges: u64,
+    /// Response body bytes sent to clients
+    pub bytes: u64,
+    pub domains: Vec<Domain>,
+    /// Every status seen, lowest first
+    pub statuses: Vec<Status>,
+    pub scripts: Vec<Script>,
+    /// Endpoints (URL without the query) by average upstream time, slowest first
+    pub slowest: Vec<Endpoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Domain {
+    pub domain: String,
+    pub exchanges: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub status: u16,
+    pub exchanges: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Script {
+    pub script: String,
+    pub exchanges: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Endpoint {
+    pub method: String,
+    pub endpoint: String,
+    pub exchanges: u64,
+    pub average_ms: u64,
+    pub max_ms: u64,
+}
+
+/// One captured response, as far as the report needs it.
+struct Seen {
+    id: String,
+    target: String,
+    status: u16,
+    bytes: u64,
+    upstream_ms: Option<u64>,
+    scripts: Vec<String>,
+}
+
+/// Reads `files` and sums up the responses recorded at or after `since` ago, listing the
+/// `top` entries of each table. Response records carry the fields `[capture]` adds to
+/// them; records from other tools are counted without upstream times or scripts.
+pub fn build(files: &[PathBuf], since: Option<Duration>, top: usize) -> Result<Report> {
+    if files.is_empty() {
+        bail!("no capture files; set capture.warc or pass --warc");
+    }
+    let now = SystemTime::now();
+    let start = since.map(|since| now.checked_sub(since).unwrap_or(SystemTime::UNIX_EPOCH));
+    let cutoff = start.map(warc_date);
+    let mut report = Report {
+        since: cutoff.clone(),
+        until: warc_date(now),
+        ..Default::default()
+    };
+
+    let mut seen = vec![];
+    let mut methods: HashMap<String, String> = HashMap::new();
+    for file in files {
+        // A file last written before the window holds nothing in it
+        let modified = std::fs::metadata(file).and_then(|meta| meta.modified()).ok();
+        if let (Some(start), Some(modified)) = (start, modified) {
+            if modified < start {
+                continue;
+            }
+        }
+        let path = file.to_string_lossy();
+        read_warc(&path, |record| {
+            // WARC dates are UTC in one fixed form, so they compare as text
+            if cutoff.as_deref().is_some_and(|cutoff| record.date.as_str() < cutoff) {
+                return;
+            }
+            match record.kind.as_str() {
+                "request" => {
+                    let method = String::from_utf8_lossy(&record.block).split(' ').next().unwrap_or("GET").to_string();
+                    for id in &record.concurrent {
+                        methods.insert(id.clone(), method.clone());
+                    }
+                }
+                "response" => {
+                    let Some((status, _, body)) = parse_response(&record.block) else {
+                        return;
+                    };
+                    let bytes = record.fields.get(&BODY_LENGTH_FIELD.to_ascii_lowercase()).and_then(|n| n.parse().ok()).unwrap_or(body.len() as u64);
+                    seen.push(Seen {
+                        id: record.id,
+                        target: record.target,
+                        status,
+                        bytes,
+                        upstream_ms: record.fields.get(&UPSTREAM_FIELD.to_ascii_lowercase()).and_then(|ms| ms.parse().ok()),
+                        scripts: record
+                            .fields
+                            .get(&SCRIPTS_FIELD.to_ascii_lowercase())
+                            .map(|scripts| scripts.split(',').map(|script| script.trim().to_string()).filter(|script| !script.is_empty()).collect())
+                            .unwrap_or_default(),
+                    });
+                }
+                _ => {}
+            }
+        })?;
+        report.files.push(path.into_owned());
+    }
+
+    let mut domains: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut statuses: HashMap<u16, u64> = HashMap::new();
+    let mut scripts: HashMap<String, u64> = HashMap::new();
+    let mut endpoints: HashMap<(String, String), (u64, u64, u64)> = HashMap::new();
+    for exchange in seen {
+        report.exchanges += 1;
+        report.bytes += exchange.bytes;
+        let url: Option<Uri> = exchange.target.parse().ok();
+        let domain = url.as_ref().and_then(Uri::host).unwrap_or("unknown").to_string();
+        let entry = domains.entry(domain).or_default();
+        entry.0 += 1;
+        entry.1 += exchange.bytes;
+        *statuses.entry(exchange.status).or_default() += 1;
+        for script in exchange.scripts {
+            *scripts.entry(script).or_default() += 1;
+        }
+        if let Some(ms) = exchange.upstream_ms {
+            let endpoint = match &url {
+                Some(url) => format!("{}://{}{}", url.scheme_str().unwrap_or("http"), url.authority().map(|a| a.as_str()).unwrap_or(""), url.path()),
+                None => exchange.target.clone(),
+            };
+            let method = methods.get(&exchange.id).cloned().unwrap_or_else(|| "GET".to_string());
+            let entry = endpoints.entry((method, endpoint)).or_default();
+            entry.0 += 1;
+            entry.1 += ms;
+            entry.2 = entry.2.max(ms);
+        }
+    }
+
+    report.domains = domains.into_iter().map(|(domain, (exchanges, bytes))| Domain { domain, exchanges, bytes }).collect();
+    report.domains.sort_by(|a, b| b.exchanges.cmp(&a.exchanges).then(b.bytes.cmp(&a.bytes)).then(a.domain.cmp(&b.domain)));
+    report.domains.truncate(top);
+    report.statuses = statuses.into_iter().map(|(status, exchanges)| Status { status, exchanges }).collect();
+    report.statuses.sort_by_key(|status| status.status);
+    report.scripts = scripts.into_iter().map(|(script, exchanges)| Script { script, exchanges }).collect();
+    report.scripts.sort_by(|a, b| b.exchanges.cmp(&a.exchanges).then(a.script.cmp(&b.script)));
+    report.scripts.truncate(top);
+    report.slowest = endpoints
+        .into_iter()
+        .map(|((method, endpoint), (exchanges, total, max_ms))| Endpoint { method, endpoint, exchanges, average_ms: total / exchanges, max_ms })
+        .collect();
+    report.slowest.sort_by(|a, b| b.average_ms.cmp(&a.average_ms).then(b.max_ms.cmp(&a.max_ms)).then(a.endpoint.cmp(&b.endpoint)));
+    report.slowest.truncate(top);
+    Ok(report)
+}
+
+/// The capture files to report on: those given, or `[capture] warc` and its rotations.
+pub fn files(given: &[String], config: &CaptureConfig) -> Vec<PathBuf> {
+    if given.is_empty() {
+        capture::files(config)
+    } else {
+        given.iter().map(PathBuf::from).collect()
+    }
+}
+
+/// `30s`, `15m`, `1h`, `2d` or `1w`; a bare number is seconds.
+pub fn parse_age(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().map_err(|_| anyhow!("{:?} is not an age like 30m, 1h or 2d", text))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3_600,
+        "d" => 86_400,
+        "w" => 604_800,
+        _ => bail!("{:?} is not an age like 30m, 1h or 2d", text),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+pub fn render(report: &Report, format: Format) -> Result<String> {
+    Ok(match format {
+        Format::Json => serde_json::to_string_pretty(report)?,
+        Format::Text => text(report),
+        Format::Html => html(report),
+    })
+}
+
+fn window(report: &Report) -> String {
+    match &report.since {
+        Some(since) => format!("from {} to {}", since, report.until),
+        None => format!("until {}", report.until),
+    }
+}
+
+fn share(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+fn text(report: &Report) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Traffic {}: {} exchanges, {}", window(report), report.exchanges, size(report.bytes));
+    let _ = writeln!(out, "From {}", report.files.join(", "));
+
+    let _ = writeln!(out, "\nTop domains");
+    let width = report.domains.iter().map(|d| d.domain.len()).max().unwrap_or(0);
+    for domain in &report.domains {
+        let _ = writeln!(out, "  {:<width$}  {:>8}  {:>10}", domain.domain, domain.exchanges, size(domain.bytes), width = width);
+    }
+    let _ = writeln!(out, "\nStatuses");
+    for status in &report.statuses {
+        let _ = writeln!(out, "  {}  {:>8}  {:>5.1}%", status.status, status.exchanges, share(status.exchanges, report.exchanges));
+    }
+    let _ = writeln!(out, "\nTop matched scripts");
+    if report.scripts.is_empty() {
+        let _ = writeln!(out, "  none");
+    }
+    let width = report.scripts.iter().map(|s| s.script.len()).max().unwrap_or(0);
+    for script in &report.scripts {
+        let _ = writeln!(out, "  {:<width$}  {:>8}", script.script, script.exchanges, width = width);
+    }
+    let _ = writeln!(out, "\nSlowest endpoints (upstream time)");
+    if report.slowest.is_empty() {
+        let _ = writeln!(out, "  none timed");
+    }
+    for endpoint in &report.slowest {
+        let _ = writeln!(
+            out,
+            "  avg {:>6} ms  max {:>6} ms  {:>6}x  {} {}",
+            endpoint.average_ms, endpoint.max_ms, endpoint.exchanges, endpoint.method, endpoint.endpoint
+        );
+    }
+    out
+}
+
+fn html(report: &Report) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>rusty-proxy traffic report</title>\n\
+         <style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:2em}\
+         th,td{padding:.2em .8em;border-bottom:1px solid #ddd;text-align:left}td.n{text-align:right}</style></head><body>\n",
+    );
+    let _ = writeln!(out, "<h1>Traffic {}</h1>", escape(&window(report)));
+    let _ = writeln!(out, "<p>{} exchanges, {}, from {}</p>", report.exchanges, size(report.bytes), escape(&report.files.join(", ")));
+
+    let _ = writeln!(out, "<h2>Top domains</h2><table><tr><th>Domain</th><th>Exchanges</th><th>Bytes</th></tr>");
+    for domain in &report.domains {
+        let _ = writeln!(out, "<tr><td>{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td></tr>", escape(&domain.domain), domain.exchanges, size(domain.bytes));
+    }
+    let _ = writeln!(out, "</table><h2>Statuses</h2><table><tr><th>Status</th><th>Exchanges</th><th>Share</th></tr>");
+    for status in &report.statuses {
+        let _ = writeln!(
+            out,
+            "<tr><td>{}</td><td class=\"n\">{}</td><td class=\"n\">{:.1}%</td></tr>",
+            status.status,
+            status.exchanges,
+            share(status.exchanges, report.exchanges)
+        );
+    }
+    let _ = writeln!(out, "</table><h2>Top matched scripts</h2><table><tr><th>Script</th><th>Exchanges</th></tr>");
+    for script in &report.scripts {
+        let _ = writeln!(out, "<tr><td>{}</td><td class=\"n\">{}</td></tr>", escape(&script.script), script.exchanges);
+    }
+    let _ = writeln!(
+        out,
+        "</table><h2>Slowest endpoints</h2><table><tr><th>Endpoint</th><th>Exchanges</th><th>Average upstream ms</th><th>Max ms</th></tr>"
+    );
+    for endpoint in &report.slowest {
+        let _ = writeln!(
+            out,
+            "<tr><td>{} {}</td><td class=\"n\">{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td></tr>",
+            escape(&endpoint.method),
+            escape(&endpoint.endpoint),
+            endpoint.exchanges,
+            endpoint.average_ms,
+            endpoint.max_ms
+        );
+    }
+    out.push_str("</table></body></html>\n");
+    out
+}
+
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
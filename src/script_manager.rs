@@ -1,12 +1,33 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::{debug, error, info, warn};
+use hyper::{Method, Uri};
 use regex::Regex;
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::config::{self, FeaturesConfig};
+use crate::context::RequestContext;
+use crate::locale::{self, Geolocation};
+use crate::snapshot;
+use crate::assets;
+use crate::clock;
+use crate::html;
+use crate::history;
+use crate::jwt;
+use crate::optimize;
+use crate::pattern;
+use crate::signing::{self, Credential, HmacAlgorithm, SignScheme};
+use crate::xml::{self, XmlAction};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct InjectionScript {
     pub name: String,
     pub description: String,
@@ -17,38 +38,525 @@ pub struct InjectionScript {
     pub script_content: String,
     pub headers: HashMap<String, String>,
     pub enabled: bool,
+    /// Regex matched against content for pattern-based inject types such as `SseEvent`;
+    /// `script_content` is used as the replacement.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Upstream statuses the response injections apply to (e.g. `[404]`); empty means all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub target_status: Vec<u16>,
+    /// Inject only the first time per browser session, client IP, or client IP and URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub once_per: Option<OncePer>,
+    /// Bytes this script may add to one response body; a response it would grow more is
+    /// left without it. `scripts.max_added_bytes` caps all scripts together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_added_bytes: Option<usize>,
+    /// For `ResponseReplace` scripts: matches replaced in one body, in place of
+    /// `scripts.max_replacements`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_replacements: Option<usize>,
+    /// What `XPathReplace` does with `script_content` at the nodes `pattern` selects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xml_action: Option<XmlAction>,
+    /// Namespace prefixes usable in an `XPathReplace` XPath and content, e.g.
+    /// `soap = "http://schemas.xmlsoap.org/soap/envelope/"`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub xml_namespaces: HashMap<String, String>,
+    /// Remote files (e.g. a library on a CDN) cached by the proxy and injected ahead of a
+    /// `JavaScript` or `CSS` payload as tags loading them from `/__rusty_proxy/assets/`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assets: Vec<String>,
+    /// For `ResponseHeader` scripts: answer CORS preflights for the domain with the
+    /// script's headers instead of forwarding them upstream
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub answer_preflight: bool,
+    /// For `ResponseHeader` scripts: allow the requesting `Origin` (with credentials)
+    /// rather than the script's `Access-Control-Allow-Origin`, which browsers reject for
+    /// credentialed requests when it is `*`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub echo_origin: bool,
+    /// Regex the full request URL (scheme, host, path and query) must match. Named groups
+    /// are available to `script_content` and header values as `{{url:name}}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url_pattern: Option<String>,
+    /// For `Alert` scripts: URL the alert is POSTed to as JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    /// For `Alert` scripts: also show a desktop notification (`notify-send`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub desktop: bool,
+    /// For `Clock` scripts: the time pages and `Date` headers show, RFC 3339 or Unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fake_time: Option<String>,
+    /// For `Clock` scripts: how fast the fake clock runs (1 when unset; 0 freezes it)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_rate: Option<f64>,
+    /// For `Locale` scripts: language tag pages and `Accept-Language` report, e.g. `fr-CA`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// For `Locale` scripts: IANA time zone `Intl` formats dates in, e.g. `America/Toronto`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// For `Locale` scripts: position `navigator.geolocation` reports
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geolocation: Option<Geolocation>,
+    /// For `Snapshot` scripts: also archive each `<canvas>` as a PNG
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub screenshot: bool,
+    /// For `Snapshot` scripts: milliseconds after `load` the page is snapshotted (1000 when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_delay: Option<u64>,
+    /// For `Mock` scripts: status of the answer (200 when unset)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// For `Mock` scripts: request methods answered, e.g. `["GET"]`; empty means all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+    /// For `Jwt` scripts: claims set in the request's bearer token (`null` removes one;
+    /// `"now+3600"` is an hour from now in Unix seconds)
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub claims: serde_json::Map<String, serde_json::Value>,
+    /// For `Jwt` scripts: HMAC secret or PEM private key the rewritten token is signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+    /// For `Sign` scripts: the `[credentials]` entry requests are signed with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    /// For `Sign` scripts: `aws-sigv4` (when unset) or `hmac`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sign_scheme: Option<SignScheme>,
+    /// For `aws-sigv4`: region and service signed for, when the host is not an
+    /// `<service>.<region>.amazonaws.com` endpoint they can be told from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_service: Option<String>,
+    /// For `hmac`: the digest, `sha256` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmac_algorithm: Option<HmacAlgorithm>,
+    /// For `hmac`: template of what is signed, `{{method}}\n{{path}}\n{{timestamp}}\n{{body_sha256}}`
+    /// when unset; `headers` then carry the signature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub string_to_sign: Option<String>,
+    /// Values for `{{var:NAME}}`, overriding `[scripts.vars]` for this script
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub vars: HashMap<String, String>,
+    /// File holding the payload in place of `script_content`, relative to the script's
+    /// own file. It is only read once a request needs it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_file: Option<String>,
+    #[serde(skip)]
+    pub payload: LazyPayload,
+}
+
+/// A `content_file`, read the first time the script applies.
+#[derive(Debug, Clone, Default)]
+pub struct LazyPayload {
+    path: PathBuf,
+    /// Size and modification time of the file when the script loaded, hashed in place of
+    /// the content it hasn't read
+    stamp: String,
+    content: Arc<OnceLock<String>>,
+}
+
+impl LazyPayload {
+    /// Drops a payload already read, so the next use reads it again with the current
+    /// vars and minification.
+    fn forget(&mut self) {
+        self.content = Arc::default();
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OncePer {
+    /// Tracked with the proxy's session cookie
+    Session,
+    Client,
+    Url,
+}
+
+impl InjectionScript {
+    /// HTML comment placed in front of the payload. Pages that already contain it (served
+    /// from a cache, or proxied twice) are not injected again.
+    pub fn marker(&self) -> String {
+        let digest = Sha256::digest(self.name.as_bytes());
+        let nonce: String = digest.iter().take(4).map(|b| format!("{:02x}", b)).collect();
+        format!("<!--rusty-proxy:{}-->", nonce)
+    }
+
+    /// Markup injected into HTML pages for `JavaScript` and `CSS` scripts: the marker, tags
+    /// for pinned assets, then the inline payload.
+    pub fn html_payload(&self) -> String {
+        let assets: String = self
+            .assets
+            .iter()
+            .map(|url| {
+                let path = assets::pinned_path(url);
+                if assets::is_stylesheet(url) {
+                    format!("<link rel=\"stylesheet\" href=\"{}\">", path)
+                } else {
+                    format!("<script src=\"{}\"></script>", path)
+                }
+            })
+            .collect();
+        let inline = match self.inject_type {
+            InjectType::Css => format!("<style>{}</style>", self.script_content),
+            _ => format!("<script>{}</script>", self.script_content),
+        };
+        format!("{}{}{}", self.marker(), assets, inline)
+    }
+
+    /// Whether a response with this upstream status is subject to the script.
+    pub fn matches_status(&self, status: u16) -> bool {
+        self.target_status.is_empty() || self.target_status.contains(&status)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum InjectType {
+    #[default]
     Header,
     Body,
     ResponseHeader,
     ResponseBody,
     JavaScript,
-    CSS,
+    #[serde(rename = "CSS")]
+    Css,
+    SseEvent,
+    ResponseReplace,
+    /// XML bodies (SOAP, RSS): `pattern` is an XPath, `script_content` the new content
+    XPathReplace,
+    /// Changes nothing: fires `webhook` and/or `desktop` when a response with a
+    /// `target_status` (and, if set, a body matching `pattern`) comes back
+    Alert,
+    /// Sets the clock to `fake_time`: HTML pages get `Date` and `performance.now`
+    /// overridden, and responses a `Date` header to match
+    Clock,
+    /// Spoofs `locale`, `timezone` and `geolocation` in HTML pages, and sends a matching
+    /// `Accept-Language` upstream
+    Locale,
+    /// Posts the rendered DOM of HTML pages (and their canvases, with `screenshot`) back
+    /// to the proxy, which archives it in the capture
+    Snapshot,
+    /// Answers matching requests itself with `status`, `headers` and `script_content` as
+    /// the body; nothing is sent upstream
+    Mock,
+    /// Logs the claims of the request's bearer token, redacted, and rewrites `claims`,
+    /// re-signing with `signing_key`
+    Jwt,
+    /// Signs matching requests with `credential` once every other script has changed
+    /// them, by AWS Signature V4 or an HMAC `sign_scheme`
+    Sign,
 }
 
-#[derive(Debug, Clone)]
+/// What one pass of injections changed. The headers and body handed to the
+/// `apply_*_injections` functions are left untouched; `apply_to` writes the changes back.
+#[derive(Debug, Clone, Default)]
 pub struct InjectionResult {
-    pub modified: bool,
-    pub headers: Option<HashMap<String, String>>,
+    /// Scripts that changed something, in the order they ran
+    pub applied: Vec<String>,
+    /// Headers set or replaced, by lowercase name
+    pub headers_set: HashMap<String, String>,
+    pub headers_removed: Vec<String>,
+    /// The rewritten body, when any script changed it
     pub body: Option<String>,
-    pub javascript: Option<String>,
-    pub css: Option<String>,
+    /// Body length before the scripts ran
+    pub body_len_before: usize,
+    /// Content each script put into the body, by script name
+    pub snippets: Vec<(String, String)>,
 }
 
+impl InjectionResult {
+    pub fn modified(&self) -> bool {
+        !self.applied.is_empty()
+    }
+
+    /// Records the difference between the original and rewritten headers and body.
+    fn diff(&mut self, headers: &HashMap<String, String>, new_headers: HashMap<String, String>, body: &str, new_body: String) {
+        self.headers_removed = headers.keys().filter(|name| !new_headers.contains_key(*name)).cloned().collect();
+        self.headers_removed.sort();
+        self.headers_set = new_headers
+            .into_iter()
+            .filter(|(name, value)| headers.get(name) != Some(value))
+            .collect();
+        self.body_len_before = body.len();
+        if new_body != body {
+            self.body = Some(new_body);
+        }
+    }
+
+    /// Writes the changes into the headers and body the injections were computed from.
+    pub fn apply_to(&self, headers: &mut HashMap<String, String>, body: &mut String) {
+        for name in &self.headers_removed {
+            headers.remove(name);
+        }
+        for (name, value) in &self.headers_set {
+            headers.insert(name.clone(), value.clone());
+        }
+        if let Some(new_body) = &self.body {
+            body.clone_from(new_body);
+        }
+    }
+}
+
+/// How a streamed body is rewritten: `rules` as it passes, each from the script of the same
+/// index in `replacing`, and the scripts in `head` into the page's `<head>` once enough
+/// of it has arrived to place them.
+#[derive(Default)]
+pub struct StreamRewrites {
+    pub rules: Vec<(Regex, String)>,
+    pub replacing: Vec<InjectionScript>,
+    pub head: Vec<InjectionScript>,
+}
+
+/// The script manager shared by the injector and the admin API, so scripts can be
+/// reloaded while the proxy runs. Hold the guards briefly and never across an `.await`.
+pub type SharedScripts = Arc<RwLock<ScriptManager>>;
+
 pub struct ScriptManager {
     scripts_dir: PathBuf,
     scripts: HashMap<String, InjectionScript>,
+    hits: HashMap<String, AtomicU64>,
+    /// Compiled `url_pattern`s by script name; scripts whose pattern failed to compile
+    /// have none and match nothing
+    url_patterns: HashMap<String, Regex>,
+    /// Compiled regex `pattern`s by script name, likewise
+    patterns: HashMap<String, Regex>,
+    /// `scripts.max_execution_time`: how long one script's replacements may run on a body
+    time_budget: Duration,
+    /// `scripts.max_added_bytes` and `scripts.max_replacements`; 0 is no limit
+    max_added_bytes: usize,
+    max_replacements: usize,
+    features: FeaturesConfig,
+    /// `script|scope` keys of `once_per` scripts that were already injected
+    injected_once: Mutex<HashSet<String>>,
+    /// `optimize.minify_payloads`: JavaScript and CSS payloads are minified as they load
+    minify_payloads: bool,
+    /// When scripts last loaded; `Clock` scripts show their `fake_time` at this moment
+    clock_started: Instant,
+    /// `[scripts.vars]`, filled into scripts as they load. Unset outside the proxy, so
+    /// `script export` bundles scripts with their placeholders
+    vars: Option<HashMap<String, String>>,
+    /// `enabled` as set at runtime (admin API or cluster peers), by script name. Kept
+    /// across reloads, and wins over the script files
+    enabled_overrides: HashMap<String, bool>,
+    /// `[credentials]` with their secrets read, for `Sign` scripts
+    credentials: HashMap<String, Credential>,
+}
+
+/// `scripts.max_execution_time` until the config is applied.
+const DEFAULT_TIME_BUDGET: Duration = Duration::from_secs(5);
+
+/// URLs longer than this never match a `url_pattern` script.
+const URL_MATCH_LIMIT: usize = 8 * 1024;
+
+/// Bound on remembered `once_per` injections; past it the set starts over, so a payload
+/// may be injected again rather than memory growing without limit.
+const ONCE_PER_LIMIT: usize = 100_000;
+
+/// Most threads reading and parsing script files at once while scripts load.
+const LOAD_WORKERS: usize = 8;
+
+/// A script file found while walking the scripts directory, not yet read.
+struct Pending {
+    path: PathBuf,
+    /// Directory of the script relative to the scripts directory, e.g. `team/api/`
+    prefix: String,
+    defaults: Arc<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Per-script summary used by diagnostics.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptStats {
+    pub name: String,
+    pub enabled: bool,
+    pub inject_type: InjectType,
+    pub hits: u64,
+}
+
+/// Whether a script would run for a request, and the checks that decided it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+    pub script: String,
+    pub inject_type: InjectType,
+    /// No check failed; checks that wait on the response may still stop it
+    pub fires: bool,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub check: &'static str,
+    /// `None` when it depends on what the upstream answers
+    pub passed: Option<bool>,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(check: &'static str, passed: Option<bool>, detail: impl Into<String>) -> Self {
+        Check {
+            check,
+            passed,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Lists names in a scripts directory, and below it, that aren't loaded.
+const IGNORE_FILE: &str = ".proxyignore";
+
+/// Fields shared by every script in a directory, and below it, unless a script sets them.
+const DEFAULTS_FILE: &str = "_defaults.json";
+
+/// One line of a `.proxyignore`: a file or directory name with `*` and `?` wildcards; a
+/// trailing `/` matches directories only.
+#[derive(Clone)]
+struct IgnoreRule {
+    pattern: String,
+    dir_only: bool,
+}
+
+impl IgnoreRule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let pattern = line.trim_end_matches('/');
+        Some(IgnoreRule {
+            pattern: pattern.to_string(),
+            dir_only: pattern.len() != line.len(),
+        })
+    }
+
+    fn matches(&self, name: &str, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && wildcard_matches(&self.pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+    }
+}
+
+fn wildcard_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| wildcard_matches(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && wildcard_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_matches(rest, &name[1..]),
+    }
+}
+
+/// Replaces `{{var:NAME}}` in a script's payload, headers, target domains, webhook and
+/// assets with the script's own `vars`, then `globals`. In `pattern` and `url_pattern`
+/// the value is matched literally. Unknown names are left in place with a warning.
+fn fill_vars(script: &mut InjectionScript, globals: &HashMap<String, String>) {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let placeholder = PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{var:([A-Za-z0-9_.-]+)\}\}").unwrap());
+    let name = script.name.clone();
+    let vars = script.vars.clone();
+    let fill = |text: &mut String, escape: bool| {
+        if !text.contains("{{var:") {
+            return;
+        }
+        let filled = placeholder.replace_all(text, |captures: &regex::Captures| {
+            match vars.get(&captures[1]).or_else(|| globals.get(&captures[1])) {
+                Some(value) if escape => regex::escape(value),
+                Some(value) => value.clone(),
+                None => {
+                    warn!("Script {} uses {} but no such variable is defined", name, &captures[0]);
+                    captures[0].to_string()
+                }
+            }
+        });
+        *text = filled.into_owned();
+    };
+
+    fill(&mut script.script_content, false);
+    script.headers.values_mut().for_each(|value| fill(value, false));
+    script.target_domains.iter_mut().for_each(|domain| fill(domain, false));
+    script.assets.iter_mut().for_each(|url| fill(url, false));
+    if let Some(webhook) = &mut script.webhook {
+        fill(webhook, false);
+    }
+    if let Some(signing_key) = &mut script.signing_key {
+        fill(signing_key, false);
+    }
+    if let Some(string_to_sign) = &mut script.string_to_sign {
+        fill(string_to_sign, false);
+    }
+    // An XPathReplace pattern is an XPath, not a regex
+    let pattern_is_regex = !matches!(script.inject_type, InjectType::XPathReplace);
+    if let Some(pattern) = &mut script.pattern {
+        fill(pattern, pattern_is_regex);
+    }
+    if let Some(url_pattern) = &mut script.url_pattern {
+        fill(url_pattern, true);
+    }
+}
+
+/// Fills `{{request.method}}`, `{{request.url}}`, `{{request.domain}}`,
+/// `{{request.client_ip}}`, `{{request.matched_scripts}}` (comma-separated),
+/// `{{request.elapsed_ms}}` (since the request arrived, as the script runs) and
+/// `{{request.header:NAME}}` into a script's payload and header values. Values are
+/// escaped like URL captures; unknown names are left in place.
+fn fill_request<'a>(script: Cow<'a, InjectionScript>, ctx: &RequestContext) -> Cow<'a, InjectionScript> {
+    const MARKER: &str = "{{request.";
+    if !script.script_content.contains(MARKER) && !script.headers.values().any(|value| value.contains(MARKER)) {
+        return script;
+    }
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let placeholder = PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{request\.(header:[A-Za-z0-9_-]+|[a-z_]+)\}\}").unwrap());
+    let fill = |text: &str| {
+        placeholder
+            .replace_all(text, |captures: &regex::Captures| {
+                let value = match &captures[1] {
+                    "method" => ctx.method.to_string(),
+                    "url" => ctx.url.to_string(),
+                    "domain" => ctx.domain.clone(),
+                    "client_ip" => ctx.client_ip.to_string(),
+                    "matched_scripts" => ctx.matched_scripts.join(","),
+                    "elapsed_ms" => ctx.elapsed().as_millis().to_string(),
+                    field => match field.strip_prefix("header:") {
+                        Some(name) => ctx.headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or("").to_string(),
+                        None => return captures[0].to_string(),
+                    },
+                };
+                escape_capture(&value)
+            })
+            .into_owned()
+    };
+    let mut script = script.into_owned();
+    script.script_content = fill(&script.script_content);
+    for value in script.headers.values_mut() {
+        *value = fill(value);
+    }
+    Cow::Owned(script)
+}
+
+/// A URL capture as inserted into a script: it stays percent-encoded as in the URL, and
+/// quotes, angle brackets, backslashes and backticks are encoded too, so a crafted URL
+/// can't break out of a string or tag in the payload.
+fn escape_capture(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '"' | '\'' | '<' | '>' | '\\' | '`' => format!("%{:02X}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
 }
 
 impl ScriptManager {
     pub fn new<P: AsRef<Path>>(scripts_dir: P) -> Result<Self> {
+        Self::open(scripts_dir, true)
+    }
+
+    /// Loads the scripts in `scripts_dir`. With `create` unset (read-only filesystems) a
+    /// missing directory just means no scripts.
+    pub fn open<P: AsRef<Path>>(scripts_dir: P, create: bool) -> Result<Self> {
         let scripts_dir = scripts_dir.as_ref().to_path_buf();
         
         // Create scripts directory if it doesn't exist
-        if !scripts_dir.exists() {
+        if !scripts_dir.exists() && create {
             fs::create_dir_all(&scripts_dir)?;
             info!("Created scripts directory: {:?}", scripts_dir);
         }
@@ -56,196 +564,1123 @@ impl ScriptManager {
         let mut manager = ScriptManager {
             scripts_dir,
             scripts: HashMap::new(),
+            hits: HashMap::new(),
+            url_patterns: HashMap::new(),
+            patterns: HashMap::new(),
+            time_budget: DEFAULT_TIME_BUDGET,
+            max_added_bytes: 0,
+            max_replacements: 0,
+            features: FeaturesConfig::default(),
+            injected_once: Mutex::new(HashSet::new()),
+            minify_payloads: false,
+            clock_started: Instant::now(),
+            vars: None,
+            enabled_overrides: HashMap::new(),
+            credentials: HashMap::new(),
         };
 
-        manager.load_scripts()?;
-        manager.create_example_scripts()?;
-        
+        if manager.scripts_dir.exists() {
+            manager.load_scripts()?;
+        }
+
         Ok(manager)
     }
 
     pub fn load_scripts(&mut self) -> Result<()> {
         self.scripts.clear();
-        
-        for entry in fs::read_dir(&self.scripts_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.extension().and_then(|s| s.to_str()) == Some("json") {
-                match self.load_script(&path) {
-                    Ok(script) => {
-                        info!("Loaded script: {}", script.name);
-                        self.scripts.insert(script.name.clone(), script);
+        let scripts_dir = self.scripts_dir.clone();
+        let mut pending = Vec::new();
+        Self::find_scripts(&scripts_dir, "", &[], &Arc::new(serde_json::Map::new()), &mut pending)?;
+        for (file, loaded) in pending.iter().zip(Self::read_scripts(&pending)) {
+            match loaded {
+                Ok(mut script) => {
+                    script.name = format!("{}{}", file.prefix, script.name);
+                    if self.scripts.contains_key(&script.name) {
+                        warn!("Ignoring {:?}: a script named {} is already loaded", file.path, script.name);
+                        continue;
                     }
-                    Err(e) => {
-                        error!("Failed to load script {:?}: {}", path, e);
+                    if let Some(vars) = &self.vars {
+                        fill_vars(&mut script, vars);
                     }
+                    if self.minify_payloads {
+                        optimize::minify_payload(&mut script);
+                    }
+                    info!("Loaded script: {}", script.name);
+                    self.scripts.insert(script.name.clone(), script);
+                }
+                Err(e) => {
+                    error!("Failed to load script {:?}: {}", file.path, e);
                 }
             }
         }
+        for (name, enabled) in &self.enabled_overrides {
+            if let Some(script) = self.scripts.get_mut(name) {
+                script.enabled = *enabled;
+            }
+        }
+
+        self.hits = self.scripts.keys().map(|name| (name.clone(), AtomicU64::new(0))).collect();
+        self.compile_all();
+        self.clock_started = Instant::now();
 
         info!("Loaded {} injection scripts", self.scripts.len());
         Ok(())
     }
 
-    fn load_script<P: AsRef<Path>>(&self, path: P) -> Result<InjectionScript> {
+    fn compile_all(&mut self) {
+        self.url_patterns = self.compile_patterns("url_pattern", |script| script.url_pattern.as_deref());
+        // An XPathReplace pattern is an XPath, not a regex
+        self.patterns = self.compile_patterns("pattern", |script| match script.inject_type {
+            InjectType::XPathReplace => None,
+            _ => script.pattern.as_deref(),
+        });
+    }
+
+    /// Lists the script files in `dir` and its subdirectories. A nested script's name is
+    /// prefixed with its directory relative to the scripts directory (`team/api/name`),
+    /// so the same name can be reused in different directories.
+    fn find_scripts(
+        dir: &Path,
+        prefix: &str,
+        ignore: &[IgnoreRule],
+        defaults: &Arc<serde_json::Map<String, serde_json::Value>>,
+        pending: &mut Vec<Pending>,
+    ) -> Result<()> {
+        // Both files apply to their directory and everything below it
+        let mut ignore = ignore.to_vec();
+        if let Ok(content) = fs::read_to_string(dir.join(IGNORE_FILE)) {
+            ignore.extend(content.lines().filter_map(IgnoreRule::parse));
+        }
+        let mut defaults = Arc::clone(defaults);
+        let defaults_path = dir.join(DEFAULTS_FILE);
+        if defaults_path.exists() {
+            match fs::read_to_string(&defaults_path).map_err(anyhow::Error::from).and_then(|content| Ok(serde_json::from_str(&content)?)) {
+                Ok(serde_json::Value::Object(fields)) => Arc::make_mut(&mut defaults).extend(fields),
+                Ok(_) => error!("Ignoring {:?}: defaults must be a JSON object", defaults_path),
+                Err(e) => error!("Failed to load script defaults {:?}: {}", defaults_path, e),
+            }
+        }
+
+        // Sorted, so which of two scripts with the same name wins doesn't vary between loads
+        let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let Some(file_name) = path.file_name().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            // Symlinked directories aren't followed, so a link loop can't recurse forever
+            let is_dir = entry.file_type()?.is_dir();
+            if ignore.iter().any(|rule| rule.matches(file_name, is_dir)) || (is_dir && prefix.is_empty() && file_name == history::HISTORY_DIR) {
+                debug!("Ignoring {:?}", path);
+                continue;
+            }
+
+            if is_dir {
+                let nested = format!("{}{}/", prefix, file_name);
+                if let Err(e) = Self::find_scripts(&path, &nested, &ignore, &defaults, pending) {
+                    error!("Failed to read scripts directory {:?}: {}", path, e);
+                }
+            } else if file_name != DEFAULTS_FILE && path.extension().and_then(|s| s.to_str()) == Some("json") {
+                pending.push(Pending {
+                    path,
+                    prefix: prefix.to_string(),
+                    defaults: Arc::clone(&defaults),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and parses the files on up to `LOAD_WORKERS` threads. Results come back in
+    /// the order of `pending`, so loads stay deterministic.
+    fn read_scripts(pending: &[Pending]) -> Vec<Result<InjectionScript>> {
+        let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(LOAD_WORKERS);
+        let chunk = pending.len().div_ceil(workers).max(1);
+        thread::scope(|scope| {
+            let handles: Vec<_> = pending
+                .chunks(chunk)
+                .map(|files| scope.spawn(move || files.iter().map(|file| Self::load_script(&file.path, &file.defaults)).collect::<Vec<_>>()))
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        })
+    }
+
+    /// Compiles one regex field of every script within the `pattern` limits.
+    fn compile_patterns(&self, field: &str, pattern_of: impl Fn(&InjectionScript) -> Option<&str>) -> HashMap<String, Regex> {
+        self.scripts
+            .values()
+            .filter_map(|script| {
+                let source = pattern_of(script)?;
+                match pattern::compile(source) {
+                    Ok(regex) => Some((script.name.clone(), regex)),
+                    Err(e) => {
+                        warn!("Script {} has an unusable {}, ignoring it: {}", script.name, field, e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_max_execution_time(&mut self, millis: u64) {
+        self.time_budget = Duration::from_millis(millis);
+    }
+
+    pub fn set_growth_limits(&mut self, max_added_bytes: usize, max_replacements: usize) {
+        self.max_added_bytes = max_added_bytes;
+        self.max_replacements = max_replacements;
+    }
+
+    /// Whether a script may add `added` bytes to a response body that its earlier scripts
+    /// already grew by `grown`; logs the limit it would break otherwise.
+    fn within_growth(&self, script: &InjectionScript, ctx: &RequestContext, added: usize, grown: usize) -> bool {
+        let limit = match script.max_added_bytes {
+            Some(max) if added > max => format!("its max_added_bytes of {}", max),
+            _ if self.max_added_bytes > 0 && grown + added > self.max_added_bytes => {
+                format!("scripts.max_added_bytes of {} for the whole response", self.max_added_bytes)
+            }
+            _ => return true,
+        };
+        warn!("Script {} skipped for {}: adding {} bytes would pass {}", script.name, ctx.url, added, limit);
+        false
+    }
+
+    /// Sets `[scripts.vars]` and fills them into the scripts already loaded.
+    pub fn set_vars(&mut self, vars: HashMap<String, String>) {
+        for script in self.scripts.values_mut() {
+            fill_vars(script, &vars);
+            script.payload.forget();
+        }
+        self.vars = Some(vars);
+        self.compile_all();
+    }
+
+    pub fn set_credentials(&mut self, credentials: HashMap<String, Credential>) {
+        self.credentials = credentials;
+    }
+
+    pub fn set_features(&mut self, features: FeaturesConfig) {
+        self.features = features;
+    }
+
+    /// Turns payload minification on for already loaded scripts and every later reload.
+    pub fn set_minify_payloads(&mut self, on: bool) {
+        self.minify_payloads = on;
+        if on {
+            for script in self.scripts.values_mut() {
+                optimize::minify_payload(script);
+                script.payload.forget();
+            }
+        }
+    }
+
+    /// Copies a script's headers into `headers`, honouring the `[features]` gates: CORS
+    /// headers need `cors-injection`, and an empty value removes the header only with
+    /// `header-stripping`. Returns whether anything changed.
+    fn apply_script_headers(&self, script: &InjectionScript, headers: &mut HashMap<String, String>) -> bool {
+        let mut modified = false;
+        for (key, value) in &script.headers {
+            let name = key.to_lowercase();
+            if name.starts_with("access-control-") && !self.features.cors_injection {
+                warn!("Script {} sets {} but cors-injection is disabled", script.name, key);
+                continue;
+            }
+            if value.is_empty() {
+                if !self.features.header_stripping {
+                    warn!("Script {} strips {} but header-stripping is disabled", script.name, key);
+                    continue;
+                }
+                modified |= headers.remove(&name).is_some();
+                continue;
+            }
+            headers.insert(name, value.clone());
+            modified = true;
+        }
+        modified
+    }
+
+    /// Logs the claims of the bearer token a `Jwt` script sees, then rewrites them and
+    /// re-signs it. Returns whether the token changed.
+    fn apply_jwt(&self, script: &InjectionScript, ctx: &RequestContext, headers: &mut HashMap<String, String>) -> bool {
+        let Some((scheme, token)) = headers.get("authorization").and_then(|value| value.split_once(' ')) else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return false;
+        }
+        let Some(mut token) = jwt::Token::decode(token) else {
+            debug!("Script {}: bearer token for {} is not a JWT", script.name, ctx.url);
+            return false;
+        };
+        info!("JWT for {} ({}): {}", ctx.url, script.name, token.redacted());
+        if script.claims.is_empty() && script.signing_key.is_none() {
+            return false;
+        }
+        token.apply(&script.claims);
+        match token.encode(script.signing_key.as_deref()) {
+            Ok(encoded) => {
+                info!("JWT rewritten by {}: {}", script.name, token.redacted());
+                headers.insert("authorization".to_string(), format!("Bearer {}", encoded));
+                true
+            }
+            Err(e) => {
+                warn!("Script {} can't sign the rewritten token: {}", script.name, e);
+                false
+            }
+        }
+    }
+
+    /// `apply_script_headers` for a response, echoing the request's `Origin` for
+    /// `echo_origin` scripts.
+    fn apply_response_script_headers(&self, script: &InjectionScript, ctx: &RequestContext, headers: &mut HashMap<String, String>) -> bool {
+        let mut modified = self.apply_script_headers(script, headers);
+        if !script.echo_origin || !self.features.cors_injection {
+            return modified;
+        }
+        if let Some(origin) = ctx.headers.get("origin").and_then(|v| v.to_str().ok()) {
+            headers.insert("access-control-allow-origin".to_string(), origin.to_string());
+            headers.insert("access-control-allow-credentials".to_string(), "true".to_string());
+            let vary = headers.entry("vary".to_string()).or_default();
+            if !vary.split(',').any(|v| v.trim().eq_ignore_ascii_case("origin")) {
+                if !vary.is_empty() {
+                    vary.push_str(", ");
+                }
+                vary.push_str("Origin");
+            }
+            modified = true;
+        }
+        modified
+    }
+
+    fn once_key(script: &InjectionScript, ctx: &RequestContext) -> Option<String> {
+        let scope = match script.once_per? {
+            OncePer::Session => ctx.session_id.clone(),
+            OncePer::Client => ctx.client_ip.to_string(),
+            OncePer::Url => format!("{} {}", ctx.client_ip, ctx.url),
+        };
+        Some(format!("{}|{}", script.name, scope))
+    }
+
+    fn already_injected(&self, script: &InjectionScript, ctx: &RequestContext) -> bool {
+        match Self::once_key(script, ctx) {
+            Some(key) => self.injected_once.lock().unwrap().contains(&key),
+            None => false,
+        }
+    }
+
+    fn mark_injected(&self, script: &InjectionScript, ctx: &RequestContext) {
+        if let Some(key) = Self::once_key(script, ctx) {
+            let mut injected = self.injected_once.lock().unwrap();
+            if injected.len() >= ONCE_PER_LIMIT {
+                injected.clear();
+            }
+            injected.insert(key);
+        }
+    }
+
+    /// Whether scripts may rewrite non-HTML bodies (e.g. JS or CSS assets) on the domain.
+    pub fn modifies_assets(&self, domain: &str) -> bool {
+        self.get_scripts_for_domain(domain).iter().any(|script| {
+            matches!(script.inject_type, InjectType::ResponseBody | InjectType::ResponseReplace)
+                && script.matches_status(200)
+        })
+    }
+
+    /// Whether scripts may rewrite non-HTML bodies on any domain, so pages anywhere may
+    /// reference assets whose `integrity=` no longer holds.
+    pub fn modifies_any_assets(&self) -> bool {
+        self.scripts.values().any(|script| {
+            script.enabled && matches!(script.inject_type, InjectType::ResponseBody | InjectType::ResponseReplace) && script.matches_status(200)
+        })
+    }
+
+    /// Whether a matching script relies on the session cookie, so a new one must be set.
+    pub fn uses_sessions(&self, ctx: &RequestContext) -> bool {
+        self.scripts_for(ctx)
+            .iter()
+            .any(|script| script.once_per == Some(OncePer::Session))
+    }
+
+    fn record_hit(&self, name: &str) {
+        if let Some(counter) = self.hits.get(name) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Enables or disables a script until this is called again, reloads included. Returns
+    /// whether the script is loaded; the setting also applies once it is.
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        self.enabled_overrides.insert(name.to_string(), enabled);
+        match self.scripts.get_mut(name) {
+            Some(script) => {
+                script.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hits per script since scripts last loaded.
+    pub fn hit_counts(&self) -> HashMap<String, u64> {
+        self.hits.iter().map(|(name, counter)| (name.clone(), counter.load(Ordering::Relaxed))).collect()
+    }
+
+    pub fn script_stats(&self) -> Vec<ScriptStats> {
+        let mut stats: Vec<ScriptStats> = self
+            .scripts
+            .values()
+            .map(|script| ScriptStats {
+                name: script.name.clone(),
+                enabled: script.enabled,
+                inject_type: script.inject_type.clone(),
+                hits: self.hits.get(&script.name).map(|c| c.load(Ordering::Relaxed)).unwrap_or(0),
+            })
+            .collect();
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+        stats
+    }
+
+    /// Reads one script, filling the fields it leaves out from its directory's defaults.
+    fn load_script(path: &Path, defaults: &serde_json::Map<String, serde_json::Value>) -> Result<InjectionScript> {
         let content = fs::read_to_string(path)?;
-        let script: InjectionScript = serde_json::from_str(&content)?;
+        let mut fields: serde_json::Value = serde_json::from_str(&content)?;
+        if let serde_json::Value::Object(fields) = &mut fields {
+            for (key, value) in defaults {
+                fields.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+        let mut script: InjectionScript = serde_json::from_value(fields)?;
+        if let Some(file) = &script.content_file {
+            let content_path = path.parent().unwrap_or(Path::new(".")).join(file);
+            let metadata = fs::metadata(&content_path).map_err(|e| anyhow::anyhow!("content_file {:?}: {}", content_path, e))?;
+            let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+            script.payload = LazyPayload {
+                path: content_path,
+                stamp: format!("{}:{}", metadata.len(), modified.as_nanos()),
+                content: Arc::default(),
+            };
+        }
+        if let InjectType::Clock = script.inject_type {
+            let fake_time = script.fake_time.as_deref().ok_or_else(|| anyhow::anyhow!("Clock script without fake_time"))?;
+            clock::parse(fake_time).ok_or_else(|| anyhow::anyhow!("Unusable fake_time {}", fake_time))?;
+            if script.clock_rate.is_some_and(|rate| !rate.is_finite() || rate < 0.0) {
+                anyhow::bail!("clock_rate must be 0 or more");
+            }
+        }
+        if let InjectType::Locale = script.inject_type {
+            locale::validate(script.locale.as_deref(), script.timezone.as_deref(), script.geolocation.as_ref()).map_err(anyhow::Error::msg)?;
+        }
         Ok(script)
     }
 
+    /// Remote asset URLs declared by enabled scripts.
+    pub fn pinned_assets(&self) -> Vec<String> {
+        let mut urls: Vec<String> = self
+            .scripts
+            .values()
+            .filter(|script| script.enabled)
+            .flat_map(|script| script.assets.iter().cloned())
+            .collect();
+        urls.sort();
+        urls.dedup();
+        urls
+    }
+
+    pub fn scripts_dir(&self) -> &Path {
+        &self.scripts_dir
+    }
+
     pub fn list_scripts(&self) -> Vec<String> {
         self.scripts.keys().cloned().collect()
     }
 
+    pub fn get_script(&self, name: &str) -> Option<&InjectionScript> {
+        self.scripts.get(name)
+    }
+
+    /// The scripts for the request: those targeting its domain whose `url_pattern`, if
+    /// any, matches its URL. Scripts using named groups come back with `{{url:name}}`
+    /// filled in.
+    /// The scripts for the request, with its `{{request.*}}` placeholders filled in.
+    pub fn scripts_for(&self, ctx: &RequestContext) -> Vec<Cow<'_, InjectionScript>> {
+        self.scripts_for_url(&ctx.domain, &ctx.url).into_iter().map(|script| fill_request(script, ctx)).collect()
+    }
+
+    pub fn scripts_for_url(&self, domain: &str, url: &Uri) -> Vec<Cow<'_, InjectionScript>> {
+        let url = url.to_string();
+        self.get_scripts_for_domain(domain)
+            .into_iter()
+            .filter_map(|script| {
+                if matches!(script.inject_type, InjectType::Clock | InjectType::Locale | InjectType::Snapshot) {
+                    return self.built_in(script, &url).map(Cow::Owned);
+                }
+                if script.url_pattern.is_none() {
+                    return Some(self.with_payload(script));
+                }
+                let regex = self.url_patterns.get(&script.name)?;
+                if url.len() > URL_MATCH_LIMIT {
+                    return None;
+                }
+                let captures = regex.captures(&url)?;
+                let names: Vec<&str> = regex.capture_names().flatten().collect();
+                let script = self.with_payload(script);
+                if names.is_empty() {
+                    return Some(script);
+                }
+                let fill = |text: &str| {
+                    names.iter().fold(text.to_string(), |text, name| {
+                        let value = captures.name(name).map(|m| escape_capture(m.as_str())).unwrap_or_default();
+                        text.replace(&format!("{{{{url:{}}}}}", name), &value)
+                    })
+                };
+                let mut script = script.into_owned();
+                script.script_content = fill(&script.script_content);
+                for value in script.headers.values_mut() {
+                    *value = fill(value);
+                }
+                Some(Cow::Owned(script))
+            })
+            .collect()
+    }
+
+    /// The script with its `content_file`, if it has one, as `script_content`. The file
+    /// is read the first time a request needs it and kept until the scripts reload.
+    pub fn with_payload<'a>(&self, script: &'a InjectionScript) -> Cow<'a, InjectionScript> {
+        if script.content_file.is_none() {
+            return Cow::Borrowed(script);
+        }
+        let content = script.payload.content.get_or_init(|| {
+            let mut loaded = InjectionScript {
+                name: script.name.clone(),
+                inject_type: script.inject_type.clone(),
+                vars: script.vars.clone(),
+                ..Default::default()
+            };
+            match fs::read_to_string(&script.payload.path) {
+                Ok(content) => {
+                    debug!("Read {} bytes of payload for {} from {:?}", content.len(), script.name, script.payload.path);
+                    loaded.script_content = content;
+                }
+                Err(e) => error!("Script {} can't read its content_file {:?}: {}", script.name, script.payload.path, e),
+            }
+            if let Some(vars) = &self.vars {
+                fill_vars(&mut loaded, vars);
+            }
+            if self.minify_payloads {
+                optimize::minify_payload(&mut loaded);
+            }
+            loaded.script_content
+        });
+        let mut script = script.clone();
+        script.script_content.clone_from(content);
+        Cow::Owned(script)
+    }
+
+    /// A built-in script as applied to one exchange, with its generated payload and
+    /// headers: for `Clock` the fake time now and a `Date` header, for `Locale` the
+    /// overrides and an `Accept-Language` header, for `Snapshot` the beacon. `None` when
+    /// its `url_pattern` doesn't match.
+    fn built_in(&self, script: &InjectionScript, url: &str) -> Option<InjectionScript> {
+        if script.url_pattern.is_some() {
+            let regex = self.url_patterns.get(&script.name)?;
+            if url.len() > URL_MATCH_LIMIT || !regex.is_match(url) {
+                return None;
+            }
+        }
+        let mut script = script.clone();
+        if let InjectType::Snapshot = script.inject_type {
+            script.script_content = snapshot::payload(script.screenshot, script.snapshot_delay.unwrap_or(snapshot::DEFAULT_DELAY_MS));
+        } else if let InjectType::Clock = script.inject_type {
+            let start = clock::parse(script.fake_time.as_deref()?)?;
+            let rate = script.clock_rate.unwrap_or(1.0);
+            let now = clock::now(start, self.clock_started, rate);
+            script.script_content = clock::payload(now, rate);
+            script.headers = HashMap::from([("Date".to_string(), httpdate::fmt_http_date(now))]);
+        } else {
+            script.script_content = locale::payload(script.locale.as_deref(), script.timezone.as_deref(), script.geolocation.as_ref());
+            script.headers = script
+                .locale
+                .as_deref()
+                .map(|tag| HashMap::from([("Accept-Language".to_string(), locale::accept_language(tag))]))
+                .unwrap_or_default();
+        }
+        Some(script)
+    }
+
     pub fn get_scripts_for_domain(&self, domain: &str) -> Vec<&InjectionScript> {
         self.scripts
             .values()
             .filter(|script| {
-                script.enabled && self.domain_matches(domain, &script.target_domains)
+                script.enabled && Self::domain_matches(domain, &script.target_domains)
+            })
+            .collect()
+    }
+
+    /// The `Mock` script answering this request instead of the upstream. Of several, one
+    /// with a `url_pattern` wins, then the one with the fewest captures (so `/pets/mine`
+    /// beats `/pets/(?P<id>[^/]+)`), then the first by name.
+    pub fn mock_for(&self, ctx: &RequestContext) -> Option<InjectionScript> {
+        let mut mocks: Vec<Cow<'_, InjectionScript>> = self
+            .scripts_for(ctx)
+            .into_iter()
+            .filter(|script| matches!(script.inject_type, InjectType::Mock))
+            .filter(|script| script.methods.is_empty() || script.methods.iter().any(|m| m.eq_ignore_ascii_case(ctx.method.as_str())))
+            .collect();
+        mocks.sort_by_key(|script| {
+            let captures = self.url_patterns.get(&script.name).map(Regex::captures_len);
+            (captures.is_none(), captures, script.name.clone())
+        });
+        let script = mocks.into_iter().next()?;
+        self.record_hit(&script.name);
+        Some(script.into_owned())
+    }
+
+    /// `Alert` scripts watching the domain for responses with this status.
+    pub fn alert_scripts(&self, ctx: &RequestContext, status: u16) -> Vec<InjectionScript> {
+        self.scripts_for(ctx)
+            .into_iter()
+            .filter(|script| matches!(script.inject_type, InjectType::Alert) && script.matches_status(status))
+            .map(Cow::into_owned)
+            .collect()
+    }
+
+    /// Compiled rewrite rules for `SseEvent` scripts targeting the domain.
+    pub fn get_sse_rewrites(&self, ctx: &RequestContext, status: u16) -> Vec<(Regex, String)> {
+        self.scripts_for(ctx)
+            .into_iter()
+            .filter(|script| matches!(script.inject_type, InjectType::SseEvent))
+            .filter(|script| script.matches_status(status))
+            .filter_map(|script| {
+                let regex = self.patterns.get(&script.name)?;
+                self.record_hit(&script.name);
+                Some((regex.clone(), script.script_content.clone()))
             })
             .collect()
     }
 
-    fn domain_matches(&self, domain: &str, patterns: &[String]) -> bool {
+    /// Short stable hash of every script that applies to the domain, so cached
+    /// injected content can be told apart from content produced by another script set.
+    pub fn script_set_hash(&self, domain: &str) -> String {
+        let mut scripts = self.get_scripts_for_domain(domain);
+        scripts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut hasher = Sha256::new();
+        for script in scripts {
+            let mut headers: Vec<_> = script.headers.iter().collect();
+            headers.sort();
+            hasher.update(script.name.as_bytes());
+            hasher.update(script.version.as_bytes());
+            hasher.update(format!("{:?}", script.inject_type).as_bytes());
+            hasher.update(script.script_content.as_bytes());
+            hasher.update(script.payload.stamp.as_bytes());
+            hasher.update(script.pattern.as_deref().unwrap_or("").as_bytes());
+            hasher.update(format!("{:?}", script.target_status).as_bytes());
+            let mut namespaces: Vec<_> = script.xml_namespaces.iter().collect();
+            namespaces.sort();
+            hasher.update(format!("{:?}{:?}", script.xml_action, namespaces).as_bytes());
+            hasher.update(format!("{:?}", script.assets).as_bytes());
+            hasher.update(format!("{:?}", headers).as_bytes());
+            hasher.update([0u8]);
+        }
+        hasher.finalize().iter().take(4).map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// How to rewrite a response while streaming it, or `None` when a matching script needs
+    /// the complete body (e.g. `ResponseBody`, which appends when `</body>` is absent).
+    /// Nothing is counted yet: see `inject_head` and `mark_streamed`.
+    pub fn get_stream_rewrites(&self, ctx: &RequestContext, status: u16) -> Option<StreamRewrites> {
+        let mut rewrites = StreamRewrites::default();
+        for script in self.scripts_for(ctx) {
+            if !script.matches_status(status) || self.already_injected(&script, ctx) {
+                continue;
+            }
+            match script.inject_type {
+                InjectType::ResponseReplace => {
+                    // Without a pattern the script needs the whole body
+                    script.pattern.as_ref()?;
+                    if let Some(regex) = self.patterns.get(&script.name) {
+                        rewrites.rules.push((regex.clone(), script.script_content.clone()));
+                        rewrites.replacing.push(script.into_owned());
+                    }
+                }
+                InjectType::JavaScript | InjectType::Css | InjectType::Clock | InjectType::Locale | InjectType::Snapshot => {
+                    rewrites.head.push(script.into_owned())
+                }
+                InjectType::ResponseBody | InjectType::XPathReplace => return None,
+                _ => {}
+            }
+        }
+        Some(rewrites)
+    }
+
+    /// Injects the `head` scripts of `get_stream_rewrites` into the start of a streamed
+    /// page, as they would go into a buffered one.
+    pub fn inject_head(&self, scripts: &[InjectionScript], ctx: &RequestContext, status: u16, html: &str) -> InjectionResult {
+        let scripts: Vec<Cow<'_, InjectionScript>> = scripts.iter().map(Cow::Borrowed).collect();
+        self.run_response_scripts(&scripts, ctx, status, &HashMap::new(), html, true)
+    }
+
+    /// Counts a hit for a script whose rule changed a streamed body, and remembers its
+    /// `once_per` injection.
+    pub fn mark_streamed(&self, script: &InjectionScript, ctx: &RequestContext) {
+        self.record_hit(&script.name);
+        self.mark_injected(script, ctx);
+    }
+
+    /// Applies only `ResponseHeader` scripts; used when the body is streamed.
+    pub fn apply_response_header_injections(&self, ctx: &RequestContext, status: u16, headers: &HashMap<String, String>) -> InjectionResult {
+        let mut result = InjectionResult::default();
+        let mut new_headers = headers.clone();
+        for script in &self.scripts_for(ctx) {
+            if !script.matches_status(status) {
+                continue;
+            }
+            match script.inject_type {
+                InjectType::ResponseHeader => {
+                    self.record_hit(&script.name);
+                    if self.apply_response_script_headers(script, ctx, &mut new_headers) {
+                        result.applied.push(script.name.clone());
+                    }
+                }
+                // Its payload, if any, is injected by the stream rewrites
+                InjectType::Clock if self.apply_script_headers(script, &mut new_headers) => {
+                    result.applied.push(script.name.clone());
+                }
+                _ => {}
+            }
+        }
+        result.diff(headers, new_headers, "", String::new());
+        result
+    }
+
+    /// Headers of the `ResponseHeader` scripts for the domain that answer preflights
+    /// themselves; `None` when no script does. `echo_origin` scripts also allow the
+    /// requested headers.
+    pub fn preflight_headers(&self, ctx: &RequestContext) -> Option<InjectionResult> {
+        let mut result = InjectionResult::default();
+        let mut headers = HashMap::new();
+        for script in &self.scripts_for(ctx) {
+            if matches!(script.inject_type, InjectType::ResponseHeader) && script.answer_preflight {
+                self.record_hit(&script.name);
+                let mut changed = self.apply_response_script_headers(script, ctx, &mut headers);
+                // Echoing scripts allow whatever headers the page asks to send as well
+                let requested = ctx.headers.get("access-control-request-headers").and_then(|v| v.to_str().ok());
+                if let (true, true, Some(requested)) = (script.echo_origin, self.features.cors_injection, requested) {
+                    headers.insert("access-control-allow-headers".to_string(), requested.to_string());
+                    changed = true;
+                }
+                if changed {
+                    result.applied.push(script.name.clone());
+                }
+            }
+        }
+        if result.applied.is_empty() {
+            return None;
+        }
+        result.diff(&HashMap::new(), headers, "", String::new());
+        Some(result)
+    }
+
+    /// Whether `domain` matches any of `patterns`: `*`, an exact name, `*.suffix`, or a regex.
+    pub fn domain_matches(domain: &str, patterns: &[String]) -> bool {
+        Self::matching_domain_pattern(domain, patterns).is_some()
+    }
+
+    /// The first of `patterns` that matches `domain`.
+    fn matching_domain_pattern<'a>(domain: &str, patterns: &'a [String]) -> Option<&'a String> {
         for pattern in patterns {
             if pattern == "*" || pattern == domain {
-                return true;
+                return Some(pattern);
             }
             
-            if pattern.starts_with("*.") {
-                let suffix = &pattern[2..];
-                if domain.ends_with(suffix) {
-                    return true;
-                }
+            if pattern.starts_with("*.") && config::domain_covers(pattern, domain) {
+                return Some(pattern);
             }
             
-            if let Ok(regex) = Regex::new(pattern) {
+            if let Some(regex) = pattern::cached(pattern) {
                 if regex.is_match(domain) {
-                    return true;
+                    return Some(pattern);
                 }
             }
         }
-        false
+        None
     }
 
-    pub fn apply_request_injections(&self, domain: &str, headers: &mut HashMap<String, String>, body: &mut String) -> Result<InjectionResult> {
-        let scripts = self.get_scripts_for_domain(domain);
-        let mut result = InjectionResult {
-            modified: false,
-            headers: None,
-            body: None,
-            javascript: None,
-            css: None,
-        };
-
-        for script in scripts {
-            match script.inject_type {
-                InjectType::Header => {
-                    for (key, value) in &script.headers {
-                        headers.insert(key.clone(), value.clone());
-                        result.modified = true;
-                    }
+    /// Dry-runs the matching of every loaded script against a request, without counting
+    /// hits or marking `once_per` scripts injected: which would run, and why the others
+    /// wouldn't. With `status`, the checks on the upstream's status are settled too.
+    pub fn explain(&self, ctx: &RequestContext, status: Option<u16>) -> Vec<Explanation> {
+        let url = ctx.url.to_string();
+        let mut names: Vec<&String> = self.scripts.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .filter_map(|name| self.scripts.get(name))
+            .map(|script| {
+                let mut checks = vec![match (script.enabled, self.enabled_overrides.get(&script.name)) {
+                    (true, _) => Check::new("enabled", Some(true), "enabled"),
+                    (false, Some(false)) => Check::new("enabled", Some(false), "disabled through the admin API or by a cluster peer"),
+                    (false, _) => Check::new("enabled", Some(false), "disabled in its script file"),
+                }];
+                checks.push(match Self::matching_domain_pattern(&ctx.domain, &script.target_domains) {
+                    Some(pattern) => Check::new("target_domains", Some(true), format!("{} matches {}", pattern, ctx.domain)),
+                    None => Check::new("target_domains", Some(false), format!("none of {:?} matches {}", script.target_domains, ctx.domain)),
+                });
+                if let Some(pattern) = &script.url_pattern {
+                    checks.push(match self.url_patterns.get(&script.name) {
+                        None => Check::new("url_pattern", Some(false), format!("{} does not compile", pattern)),
+                        Some(_) if url.len() > URL_MATCH_LIMIT => Check::new("url_pattern", Some(false), format!("URLs over {} bytes are never matched", URL_MATCH_LIMIT)),
+                        Some(regex) => match regex.captures(&url) {
+                            Some(captures) => {
+                                let groups: Vec<String> = regex
+                                    .capture_names()
+                                    .flatten()
+                                    .map(|group| format!("{}={}", group, captures.name(group).map(|m| m.as_str()).unwrap_or("")))
+                                    .collect();
+                                let detail = if groups.is_empty() { format!("{} matches", pattern) } else { format!("{} matches with {}", pattern, groups.join(", ")) };
+                                Check::new("url_pattern", Some(true), detail)
+                            }
+                            None => Check::new("url_pattern", Some(false), format!("{} does not match {}", pattern, url)),
+                        },
+                    });
                 }
+                self.explain_type(script, ctx, status, &mut checks);
+                Explanation {
+                    script: script.name.clone(),
+                    inject_type: script.inject_type.clone(),
+                    fires: checks.iter().all(|check| check.passed != Some(false)),
+                    checks,
+                }
+            })
+            .collect()
+    }
+
+    /// The checks particular to the script's inject type.
+    fn explain_type(&self, script: &InjectionScript, ctx: &RequestContext, status: Option<u16>, checks: &mut Vec<Check>) {
+        let responds = !matches!(script.inject_type, InjectType::Header | InjectType::Body | InjectType::Jwt | InjectType::Sign | InjectType::Mock);
+        match script.inject_type {
+            InjectType::Mock if !script.methods.is_empty() => {
+                let answered = script.methods.iter().any(|m| m.eq_ignore_ascii_case(ctx.method.as_str()));
+                checks.push(Check::new("methods", Some(answered), format!("{} {} {:?}", ctx.method, if answered { "is in" } else { "is not in" }, script.methods)));
+            }
+            InjectType::Jwt => {
+                let token = ctx.headers.get("authorization").and_then(|v| v.to_str().ok()).and_then(|v| v.split_once(' ')).filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"));
+                checks.push(match token {
+                    Some((_, token)) if jwt::Token::decode(token).is_some() => Check::new("bearer token", Some(true), "the request carries a JWT bearer token"),
+                    Some(_) => Check::new("bearer token", Some(false), "the bearer token is not a JWT"),
+                    None => Check::new("bearer token", Some(false), "the request has no bearer token"),
+                });
+            }
+            InjectType::Sign => checks.push(match script.credential.as_deref() {
+                Some(name) if self.credentials.contains_key(name) => Check::new("credential", Some(true), format!("signs with {}", name)),
+                Some(name) => Check::new("credential", Some(false), format!("{} is not in [credentials], or its secret can't be read", name)),
+                None => Check::new("credential", Some(false), "it names no credential"),
+            }),
+            InjectType::ResponseHeader if script.answer_preflight && ctx.method == Method::OPTIONS && ctx.headers.contains_key("access-control-request-method") => {
+                checks.push(Check::new("preflight", Some(true), "answers this CORS preflight itself; it is not sent upstream"));
+            }
+            InjectType::XPathReplace => checks.push(Check::new("content type", None, "runs only on XML responses")),
+            InjectType::ResponseReplace => checks.push(match self.patterns.get(&script.name) {
+                Some(_) => Check::new("pattern", None, "runs only when its pattern matches the response body"),
+                None => Check::new("pattern", Some(false), "its pattern is missing or does not compile, so it never runs"),
+            }),
+            _ => {}
+        }
+        if responds && !script.target_status.is_empty() {
+            checks.push(match status {
+                Some(status) => Check::new("target_status", Some(script.matches_status(status)), format!("{} {} {:?}", status, if script.matches_status(status) { "is in" } else { "is not in" }, script.target_status)),
+                None => Check::new("target_status", None, format!("only responses with status {:?}", script.target_status)),
+            });
+        }
+        if let Some(once_per) = script.once_per {
+            let done = self.already_injected(script, ctx);
+            let scope = match once_per {
+                OncePer::Session => "this session",
+                OncePer::Client => "this client",
+                OncePer::Url => "this client and URL",
+            };
+            checks.push(Check::new("once_per", Some(!done), format!("{} injected for {}", if done { "already" } else { "not yet" }, scope)));
+        }
+    }
+
+    pub fn apply_request_injections(&self, ctx: &RequestContext, headers: &HashMap<String, String>, body: &str) -> Result<InjectionResult> {
+        let scripts = self.scripts_for(ctx);
+        let mut result = InjectionResult::default();
+        let mut new_headers = headers.clone();
+        let mut new_body = body.to_string();
+
+        for script in &scripts {
+            let changed = match script.inject_type {
+                InjectType::Header | InjectType::Locale => self.apply_script_headers(script, &mut new_headers),
+                InjectType::Jwt => self.apply_jwt(script, ctx, &mut new_headers),
                 InjectType::Body => {
-                    if !script.script_content.is_empty() {
-                        body.push_str(&script.script_content);
-                        result.modified = true;
+                    if script.script_content.is_empty() {
+                        false
+                    } else {
+                        new_body.push_str(&script.script_content);
+                        result.snippets.push((script.name.clone(), script.script_content.clone()));
+                        true
                     }
                 }
-                InjectType::JavaScript => {
-                    result.javascript = Some(script.script_content.clone());
-                    result.modified = true;
+                _ => continue, // Response injections handled separately
+            };
+
+            self.record_hit(&script.name);
+            if changed {
+                result.applied.push(script.name.clone());
+            }
+            debug!("Applied script: {} for domain: {}", script.name, ctx.domain);
+        }
+
+        result.diff(headers, new_headers, body, new_body);
+        Ok(result)
+    }
+
+    /// Whether any `Sign` script matches the request.
+    pub fn signs(&self, ctx: &RequestContext) -> bool {
+        self.scripts_for(ctx).iter().any(|script| matches!(script.inject_type, InjectType::Sign))
+    }
+
+    /// Runs the `Sign` scripts matching the request. Scripts are matched on the request
+    /// the client sent, but what they sign is what goes upstream: `method`, `uri`,
+    /// `headers` and `body` after every other script, `[[hosts]]` and the Forwarded
+    /// headers have had their say.
+    pub fn sign_request(&self, ctx: &RequestContext, method: &Method, uri: &Uri, headers: &HashMap<String, String>, body: &[u8]) -> InjectionResult {
+        let mut result = InjectionResult::default();
+        let mut new_headers = headers.clone();
+        for script in self.scripts_for(ctx).iter().filter(|script| matches!(script.inject_type, InjectType::Sign)) {
+            self.record_hit(&script.name);
+            let Some(credential) = script.credential.as_deref().and_then(|name| self.credentials.get(name)) else {
+                warn!("Script {} left {} unsigned: credential {:?} is not loaded", script.name, ctx.url, script.credential);
+                continue;
+            };
+            let request = signing::Outgoing { method: method.as_str(), url: uri, headers: &mut new_headers, body };
+            match script.sign_scheme.unwrap_or_default() {
+                SignScheme::AwsSigV4 => {
+                    if let Err(e) = signing::aws_sigv4(credential, script.aws_region.as_deref(), script.aws_service.as_deref(), request, SystemTime::now()) {
+                        warn!("Script {} left {} unsigned: {}", script.name, uri, e);
+                        continue;
+                    }
                 }
-                InjectType::CSS => {
-                    result.css = Some(script.script_content.clone());
-                    result.modified = true;
+                SignScheme::Hmac => {
+                    signing::hmac_sign(credential, script.hmac_algorithm.unwrap_or_default(), script.string_to_sign.as_deref(), &script.headers, request, SystemTime::now());
                 }
-                _ => {} // Response injections handled separately
             }
-            
-            debug!("Applied script: {} for domain: {}", script.name, domain);
+            result.applied.push(script.name.clone());
+            debug!("Signed {} with {}", uri, script.name);
         }
+        result.diff(headers, new_headers, "", String::new());
+        result
+    }
 
-        Ok(result)
+    pub fn apply_response_injections(&self, ctx: &RequestContext, status: u16, headers: &HashMap<String, String>, body: &str) -> Result<InjectionResult> {
+        let scripts = self.scripts_for(ctx);
+        Ok(self.run_response_scripts(&scripts, ctx, status, headers, body, true))
     }
 
-    pub fn apply_response_injections(&self, domain: &str, headers: &mut HashMap<String, String>, body: &mut String) -> Result<InjectionResult> {
-        let scripts = self.get_scripts_for_domain(domain);
-        let mut result = InjectionResult {
-            modified: false,
-            headers: None,
-            body: None,
-            javascript: None,
-            css: None,
+    /// Runs the script `name` alone on a response, as a dry run: no hit is counted and no
+    /// `once_per` injection remembered. It runs whether or not it is enabled or targets
+    /// the request; `explain` tells whether it would.
+    pub fn try_response_script(&self, name: &str, ctx: &RequestContext, status: u16, headers: &HashMap<String, String>, body: &str) -> Result<InjectionResult> {
+        let script = self.scripts.get(name).ok_or_else(|| anyhow!("No script {}", name))?;
+        if matches!(script.inject_type, InjectType::Header | InjectType::Body | InjectType::Jwt | InjectType::Sign | InjectType::Mock) {
+            bail!("{} is a {:?} script; it runs on requests, not responses", name, script.inject_type);
+        }
+        let script = match self.scripts_for(ctx).into_iter().find(|matched| matched.name == name) {
+            Some(script) => script,
+            None if matches!(script.inject_type, InjectType::Clock | InjectType::Locale | InjectType::Snapshot) => self
+                .built_in(script, &ctx.url.to_string())
+                .map(Cow::Owned)
+                .ok_or_else(|| anyhow!("The url_pattern of {} does not match {}", name, ctx.url))?,
+            None => self.with_payload(script),
         };
+        Ok(self.run_response_scripts(&[script], ctx, status, headers, body, false))
+    }
+
+    /// One pass of response scripts; `live` passes count a hit for each script that
+    /// changed something and remember its `once_per` injection.
+    fn run_response_scripts(&self, scripts: &[Cow<'_, InjectionScript>], ctx: &RequestContext, status: u16, headers: &HashMap<String, String>, body: &str, live: bool) -> InjectionResult {
+        let mut result = InjectionResult::default();
+        let mut new_headers = headers.clone();
+        let mut new_body = body.to_string();
 
         for script in scripts {
-            match script.inject_type {
+            if !script.matches_status(status) || (live && self.already_injected(script, ctx)) {
+                continue;
+            }
+            let grown = new_body.len().saturating_sub(body.len());
+            let body = &mut new_body;
+            let snippet = match script.inject_type {
                 InjectType::ResponseHeader => {
-                    for (key, value) in &script.headers {
-                        headers.insert(key.clone(), value.clone());
-                        result.modified = true;
+                    if self.apply_response_script_headers(script, ctx, &mut new_headers) {
+                        result.applied.push(script.name.clone());
+                        if live {
+                            self.record_hit(&script.name);
+                            self.mark_injected(script, ctx);
+                        }
                     }
+                    continue;
                 }
                 InjectType::ResponseBody => {
-                    if !script.script_content.is_empty() {
+                    if script.script_content.is_empty() {
+                        None
+                    } else if body.contains(&script.marker()) {
+                        debug!("Skipping {}: page already carries its payload", script.name);
+                        None
+                    } else if !self.within_growth(script, ctx, script.marker().len() + script.script_content.len(), grown) {
+                        None
+                    } else {
                         // Inject before closing body tag if HTML
-                        if body.contains("</body>") {
-                            *body = body.replace("</body>", &format!("{}</body>", script.script_content));
+                        let snippet = format!("{}{}", script.marker(), script.script_content);
+                        if html::insert_before_body_end(body, &snippet) {
+                            Some(snippet)
                         } else {
                             body.push_str(&script.script_content);
+                            Some(script.script_content.clone())
                         }
-                        result.modified = true;
                     }
                 }
-                InjectType::JavaScript => {
-                    if body.contains("</head>") {
-                        let js_injection = format!("<script>{}</script>", script.script_content);
-                        *body = body.replace("</head>", &format!("{}</head>", js_injection));
-                        result.modified = true;
+                InjectType::JavaScript | InjectType::Css | InjectType::Clock | InjectType::Locale | InjectType::Snapshot => {
+                    // A Clock's `Date` header goes on every response, HTML or not
+                    let dated = matches!(script.inject_type, InjectType::Clock) && self.apply_script_headers(script, &mut new_headers);
+                    let snippet = if body.contains(&script.marker()) {
+                        debug!("Skipping {}: page already carries its payload", script.name);
+                        None
+                    } else {
+                        let snippet = script.html_payload();
+                        if self.within_growth(script, ctx, snippet.len(), grown) {
+                            html::insert_in_head(body, &snippet).then_some(snippet)
+                        } else {
+                            None
+                        }
+                    };
+                    if dated && snippet.is_none() {
+                        result.applied.push(script.name.clone());
+                        if live {
+                            self.record_hit(&script.name);
+                        }
                     }
+                    snippet
                 }
-                InjectType::CSS => {
-                    if body.contains("</head>") {
-                        let css_injection = format!("<style>{}</style>", script.script_content);
-                        *body = body.replace("</head>", &format!("{}</head>", css_injection));
-                        result.modified = true;
+                InjectType::ResponseReplace => match self.patterns.get(&script.name) {
+                    Some(regex) if regex.is_match(body) => {
+                        let deadline = Instant::now() + self.time_budget;
+                        let max_replacements = script.max_replacements.unwrap_or(self.max_replacements);
+                        match pattern::replace_all(regex, body, &script.script_content, deadline, max_replacements) {
+                            Ok(replaced) if self.within_growth(script, ctx, replaced.len().saturating_sub(body.len()), grown) => {
+                                *body = replaced;
+                                Some(script.script_content.clone())
+                            }
+                            Ok(_) => None,
+                            Err(pattern::Stop::Deadline) => {
+                                warn!(
+                                    "Script {} left {} unchanged: its replacements ran past max_execution_time",
+                                    script.name, ctx.url
+                                );
+                                None
+                            }
+                            Err(pattern::Stop::Replacements) => {
+                                warn!(
+                                    "Script {} left {} unchanged: its pattern matched more than max_replacements ({}) times",
+                                    script.name, ctx.url, max_replacements
+                                );
+                                None
+                            }
+                        }
+                    }
+                    _ => None,
+                },
+                InjectType::XPathReplace => {
+                    let is_xml = new_headers.get("content-type").map(|ct| ct.to_lowercase().contains("xml")).unwrap_or(false);
+                    match (is_xml, &script.pattern) {
+                        (true, Some(xpath)) => {
+                            let action = script.xml_action.unwrap_or_default();
+                            match xml::apply(body, xpath, &script.script_content, action, &script.xml_namespaces) {
+                                Ok(Some(rewritten)) if self.within_growth(script, ctx, rewritten.len().saturating_sub(body.len()), grown) => {
+                                    *body = rewritten;
+                                    Some(script.script_content.clone())
+                                }
+                                Ok(Some(_)) => None,
+                                Ok(None) => {
+                                    debug!("XPath of script {} matched nothing", script.name);
+                                    None
+                                }
+                                Err(e) => {
+                                    warn!("Failed to apply script {}: {}", script.name, e);
+                                    None
+                                }
+                            }
+                        }
+                        _ => None,
                     }
                 }
-                _ => {} // Request injections handled separately
+                _ => None, // Request injections handled separately
+            };
+            if let Some(snippet) = snippet {
+                result.applied.push(script.name.clone());
+                result.snippets.push((script.name.clone(), snippet));
+                if live {
+                    self.record_hit(&script.name);
+                    self.mark_injected(script, ctx);
+                }
             }
         }
 
-        Ok(result)
+        result.diff(headers, new_headers, body, new_body);
+        result
     }
 
-    fn create_example_scripts(&self) -> Result<()> {
-        let examples = vec![
-            InjectionScript {
-                name: "custom-headers".to_string(),
-                description: "Inject custom headers for debugging".to_string(),
-                version: "1.0.0".to_string(),
-                author: "Rusty Proxy".to_string(),
-                target_domains: vec!["*.example.com".to_string()],
-                inject_type: InjectType::Header,
-                script_content: String::new(),
-                headers: {
-                    let mut headers = HashMap::new();
-                    headers.insert("X-Debug".to_string(), "true".to_string());
-                    headers.insert("X-Proxy".to_string(), "rusty-proxy".to_string());
-                    headers
-                },
-                enabled: false,
+    /// Writes the example scripts, unless the directory already holds scripts: next to
+    /// existing ones they'd only get in the way. Examples already on disk are kept.
+    pub fn create_example_scripts(&self) -> Result<()> {
+        if !self.scripts.is_empty() {
+            return Ok(());
+        }
+        for script in example_scripts() {
+            let script_path = self.scripts_dir.join(format!("{}.json", script.name));
+            if !script_path.exists() {
+                let script_json = serde_json::to_string_pretty(&script)?;
+                fs::write(script_path, script_json)?;
+                info!("Created example script: {}", script.name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The scripts written into a new scripts directory, and by `script new --example`.
+pub fn example_scripts() -> Vec<InjectionScript> {
+    vec![
+        InjectionScript {
+            name: "custom-headers".to_string(),
+            description: "Inject custom headers for debugging".to_string(),
+            version: "1.0.0".to_string(),
+            author: "Rusty Proxy".to_string(),
+            target_domains: vec!["*.example.com".to_string()],
+            inject_type: InjectType::Header,
+            script_content: String::new(),
+            headers: {
+                let mut headers = HashMap::new();
+                headers.insert("X-Debug".to_string(), "true".to_string());
+                headers.insert("X-Proxy".to_string(), "rusty-proxy".to_string());
+                headers
             },
-            InjectionScript {
-                name: "debug-console".to_string(),
-                description: "Inject debug console for web debugging".to_string(),
-                version: "1.0.0".to_string(),
-                author: "Rusty Proxy".to_string(),
-                target_domains: vec!["*".to_string()],
-                inject_type: InjectType::JavaScript,
-                script_content: r#"
+            enabled: false,
+            ..Default::default()
+        },
+        InjectionScript {
+            name: "debug-console".to_string(),
+            description: "Inject debug console for web debugging".to_string(),
+            version: "1.0.0".to_string(),
+            author: "Rusty Proxy".to_string(),
+            target_domains: vec!["*".to_string()],
+            inject_type: InjectType::JavaScript,
+            script_content: r#"
 console.log('Rusty Proxy Debug Console Loaded');
 window.rustyProxy = {
     version: '0.1.0',
@@ -261,37 +1696,76 @@ window.rustyProxy = {
     }
 };
 "#.to_string(),
-                headers: HashMap::new(),
-                enabled: false,
-            },
-            InjectionScript {
-                name: "cors-bypass".to_string(),
-                description: "Add CORS headers to responses".to_string(),
-                version: "1.0.0".to_string(),
-                author: "Rusty Proxy".to_string(),
-                target_domains: vec!["*".to_string()],
-                inject_type: InjectType::ResponseHeader,
-                script_content: String::new(),
-                headers: {
-                    let mut headers = HashMap::new();
-                    headers.insert("Access-Control-Allow-Origin".to_string(), "*".to_string());
-                    headers.insert("Access-Control-Allow-Methods".to_string(), "GET, POST, PUT, DELETE, OPTIONS".to_string());
-                    headers.insert("Access-Control-Allow-Headers".to_string(), "Content-Type, Authorization".to_string());
-                    headers
-                },
-                enabled: false,
+            headers: HashMap::new(),
+            enabled: false,
+            ..Default::default()
+        },
+        InjectionScript {
+            name: "cors-bypass".to_string(),
+            description: "Add CORS headers to responses".to_string(),
+            version: "1.0.0".to_string(),
+            author: "Rusty Proxy".to_string(),
+            target_domains: vec!["*".to_string()],
+            inject_type: InjectType::ResponseHeader,
+            script_content: String::new(),
+            headers: {
+                let mut headers = HashMap::new();
+                headers.insert("Access-Control-Allow-Origin".to_string(), "*".to_string());
+                headers.insert("Access-Control-Allow-Methods".to_string(), "GET, POST, PUT, DELETE, OPTIONS".to_string());
+                headers.insert("Access-Control-Allow-Headers".to_string(), "Content-Type, Authorization".to_string());
+                headers
             },
-        ];
+            enabled: false,
+            answer_preflight: true,
+            echo_origin: true,
+            ..Default::default()
+        },
+        InjectionScript {
+            name: "locale-spoof".to_string(),
+            description: "Browse as a visitor from Montreal: French locale, Toronto time zone and position".to_string(),
+            version: "1.0.0".to_string(),
+            author: "Rusty Proxy".to_string(),
+            target_domains: vec!["*.example.com".to_string()],
+            inject_type: InjectType::Locale,
+            script_content: String::new(),
+            headers: HashMap::new(),
+            enabled: false,
+            locale: Some("fr-CA".to_string()),
+            timezone: Some("America/Toronto".to_string()),
+            geolocation: Some(Geolocation {
+                latitude: 45.5019,
+                longitude: -73.5674,
+                accuracy: 50.0,
+            }),
+            ..Default::default()
+        },
+    ]
+}
 
-        for script in examples {
-            let script_path = self.scripts_dir.join(format!("{}.json", script.name));
-            if !script_path.exists() {
-                let script_json = serde_json::to_string_pretty(&script)?;
-                fs::write(script_path, script_json)?;
-                info!("Created example script: {}", script.name);
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    #[test]
+    fn request_placeholders_are_filled_and_escaped() {
+        let mut ctx = RequestContext::for_request(Method::POST, "http://shop.example.com/cart?q=%22x%22");
+        ctx.headers.insert("x-team", "<ops>".parse().unwrap());
+        ctx.matched_scripts = vec!["a".to_string(), "b".to_string()];
+        let script = InjectionScript {
+            script_content: "{{request.method}} {{request.url}} {{request.client_ip}} {{request.matched_scripts}} {{request.header:x-team}} {{request.unknown}}".to_string(),
+            headers: HashMap::from([("x-seen-after".to_string(), "{{request.elapsed_ms}}ms".to_string())]),
+            ..Default::default()
+        };
+        let filled = fill_request(Cow::Borrowed(&script), &ctx);
+        assert_eq!(filled.script_content, "POST http://shop.example.com/cart?q=%22x%22 127.0.0.1 a,b %3Cops%3E {{request.unknown}}");
+        let elapsed = filled.headers["x-seen-after"].strip_suffix("ms").unwrap();
+        assert!(elapsed.parse::<u64>().is_ok(), "{}", elapsed);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn scripts_without_request_placeholders_stay_borrowed() {
+        let script = InjectionScript { script_content: "{{url:id}} {{var:HOST}}".to_string(), ..Default::default() };
+        let ctx = RequestContext::for_request(Method::GET, "http://shop.example.com/");
+        assert!(matches!(fill_request(Cow::Borrowed(&script), &ctx), Cow::Borrowed(_)));
+    }
+}
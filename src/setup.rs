@@ -0,0 +1,137 @@
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::script_manager::ScriptManager;
+
+/// Answers for `rusty-proxy init`; anything not given as a flag is asked for when
+/// running interactively.
+pub struct InitOptions {
+    pub config_path: String,
+    pub scripts_dir: Option<String>,
+    pub port: Option<u16>,
+    pub bind_address: Option<String>,
+    pub require_auth: Option<bool>,
+    /// `--no-examples`: written to the config as `scripts.create_examples = false`
+    pub no_examples: bool,
+    pub interactive: bool,
+    pub force: bool,
+}
+
+pub fn run(opts: InitOptions) -> Result<()> {
+    if Path::new(&opts.config_path).exists() && !opts.force {
+        return Err(anyhow!("{} already exists (use --force to overwrite)", opts.config_path));
+    }
+
+    let mut prompt = Prompt::new(opts.interactive);
+    let mut config = Config::default();
+
+    config.proxy.port = match opts.port {
+        Some(port) => port,
+        None => prompt.ask("Proxy port", &config.proxy.port.to_string())?.parse()?,
+    };
+    config.proxy.bind_address = match opts.bind_address {
+        Some(addr) => addr,
+        None => prompt.ask("Listen address", &config.proxy.bind_address)?,
+    };
+    if config.proxy.bind_address != "127.0.0.1" && config.proxy.bind_address != "::1" {
+        config.features.open_ports = true;
+    }
+    config.scripts.directory = match opts.scripts_dir {
+        Some(dir) => dir,
+        None => prompt.ask("Scripts directory", &config.scripts.directory)?,
+    };
+
+    let require_auth = match opts.require_auth {
+        Some(value) => value,
+        None => prompt.confirm("Require proxy authentication", config.features.open_ports)?,
+    };
+    if require_auth {
+        config.security.require_auth = true;
+        config.security.auth_token = Some(generate_token());
+    }
+
+    if opts.no_examples {
+        config.scripts.create_examples = false;
+    }
+
+    config.save(&opts.config_path)?;
+    println!("Wrote {}", opts.config_path);
+
+    let script_manager = ScriptManager::new(&config.scripts.directory)?;
+    if config.scripts.create_examples {
+        script_manager.create_example_scripts()?;
+    }
+    println!("Scripts directory ready at {}", config.scripts.directory);
+
+    print_instructions(&config, &opts.config_path);
+    Ok(())
+}
+
+fn generate_token() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn print_instructions(config: &Config, config_path: &str) {
+    let host = match config.proxy.bind_address.as_str() {
+        "0.0.0.0" | "::" => "<this-host>",
+        addr => addr,
+    };
+    let proxy_url = format!("http://{}:{}", host, config.proxy.port);
+
+    println!();
+    println!("Start the proxy:");
+    println!("  rusty-proxy -c {} -p {} -s {} start", config_path, config.proxy.port, config.scripts.directory);
+    println!();
+    println!("Point clients at {}:", proxy_url);
+    println!("  Shell:   export http_proxy={} https_proxy={}", proxy_url, proxy_url);
+    println!("  Firefox: Settings > Network Settings > Manual proxy configuration");
+    println!("  Chrome:  uses the OS proxy settings, or --proxy-server={}", proxy_url);
+    println!("  macOS:   System Settings > Network > Details > Proxies > Web Proxy (HTTP)");
+    println!("  Windows: Settings > Network & Internet > Proxy > Manual proxy setup");
+    println!("  GNOME:   gsettings set org.gnome.system.proxy mode manual");
+    if let Some(token) = &config.security.auth_token {
+        println!();
+        println!("Proxy authentication is on; use any username with password {}", token);
+    }
+}
+
+/// Reads answers from stdin, or returns the defaults when not interactive.
+struct Prompt {
+    interactive: bool,
+    stdin: io::StdinLock<'static>,
+}
+
+impl Prompt {
+    fn new(interactive: bool) -> Self {
+        Prompt {
+            interactive,
+            stdin: io::stdin().lock(),
+        }
+    }
+
+    fn ask(&mut self, question: &str, default: &str) -> Result<String> {
+        if !self.interactive {
+            return Ok(default.to_string());
+        }
+        print!("{} [{}]: ", question, default);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        self.stdin.read_line(&mut line)?;
+        let answer = line.trim();
+        Ok(if answer.is_empty() { default.to_string() } else { answer.to_string() })
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        let answer = self.ask(question, hint)?;
+        Ok(match answer.to_lowercase().as_str() {
+            "y" | "yes" => true,
+            "n" | "no" => false,
+            _ => default,
+        })
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::Uri;
+use regex::Regex;
+use std::sync::OnceLock;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::assets::percent_decode;
+use crate::capture::warc_date;
+use crate::config::CredentialConfig;
+
+/// How a `Sign` script signs its requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SignScheme {
+    /// AWS Signature Version 4, as the AWS SDKs sign
+    #[default]
+    #[serde(rename = "aws-sigv4")]
+    AwsSigV4,
+    /// An HMAC of `string_to_sign`, sent in the script's `headers`
+    #[serde(rename = "hmac")]
+    Hmac,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HmacAlgorithm {
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl HmacAlgorithm {
+    fn ring(self) -> hmac::Algorithm {
+        match self {
+            HmacAlgorithm::Sha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            HmacAlgorithm::Sha256 => hmac::HMAC_SHA256,
+            HmacAlgorithm::Sha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// What an `hmac` script signs when it sets no `string_to_sign`.
+const DEFAULT_STRING_TO_SIGN: &str = "{{method}}\n{{path}}\n{{timestamp}}\n{{body_sha256}}";
+
+/// Headers an `hmac` script sends when it sets no `headers`.
+const DEFAULT_HMAC_HEADERS: [(&str, &str); 2] = [("authorization", "HMAC {{key_id}}:{{signature}}"), ("x-timestamp", "{{timestamp}}")];
+
+/// A `[credentials]` entry with its secret read. Never logged.
+#[derive(Clone)]
+pub struct Credential {
+    pub key_id: String,
+    secret: String,
+    session_token: Option<String>,
+}
+
+/// Reads the secret of each `[credentials]` entry. An entry whose secret can't be read is
+/// left out with a warning, so the scripts naming it sign nothing.
+pub fn load(configs: &HashMap<String, CredentialConfig>) -> HashMap<String, Credential> {
+    let mut credentials = HashMap::new();
+    for (name, config) in configs {
+        match read_secret(config) {
+            Ok(secret) => {
+                credentials.insert(
+                    name.clone(),
+                    Credential {
+                        key_id: config.key_id.clone(),
+                        secret,
+                        session_token: config.session_token.clone().filter(|token| !token.is_empty()),
+                    },
+                );
+            }
+            Err(e) => warn!("Credential {} is unusable, scripts signing with it will not: {}", name, e),
+        }
+    }
+    if !credentials.is_empty() {
+        info!("Loaded {} signing credentials", credentials.len());
+    }
+    credentials
+}
+
+fn read_secret(config: &CredentialConfig) -> Result<String> {
+    if let Some(secret) = config.secret.as_deref().filter(|secret| !secret.is_empty()) {
+        return Ok(secret.to_string());
+    }
+    let secret = if let Some(path) = config.secret_file.as_deref().filter(|path| !path.is_empty()) {
+        fs::read_to_string(path).map_err(|e| anyhow!("can't read secret_file {}: {}", path, e))?
+    } else if let Some(command) = config.secret_command.as_deref().filter(|command| !command.is_empty()) {
+        let output = Command::new("sh").arg("-c").arg(command).output().map_err(|e| anyhow!("can't run secret_command: {}", e))?;
+        if !output.status.success() {
+            bail!("secret_command exited with {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        String::from_utf8(output.stdout).map_err(|_| anyhow!("secret_command printed something other than UTF-8"))?
+    } else {
+        bail!("it has no secret, secret_file or secret_command");
+    };
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        bail!("its secret is empty");
+    }
+    Ok(secret.to_string())
+}
+
+/// A request about to go upstream, every other script applied: what a signature covers.
+/// Headers are by lowercase name, and get the signature added.
+pub struct Outgoing<'a> {
+    pub method: &'a str,
+    pub url: &'a Uri,
+    pub headers: &'a mut HashMap<String, String>,
+    pub body: &'a [u8],
+}
+
+impl Outgoing<'_> {
+    /// The `Host` sent upstream, falling back to the URL's.
+    fn host(&self) -> Option<String> {
+        self.headers.get("host").cloned().or_else(|| self.url.authority().map(|authority| authority.to_string()))
+    }
+}
+
+/// The canonical parts of a request AWS Signature V4 signs.
+pub struct SigV4<'a> {
+    pub method: &'a str,
+    /// Path and query, URI-encoded as the service expects (see `uri_encode`); the query
+    /// pairs sorted
+    pub path: &'a str,
+    pub query: &'a str,
+    /// Signed headers: lowercase names, sorted, values trimmed
+    pub headers: &'a [(String, String)],
+    pub payload_hash: &'a str,
+    /// `20260131T120000Z`
+    pub stamp: &'a str,
+    pub region: &'a str,
+    pub service: &'a str,
+}
+
+impl SigV4<'_> {
+    fn signed_headers(&self) -> String {
+        self.headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";")
+    }
+
+    /// The canonical request, whose hash is signed.
+    pub fn canonical_request(&self) -> String {
+        let canonical_headers: String = self.headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        format!("{}\n{}\n{}\n{}\n{}\n{}", self.method, self.path, self.query, canonical_headers, self.signed_headers(), self.payload_hash)
+    }
+
+    /// The `Authorization` header value for the request.
+    pub fn authorization(&self, access_key: &str, secret_key: &str) -> String {
+        let date = &self.stamp[..8];
+        let signed_headers = self.signed_headers();
+        let canonical_request = self.canonical_request();
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", self.stamp, scope, hex(&Sha256::digest(canonical_request.as_bytes())));
+        let mut key_bytes = format!("AWS4{}", secret_key).into_bytes();
+        for part in [date, self.region, self.service, "aws4_request"] {
+            key_bytes = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key_bytes), part.as_bytes()).as_ref().to_vec();
+        }
+        let signature = hex(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key_bytes), string_to_sign.as_bytes()).as_ref());
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", access_key, scope, signed_headers, signature)
+    }
+}
+
+/// Signs `request` with AWS Signature V4 for `region` and `service`, or those of its
+/// `<service>.<region>.amazonaws.com` host when unset. The `Host`, `Content-Type` and
+/// `X-Amz-*` headers are signed.
+pub fn aws_sigv4(credential: &Credential, region: Option<&str>, service: Option<&str>, request: Outgoing, now: SystemTime) -> Result<()> {
+    let host = request.host().ok_or_else(|| anyhow!("the request has no host"))?;
+    let (region, service) = match (region, service, aws_scope(&host)) {
+        (Some(region), Some(service), _) => (region.to_string(), service.to_string()),
+        (region, service, Some((host_region, host_service))) => (region.map(str::to_string).unwrap_or(host_region), service.map(str::to_string).unwrap_or(host_service)),
+        _ => bail!("{} is not an AWS endpoint, so the script needs aws_region and aws_service", host),
+    };
+
+    let payload_hash = hex(&Sha256::digest(request.body));
+    let stamp = warc_date(now).replace(['-', ':'], "");
+    let headers = request.headers;
+    headers.remove("authorization");
+    headers.insert("host".to_string(), host);
+    headers.insert("x-amz-date".to_string(), stamp.clone());
+    // S3 refuses requests without it; the other services don't want it
+    if service == "s3" {
+        headers.insert("x-amz-content-sha256".to_string(), payload_hash.clone());
+    }
+    if let Some(token) = &credential.session_token {
+        headers.insert("x-amz-security-token".to_string(), token.clone());
+    }
+    let mut signed: Vec<(String, String)> = headers
+        .iter()
+        .filter(|(name, _)| *name == "host" || *name == "content-type" || name.starts_with("x-amz-"))
+        .map(|(name, value)| (name.clone(), value.split_whitespace().collect::<Vec<_>>().join(" ")))
+        .collect();
+    signed.sort();
+
+    // S3 signs the path as sent; the other services encode it once more
+    let path = match request.url.path() {
+        "" => "/",
+        path => path,
+    };
+    let path = if service == "s3" { uri_encode(&percent_decode(path).unwrap_or_else(|| path.to_string()), false) } else { uri_encode(path, false) };
+    let mut query: Vec<(String, String)> = request
+        .url
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let encode = |part: &str| uri_encode(&percent_decode(&part.replace('+', "%20")).unwrap_or_else(|| part.to_string()), true);
+            (encode(name), encode(value))
+        })
+        .collect();
+    query.sort();
+    let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+
+    let authorization = SigV4 {
+        method: request.method,
+        path: &path,
+        query: &query,
+        headers: &signed,
+        payload_hash: &payload_hash,
+        stamp: &stamp,
+        region: &region,
+        service: &service,
+    }
+    .authorization(&credential.key_id, &credential.secret);
+    headers.insert("authorization".to_string(), authorization);
+    Ok(())
+}
+
+/// `(region, service)` of an AWS endpoint: `bucket.s3.eu-west-1.amazonaws.com` is
+/// `eu-west-1` and `s3`; global endpoints such as `iam.amazonaws.com` sign for `us-east-1`.
+fn aws_scope(host: &str) -> Option<(String, String)> {
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let host = host.to_ascii_lowercase();
+    let labels: Vec<&str> = host.strip_suffix(".amazonaws.com")?.split('.').collect();
+    match labels.as_slice() {
+        [.., service, region] if region.contains('-') => Some((region.to_string(), service.to_string())),
+        [.., service] => Some(("us-east-1".to_string(), service.to_string())),
+        [] => None,
+    }
+}
+
+/// Signs `request` with an HMAC of `string_to_sign` under the credential's secret, then
+/// sets `headers` (name to template). Both templates take `{{method}}`, `{{path}}`,
+/// `{{query}}`, `{{host}}`, `{{timestamp}}` (Unix seconds), `{{date}}` (HTTP date),
+/// `{{iso_date}}`, `{{body_sha256}}` (hex), `{{body_sha256_base64}}`, `{{nonce}}`,
+/// `{{key_id}}` and `{{header:NAME}}`; the headers also `{{signature}}` (base64) and
+/// `{{signature_hex}}`.
+pub fn hmac_sign(credential: &Credential, algorithm: HmacAlgorithm, string_to_sign: Option<&str>, headers: &HashMap<String, String>, request: Outgoing, now: SystemTime) {
+    let digest = Sha256::digest(request.body);
+    let mut fields = HashMap::from([
+        ("method", request.method.to_string()),
+        ("path", request.url.path().to_string()),
+        ("query", request.url.query().unwrap_or("").to_string()),
+        ("host", request.host().unwrap_or_default()),
+        ("timestamp", now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0).to_string()),
+        ("date", httpdate::fmt_http_date(now)),
+        ("iso_date", warc_date(now)),
+        ("body_sha256", hex(&digest)),
+        ("body_sha256_base64", STANDARD.encode(digest)),
+        ("nonce", uuid::Uuid::new_v4().simple().to_string()),
+        ("key_id", credential.key_id.clone()),
+    ]);
+
+    let signed = fill(string_to_sign.unwrap_or(DEFAULT_STRING_TO_SIGN), &fields, request.headers);
+    let signature = hmac::sign(&hmac::Key::new(algorithm.ring(), credential.secret.as_bytes()), signed.as_bytes());
+    fields.insert("signature", STANDARD.encode(signature.as_ref()));
+    fields.insert("signature_hex", hex(signature.as_ref()));
+
+    let templates: Vec<(String, String)> = if headers.is_empty() {
+        DEFAULT_HMAC_HEADERS.iter().map(|(name, template)| (name.to_string(), template.to_string())).collect()
+    } else {
+        headers.iter().map(|(name, template)| (name.to_ascii_lowercase(), template.clone())).collect()
+    };
+    let values: Vec<(String, String)> = templates.into_iter().map(|(name, template)| (name, fill(&template, &fields, request.headers))).collect();
+    request.headers.extend(values);
+}
+
+/// Replaces the `{{...}}` fields of `template`; unknown ones are left in place.
+fn fill(template: &str, fields: &HashMap<&str, String>, headers: &HashMap<String, String>) -> String {
+    static PLACEHOLDER: OnceLock<Regex> = OnceLock::new();
+    let placeholder = PLACEHOLDER.get_or_init(|| Regex::new(r"\{\{(header:[^}]+|[a-z0-9_]+)\}\}").unwrap());
+    placeholder
+        .replace_all(template, |captures: &regex::Captures| match captures[1].strip_prefix("header:") {
+            Some(name) => headers.get(&name.to_ascii_lowercase()).cloned().unwrap_or_default(),
+            None => fields.get(&captures[1]).cloned().unwrap_or_else(|| captures[0].to_string()),
+        })
+        .into_owned()
+}
+
+/// SigV4's URI encoding: everything but unreserved characters, and `/` unless
+/// `encode_slash`.
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,35 @@
+use base64::Engine;
+
+/// Wait after `load` before a page is snapshotted, when the script sets no `snapshot_delay`.
+pub const DEFAULT_DELAY_MS: u64 = 1000;
+
+/// Page API endpoint snapshots are posted to.
+const ENDPOINT: &str = "/__rusty_proxy/api/snapshot";
+
+/// Script that waits until `delay_ms` after the page's `load` event, then posts the
+/// rendered DOM (and, with `screenshot`, every canvas as a PNG) to the page API. Canvases
+/// tainted by cross-origin images can't be read and are left out.
+pub fn payload(screenshot: bool, delay_ms: u64) -> String {
+    format!(
+        "(function(){{var screenshot={},delay={};\
+function send(){{var doc=document,dt=doc.doctype,canvases=[];\
+if(screenshot){{Array.prototype.forEach.call(doc.querySelectorAll('canvas'),function(c){{try{{canvases.push(c.toDataURL('image/png'));}}catch(e){{}}}});}}\
+var html=(dt?'<!DOCTYPE '+dt.name+(dt.publicId?' PUBLIC \"'+dt.publicId+'\"':'')+(dt.systemId?' \"'+dt.systemId+'\"':'')+'>\\n':'')+doc.documentElement.outerHTML;\
+fetch('{}',{{method:'POST',headers:{{'content-type':'application/json'}},\
+body:JSON.stringify({{url:location.href,title:doc.title,html:html,canvases:canvases}})}}).catch(function(){{}});}}\
+function later(){{setTimeout(send,delay);}}\
+if(document.readyState==='complete'){{later();}}else{{window.addEventListener('load',later);}}}})();",
+        screenshot, delay_ms, ENDPOINT
+    )
+}
+
+/// Splits a `data:` URL from `canvas.toDataURL` into its media type and decoded bytes.
+pub fn decode_data_url(url: &str) -> Option<(&str, Vec<u8>)> {
+    let (meta, data) = url.strip_prefix("data:")?.split_once(',')?;
+    let media_type = meta.strip_suffix(";base64")?;
+    if !media_type.starts_with("image/") {
+        return None;
+    }
+    let bytes = base64::engine::general_purpose::STANDARD.decode(data).ok()?;
+    Some((media_type, bytes))
+}
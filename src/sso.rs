@@ -0,0 +1,649 @@
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::DeflateDecoder;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, SET_COOKIE};
+use hyper::{Method, Request, Response};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Read;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::info;
+
+use crate::assets::percent_decode;
+use crate::body::{self, Body};
+use crate::capture::warc_date;
+use crate::config::SsoConfig;
+use crate::context::RequestContext;
+use crate::jwt;
+use crate::streaming::{self, ChunkRewriter};
+
+/// Flows kept for the report; the oldest are dropped.
+const FLOW_LIMIT: usize = 100;
+
+/// Steps kept per flow.
+const STEP_LIMIT: usize = 200;
+
+/// Form posts longer than this are passed on without being read.
+const FORM_LIMIT: usize = 256 * 1024;
+
+/// Token endpoint answers longer than this are not inspected.
+const TOKEN_LIMIT: usize = 64 * 1024;
+
+/// Decoded SAML messages longer than this are not inspected.
+const SAML_LIMIT: u64 = 1024 * 1024;
+
+/// Parameters whose values are only shown by their first characters.
+const SECRET_PARAMS: [&str; 12] = [
+    "code",
+    "id_token",
+    "access_token",
+    "refresh_token",
+    "SAMLRequest",
+    "SAMLResponse",
+    "Signature",
+    "client_secret",
+    "client_assertion",
+    "code_verifier",
+    "password",
+    "token",
+];
+
+/// Single sign-on logins followed through the proxy: OIDC authorization requests,
+/// callbacks and token requests, SAML requests and responses, and the redirects and form
+/// posts between them, grouped into one timeline per login.
+pub struct Sso {
+    flow_timeout: Duration,
+    saml: &'static SamlPatterns,
+    traces: Mutex<Traces>,
+}
+
+#[derive(Default)]
+struct Traces {
+    flows: VecDeque<Flow>,
+    next_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Flow {
+    pub id: u64,
+    /// `oidc` or `saml`
+    pub protocol: String,
+    pub client: String,
+    pub started: String,
+    /// The callback or SAML response has arrived
+    pub complete: bool,
+    /// Values the steps were matched by, such as `state` and `request_id`
+    #[serde(default)]
+    pub correlation: BTreeMap<String, String>,
+    #[serde(default)]
+    pub problems: Vec<String>,
+    pub steps: Vec<Step>,
+    /// Authorization codes the client received, to match the token request that redeems one
+    #[serde(skip)]
+    codes: Vec<String>,
+    #[serde(skip)]
+    started_at: Option<Instant>,
+    #[serde(skip)]
+    last_seen: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    /// Milliseconds since the flow started
+    pub at_ms: u64,
+    pub kind: String,
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub details: Map<String, Value>,
+}
+
+/// What identifies a SAML message, read without a full XML parse since only a handful
+/// of values are shown.
+struct SamlPatterns {
+    root: Regex,
+    issuer: Regex,
+    status: Regex,
+    name_id: Regex,
+    audience: Regex,
+    signature: Regex,
+    encrypted: Regex,
+}
+
+impl SamlPatterns {
+    /// The patterns, compiled on first use.
+    fn get() -> &'static Self {
+        static PATTERNS: OnceLock<SamlPatterns> = OnceLock::new();
+        PATTERNS.get_or_init(Self::new)
+    }
+
+    fn new() -> Self {
+        let element = |name: &str| Regex::new(&format!(r"<(?:[\w.-]+:)?{}\b[^>]*>\s*([^<]*?)\s*<", name)).unwrap();
+        SamlPatterns {
+            root: Regex::new(r"<(?:[\w.-]+:)?(AuthnRequest|Response|LogoutRequest|LogoutResponse)\b([^>]*)>").unwrap(),
+            issuer: element("Issuer"),
+            status: Regex::new(r#"<(?:[\w.-]+:)?StatusCode\b[^>]*\bValue="([^"]*)""#).unwrap(),
+            name_id: element("NameID"),
+            audience: element("Audience"),
+            signature: Regex::new(r"<(?:[\w.-]+:)?Signature\b").unwrap(),
+            encrypted: Regex::new(r"<(?:[\w.-]+:)?EncryptedAssertion\b").unwrap(),
+        }
+    }
+
+    /// Details of a SAML message: its element, attributes and the values worth showing.
+    fn read(&self, xml: &str) -> Map<String, Value> {
+        let mut details = Map::new();
+        if let Some(root) = self.root.captures(xml) {
+            details.insert("message".to_string(), json!(&root[1]));
+            for (attribute, name) in [
+                ("ID", "id"),
+                ("InResponseTo", "in_response_to"),
+                ("Destination", "destination"),
+                ("AssertionConsumerServiceURL", "acs_url"),
+                ("ProtocolBinding", "protocol_binding"),
+            ] {
+                if let Some(value) = attribute_value(&root[2], attribute) {
+                    details.insert(name.to_string(), json!(value));
+                }
+            }
+        }
+        if let Some(issuer) = self.issuer.captures(xml) {
+            details.insert("issuer".to_string(), json!(&issuer[1]));
+        }
+        if let Some(status) = self.status.captures(xml) {
+            let status = status[1].rsplit(':').next().unwrap_or_default();
+            details.insert("status".to_string(), json!(status));
+        }
+        if let Some(name_id) = self.name_id.captures(xml) {
+            details.insert("name_id".to_string(), json!(jwt::redact(&name_id[1])));
+        }
+        if let Some(audience) = self.audience.captures(xml) {
+            details.insert("audience".to_string(), json!(&audience[1]));
+        }
+        details.insert("signed".to_string(), json!(self.signature.is_match(xml)));
+        if self.encrypted.is_match(xml) {
+            details.insert("encrypted".to_string(), json!(true));
+        }
+        details
+    }
+}
+
+impl Sso {
+    pub fn open(config: &SsoConfig) -> Option<Arc<Sso>> {
+        config.trace.then(|| {
+            Arc::new(Sso {
+                flow_timeout: Duration::from_secs(config.flow_timeout),
+                saml: SamlPatterns::get(),
+                traces: Mutex::new(Traces::default()),
+            })
+        })
+    }
+
+    /// Reads a form-encoded POST of at most `FORM_LIMIT` bytes so its fields can be
+    /// traced; the request goes on with the same body.
+    pub async fn read_form(req: Request<Body>) -> Result<(Request<Body>, Option<Vec<(String, String)>>)> {
+        let is_form = req.method() == Method::POST
+            && header(req.headers(), CONTENT_TYPE.as_str()).is_some_and(|t| t.starts_with("application/x-www-form-urlencoded"));
+        let small = header(req.headers(), CONTENT_LENGTH.as_str()).and_then(|l| l.parse::<usize>().ok()).is_some_and(|l| l <= FORM_LIMIT);
+        if !is_form || !small {
+            return Ok((req, None));
+        }
+        let (parts, body) = req.into_parts();
+        let body = body::to_bytes(body).await?;
+        let form = params(&String::from_utf8_lossy(&body));
+        Ok((Request::from_parts(parts, Body::from(body)), Some(form)))
+    }
+
+    /// Adds the exchange to the flow it belongs to, when it is part of a login. Token
+    /// endpoint answers are inspected once their body has passed through.
+    pub fn observe(self: &Arc<Self>, ctx: &RequestContext, form: Option<Vec<(String, String)>>, res: Response<Body>) -> Response<Body> {
+        let query = ctx.url.query().map(params).unwrap_or_default();
+        let posted = form.is_some();
+        let form = form.unwrap_or_default();
+        let param = |name: &str| form.iter().chain(&query).find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+        let in_form = |name: &str| form.iter().any(|(n, _)| n == name);
+        let status = res.status().as_u16();
+        let location = header(res.headers(), LOCATION.as_str()).map(str::to_string);
+
+        let kind = if param("SAMLRequest").is_some() {
+            "saml_request"
+        } else if param("SAMLResponse").is_some() {
+            "saml_response"
+        } else if param("response_type").is_some() && param("client_id").is_some() {
+            "authorize"
+        } else if in_form("grant_type") {
+            "token"
+        } else if param("state").is_some() && ["code", "id_token", "access_token", "error"].iter().any(|name| param(name).is_some()) {
+            "callback"
+        } else if posted {
+            "form_post"
+        } else if (300..400).contains(&status) && location.is_some() {
+            "redirect"
+        } else {
+            return res;
+        };
+
+        let mut details = Map::new();
+        let mut keys: Vec<(&str, String)> = Vec::new();
+        let mut problems = Vec::new();
+        let detail = |details: &mut Map<String, Value>, name: &str| {
+            if let Some(value) = param(name) {
+                details.insert(name.to_string(), json!(value));
+            }
+        };
+        match kind {
+            "authorize" => {
+                for name in ["client_id", "response_type", "scope", "redirect_uri", "response_mode", "prompt"] {
+                    detail(&mut details, name);
+                }
+                details.insert("pkce".to_string(), json!(param("code_challenge").is_some()));
+                for name in ["state", "nonce", "client_id", "redirect_uri"] {
+                    if let Some(value) = param(name) {
+                        keys.push((name, value.to_string()));
+                    }
+                }
+            }
+            "callback" => {
+                let received: Vec<&str> = ["code", "id_token", "access_token"].into_iter().filter(|name| param(name).is_some()).collect();
+                details.insert("received".to_string(), json!(received));
+                if let Some(id_token) = param("id_token").and_then(jwt::Token::decode) {
+                    details.insert("id_token".to_string(), json!(id_token.redacted()));
+                }
+                if let Some(error) = param("error") {
+                    let description = param("error_description").map(|d| format!(" ({})", d)).unwrap_or_default();
+                    problems.push(format!("authorization failed: {}{}", error, description));
+                }
+                if let Some(state) = param("state") {
+                    keys.push(("state", state.to_string()));
+                }
+            }
+            "token" => {
+                for name in ["grant_type", "client_id", "redirect_uri", "scope"] {
+                    detail(&mut details, name);
+                }
+                let basic = header(&ctx.headers, "authorization").is_some_and(|a| a.len() > 6 && a[..6].eq_ignore_ascii_case("basic "));
+                let client_auth = if basic {
+                    "basic"
+                } else if in_form("client_secret") {
+                    "post"
+                } else if in_form("client_assertion") {
+                    "assertion"
+                } else {
+                    "none"
+                };
+                details.insert("client_auth".to_string(), json!(client_auth));
+                details.insert("pkce".to_string(), json!(in_form("code_verifier")));
+            }
+            "saml_request" | "saml_response" => {
+                let field = if kind == "saml_request" { "SAMLRequest" } else { "SAMLResponse" };
+                let redirect_binding = !in_form(field);
+                details.insert("binding".to_string(), json!(if redirect_binding { "redirect" } else { "post" }));
+                if let Some(xml) = param(field).and_then(|value| saml_xml(value, redirect_binding)) {
+                    details.extend(self.saml.read(&xml));
+                }
+                if redirect_binding && param("Signature").is_some() {
+                    details.insert("signed".to_string(), json!(true));
+                }
+                if let Some(relay_state) = param("RelayState") {
+                    keys.push(("relay_state", relay_state.to_string()));
+                }
+                let id = if kind == "saml_request" { "id" } else { "in_response_to" };
+                if let Some(request_id) = details.get(id).and_then(Value::as_str) {
+                    keys.push(("request_id", request_id.to_string()));
+                }
+                if kind == "saml_response" {
+                    match details.get("status").and_then(Value::as_str) {
+                        Some("Success") | None => {}
+                        Some(status) => problems.push(format!("SAML status is {}", status)),
+                    }
+                    let posted_to = format!("{}://{}{}", ctx.url.scheme_str().unwrap_or("http"), ctx.url.authority().map(|a| a.as_str()).unwrap_or(""), ctx.url.path());
+                    if let Some(destination) = details.get("destination").and_then(Value::as_str).filter(|d| *d != posted_to) {
+                        problems.push(format!("Destination is {} but the response was sent to {}", destination, posted_to));
+                    }
+                }
+            }
+            "form_post" => {
+                let fields: Vec<&str> = form.iter().map(|(name, _)| name.as_str()).collect();
+                details.insert("fields".to_string(), json!(fields));
+            }
+            _ => {}
+        }
+        let cookies: Vec<&str> = res
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| v.split('=').next())
+            .map(str::trim)
+            .collect();
+        if !cookies.is_empty() {
+            details.insert("set_cookies".to_string(), json!(cookies));
+        }
+
+        let step = Step {
+            at_ms: 0,
+            kind: kind.to_string(),
+            method: ctx.method.to_string(),
+            url: redact_url(&ctx.url.to_string()),
+            status,
+            location: location.as_deref().map(redact_url),
+            details,
+        };
+        let Some((flow, index)) = self.record(ctx.client_ip, step, &keys, param("code"), problems) else {
+            return res;
+        };
+
+        let json = header(res.headers(), CONTENT_TYPE.as_str()).is_some_and(|t| t.contains("json"));
+        if kind != "token" || !json {
+            return res;
+        }
+        let (parts, body) = res.into_parts();
+        let answer = TokenAnswer {
+            sso: self.clone(),
+            flow,
+            step: index,
+            headers: parts.headers.clone(),
+            body: Vec::new(),
+            done: false,
+        };
+        Response::from_parts(parts, streaming::rewrite_body(body, answer))
+    }
+
+    /// Adds the step to its flow, starting one when the step begins a login or can't be
+    /// matched; redirects and form posts are only kept as part of a flow already open for
+    /// the client. A token request is matched to the flow whose callback received its
+    /// `code`. Returns the flow's id and the step's position in it.
+    fn record(&self, client: IpAddr, mut step: Step, keys: &[(&str, String)], code: Option<&str>, problems: Vec<String>) -> Option<(u64, usize)> {
+        let now = Instant::now();
+        let mut traces = self.traces.lock().unwrap();
+        let protocol = match step.kind.as_str() {
+            "authorize" | "callback" | "token" => Some("oidc"),
+            "saml_request" | "saml_response" => Some("saml"),
+            _ => None,
+        };
+        let matching = |flow: &Flow| {
+            keys.iter().any(|(name, value)| matches!(*name, "state" | "relay_state" | "request_id") && flow.correlation.get(*name) == Some(value))
+                || (step.kind == "token" && code.is_some_and(|code| flow.codes.iter().any(|c| c == code)))
+        };
+        let open = |flow: &Flow| {
+            flow.client == client.to_string()
+                && !flow.complete
+                && flow.last_seen.is_some_and(|seen| now.duration_since(seen) < self.flow_timeout)
+                && protocol.is_none_or(|p| flow.protocol == p)
+        };
+        let starts = matches!(step.kind.as_str(), "authorize" | "saml_request");
+        let position = traces
+            .flows
+            .iter()
+            .rposition(matching)
+            .or_else(|| if starts { None } else { traces.flows.iter().rposition(open) });
+
+        let mut problems = problems;
+        let position = match (position, protocol) {
+            (Some(position), _) => position,
+            (None, None) => return None,
+            (None, Some(protocol)) => {
+                match step.kind.as_str() {
+                    "callback" => problems.push("the callback's state matches no authorization request seen".to_string()),
+                    "saml_response" => {
+                        step.details.insert("initiated_by".to_string(), json!("identity provider"));
+                    }
+                    _ => {}
+                }
+                if traces.flows.len() >= FLOW_LIMIT {
+                    traces.flows.pop_front();
+                }
+                traces.next_id += 1;
+                let id = traces.next_id;
+                traces.flows.push_back(Flow {
+                    id,
+                    protocol: protocol.to_string(),
+                    client: client.to_string(),
+                    started: warc_date(SystemTime::now()),
+                    complete: false,
+                    correlation: BTreeMap::new(),
+                    problems: Vec::new(),
+                    steps: Vec::new(),
+                    codes: Vec::new(),
+                    started_at: Some(now),
+                    last_seen: None,
+                });
+                traces.flows.len() - 1
+            }
+        };
+
+        let flow = &mut traces.flows[position];
+        if flow.steps.len() >= STEP_LIMIT {
+            return None;
+        }
+        if step.kind == "callback" {
+            match (flow.correlation.get("state"), keys.iter().find(|(name, _)| *name == "state")) {
+                (Some(expected), Some((_, state))) if expected != state => {
+                    problems.push(format!("the callback's state {} doesn't match the authorization request's {}", state, expected));
+                }
+                _ => {}
+            }
+        }
+        if let (Some(code), "callback") = (code, step.kind.as_str()) {
+            flow.codes.push(code.to_string());
+        }
+        for (name, value) in keys {
+            flow.correlation.entry(name.to_string()).or_insert_with(|| value.clone());
+        }
+        if matches!(step.kind.as_str(), "callback" | "saml_response") {
+            flow.complete = true;
+        }
+        step.at_ms = flow.started_at.map(|at| now.duration_since(at).as_millis() as u64).unwrap_or(0);
+        info!("SSO flow {} ({}): {} {} {} -> {}", flow.id, flow.protocol, step.kind, step.method, step.url, step.status);
+        for problem in &problems {
+            info!("SSO flow {}: {}", flow.id, problem);
+        }
+        flow.problems.extend(problems);
+        flow.last_seen = Some(now);
+        flow.steps.push(step);
+        Some((flow.id, flow.steps.len() - 1))
+    }
+
+    /// What the token endpoint answered, added to the token step.
+    fn token_answer(&self, flow: u64, step: usize, answer: &Value, status: bool) {
+        let mut traces = self.traces.lock().unwrap();
+        let Some(flow) = traces.flows.iter_mut().find(|f| f.id == flow) else {
+            return;
+        };
+        let mut details = Map::new();
+        if !status || answer.get("error").is_some() {
+            let error = answer.get("error").and_then(Value::as_str).unwrap_or("no error given");
+            let description = answer.get("error_description").and_then(Value::as_str).map(|d| format!(" ({})", d)).unwrap_or_default();
+            flow.problems.push(format!("token endpoint refused: {}{}", error, description));
+            info!("SSO flow {}: token endpoint refused: {}{}", flow.id, error, description);
+        }
+        for name in ["token_type", "expires_in", "scope"] {
+            if let Some(value) = answer.get(name) {
+                details.insert(name.to_string(), value.clone());
+            }
+        }
+        if answer.get("refresh_token").is_some() {
+            details.insert("refresh_token".to_string(), json!(true));
+        }
+        if let Some(access_token) = answer.get("access_token").and_then(Value::as_str) {
+            let shown = jwt::Token::decode(access_token).map(|token| token.redacted()).unwrap_or_else(|| "opaque".to_string());
+            details.insert("access_token".to_string(), json!(shown));
+        }
+        if let Some(id_token) = answer.get("id_token").and_then(Value::as_str).and_then(jwt::Token::decode) {
+            let nonce = id_token.claims.get("nonce").and_then(Value::as_str);
+            match (flow.correlation.get("nonce"), nonce) {
+                (Some(expected), Some(nonce)) if expected != nonce => {
+                    flow.problems.push(format!("the ID token's nonce {} doesn't match the authorization request's {}", nonce, expected));
+                }
+                (Some(_), None) => flow.problems.push("the ID token carries no nonce".to_string()),
+                _ => {}
+            }
+            details.insert("id_token".to_string(), json!(id_token.redacted()));
+        }
+        if let Some(step) = flow.steps.get_mut(step) {
+            step.details.extend(details);
+        }
+    }
+
+    pub fn report(&self) -> Value {
+        let traces = self.traces.lock().unwrap();
+        json!({ "flows": traces.flows })
+    }
+
+    pub fn reset(&self) {
+        *self.traces.lock().unwrap() = Traces::default();
+    }
+}
+
+struct TokenAnswer {
+    sso: Arc<Sso>,
+    flow: u64,
+    step: usize,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    done: bool,
+}
+
+impl TokenAnswer {
+    fn complete(&mut self) {
+        if std::mem::replace(&mut self.done, true) {
+            return;
+        }
+        let Some(answer) = body::decode(&self.headers, &self.body, TOKEN_LIMIT).and_then(|body| serde_json::from_slice::<Value>(&body).ok()) else {
+            return;
+        };
+        let success = answer.get("access_token").is_some();
+        self.sso.token_answer(self.flow, self.step, &answer, success);
+    }
+}
+
+impl ChunkRewriter for TokenAnswer {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        let room = TOKEN_LIMIT.saturating_sub(self.body.len());
+        self.body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        Bytes::copy_from_slice(chunk)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.complete();
+        Bytes::new()
+    }
+}
+
+impl Drop for TokenAnswer {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
+
+/// Prints the flows the way `rusty-proxy sso` shows them.
+pub fn print_report(report: &Value) {
+    let flows: Vec<Flow> = report.get("flows").cloned().and_then(|flows| serde_json::from_value(flows).ok()).unwrap_or_default();
+    if flows.is_empty() {
+        println!("No login flows traced yet");
+    }
+    for flow in flows {
+        println!(
+            "Flow {} ({}) from {}, started {}{}",
+            flow.id,
+            flow.protocol,
+            flow.client,
+            flow.started,
+            if flow.complete { "" } else { ", incomplete" }
+        );
+        for step in &flow.steps {
+            let location = step.location.as_deref().map(|l| format!(" {}", l)).unwrap_or_default();
+            println!("  {:>8}  {:<13} {} {} -> {}{}", format!("+{}ms", step.at_ms), step.kind, step.method, step.url, step.status, location);
+            let details: Vec<String> = step
+                .details
+                .iter()
+                .map(|(name, value)| match value {
+                    Value::String(text) => format!("{}={}", name, text),
+                    other => format!("{}={}", name, other),
+                })
+                .collect();
+            if !details.is_empty() {
+                println!("  {:>8}  {}", "", details.join(" "));
+            }
+        }
+        for problem in &flow.problems {
+            println!("  problem: {}", problem);
+        }
+        println!();
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Form- or query-encoded pairs, decoded.
+fn params(text: &str) -> Vec<(String, String)> {
+    let decode = |part: &str| {
+        let part = part.replace('+', " ");
+        percent_decode(&part).unwrap_or(part)
+    };
+    text.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (decode(name), decode(value)),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+/// The URL with the values of `SECRET_PARAMS` in its query and fragment cut short.
+fn redact_url(url: &str) -> String {
+    let (before_fragment, fragment) = match url.split_once('#') {
+        Some((before, fragment)) => (before, Some(fragment)),
+        None => (url, None),
+    };
+    let redact_pairs = |text: &str| {
+        text.split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) if SECRET_PARAMS.contains(&name) => format!("{}={}", name, jwt::redact(value)),
+                _ => pair.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    };
+    let mut out = match before_fragment.split_once('?') {
+        Some((path, query)) => format!("{}?{}", path, redact_pairs(query)),
+        None => before_fragment.to_string(),
+    };
+    if let Some(fragment) = fragment {
+        out.push('#');
+        out.push_str(&redact_pairs(fragment));
+    }
+    out
+}
+
+/// A SAML message as XML: base64, and deflated too in the redirect binding.
+fn saml_xml(value: &str, redirect_binding: bool) -> Option<String> {
+    // `+` in an unescaped query has been decoded to a space
+    let cleaned: String = value.chars().filter(|c| !c.is_ascii_whitespace() || *c == ' ').map(|c| if c == ' ' { '+' } else { c }).collect();
+    let raw = STANDARD.decode(cleaned).ok()?;
+    let mut xml = String::new();
+    if redirect_binding {
+        DeflateDecoder::new(raw.as_slice()).take(SAML_LIMIT).read_to_string(&mut xml).ok()?;
+    } else {
+        xml = String::from_utf8(raw).ok()?;
+    }
+    Some(xml)
+}
+
+fn attribute_value<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("{}=\"", name);
+    let (at, _) = attributes.match_indices(&prefix).find(|(at, _)| attributes[..*at].ends_with(char::is_whitespace))?;
+    let value = &attributes[at + prefix.len()..];
+    Some(&value[..value.find('"')?])
+}
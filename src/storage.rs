@@ -0,0 +1,515 @@
+use anyhow::{anyhow, bail, Result};
+use futures_util::future::BoxFuture;
+use regex::Regex;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+
+use crate::bundle::safe_join;
+use crate::capture::warc_date;
+use crate::config::StorageConfig;
+use crate::diagnostics;
+use crate::proxy::ProxyState;
+use crate::signing::{hex, uri_encode, SigV4};
+
+/// Somewhere durable, or at least off the local disk, for what a long-running proxy
+/// accumulates: rotated WARC files (`captures/`), stats snapshots (`stats/`) and fetched
+/// script assets (`assets/`). Keys are `/`-separated paths.
+pub trait Storage: Send + Sync {
+    /// Where the data goes, for the log
+    fn describe(&self) -> String;
+
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<()>>;
+
+    /// `None` when nothing is stored under `key`.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>>;
+
+    /// Objects whose keys start with `prefix`, in key order.
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<Object>>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>>;
+}
+
+/// A stored object, as listed.
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    /// When it was last written, where the backend tells
+    pub modified: Option<SystemTime>,
+}
+
+/// Tries of each upload before it is given up on.
+const UPLOAD_ATTEMPTS: u32 = 5;
+
+/// Pause after the first failed upload; it doubles with each further failure.
+const UPLOAD_RETRY_PAUSE: Duration = Duration::from_secs(2);
+
+/// How often objects past `keep_days` are looked for.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The backend `[storage]` selects, or `None` when none is.
+pub fn open(config: &StorageConfig) -> Result<Option<Arc<dyn Storage>>> {
+    let storage: Arc<dyn Storage> = match config.backend.as_str() {
+        "" | "none" => return Ok(None),
+        "memory" => Arc::new(Memory::default()),
+        "filesystem" => Arc::new(Filesystem {
+            root: PathBuf::from(&config.path),
+        }),
+        "s3" => Arc::new(S3::new(config)?),
+        "sqlite" => bail!("storage.backend sqlite is not available: this build has no SQLite driver; use filesystem or s3"),
+        other => bail!("unknown storage.backend {}; expected memory, filesystem or s3", other),
+    };
+    Ok(Some(storage))
+}
+
+/// Kept in the process until it exits; for trying things out and for tests.
+#[derive(Default)]
+struct Memory {
+    objects: Mutex<BTreeMap<String, (Vec<u8>, SystemTime)>>,
+}
+
+impl Storage for Memory {
+    fn describe(&self) -> String {
+        "memory (lost on exit)".to_string()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+        self.objects.lock().unwrap().insert(key.to_string(), (data, SystemTime::now()));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        let data = self.objects.lock().unwrap().get(key).map(|(data, _)| data.clone());
+        Box::pin(async { Ok(data) })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+        let keys = self
+            .objects
+            .lock()
+            .unwrap()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (data, modified))| Object {
+                key: key.clone(),
+                size: data.len() as u64,
+                modified: Some(*modified),
+            })
+            .collect();
+        Box::pin(async { Ok(keys) })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        self.objects.lock().unwrap().remove(key);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Files below a directory, e.g. a mounted network volume.
+struct Filesystem {
+    root: PathBuf,
+}
+
+impl Filesystem {
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        safe_join(&self.root, key).ok_or_else(|| anyhow!("storage key {} leaves {}", key, self.root.display()))
+    }
+}
+
+impl Storage for Filesystem {
+    fn describe(&self) -> String {
+        format!("filesystem at {}", self.root.display())
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            // Readers never see half a file
+            let partial = path.with_extension("partial");
+            tokio::fs::write(&partial, data).await?;
+            tokio::fs::rename(&partial, &path).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            match tokio::fs::read(self.path(key)?).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+        Box::pin(async move {
+            let root = self.root.clone();
+            let prefix = prefix.to_string();
+            let mut keys = tokio::task::spawn_blocking(move || {
+                let mut keys = Vec::new();
+                walk(&root, &root, &prefix, &mut keys);
+                keys
+            })
+            .await?;
+            keys.sort_by(|a, b| a.key.cmp(&b.key));
+            Ok(keys)
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.path(key)?).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+fn walk(root: &Path, dir: &Path, prefix: &str, keys: &mut Vec<Object>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            walk(root, &path, prefix, keys);
+            continue;
+        }
+        let Ok(relative) = path.strip_prefix(root) else {
+            continue;
+        };
+        let key = relative.to_string_lossy().replace('\\', "/");
+        if key.starts_with(prefix) && !key.ends_with(".partial") {
+            keys.push(Object {
+                key,
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
+        }
+    }
+}
+
+/// An S3 bucket, or a bucket of an S3-compatible service, signed with AWS Signature V4.
+struct S3 {
+    client: reqwest::Client,
+    /// `https://host`
+    base: String,
+    host: String,
+    /// Path of the bucket on `host`, object keys following it: empty for AWS's
+    /// virtual-hosted style
+    bucket_path: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+    prefix: String,
+}
+
+impl S3 {
+    fn new(config: &StorageConfig) -> Result<S3> {
+        let bucket = config.bucket.as_deref().filter(|b| !b.is_empty()).ok_or_else(|| anyhow!("storage.backend s3 needs storage.bucket"))?;
+        let credential = |value: &Option<String>, variable: &str| {
+            value.clone().filter(|v| !v.is_empty()).or_else(|| std::env::var(variable).ok().filter(|v| !v.is_empty()))
+        };
+        let access_key = credential(&config.access_key, "AWS_ACCESS_KEY_ID").ok_or_else(|| anyhow!("S3 storage needs storage.access_key or AWS_ACCESS_KEY_ID"))?;
+        let secret_key = credential(&config.secret_key, "AWS_SECRET_ACCESS_KEY").ok_or_else(|| anyhow!("S3 storage needs storage.secret_key or AWS_SECRET_ACCESS_KEY"))?;
+        let base = match config.endpoint.as_deref().filter(|e| !e.is_empty()) {
+            Some(endpoint) => format!("{}/{}", endpoint.trim_end_matches('/'), bucket),
+            None => format!("https://{}.s3.{}.amazonaws.com", bucket, config.region),
+        };
+        let url = reqwest::Url::parse(&base).map_err(|e| anyhow!("bad storage.endpoint {}: {}", base, e))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => bail!("storage.endpoint {} has no host", base),
+        };
+        Ok(S3 {
+            client: reqwest::Client::new(),
+            base: format!("{}://{}", url.scheme(), host),
+            host,
+            bucket_path: url.path().trim_end_matches('/').to_string(),
+            region: config.region.clone(),
+            access_key,
+            secret_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok().filter(|t| !t.is_empty()),
+            prefix: config.prefix.clone(),
+        })
+    }
+
+    /// Sends a signed request for `key` (empty for the bucket itself) with a query
+    /// given as sorted, unencoded pairs.
+    async fn send(&self, method: reqwest::Method, key: &str, query: &[(&str, &str)], body: Vec<u8>) -> Result<reqwest::Response> {
+        let path = format!("{}/{}", self.bucket_path, uri_encode(key, false));
+        let query = query.iter().map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true))).collect::<Vec<_>>().join("&");
+        let payload_hash = hex(&Sha256::digest(&body));
+        let stamp = warc_date(SystemTime::now()).replace(['-', ':'], "");
+
+        let mut headers = vec![("host", self.host.clone()), ("x-amz-content-sha256", payload_hash.clone()), ("x-amz-date", stamp.clone())];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed: Vec<(String, String)> = headers.iter().map(|(name, value)| (name.to_string(), value.trim().to_string())).collect();
+        let authorization = SigV4 {
+            method: method.as_str(),
+            path: &path,
+            query: &query,
+            headers: &signed,
+            payload_hash: &payload_hash,
+            stamp: &stamp,
+            region: &self.region,
+            service: "s3",
+        }
+        .authorization(&self.access_key, &self.secret_key);
+
+        let url = if query.is_empty() { format!("{}{}", self.base, path) } else { format!("{}{}?{}", self.base, path, query) };
+        let mut request = self.client.request(method, url).header("authorization", authorization).body(body);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        Ok(request.send().await?)
+    }
+
+    /// The response, or an error carrying S3's `<Code>` for anything but a success.
+    async fn check(response: reqwest::Response) -> Result<reqwest::Response> {
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let code = xml_field(&text, "Code").unwrap_or_default();
+        bail!("S3 answered {} {}", status, code)
+    }
+}
+
+impl Storage for S3 {
+    fn describe(&self) -> String {
+        format!("s3 at {}{}/{}", self.base, self.bucket_path, self.prefix)
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            Self::check(self.send(reqwest::Method::PUT, &format!("{}{}", self.prefix, key), &[], data).await?).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let response = self.send(reqwest::Method::GET, &format!("{}{}", self.prefix, key), &[], Vec::new()).await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            Ok(Some(Self::check(response).await?.bytes().await?.to_vec()))
+        })
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, Result<Vec<Object>>> {
+        Box::pin(async move {
+            let full_prefix = format!("{}{}", self.prefix, prefix);
+            let mut keys = Vec::new();
+            let mut token: Option<String> = None;
+            loop {
+                let mut query = vec![("list-type", "2"), ("prefix", full_prefix.as_str())];
+                if let Some(token) = &token {
+                    query.insert(0, ("continuation-token", token.as_str()));
+                }
+                let response = Self::check(self.send(reqwest::Method::GET, "", &query, Vec::new()).await?).await?;
+                let text = response.text().await?;
+                for contents in contents_pattern().captures_iter(&text) {
+                    let (Some(key), Some(size)) = (xml_field(&contents[1], "Key"), xml_field(&contents[1], "Size")) else {
+                        continue;
+                    };
+                    keys.push(Object {
+                        key: key.strip_prefix(&self.prefix).unwrap_or(&key).to_string(),
+                        size: size.parse().unwrap_or(0),
+                        modified: xml_field(&contents[1], "LastModified").and_then(|at| parse_date(&at)),
+                    });
+                }
+                token = xml_field(&text, "NextContinuationToken");
+                if xml_field(&text, "IsTruncated").as_deref() != Some("true") || token.is_none() {
+                    return Ok(keys);
+                }
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            Self::check(self.send(reqwest::Method::DELETE, &format!("{}{}", self.prefix, key), &[], Vec::new()).await?).await?;
+            Ok(())
+        })
+    }
+}
+
+fn contents_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"(?s)<Contents>(.*?)</Contents>").unwrap())
+}
+
+/// The text of the first `<name>` element, unescaped.
+fn xml_field(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(
+        xml[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+/// `2026-01-31T12:00:00.000Z`, as S3 lists modification times.
+fn parse_date(text: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| text.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    // A civil date to days since the epoch, the inverse of `warc_date`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// Hands finished files, rotated captures and logs, to storage in the background, as
+/// `<kind>/<node>/<file name>` so the files of a fleet's instances don't collide.
+#[derive(Clone)]
+pub struct Uploader {
+    storage: Arc<dyn Storage>,
+    node: String,
+    runtime: Handle,
+}
+
+impl Uploader {
+    /// Must be created within the runtime; uploads may then be started from any thread.
+    pub fn new(storage: Arc<dyn Storage>, node: &str) -> Self {
+        Uploader {
+            storage,
+            node: node_key(node),
+            runtime: Handle::current(),
+        }
+    }
+
+    pub fn upload(&self, kind: &str, path: PathBuf) {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let key = format!("{}/{}/{}", kind, self.node, name);
+        self.runtime.spawn(upload(self.storage.clone(), key, path));
+    }
+}
+
+/// Puts a file in storage, trying again after a pause when that fails. The local file
+/// stays, subject to its own retention.
+async fn upload(storage: Arc<dyn Storage>, key: String, path: PathBuf) {
+    let mut pause = UPLOAD_RETRY_PAUSE;
+    for attempt in 1..=UPLOAD_ATTEMPTS {
+        let result = match tokio::fs::read(&path).await {
+            Ok(data) => storage.put(&key, data).await,
+            Err(e) => {
+                warn!("Failed to store {} as {}: {}", path.display(), key, e);
+                return;
+            }
+        };
+        match result {
+            Ok(()) => {
+                info!("Stored {} as {}", path.display(), key);
+                return;
+            }
+            Err(e) if attempt < UPLOAD_ATTEMPTS => {
+                warn!("Failed to store {} as {} (attempt {} of {}): {}", path.display(), key, attempt, UPLOAD_ATTEMPTS, e);
+                tokio::time::sleep(pause).await;
+                pause *= 2;
+            }
+            Err(e) => warn!("Gave up storing {} as {}: {}", path.display(), key, e),
+        }
+    }
+}
+
+/// Deletes captures, teed downloads, logs and stats snapshots older than `keep_days` from storage, a
+/// pass every `RETENTION_INTERVAL`. Cached assets are kept.
+async fn enforce_retention(storage: Arc<dyn Storage>, keep_days: u64) {
+    let max_age = Duration::from_secs(keep_days * 86_400);
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        for prefix in ["captures/", "downloads/", "logs/", "stats/"] {
+            let objects = match storage.list(prefix).await {
+                Ok(objects) => objects,
+                Err(e) => {
+                    warn!("Storage retention: cannot list {}: {}", prefix, e);
+                    continue;
+                }
+            };
+            for object in objects {
+                let expired = object.modified.and_then(|modified| modified.elapsed().ok()).is_some_and(|age| age > max_age);
+                if !expired {
+                    continue;
+                }
+                match storage.delete(&object.key).await {
+                    Ok(()) => info!("Storage retention: deleted {} (older than {} days)", object.key, keep_days),
+                    Err(e) => warn!("Storage retention: cannot delete {}: {}", object.key, e),
+                }
+            }
+        }
+    }
+}
+
+/// Enforces `keep_days` and writes a stats snapshot every `stats_interval` seconds while
+/// the proxy runs.
+pub async fn run(state: Arc<ProxyState>) {
+    let Some(storage) = state.storage.clone() else {
+        return;
+    };
+    if state.config.storage.keep_days > 0 {
+        tokio::spawn(enforce_retention(storage, state.config.storage.keep_days));
+    }
+    if state.config.storage.stats_interval == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.storage.stats_interval));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        save_stats(&state).await;
+    }
+}
+
+/// Stores the diagnostics snapshot and tunnel stats as
+/// `stats/<node>/<time>.json`.
+pub async fn save_stats(state: &ProxyState) {
+    let Some(storage) = &state.storage else {
+        return;
+    };
+    let stats = json!({
+        "node": state.cluster.node(),
+        "diagnostics": diagnostics::snapshot(state),
+        "tunnel_ports": state.tunnels.port_snapshot(),
+    });
+    let key = format!("stats/{}/{}.json", node_key(state.cluster.node()), warc_date(SystemTime::now()).replace(['-', ':'], ""));
+    match storage.put(&key, serde_json::to_vec_pretty(&stats).unwrap_or_default()).await {
+        Ok(()) => info!("Stored stats as {}", key),
+        Err(e) => warn!("Failed to store stats as {}: {}", key, e),
+    }
+}
+
+/// A node name as one key segment: `host:8080` becomes `host-8080`.
+fn node_key(node: &str) -> String {
+    node.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '-' }).collect()
+}
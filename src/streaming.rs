@@ -0,0 +1,426 @@
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use regex::Regex;
+use tracing::warn;
+
+use crate::body::Body;
+use crate::html;
+
+/// Incremental body transformer driven by [`rewrite_body`].
+pub trait ChunkRewriter {
+    /// Consumes an upstream chunk and returns whatever output is ready to send.
+    fn push(&mut self, chunk: &[u8]) -> Bytes;
+    /// Flushes held-back output once upstream closes the stream.
+    fn finish(&mut self) -> Bytes;
+}
+
+/// `scripts.max_added_bytes` and `scripts.max_replacements` as applied to one streamed
+/// body. Output already sent can't be taken back, so once a piece of the body would go
+/// past either, it and the rest of the body pass through unchanged.
+pub struct Limits {
+    max_added_bytes: usize,
+    max_replacements: usize,
+    added: usize,
+    replaced: usize,
+    url: String,
+    reached: bool,
+}
+
+impl Limits {
+    /// 0 means no limit.
+    pub fn new(max_added_bytes: usize, max_replacements: usize, url: String) -> Self {
+        Limits {
+            max_added_bytes,
+            max_replacements,
+            added: 0,
+            replaced: 0,
+            url,
+            reached: false,
+        }
+    }
+
+    /// Applies the rules to one piece of the body, unless that takes it past a limit.
+    /// Returns the piece and the indexes of the rules that changed it.
+    fn rewrite(&mut self, rules: &[(Regex, String)], text: String) -> (String, Vec<usize>) {
+        if self.reached {
+            return (text, Vec::new());
+        }
+        let mut rewritten = text.clone();
+        let mut replaced = self.replaced;
+        let mut applied = Vec::new();
+        for (index, (regex, replacement)) in rules.iter().enumerate() {
+            let matches = regex.find_iter(&rewritten).count();
+            if matches == 0 {
+                continue;
+            }
+            replaced += matches;
+            if self.max_replacements > 0 && replaced > self.max_replacements {
+                return (self.stop(text, "max_replacements"), Vec::new());
+            }
+            rewritten = regex.replace_all(&rewritten, replacement.as_str()).into_owned();
+            applied.push(index);
+        }
+        let added = self.added + rewritten.len().saturating_sub(text.len());
+        if self.max_added_bytes > 0 && added > self.max_added_bytes {
+            return (self.stop(text, "max_added_bytes"), Vec::new());
+        }
+        self.added = added;
+        self.replaced = replaced;
+        (rewritten, applied)
+    }
+
+    fn stop(&mut self, text: String, limit: &str) -> String {
+        warn!("Passing the rest of {} through unchanged: its injections reached scripts.{}", self.url, limit);
+        self.reached = true;
+        text
+    }
+}
+
+/// Rewrites a Server-Sent Events stream one event at a time.
+///
+/// Upstream chunks are split on event boundaries (a blank line); complete events are
+/// rewritten and emitted immediately while a trailing partial event is held back until
+/// the rest of it arrives. A stream that goes `EVENT_LIMIT` bytes without a boundary is
+/// passed through unchanged from there on, rather than held back without end.
+pub struct SseRewriter {
+    rules: Vec<(Regex, String)>,
+    limits: Limits,
+    pending: Vec<u8>,
+    passing: bool,
+}
+
+/// Longest partial event `SseRewriter` holds back.
+const EVENT_LIMIT: usize = 1024 * 1024;
+
+impl SseRewriter {
+    pub fn new(rules: Vec<(Regex, String)>, limits: Limits) -> Self {
+        SseRewriter {
+            rules,
+            limits,
+            pending: Vec::new(),
+            passing: false,
+        }
+    }
+
+    fn rewrite_event(&mut self, event: &[u8]) -> String {
+        let text = String::from_utf8_lossy(event).to_string();
+        self.limits.rewrite(&self.rules, text).0
+    }
+
+    /// Index just past the next `\n\n` or `\r\n\r\n` boundary at or after `from`.
+    fn event_end(buf: &[u8], from: usize) -> Option<usize> {
+        (from + 1..buf.len()).find_map(|i| {
+            if buf[i] != b'\n' {
+                return None;
+            }
+            if buf[i - 1] == b'\n' || (i >= 3 && &buf[i - 3..i] == b"\r\n\r") {
+                Some(i + 1)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl ChunkRewriter for SseRewriter {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        if self.passing {
+            return Bytes::copy_from_slice(chunk);
+        }
+        self.pending.extend_from_slice(chunk);
+
+        let mut output = Vec::new();
+        let mut start = 0;
+        while let Some(end) = Self::event_end(&self.pending, start) {
+            let event = self.pending[start..end].to_vec();
+            output.extend_from_slice(self.rewrite_event(&event).as_bytes());
+            start = end;
+        }
+        self.pending.drain(..start);
+
+        if self.pending.len() > EVENT_LIMIT {
+            warn!("Passing the rest of {} through unchanged: an event ran past {} bytes", self.limits.url, EVENT_LIMIT);
+            self.passing = true;
+            output.append(&mut self.pending);
+        }
+        Bytes::from(output)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        if self.pending.is_empty() {
+            return Bytes::new();
+        }
+        let rest = std::mem::take(&mut self.pending);
+        Bytes::from(self.rewrite_event(&rest))
+    }
+}
+
+/// A change made to each piece of a streamed body after the rules, such as fixing
+/// `integrity=` attributes. Spans matching `span` are never split between pieces.
+pub struct Fixup {
+    pub span: Regex,
+    pub apply: Box<dyn FnMut(&mut String) + Send + Sync>,
+}
+
+/// Called with the index of a rule the first time it changes the body.
+pub type OnApplied = Box<dyn FnMut(usize) + Send + Sync>;
+
+/// Injects into the held-back start of a page; see [`HeadInjector`].
+pub type InjectHead = Box<dyn FnOnce(&mut String) + Send + Sync>;
+
+/// Applies regex replacements to a streamed text body without buffering all of it.
+///
+/// The last `window` bytes of decoded text are always held back so a match straddling
+/// two upstream chunks is still seen whole; matches longer than the window may be missed.
+pub struct RollingReplacer {
+    rules: Vec<(Regex, String)>,
+    limits: Limits,
+    window: usize,
+    text: String,
+    undecoded: Vec<u8>,
+    fixup: Option<Fixup>,
+    on_applied: Option<OnApplied>,
+    reported: Vec<bool>,
+}
+
+impl RollingReplacer {
+    pub fn new(rules: Vec<(Regex, String)>, window: usize, limits: Limits) -> Self {
+        let reported = vec![false; rules.len()];
+        RollingReplacer {
+            rules,
+            limits,
+            window,
+            text: String::new(),
+            undecoded: Vec::new(),
+            fixup: None,
+            on_applied: None,
+            reported,
+        }
+    }
+
+    pub fn with_fixup(mut self, fixup: Fixup) -> Self {
+        self.fixup = Some(fixup);
+        self
+    }
+
+    pub fn on_applied(mut self, on_applied: OnApplied) -> Self {
+        self.on_applied = Some(on_applied);
+        self
+    }
+
+    /// Appends bytes to the text buffer, keeping an incomplete trailing UTF-8 sequence aside.
+    fn decode(&mut self, chunk: &[u8]) {
+        self.undecoded.extend_from_slice(chunk);
+        loop {
+            match std::str::from_utf8(&self.undecoded) {
+                Ok(valid) => {
+                    self.text.push_str(valid);
+                    self.undecoded.clear();
+                    return;
+                }
+                Err(e) => {
+                    let valid_up_to = e.valid_up_to();
+                    self.text
+                        .push_str(&String::from_utf8_lossy(&self.undecoded[..valid_up_to]));
+                    match e.error_len() {
+                        // Incomplete sequence at the end: wait for the next chunk
+                        None => {
+                            self.undecoded.drain(..valid_up_to);
+                            return;
+                        }
+                        Some(len) => {
+                            self.text.push(char::REPLACEMENT_CHARACTER);
+                            self.undecoded.drain(..valid_up_to + len);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Rewrites the buffer up to a safe cut point and returns the finished output.
+    fn drain(&mut self, final_chunk: bool) -> Bytes {
+        let mut cut = if final_chunk {
+            self.text.len()
+        } else {
+            self.text.len().saturating_sub(self.window)
+        };
+        while !self.text.is_char_boundary(cut) {
+            cut -= 1;
+        }
+
+        // Never split a match in two: move the cut past any match that straddles it
+        let mut moved = true;
+        while moved {
+            moved = false;
+            let spans = self.rules.iter().map(|(regex, _)| regex).chain(self.fixup.as_ref().map(|fixup| &fixup.span));
+            for regex in spans {
+                for m in regex.find_iter(&self.text) {
+                    if m.start() >= cut {
+                        break;
+                    }
+                    if m.end() > cut {
+                        cut = m.end();
+                        moved = true;
+                    }
+                }
+            }
+        }
+
+        let segment: String = self.text.drain(..cut).collect();
+        let (mut segment, applied) = self.limits.rewrite(&self.rules, segment);
+        for index in applied {
+            if !std::mem::replace(&mut self.reported[index], true) {
+                if let Some(on_applied) = self.on_applied.as_mut() {
+                    on_applied(index);
+                }
+            }
+        }
+        if let Some(fixup) = self.fixup.as_mut() {
+            (fixup.apply)(&mut segment);
+        }
+        Bytes::from(segment)
+    }
+}
+
+impl ChunkRewriter for RollingReplacer {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        self.decode(chunk);
+        self.drain(false)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        if !self.undecoded.is_empty() {
+            let rest = std::mem::take(&mut self.undecoded);
+            self.text.push_str(&String::from_utf8_lossy(&rest));
+        }
+        self.drain(true)
+    }
+}
+
+/// Injects into the `<head>` of a streamed HTML page. The start of the page is held back
+/// until its head is complete, so payloads can be placed where they would be in a
+/// buffered page; `inject` changes that prefix once, and everything then goes on through
+/// `rest`. Pages whose head doesn't end within `limit` bytes are injected into as far
+/// as they have arrived.
+pub struct HeadInjector {
+    inject: Option<InjectHead>,
+    held: Vec<u8>,
+    limit: usize,
+    rest: RollingReplacer,
+}
+
+impl HeadInjector {
+    pub fn new(inject: InjectHead, limit: usize, rest: RollingReplacer) -> Self {
+        HeadInjector {
+            inject: Some(inject),
+            held: Vec::new(),
+            limit,
+            rest,
+        }
+    }
+
+    /// Runs `inject` on what is held and hands the result to `rest`; an incomplete UTF-8
+    /// sequence at the end waits there for the rest of it.
+    fn release(&mut self) {
+        let Some(inject) = self.inject.take() else {
+            return;
+        };
+        let held = std::mem::take(&mut self.held);
+        let valid = match std::str::from_utf8(&held) {
+            Ok(_) => held.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => held.len(),
+        };
+        let mut text = String::from_utf8_lossy(&held[..valid]).into_owned();
+        let before = text.len();
+        inject(&mut text);
+        self.rest.limits.added += text.len().saturating_sub(before);
+        self.rest.text.push_str(&text);
+        self.rest.decode(&held[valid..]);
+    }
+}
+
+impl ChunkRewriter for HeadInjector {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        if self.inject.is_none() {
+            return self.rest.push(chunk);
+        }
+        self.held.extend_from_slice(chunk);
+        if self.held.len() < self.limit && !html::head_complete(&String::from_utf8_lossy(&self.held)) {
+            return Bytes::new();
+        }
+        self.release();
+        self.rest.drain(false)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.release();
+        self.rest.finish()
+    }
+}
+
+/// Wraps an upstream body so every chunk passes through the rewriter as it arrives.
+pub fn rewrite_body<R>(body: Body, rewriter: R) -> Body
+where
+    R: ChunkRewriter + Send + Sync + 'static,
+{
+    let stream = futures_util::stream::unfold(Some((body.into_data_stream(), rewriter)), |state| async move {
+        let (mut body, mut rewriter) = state?;
+        loop {
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    let output = rewriter.push(&chunk);
+                    if !output.is_empty() {
+                        return Some((Ok(output), Some((body, rewriter))));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => {
+                    let rest = rewriter.finish();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    return Some((Ok(rest), None));
+                }
+            }
+        }
+    });
+
+    Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter() -> SseRewriter {
+        SseRewriter::new(vec![(Regex::new("old").unwrap(), "new".to_string())], Limits::new(0, 0, "http://events.example.com/".to_string()))
+    }
+
+    #[test]
+    fn events_are_rewritten_whole() {
+        let mut sse = rewriter();
+        assert_eq!(sse.push(b"data: ol"), Bytes::new());
+        assert_eq!(sse.push(b"d\n\ndata: o"), Bytes::from("data: new\n\n"));
+        assert_eq!(sse.finish(), Bytes::from("data: o"));
+    }
+
+    #[test]
+    fn streams_without_boundaries_pass_through() {
+        let mut sse = rewriter();
+        let line = vec![b'x'; 64 * 1024];
+        let mut sent = Vec::new();
+        for _ in 0..EVENT_LIMIT / line.len() {
+            sent.extend_from_slice(&sse.push(&line));
+        }
+        assert!(sent.is_empty());
+        // One byte past the limit and what was held goes out as it came
+        sent.extend_from_slice(&sse.push(b"o"));
+        assert_eq!(sent.len(), EVENT_LIMIT + 1);
+        sent.extend_from_slice(&sse.push(b"ld\n\ndata: old\n\n"));
+        sent.extend_from_slice(&sse.finish());
+        assert!(sent.ends_with(b"xold\n\ndata: old\n\n"));
+        assert_eq!(sent.len(), EVENT_LIMIT + "old\n\ndata: old\n\n".len());
+    }
+}
@@ -0,0 +1,262 @@
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Method, Response, StatusCode};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+use crate::body::Body;
+use crate::capture::warc_date;
+use crate::config::TeeConfig;
+use crate::context::RequestContext;
+use crate::script_manager::ScriptManager;
+use crate::storage::Uploader;
+use crate::streaming::{self, ChunkRewriter};
+
+/// Chunks queued for the writer before a tee gives up rather than hold up the client.
+const QUEUE_CHUNKS: usize = 1024;
+
+/// Longest name taken from the URL for a teed file.
+const NAME_LIMIT: usize = 80;
+
+/// `[tee]`: response bodies copied to a file of their own as they stream to the client,
+/// for archiving large downloads. Chunks go to a background thread as they pass, so
+/// nothing is held in memory beyond the queue, and a slow disk costs the tee rather
+/// than the client: once the queue is full the copy is abandoned.
+pub struct Tee {
+    config: TeeConfig,
+    dir: PathBuf,
+    writes: mpsc::SyncSender<Message>,
+    next_id: AtomicU64,
+}
+
+/// What the writer thread is told.
+enum Message {
+    Open { id: u64, path: PathBuf, meta: serde_json::Value },
+    Chunk { id: u64, data: Bytes },
+    Close { id: u64, complete: bool },
+}
+
+/// A file being written, and what its sidecar will say.
+struct Open {
+    file: File,
+    path: PathBuf,
+    meta: serde_json::Value,
+    hasher: Sha256,
+    size: u64,
+    failed: bool,
+}
+
+impl Tee {
+    /// `None` when teeing is off or its directory can't be created. Finished files are
+    /// also handed to `uploader`, as `downloads`.
+    pub fn open(config: &TeeConfig, uploader: Option<Uploader>) -> Option<Arc<Tee>> {
+        let dir = PathBuf::from(config.dir.as_deref().filter(|dir| !dir.is_empty())?);
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            warn!("Not teeing responses: cannot create {}: {}", dir.display(), e);
+            return None;
+        }
+        info!("Teeing response bodies to {}", dir.display());
+        let uploader = uploader.filter(|_| config.upload);
+        let (writes, queue) = mpsc::sync_channel::<Message>(QUEUE_CHUNKS);
+        std::thread::spawn(move || {
+            let mut open: HashMap<u64, Open> = HashMap::new();
+            for write in queue {
+                match write {
+                    Message::Open { id, path, meta } => match File::create(&path) {
+                        Ok(file) => {
+                            open.insert(
+                                id,
+                                Open {
+                                    file,
+                                    path,
+                                    meta,
+                                    hasher: Sha256::new(),
+                                    size: 0,
+                                    failed: false,
+                                },
+                            );
+                        }
+                        Err(e) => warn!("Cannot tee to {}: {}", path.display(), e),
+                    },
+                    Message::Chunk { id, data } => {
+                        if let Some(teed) = open.get_mut(&id).filter(|teed| !teed.failed) {
+                            if let Err(e) = teed.file.write_all(&data) {
+                                warn!("Failed to write {}: {}", teed.path.display(), e);
+                                teed.failed = true;
+                            }
+                            teed.hasher.update(&data);
+                            teed.size += data.len() as u64;
+                        }
+                    }
+                    Message::Close { id, complete } => {
+                        if let Some(teed) = open.remove(&id) {
+                            finish(teed, complete, uploader.as_ref());
+                        }
+                    }
+                }
+            }
+        });
+        Some(Arc::new(Tee {
+            config: config.clone(),
+            dir,
+            writes,
+            next_id: AtomicU64::new(1),
+        }))
+    }
+
+    /// Whether the response is one to tee: it has a body, its domain and content type are
+    /// listed (or no list is given) and it is not known to be smaller than `min_size`.
+    pub fn wants(&self, ctx: &RequestContext, res: &Response<Body>) -> bool {
+        if ctx.method == Method::HEAD || matches!(res.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED) {
+            return false;
+        }
+        if !self.config.domains.is_empty() && !ScriptManager::domain_matches(&ctx.domain, &self.config.domains) {
+            return false;
+        }
+        if !self.config.content_types.is_empty() {
+            let content_type = res.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("").to_ascii_lowercase();
+            if !self.config.content_types.iter().any(|prefix| content_type.starts_with(&prefix.to_ascii_lowercase())) {
+                return false;
+            }
+        }
+        content_length(res.headers()).is_none_or(|length| length > 0 && length >= self.config.min_size)
+    }
+
+    /// Streams the body to the client and, chunk by chunk, to a file in the tee directory.
+    pub fn attach(&self, ctx: &RequestContext, res: Response<Body>) -> Response<Body> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stamp = warc_date(SystemTime::now()).replace(['-', ':'], "");
+        let path = self.dir.join(format!("{}-{}-{}", stamp, id, file_name(ctx)));
+        let meta = json!({
+            "url": ctx.url.to_string(),
+            "method": ctx.method.as_str(),
+            "client": ctx.client_ip.to_string(),
+            "status": res.status().as_u16(),
+            "headers": res.headers().iter().map(|(name, value)| json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())])).collect::<Vec<_>>(),
+            "started": warc_date(SystemTime::now()),
+        });
+        if self.writes.try_send(Message::Open { id, path, meta }).is_err() {
+            warn!("Not teeing {}: the writer is behind", ctx.url);
+            return res;
+        }
+        let (parts, body) = res.into_parts();
+        let writer = TeeWriter {
+            writes: self.writes.clone(),
+            id,
+            url: ctx.url.to_string(),
+            expected: content_length(&parts.headers),
+            sent: 0,
+            state: State::Writing,
+        };
+        Response::from_parts(parts, streaming::rewrite_body(body, writer))
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok())
+}
+
+/// The file name a URL's body is teed under: its last path segment, made safe.
+fn file_name(ctx: &RequestContext) -> String {
+    let segment = ctx.url.path().rsplit('/').find(|segment| !segment.is_empty()).unwrap_or("index");
+    let name: String = segment
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+        .take(NAME_LIMIT)
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "index".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Closes a teed file: kept with a `.json` sidecar, and uploaded, when the whole body
+/// went through; deleted otherwise.
+fn finish(mut teed: Open, complete: bool, uploader: Option<&Uploader>) {
+    let _ = teed.file.flush();
+    drop(teed.file);
+    if !complete || teed.failed {
+        let _ = std::fs::remove_file(&teed.path);
+        return;
+    }
+    teed.meta["size"] = json!(teed.size);
+    teed.meta["sha256"] = json!(teed.hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    teed.meta["finished"] = json!(warc_date(SystemTime::now()));
+    let mut sidecar = teed.path.clone().into_os_string();
+    sidecar.push(".json");
+    let sidecar = PathBuf::from(sidecar);
+    if let Err(e) = std::fs::write(&sidecar, format!("{:#}\n", teed.meta)) {
+        warn!("Failed to write {}: {}", sidecar.display(), e);
+    }
+    info!("Teed {} bytes of {} to {}", teed.size, teed.meta["url"].as_str().unwrap_or(""), teed.path.display());
+    if let Some(uploader) = uploader {
+        uploader.upload("downloads", teed.path);
+        uploader.upload("downloads", sidecar);
+    }
+}
+
+#[derive(PartialEq)]
+enum State {
+    Writing,
+    /// The writer fell behind; the rest of the body only goes to the client
+    Abandoned,
+    Closed,
+}
+
+/// Hands each chunk to the writer thread as it passes to the client.
+struct TeeWriter {
+    writes: mpsc::SyncSender<Message>,
+    id: u64,
+    url: String,
+    /// The `Content-Length`, by which a body dropped without being polled to its end
+    /// can still be told complete
+    expected: Option<u64>,
+    sent: u64,
+    state: State,
+}
+
+impl TeeWriter {
+    fn close(&mut self, complete: bool) {
+        if self.state == State::Closed {
+            return;
+        }
+        let complete = complete && self.state == State::Writing;
+        self.state = State::Closed;
+        // Blocking here is what the queue bound exists to avoid, but without the close the
+        // file would stay open; the writer drains the queue, so this waits briefly at most
+        let _ = self.writes.send(Message::Close { id: self.id, complete });
+    }
+}
+
+impl ChunkRewriter for TeeWriter {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        let data = Bytes::copy_from_slice(chunk);
+        self.sent += data.len() as u64;
+        if self.state == State::Writing && self.writes.try_send(Message::Chunk { id: self.id, data: data.clone() }).is_err() {
+            warn!("Stopped teeing {}: the writer fell behind", self.url);
+            self.state = State::Abandoned;
+        }
+        data
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.close(true);
+        Bytes::new()
+    }
+}
+
+/// Hyper may drop a body without polling it to the end once `Content-Length` is sent;
+/// a body dropped short of that (the client went away) leaves no partial file.
+impl Drop for TeeWriter {
+    fn drop(&mut self) {
+        self.close(self.expected == Some(self.sent));
+    }
+}
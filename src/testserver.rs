@@ -0,0 +1,207 @@
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::{SinkExt, StreamExt};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::json;
+use std::convert::Infallible;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, info};
+
+use crate::body::{self, Body};
+
+/// Heading of the test page, so callers can recognise it in proxied responses.
+pub const PAGE_TITLE: &str = "Rusty Proxy test page";
+
+/// Longest delay `/slow` will honour.
+const MAX_SLOW_MS: u64 = 30_000;
+
+/// Binds the test site and serves it in the background, returning the bound address
+/// (useful with port 0).
+pub async fn spawn(addr: SocketAddr) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, peer)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<Incoming>| handle(req.map(Body::from)));
+                if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).with_upgrades().await {
+                    debug!("Test server connection from {} closed with error: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(local)
+}
+
+/// Serves the test site until the process is stopped.
+pub async fn run(addr: SocketAddr) -> Result<()> {
+    let local = spawn(addr).await?;
+    info!("Test server listening on http://{}", local);
+    println!("Test server listening on http://{}", local);
+    println!("  /          HTML page (JavaScript, CSS and ResponseBody scripts)");
+    println!("  /api       JSON API");
+    println!("  /events    Server-Sent Events (SseEvent scripts)");
+    println!("  /ws        WebSocket echo");
+    println!("  /echo      Echoes the body of a request with any method (WebDAV, PATCH, ...)");
+    println!("  /slow?ms=N Response delayed by N milliseconds (max {})", MAX_SLOW_MS);
+    println!("  /gzip      gzip-encoded HTML page");
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/") => html(page()),
+        (&Method::GET, "/api") => {
+            let body = json!({
+                "message": "hello from the rusty-proxy test server",
+                "method": req.method().as_str(),
+                "headers": req
+                    .headers()
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("<binary>").to_string()))
+                    .collect::<std::collections::BTreeMap<_, _>>(),
+            })
+            .to_string();
+            Response::builder()
+                .header("content-type", "application/json")
+                .header("content-length", body.len())
+                .body(Body::from(body))
+                .unwrap()
+        }
+        (&Method::POST, "/api") => {
+            let content_type = req.headers().get("content-type").cloned();
+            let body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+            let mut builder = Response::builder();
+            if let Some(content_type) = content_type {
+                builder = builder.header("content-type", content_type);
+            }
+            builder.body(Body::from(body)).unwrap()
+        }
+        (_, "/echo") => {
+            let method = req.method().to_string();
+            let body = body::to_bytes(req.into_body()).await.unwrap_or_default();
+            Response::builder()
+                .header("x-echo-method", method)
+                .header("content-length", body.len())
+                .body(Body::from(body))
+                .unwrap()
+        }
+        (&Method::GET, "/events") => events(),
+        (&Method::GET, "/ws") => websocket(req),
+        (&Method::GET, "/slow") => {
+            let ms = req
+                .uri()
+                .query()
+                .and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("ms=")))
+                .and_then(|ms| ms.parse().ok())
+                .unwrap_or(2000u64)
+                .min(MAX_SLOW_MS);
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            html(page())
+        }
+        (&Method::GET, "/gzip") => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            let _ = encoder.write_all(page().as_bytes());
+            let body = encoder.finish().unwrap_or_default();
+            Response::builder()
+                .header("content-type", "text/html; charset=utf-8")
+                .header("content-encoding", "gzip")
+                .header("content-length", body.len())
+                .body(Body::from(body))
+                .unwrap()
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("not found"))
+            .unwrap(),
+    };
+    Ok(response)
+}
+
+fn page() -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>{title}</title>
+</head>
+<body>
+    <h1>{title}</h1>
+    <p>Point a script's <code>target_domains</code> at this host to see it injected here.</p>
+</body>
+</html>"#,
+        title = PAGE_TITLE
+    )
+}
+
+fn html(body: String) -> Response<Body> {
+    Response::builder()
+        .header("content-type", "text/html; charset=utf-8")
+        .header("content-length", body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn events() -> Response<Body> {
+    let stream = futures_util::stream::unfold(0u32, |n| async move {
+        if n >= 5 {
+            return None;
+        }
+        if n > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let event = format!("id: {}\ndata: event {}\n\n", n, n);
+        Some((Ok::<_, Infallible>(event), n + 1))
+    });
+    Response::builder()
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(Body::wrap_stream(stream))
+        .unwrap()
+}
+
+fn websocket(req: Request<Body>) -> Response<Body> {
+    let key = match req.headers().get("sec-websocket-key") {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("expected a WebSocket upgrade"))
+                .unwrap()
+        }
+    };
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let mut ws = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+                while let Some(Ok(message)) = ws.next().await {
+                    if (message.is_text() || message.is_binary()) && ws.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Err(e) => debug!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-accept", key)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,90 @@
+/// Largest TLS record, plus its 5-byte header.
+const MAX_RECORD: usize = 16 * 1024 + 5;
+
+/// How far reading a ClientHello off the start of a stream has got.
+pub enum Sniff {
+    /// The bytes so far could still be the start of a ClientHello
+    NeedMore,
+    /// The first record is complete (or the stream isn't TLS); the server name, if any
+    Done(Option<String>),
+}
+
+/// Looks for the server name a client asks for in its ClientHello, given the first bytes
+/// it sent. Nothing is decrypted; only the plaintext `server_name` extension is read.
+pub fn sniff(data: &[u8]) -> Sniff {
+    match data {
+        [] => Sniff::NeedMore,
+        [kind, ..] if *kind != 0x16 => Sniff::Done(None),
+        [_, major, ..] if *major != 0x03 => Sniff::Done(None),
+        _ if data.len() < 5 => Sniff::NeedMore,
+        _ => {
+            let length = 5 + u16::from_be_bytes([data[3], data[4]]) as usize;
+            if data.len() < length.min(MAX_RECORD) {
+                Sniff::NeedMore
+            } else {
+                Sniff::Done(client_hello_sni(&data[5..length.min(data.len())]))
+            }
+        }
+    }
+}
+
+/// The host name in a ClientHello handshake message.
+fn client_hello_sni(record: &[u8]) -> Option<String> {
+    // Handshake header: type 1 (ClientHello) and a 3-byte length
+    let mut reader = Reader(record);
+    if reader.u8()? != 1 {
+        return None;
+    }
+    reader.skip(3)?;
+    reader.skip(2 + 32)?; // client_version, random
+    let session_id = reader.u8()? as usize;
+    reader.skip(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.skip(cipher_suites)?;
+    let compression = reader.u8()? as usize;
+    reader.skip(compression)?;
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while let (Some(kind), Some(len)) = (extensions.u16(), extensions.u16()) {
+        let body = extensions.take(len as usize)?;
+        if kind != 0 {
+            continue;
+        }
+        let mut names = Reader(body);
+        names.skip(2)?; // server_name_list length
+        while let (Some(name_type), Some(name_len)) = (names.u8(), names.u16()) {
+            let name = names.take(name_len as usize)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
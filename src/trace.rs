@@ -0,0 +1,326 @@
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::body::Bytes;
+use hyper::header::HeaderMap;
+use hyper::{Response, StatusCode};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::body::Body;
+use crate::capture::warc_date;
+use crate::context::RequestContext;
+use crate::pattern;
+use crate::script_manager::InjectionResult;
+use crate::streaming::{self, ChunkRewriter};
+
+/// Bytes of each body written to the trace; the rest is only counted.
+const BODY_LIMIT: usize = 1024 * 1024;
+
+/// `rusty-proxy trace`: exchanges whose URL matches one pattern are written out in full
+/// (headers, bodies, timings and what the scripts did) to a file of their own, one JSON
+/// object per line, while everything else is logged as usual.
+pub struct Tracer {
+    /// Where trace files go: `diagnostics.dump_dir`, or the system temp directory
+    dir: PathBuf,
+    session: Mutex<Option<Arc<Session>>>,
+}
+
+struct Session {
+    pattern: String,
+    url: Regex,
+    path: PathBuf,
+    file: Mutex<File>,
+    started: SystemTime,
+    /// When the trace stops by itself
+    until: Option<Instant>,
+    traced: AtomicU64,
+}
+
+impl Session {
+    fn status(&self) -> Value {
+        json!({
+            "active": true,
+            "url": self.pattern,
+            "file": self.path.display().to_string(),
+            "started": warc_date(self.started),
+            "remaining_seconds": self.until.map(|until| until.saturating_duration_since(Instant::now()).as_secs()),
+            "traced": self.traced.load(Ordering::Relaxed),
+        })
+    }
+}
+
+impl Tracer {
+    pub fn new(dump_dir: Option<&str>) -> Self {
+        Tracer {
+            dir: dump_dir.map(PathBuf::from).unwrap_or_else(std::env::temp_dir),
+            session: Mutex::new(None),
+        }
+    }
+
+    /// Starts tracing URLs matching `pattern`, where `*` matches anything, into `file` (a
+    /// name within the trace directory) for `seconds`, or until stopped. Replaces a trace
+    /// already running.
+    pub fn start(&self, pattern: &str, file: Option<&str>, seconds: Option<u64>) -> Result<Value> {
+        if pattern.is_empty() {
+            bail!("a URL pattern is needed, e.g. https://api.example.com/*");
+        }
+        let started = SystemTime::now();
+        let name = match file.filter(|name| !name.is_empty()) {
+            Some(name) if name.contains('/') || name.contains('\\') || name.starts_with('.') => {
+                bail!("the trace file must be a plain file name; it is written to {}", self.dir.display())
+            }
+            Some(name) => name.to_string(),
+            None => format!("rusty-proxy-trace-{}.jsonl", started.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)),
+        };
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(name);
+        // Traces hold cookies and tokens in full, so only the proxy's user may read them
+        let file = OpenOptions::new().create(true).append(true).mode(0o600).open(&path)?;
+        let parts: Vec<String> = pattern.split('*').map(regex::escape).collect();
+        let session = Arc::new(Session {
+            pattern: pattern.to_string(),
+            url: pattern::compile(&format!("^{}$", parts.join(".*")))?,
+            path,
+            file: Mutex::new(file),
+            started,
+            until: seconds.map(|seconds| Instant::now() + Duration::from_secs(seconds)),
+            traced: AtomicU64::new(0),
+        });
+        info!("Trace: {} into {}", pattern, session.path.display());
+        let status = session.status();
+        *self.session.lock().unwrap() = Some(session);
+        Ok(status)
+    }
+
+    /// Stops the trace, returning what it recorded.
+    pub fn stop(&self) -> Value {
+        match self.session.lock().unwrap().take() {
+            Some(session) => {
+                info!("Trace of {} stopped after {} exchanges", session.pattern, session.traced.load(Ordering::Relaxed));
+                let mut status = session.status();
+                status["active"] = Value::Bool(false);
+                status
+            }
+            None => json!({ "active": false }),
+        }
+    }
+
+    pub fn status(&self) -> Value {
+        match self.current() {
+            Some(session) => session.status(),
+            None => json!({ "active": false }),
+        }
+    }
+
+    /// The running session, after stopping one whose time is up.
+    fn current(&self) -> Option<Arc<Session>> {
+        let mut current = self.session.lock().unwrap();
+        if current.as_ref()?.until.is_some_and(|until| Instant::now() >= until) {
+            let session = current.take()?;
+            info!("Trace of {} ended after {} exchanges", session.pattern, session.traced.load(Ordering::Relaxed));
+            return None;
+        }
+        current.clone()
+    }
+
+    /// Starts the record of an exchange the trace covers.
+    pub fn begin(&self, ctx: &RequestContext) -> Option<Arc<Traced>> {
+        let session = self.current()?;
+        let url = ctx.url.to_string();
+        if !session.url.is_match(&url) {
+            return None;
+        }
+        let record = json!({
+            "at": warc_date(SystemTime::now()),
+            "method": ctx.method.as_str(),
+            "url": url,
+            "client": ctx.client_ip.to_string(),
+            "connection_id": ctx.connection_id,
+            "connection_request": ctx.connection_request,
+            "session": ctx.session_id,
+            "request": { "headers": headers_json(&ctx.headers) },
+            "scripts": { "matched": ctx.matched_scripts },
+        });
+        Some(Arc::new(Traced {
+            session,
+            started: Instant::now(),
+            record: Mutex::new(record),
+        }))
+    }
+}
+
+/// One traced exchange, filled in as it passes through the stages and written once the
+/// response body has gone out.
+pub struct Traced {
+    session: Arc<Session>,
+    started: Instant,
+    record: Mutex<Value>,
+}
+
+impl std::fmt::Debug for Traced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Traced").field("trace", &self.session.pattern).finish()
+    }
+}
+
+impl Traced {
+    /// Tees the request body the client sent into the record.
+    pub fn request_body(self: &Arc<Self>, body: Body) -> Body {
+        streaming::rewrite_body(body, BodyTee::new(self.clone(), "request"))
+    }
+
+    /// What one pass of injections did, including passes that changed nothing.
+    pub fn decision(&self, phase: &str, result: &InjectionResult) {
+        let body_after = result.body.as_ref().map(String::len).unwrap_or(result.body_len_before);
+        let decision = json!({
+            "applied": result.applied,
+            "headers_set": result.headers_set,
+            "headers_removed": result.headers_removed,
+            "body_bytes_before": result.body_len_before,
+            "body_bytes_after": body_after,
+            "snippets": result.snippets.iter().map(|(script, snippet)| json!({ "script": script, "content": snippet })).collect::<Vec<_>>(),
+        });
+        self.record.lock().unwrap()["scripts"][phase] = decision;
+    }
+
+    /// The response as the upstream sent it, before scripts ran.
+    pub fn upstream(&self, status: StatusCode, headers: &HeaderMap) {
+        self.record.lock().unwrap()["upstream"] = json!({ "status": status.as_u16(), "headers": headers_json(headers) });
+    }
+
+    /// Records the response as delivered and writes the exchange out once its body has
+    /// been sent.
+    pub fn finish(self: Arc<Self>, res: Response<Body>, upstream: Duration, inject: Duration) -> Response<Body> {
+        self.response(&res, upstream, inject);
+        let (parts, body) = res.into_parts();
+        Response::from_parts(parts, streaming::rewrite_body(body, BodyTee::new(self, "response")))
+    }
+
+    /// Records a CONNECT or upgrade answer, whose body is tunnelled rather than traced,
+    /// and writes the exchange out at once.
+    pub fn finish_tunnel(&self, res: &Response<Body>, upstream: Duration) {
+        self.response(res, upstream, Duration::ZERO);
+        self.write();
+    }
+
+    fn response(&self, res: &Response<Body>, upstream: Duration, inject: Duration) {
+        let mut record = self.record.lock().unwrap();
+        record["response"] = json!({ "status": res.status().as_u16(), "headers": headers_json(res.headers()) });
+        record["timings"] = json!({ "upstream_ms": millis(upstream), "inject_ms": millis(inject) });
+    }
+
+    fn write(&self) {
+        let mut record = self.record.lock().unwrap();
+        record["timings"]["total_ms"] = json!(millis(self.started.elapsed()));
+        let mut line = record.to_string();
+        line.push('\n');
+        if let Err(e) = self.session.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write trace to {}: {}", self.session.path.display(), e);
+            return;
+        }
+        self.session.traced.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Traced {} {} -> {}",
+            record["method"].as_str().unwrap_or(""),
+            record["url"].as_str().unwrap_or(""),
+            record["response"]["status"]
+        );
+    }
+}
+
+/// Copies a body into the record, up to `BODY_LIMIT` bytes, as it streams past.
+struct BodyTee {
+    traced: Arc<Traced>,
+    side: &'static str,
+    kept: Vec<u8>,
+    len: usize,
+    done: bool,
+}
+
+impl BodyTee {
+    fn new(traced: Arc<Traced>, side: &'static str) -> Self {
+        BodyTee {
+            traced,
+            side,
+            kept: Vec::new(),
+            len: 0,
+            done: false,
+        }
+    }
+
+    fn complete(&mut self) {
+        if std::mem::replace(&mut self.done, true) {
+            return;
+        }
+        let mut fields = Map::new();
+        fields.insert("body_bytes".to_string(), json!(self.len));
+        if self.len > 0 {
+            // A cut may fall inside a character; text stays text
+            let text = match std::str::from_utf8(&self.kept) {
+                Ok(text) => Some(text),
+                Err(e) if e.error_len().is_none() => std::str::from_utf8(&self.kept[..e.valid_up_to()]).ok(),
+                Err(_) => None,
+            };
+            match text {
+                Some(text) => fields.insert("body".to_string(), json!(text)),
+                None => fields.insert("body_base64".to_string(), json!(STANDARD.encode(&self.kept))),
+            };
+        }
+        if self.len > self.kept.len() {
+            fields.insert("body_truncated".to_string(), json!(true));
+        }
+        {
+            let mut record = self.traced.record.lock().unwrap();
+            if let Some(side) = record[self.side].as_object_mut() {
+                side.extend(fields);
+            }
+        }
+        // The exchange is complete once the response body is
+        if self.side == "response" {
+            self.traced.write();
+        }
+    }
+}
+
+impl ChunkRewriter for BodyTee {
+    fn push(&mut self, chunk: &[u8]) -> Bytes {
+        self.len += chunk.len();
+        let room = BODY_LIMIT.saturating_sub(self.kept.len());
+        self.kept.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        Bytes::copy_from_slice(chunk)
+    }
+
+    fn finish(&mut self) -> Bytes {
+        self.complete();
+        Bytes::new()
+    }
+}
+
+impl Drop for BodyTee {
+    // A client that hangs up mid-body still leaves a record of what got through
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
+
+fn headers_json(headers: &HeaderMap) -> Value {
+    Value::Array(
+        headers
+            .iter()
+            .map(|(name, value)| json!([name.as_str(), String::from_utf8_lossy(value.as_bytes())]))
+            .collect(),
+    )
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
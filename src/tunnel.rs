@@ -0,0 +1,412 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+use tracing::info;
+
+use crate::metrics::Metrics;
+use crate::tls::{self, Sniff};
+
+/// Byte counters of one tunnel: a CONNECT, or an upgraded connection (WebSocket or other
+/// protocol switched to by a 101).
+pub struct TunnelStats {
+    id: u64,
+    pub target: String,
+    /// Target port of a CONNECT, the key of the per-port totals
+    pub port: Option<u16>,
+    pub protocol: String,
+    /// `Sec-WebSocket-Protocol` the upstream selected
+    pub subprotocol: Option<String>,
+    /// Server name from the client's TLS ClientHello, if the tunnel carries TLS
+    sni: OnceLock<String>,
+    pub client: SocketAddr,
+    /// Long-lived binary protocols are exempt from `tunnel_idle_timeout`
+    pub long_lived: bool,
+    started: Instant,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    /// Milliseconds after `started` of the last byte in either direction
+    last_activity: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+impl TunnelStats {
+    fn record(&self, up: bool, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let (own, total) = if up {
+            (&self.bytes_up, &self.metrics.tunnel_bytes_up)
+        } else {
+            (&self.bytes_down, &self.metrics.tunnel_bytes_down)
+        };
+        own.fetch_add(bytes as u64, Ordering::Relaxed);
+        total.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.bytes_up.load(Ordering::Relaxed), self.bytes_down.load(Ordering::Relaxed))
+    }
+
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.get().map(String::as_str)
+    }
+
+    /// Logs the tunnel's totals once `relay` has returned with `result`.
+    pub fn log_closed(&self, result: &io::Result<(u64, u64)>) {
+        let (up, down) = self.bytes();
+        let reason = match result {
+            Ok(_) => "closed".to_string(),
+            Err(e) => e.to_string(),
+        };
+        info!(
+            "{} tunnel to {} (SNI {}) ended after {:.1}s: {} bytes up, {} down ({})",
+            self.protocol,
+            self.target,
+            self.sni().unwrap_or("-"),
+            self.started.elapsed().as_secs_f64(),
+            up,
+            down,
+            reason
+        );
+    }
+
+    fn snapshot(&self) -> Value {
+        let (up, down) = self.bytes();
+        json!({
+            "id": self.id,
+            "target": self.target,
+            "protocol": self.protocol,
+            "subprotocol": self.subprotocol,
+            "sni": self.sni(),
+            "client": self.client.to_string(),
+            "long_lived": self.long_lived,
+            "open_secs": self.started.elapsed().as_secs(),
+            "idle_secs": self.idle().as_secs(),
+            "bytes_up": up,
+            "bytes_down": down,
+        })
+    }
+}
+
+/// Totals of the CONNECT tunnels to one target port that have closed.
+#[derive(Default)]
+struct PortTotals {
+    tunnels: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    open_secs: f64,
+}
+
+/// Tunnels currently open, listed by the admin API, and totals per CONNECT target port.
+pub struct Tunnels {
+    next_id: AtomicU64,
+    open: Arc<Mutex<HashMap<u64, Arc<TunnelStats>>>>,
+    ports: Arc<Mutex<BTreeMap<u16, PortTotals>>>,
+    metrics: Arc<Metrics>,
+}
+
+/// Removes the tunnel from the open list, adding it to its port's totals, when dropped.
+pub struct TunnelGuard {
+    open: Arc<Mutex<HashMap<u64, Arc<TunnelStats>>>>,
+    ports: Arc<Mutex<BTreeMap<u16, PortTotals>>>,
+    pub stats: Arc<TunnelStats>,
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.open.lock().unwrap().remove(&self.stats.id);
+        if let Some(port) = self.stats.port {
+            let (up, down) = self.stats.bytes();
+            let mut ports = self.ports.lock().unwrap();
+            let totals = ports.entry(port).or_default();
+            totals.tunnels += 1;
+            totals.bytes_up += up;
+            totals.bytes_down += down;
+            totals.open_secs += self.stats.started.elapsed().as_secs_f64();
+        }
+    }
+}
+
+impl Tunnels {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Tunnels {
+            next_id: AtomicU64::new(0),
+            open: Arc::default(),
+            ports: Arc::default(),
+            metrics,
+        }
+    }
+
+    pub fn register(
+        &self,
+        target: String,
+        port: Option<u16>,
+        protocol: String,
+        subprotocol: Option<String>,
+        client: SocketAddr,
+        long_lived: bool,
+    ) -> TunnelGuard {
+        let stats = Arc::new(TunnelStats {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            target,
+            port,
+            protocol,
+            subprotocol,
+            sni: OnceLock::new(),
+            client,
+            long_lived,
+            started: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+            metrics: self.metrics.clone(),
+        });
+        self.open.lock().unwrap().insert(stats.id, stats.clone());
+        TunnelGuard {
+            open: self.open.clone(),
+            ports: self.ports.clone(),
+            stats,
+        }
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let open = self.open.lock().unwrap();
+        let mut tunnels: Vec<_> = open.values().collect();
+        tunnels.sort_by_key(|stats| stats.id);
+        Value::Array(tunnels.into_iter().map(|stats| stats.snapshot()).collect())
+    }
+
+    /// Closed CONNECT tunnels per target port, with the number still open.
+    pub fn port_snapshot(&self) -> Value {
+        let mut open: BTreeMap<u16, u64> = BTreeMap::new();
+        for port in self.open.lock().unwrap().values().filter_map(|stats| stats.port) {
+            *open.entry(port).or_default() += 1;
+        }
+        let ports = self.ports.lock().unwrap();
+        let mut all: Vec<u16> = ports.keys().chain(open.keys()).copied().collect();
+        all.sort_unstable();
+        all.dedup();
+        Value::Array(
+            all.into_iter()
+                .map(|port| {
+                    let closed = ports.get(&port);
+                    json!({
+                        "port": port,
+                        "open": open.get(&port).copied().unwrap_or(0),
+                        "closed": closed.map_or(0, |t| t.tunnels),
+                        "bytes_up": closed.map_or(0, |t| t.bytes_up),
+                        "bytes_down": closed.map_or(0, |t| t.bytes_down),
+                        "open_secs": closed.map_or(0.0, |t| (t.open_secs * 10.0).round() / 10.0),
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Whether the negotiated subprotocol is one of the long-lived binary ones (MQTT, STOMP,
+/// AMQP, ...) in `patterns`, matched case-insensitively as substrings so `mqttv3.1` and
+/// `v12.stomp` are covered by `mqtt` and `stomp`.
+pub fn is_long_lived(subprotocol: Option<&str>, patterns: &[String]) -> bool {
+    let subprotocol = match subprotocol {
+        Some(subprotocol) => subprotocol.to_lowercase(),
+        None => return false,
+    };
+    patterns.iter().any(|pattern| subprotocol.contains(&pattern.to_lowercase()))
+}
+
+/// Reads the client's first bytes until they hold a whole ClientHello or turn out not to
+/// be TLS, returning them with the server name. Clients of server-speaks-first protocols
+/// send nothing, so after `timeout` whatever arrived is returned without a name.
+pub async fn read_client_hello<C>(client: &mut C, timeout: Duration) -> io::Result<(Vec<u8>, Option<String>)>
+where
+    C: AsyncRead + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut hello = Vec::new();
+    loop {
+        if let Sniff::Done(sni) = tls::sniff(&hello) {
+            return Ok((hello, sni));
+        }
+        match tokio::time::timeout_at(deadline, client.read_buf(&mut hello)).await {
+            Ok(Ok(0)) | Err(_) => return Ok((hello, None)),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+}
+
+/// Relays bytes both ways until either side closes, counting them on `stats`. Frames are
+/// never inspected; only the client's first bytes are read for a TLS server name. `early`
+/// holds bytes already read from the client, sent upstream first. With a `throttle`, the
+/// tunnel relays that many bytes per second on average. Unless the tunnel is long-lived, it
+/// is closed after `idle_timeout` without traffic (zero disables the timeout).
+pub async fn relay<C, U>(
+    client: C,
+    mut upstream: U,
+    stats: Arc<TunnelStats>,
+    idle_timeout: Duration,
+    early: Vec<u8>,
+    throttle: Option<u64>,
+) -> io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client = Counted {
+        inner: client,
+        stats: stats.clone(),
+        hello: Some(Vec::new()),
+        early,
+        throttle: throttle.filter(|rate| *rate > 0).map(Throttle::new),
+    };
+    let copy = tokio::io::copy_bidirectional(&mut client, &mut upstream);
+    if idle_timeout.is_zero() || stats.long_lived {
+        return copy.await;
+    }
+
+    tokio::pin!(copy);
+    let mut check = tokio::time::interval(idle_timeout.min(Duration::from_secs(30)));
+    loop {
+        tokio::select! {
+            result = copy.as_mut() => return result,
+            _ = check.tick() => {
+                if stats.idle() >= idle_timeout {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"));
+                }
+            }
+        }
+    }
+}
+
+/// Holds a tunnel to `rate` bytes per second on average since it opened.
+struct Throttle {
+    rate: u64,
+    started: Instant,
+    bytes: u64,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Throttle {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+            wait: None,
+        }
+    }
+
+    fn poll_wait(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(wait) = self.wait.as_mut() {
+            ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn consumed(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        let due = self.started + Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        if due > Instant::now() {
+            self.wait = Some(Box::pin(tokio::time::sleep_until(due.into())));
+        }
+    }
+}
+
+/// The client side of a tunnel: reads are bytes going upstream, writes bytes coming back.
+struct Counted<T> {
+    inner: T,
+    stats: Arc<TunnelStats>,
+    /// The client's first bytes, kept until they are known to be (or not be) a ClientHello
+    hello: Option<Vec<u8>>,
+    /// Client bytes read before the relay started, handed out before reading more
+    early: Vec<u8>,
+    throttle: Option<Throttle>,
+}
+
+impl<T> Counted<T> {
+    fn sniff(&mut self, data: &[u8]) {
+        let Some(hello) = self.hello.as_mut() else { return };
+        hello.extend_from_slice(data);
+        if let Sniff::Done(sni) = tls::sniff(hello) {
+            if let Some(sni) = sni {
+                let _ = self.stats.sni.set(sni);
+            }
+            self.hello = None;
+        }
+    }
+
+    fn poll_throttle(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.throttle.as_mut() {
+            Some(throttle) => throttle.poll_wait(cx),
+            None => Poll::Ready(()),
+        }
+    }
+
+    fn consumed(&mut self, up: bool, bytes: usize) {
+        self.stats.record(up, bytes);
+        if let Some(throttle) = self.throttle.as_mut() {
+            throttle.consumed(bytes);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counted<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_throttle(cx));
+        if !self.early.is_empty() {
+            let n = self.early.len().min(buf.remaining());
+            let early: Vec<u8> = self.early.drain(..n).collect();
+            buf.put_slice(&early);
+            self.consumed(true, n);
+            self.sniff(&early);
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = &buf.filled()[before..];
+            self.consumed(true, read.len());
+            if !read.is_empty() {
+                self.sniff(read);
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counted<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_throttle(cx));
+        let result = Pin::new(&mut self.inner).poll_write(cx, data);
+        if let Poll::Ready(Ok(written)) = result {
+            self.consumed(false, written);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Result};
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+use crate::proxy::ProxyState;
+
+/// Set by the old process to the number of the listening socket its successor inherits.
+const LISTEN_FD_VAR: &str = "RUSTY_PROXY_LISTEN_FD";
+
+/// First descriptor passed by systemd socket activation (`SD_LISTEN_FDS_START`).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// How long a freshly started successor must stay up before the old process hands over.
+const STARTUP_GRACE: Duration = Duration::from_secs(1);
+
+/// Whether a listening socket was handed over, by an upgrade or by systemd, without
+/// taking it yet.
+pub fn has_inherited_listener() -> bool {
+    let for_us = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    std::env::var_os(LISTEN_FD_VAR).is_some() || for_us
+}
+
+/// The listener handed over by the previous process during an upgrade, if any.
+pub fn inherited_listener() -> Result<Option<TcpListener>> {
+    let fd = match std::env::var(LISTEN_FD_VAR) {
+        Ok(fd) => fd,
+        Err(_) => return Ok(None),
+    };
+    std::env::remove_var(LISTEN_FD_VAR);
+    let fd: RawFd = fd.parse().map_err(|_| anyhow!("Invalid {}: {}", LISTEN_FD_VAR, fd))?;
+
+    set_cloexec(fd, true)?;
+    // SAFETY: the fd was left open for us by the parent and nothing else in this process owns it
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    info!("Inherited listening socket {} from previous process", fd);
+    Ok(Some(listener))
+}
+
+/// The listener passed by systemd socket activation, following `sd_listen_fds(3)`: the
+/// variables only count when `LISTEN_PID` is this process, and are cleared so children
+/// do not pick them up.
+pub fn systemd_listener() -> Result<Option<TcpListener>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map(|pid| pid == std::process::id())
+        .unwrap_or(false);
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if !for_us || count < 1 {
+        return Ok(None);
+    }
+    if count > 1 {
+        warn!("systemd passed {} sockets, only the first is used", count);
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    set_cloexec(fd, true)?;
+    // SAFETY: systemd hands this descriptor to us alone
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    info!("Using listening socket passed by systemd");
+    Ok(Some(listener))
+}
+
+/// Starts the current binary again with the same arguments, sharing the listening socket
+/// `fd`. Both processes accept from the same queue until the caller stops, so no
+/// connection is refused during the handover. Returns the successor's pid once it has
+/// survived its startup.
+pub async fn spawn_successor(fd: RawFd) -> Result<u32> {
+    // argv[0] rather than current_exe(): once the binary has been replaced, /proc/self/exe
+    // points at the old, deleted file
+    let exe = match std::env::args_os().next() {
+        Some(argv0) => argv0,
+        None => std::env::current_exe()?.into_os_string(),
+    };
+
+    set_cloexec(fd, false)?;
+    let spawned = Command::new(&exe)
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_VAR, fd.to_string())
+        .spawn();
+    set_cloexec(fd, true)?;
+    let mut child = spawned?;
+
+    tokio::time::sleep(STARTUP_GRACE).await;
+    if let Some(status) = child.try_wait()? {
+        return Err(anyhow!("New process exited during startup ({})", status));
+    }
+    Ok(child.id())
+}
+
+/// SIGUSR2 asks the running instance to upgrade itself, like `POST /admin/upgrade`.
+pub fn spawn_signal_handler(state: Arc<ProxyState>) {
+    tokio::spawn(async move {
+        let mut usr2 = match signal(SignalKind::user_defined2()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to install SIGUSR2 handler: {}", e);
+                return;
+            }
+        };
+        while usr2.recv().await.is_some() {
+            info!("Received SIGUSR2, starting upgrade");
+            state.upgrade.notify_one();
+        }
+    });
+}
+
+fn set_cloexec(fd: RawFd, on: bool) -> Result<()> {
+    // SAFETY: fcntl on a descriptor number has no memory-safety requirements
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let flags = if on { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+        if libc::fcntl(fd, libc::F_SETFD, flags) < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
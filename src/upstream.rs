@@ -0,0 +1,173 @@
+use hyper::Uri;
+use hyper_util::client::legacy::connect::dns::{GaiAddrs, GaiResolver, Name};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::Service;
+use tracing::info;
+
+use crate::body::Body;
+use crate::config::ProxyConfig;
+use crate::metrics::Metrics;
+
+pub type UpstreamClient = Client<CountingConnector, Body>;
+
+tokio::task_local! {
+    /// Timings of the request currently being sent; set around `client.request(..)` so the
+    /// connector and resolver can attribute their work to it.
+    pub static PHASE_TIMINGS: Arc<PhaseTimings>;
+}
+
+/// Time spent in each upstream phase of one request, in microseconds. Phases that did
+/// not happen (e.g. DNS and connect on a pooled connection) stay at zero.
+#[derive(Default)]
+pub struct PhaseTimings {
+    pub dns_us: AtomicU64,
+    pub connect_us: AtomicU64,
+}
+
+impl PhaseTimings {
+    pub fn record(slot: &AtomicU64, started: Instant) {
+        slot.store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn dns(&self) -> Duration {
+        Duration::from_micros(self.dns_us.load(Ordering::Relaxed))
+    }
+
+    pub fn connect(&self) -> Duration {
+        Duration::from_micros(self.connect_us.load(Ordering::Relaxed))
+    }
+
+    /// Clones the current request's timings, if one is in scope.
+    pub fn current() -> Option<Arc<PhaseTimings>> {
+        PHASE_TIMINGS.try_with(|t| t.clone()).ok()
+    }
+}
+
+/// System resolver that reports how long each lookup took.
+#[derive(Clone)]
+pub struct TimingResolver {
+    inner: GaiResolver,
+}
+
+impl Service<Name> for TimingResolver {
+    type Response = GaiAddrs;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let timings = PhaseTimings::current();
+        let started = Instant::now();
+        let lookup = self.inner.call(name);
+        Box::pin(async move {
+            let result = lookup.await;
+            if let Some(timings) = timings {
+                PhaseTimings::record(&timings.dns_us, started);
+            }
+            result
+        })
+    }
+}
+
+/// HTTP connector that records every new upstream connection, so the pool hit ratio
+/// can be derived from the number of requests sent.
+#[derive(Clone)]
+pub struct CountingConnector {
+    inner: HttpConnector<TimingResolver>,
+    metrics: Arc<Metrics>,
+}
+
+impl Service<Uri> for CountingConnector {
+    type Response = <HttpConnector<TimingResolver> as Service<Uri>>::Response;
+    type Error = <HttpConnector<TimingResolver> as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        Metrics::incr(&self.metrics.upstream_connections_opened);
+        let connecting = self.inner.call(uri);
+        match PhaseTimings::current() {
+            Some(timings) => {
+                let started = Instant::now();
+                Box::pin(PHASE_TIMINGS.scope(timings.clone(), async move {
+                    let result = connecting.await;
+                    // Connect time excludes the DNS lookup recorded by the resolver
+                    let total = started.elapsed().as_micros() as u64;
+                    let dns = timings.dns_us.load(Ordering::Relaxed);
+                    timings.connect_us.store(total.saturating_sub(dns), Ordering::Relaxed);
+                    result
+                }))
+            }
+            None => Box::pin(connecting),
+        }
+    }
+}
+
+/// Keep-alive pool of upstream connections.
+///
+/// Idle connections are closed after `pool_idle_timeout`, by the pool's reaper running on a
+/// Tokio timer (without one the legacy client never reaps them). [`flush`](Self::flush)
+/// swaps in a fresh client so every pooled connection is dropped once in-flight requests on it
+/// finish, picking up new certificates or IPs after an upstream deployment.
+pub struct UpstreamPool {
+    client: RwLock<UpstreamClient>,
+    idle_timeout: Duration,
+    max_idle_per_host: usize,
+    metrics: Arc<Metrics>,
+}
+
+impl UpstreamPool {
+    pub fn new(config: &ProxyConfig, metrics: Arc<Metrics>) -> Self {
+        let idle_timeout = Duration::from_secs(config.pool_idle_timeout);
+        let max_idle_per_host = config.pool_max_idle_per_host;
+        let client = Self::build_client(idle_timeout, max_idle_per_host, metrics.clone());
+
+        UpstreamPool {
+            client: RwLock::new(client),
+            idle_timeout,
+            max_idle_per_host,
+            metrics,
+        }
+    }
+
+    fn build_client(idle_timeout: Duration, max_idle_per_host: usize, metrics: Arc<Metrics>) -> UpstreamClient {
+        let resolver = TimingResolver {
+            inner: GaiResolver::new(),
+        };
+        let connector = CountingConnector {
+            inner: HttpConnector::new_with_resolver(resolver),
+            metrics,
+        };
+        Client::builder(TokioExecutor::new())
+            .pool_timer(TokioTimer::new())
+            .pool_idle_timeout(idle_timeout)
+            .pool_max_idle_per_host(max_idle_per_host)
+            .build(connector)
+    }
+
+    pub fn client(&self) -> UpstreamClient {
+        Metrics::incr(&self.metrics.upstream_requests);
+        self.client.read().unwrap().clone()
+    }
+
+    pub fn flush(&self) {
+        let fresh = Self::build_client(self.idle_timeout, self.max_idle_per_host, self.metrics.clone());
+        *self.client.write().unwrap() = fresh;
+        Metrics::incr(&self.metrics.pool_flushes);
+        info!("Flushed upstream connection pool");
+    }
+}
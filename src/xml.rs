@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use sxd_document::dom::{ChildOfElement, Document, Element, ParentOfChild};
+use sxd_document::{parser, writer, QName};
+use sxd_xpath::nodeset::Node;
+use sxd_xpath::{Context, Factory, Value};
+
+/// Where `XPathReplace` puts its content relative to each matched node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XmlAction {
+    /// Replace the element; for text and attribute nodes, replace the value
+    #[default]
+    Replace,
+    Before,
+    After,
+    /// Add as the last child of the element
+    Append,
+}
+
+/// Applies `content` (an XML fragment, or plain text for text/attribute nodes) to every
+/// node `xpath` selects. `namespaces` maps the prefixes used in the XPath and fragment
+/// to namespace URIs. Returns `None` when nothing matched.
+///
+/// The original XML declaration is kept. Bodies declaring an encoding other than UTF-8
+/// are refused, since the proxy handles bodies as UTF-8 text.
+pub fn apply(
+    body: &str,
+    xpath: &str,
+    content: &str,
+    action: XmlAction,
+    namespaces: &HashMap<String, String>,
+) -> Result<Option<String>> {
+    static DECLARATION: OnceLock<Regex> = OnceLock::new();
+    let declaration_re = DECLARATION.get_or_init(|| Regex::new(r#"^\s*<\?xml[^>]*\?>"#).unwrap());
+    let declaration = declaration_re.find(body).map(|m| m.as_str().trim().to_string());
+    if let Some(declaration) = &declaration {
+        static ENCODING: OnceLock<Regex> = OnceLock::new();
+        let encoding_re = ENCODING.get_or_init(|| Regex::new(r#"(?i)encoding\s*=\s*["']([^"']+)["']"#).unwrap());
+        if let Some(encoding) = encoding_re.captures(declaration).map(|c| c[1].to_lowercase()) {
+            if encoding != "utf-8" && encoding != "utf8" && encoding != "us-ascii" {
+                return Err(anyhow!("Unsupported XML encoding {}", encoding));
+            }
+        }
+    }
+
+    let package = parser::parse(body).map_err(|e| anyhow!("Invalid XML body: {:?}", e))?;
+    let doc = package.as_document();
+
+    let expression = Factory::new()
+        .build(xpath)
+        .map_err(|e| anyhow!("Invalid XPath {}: {:?}", xpath, e))?
+        .ok_or_else(|| anyhow!("Empty XPath"))?;
+    let mut context = Context::new();
+    for (prefix, uri) in namespaces {
+        context.set_namespace(prefix, uri);
+    }
+    let nodes = match expression
+        .evaluate(&context, doc.root())
+        .map_err(|e| anyhow!("XPath {} failed: {:?}", xpath, e))?
+    {
+        Value::Nodeset(nodes) => nodes.document_order(),
+        _ => return Err(anyhow!("XPath {} does not select nodes", xpath)),
+    };
+    if nodes.is_empty() {
+        return Ok(None);
+    }
+
+    // The fragment is parsed once, inside a wrapper that declares the namespaces
+    let declarations: String = namespaces
+        .iter()
+        .map(|(prefix, uri)| format!(" xmlns:{}=\"{}\"", prefix, uri))
+        .collect();
+    let fragment_xml = format!("<rp-fragment{}>{}</rp-fragment>", declarations, content);
+    let fragment_package = parser::parse(&fragment_xml).ok();
+    let fragment = fragment_package.as_ref().and_then(|p| p.as_document().root().children()[0].element());
+
+    for node in nodes {
+        match node {
+            Node::Element(element) => {
+                let imported = match fragment {
+                    Some(fragment) => import_children(doc, fragment),
+                    None => vec![doc.create_text(content).into()],
+                };
+                place(element, imported, action)?;
+            }
+            Node::Text(text) if action == XmlAction::Replace => text.set_text(content),
+            Node::Attribute(attribute) if action == XmlAction::Replace => {
+                if let Some(parent) = attribute.parent() {
+                    parent.set_attribute_value(attribute.name(), content);
+                }
+            }
+            _ => return Err(anyhow!("XPath {} selected a node that cannot take {:?}", xpath, action)),
+        }
+    }
+
+    let mut out = Vec::new();
+    writer::format_document(&doc, &mut out)?;
+    let written = String::from_utf8(out)?;
+    let without_declaration = declaration_re.replace(&written, "");
+    Ok(Some(match declaration {
+        Some(declaration) => format!("{}{}", declaration, without_declaration),
+        None => without_declaration.into_owned(),
+    }))
+}
+
+fn place<'d>(element: Element<'d>, imported: Vec<ChildOfElement<'d>>, action: XmlAction) -> Result<()> {
+    if action == XmlAction::Append {
+        element.append_children(imported);
+        return Ok(());
+    }
+
+    match element.parent() {
+        Some(ParentOfChild::Element(parent)) => {
+            let mut children = Vec::new();
+            for child in parent.children() {
+                if child.element() == Some(element) {
+                    match action {
+                        XmlAction::Before => {
+                            children.extend(imported.iter().copied());
+                            children.push(child);
+                        }
+                        XmlAction::After => {
+                            children.push(child);
+                            children.extend(imported.iter().copied());
+                        }
+                        _ => children.extend(imported.iter().copied()),
+                    }
+                } else {
+                    children.push(child);
+                }
+            }
+            parent.replace_children(children);
+            Ok(())
+        }
+        // The document element can only be swapped for another single element
+        Some(ParentOfChild::Root(root)) if action == XmlAction::Replace => match imported.as_slice() {
+            [ChildOfElement::Element(replacement)] => {
+                root.remove_child(element);
+                root.append_child(*replacement);
+                Ok(())
+            }
+            _ => Err(anyhow!("The document element must be replaced by exactly one element")),
+        },
+        _ => Err(anyhow!("Cannot insert next to the document element")),
+    }
+}
+
+/// Copies the children of `source` (from another document) into `doc`.
+fn import_children<'d>(doc: Document<'d>, source: Element<'_>) -> Vec<ChildOfElement<'d>> {
+    source
+        .children()
+        .into_iter()
+        .map(|child| match child {
+            ChildOfElement::Element(element) => import_element(doc, element).into(),
+            ChildOfElement::Text(text) => doc.create_text(text.text()).into(),
+            ChildOfElement::Comment(comment) => doc.create_comment(comment.text()).into(),
+            ChildOfElement::ProcessingInstruction(pi) => doc.create_processing_instruction(pi.target(), pi.value()).into(),
+        })
+        .collect()
+}
+
+fn import_element<'d>(doc: Document<'d>, source: Element<'_>) -> Element<'d> {
+    let name = source.name();
+    let element = doc.create_element(QName::with_namespace_uri(name.namespace_uri(), name.local_part()));
+    element.set_preferred_prefix(source.preferred_prefix());
+    for attribute in source.attributes() {
+        let attr_name = attribute.name();
+        let copied = element.set_attribute_value(
+            QName::with_namespace_uri(attr_name.namespace_uri(), attr_name.local_part()),
+            attribute.value(),
+        );
+        copied.set_preferred_prefix(attribute.preferred_prefix());
+    }
+    element.append_children(import_children(doc, source));
+    element
+}